@@ -4,447 +4,10579 @@ package.edition = "2024"
 
 [dependencies]
 anyhow = "1.0.100"
+regex = "1"
+serde = { version = "1", features = ["derive"] }
 clap = { version = "4.5.54", features = ["derive", "env"] }
 duct = "1.1.1"
 json-patch = "4.1.0"
 jsonc-parser = { version = "0.29.0", features = ["serde"] }
-log = "0.4.29"
-serde_json = "1.0.149"
-simple_logger = "5.1.0"
+log = { version = "0.4.29", features = ["std"] }
+serde_json = { version = "1.0.149", features = ["preserve_order"] }
+colored = "3"
+time = { version = "0.3", features = ["formatting", "local-offset", "macros"] }
 toml = "0.8"
+toml_edit = "0.22"
+serde_yaml = "0.9"
+tar = "0.4"
+flate2 = "1"
+wasmi = "0.40"
+similar = "2"
+csv = "1"
+roxmltree = "0.20"
+deser-hjson = "2"
+ratatui = "0.29"
+trash = "5"
+reflink-copy = "0.1"
+sha2 = "0.10"
+notify-rust = "4"
+zbus = "5"
+blocking = "1"
+tokio = { version = "1", features = ["rt-multi-thread"] }
+memmap2 = "0.9"
+zstd = "0.13"
 
 [dev-dependencies]
 tempfile = "3.24.0"
+wat = "1"
 ---
 #![feature(iterator_try_reduce)]
 
-use std::{io::Seek, sync::LazyLock};
+use std::{io::Seek, iter::once, sync::LazyLock};
 static IGNORE_LIST: LazyLock<Vec<&'static str>> = LazyLock::new(|| {
     vec![
-        "AGENTS.md", "README.md",
+        "AGENTS.md", "README.md", "PROTECTED", "FORMAT", "NO_SEED", "KEY", "RENDER_AS",
+        "ALIASES", "SENSITIVE", "SCHEMA", "COERCE", "matrix.toml",
     ]
 });
 
 use std::path::{Path, PathBuf};
 use log::Level::*;
 
-#[derive(Debug, clap::Parser)]
+#[derive(Debug, Clone, clap::Parser)]
 /// Provides some customizations that better than nothing.
 struct Cli {
     #[arg(short, long, default_value = "patches")]
-    /// Path to the config house.
-    directory: PathBuf,
+    /// Path to the config house. Repeat for layers, like base, role and host,
+    /// where later layers override earlier ones.
+    directory: Vec<PathBuf>,
     #[arg(long, env = "HOME")]
-    /// Path to the target directory. Defaults to user home.
+    /// Path to the target directory. Defaults to user home. Use
+    /// `docker://<container>:<path>` for a directory in a running container.
     target: PathBuf,
+    #[arg(long)]
+    /// Settings of the tool. Defaults to `patch.toml` in the config house.
+    config: Option<PathBuf>,
+    #[arg(long)]
+    /// Alternate root prefixed to target paths, like a mounted image or a
+    /// container rootfs.
+    root: Option<PathBuf>,
+    #[arg(long = "user", conflicts_with = "all_users")]
+    /// Apply to the home of a user instead, owning written targets to them.
+    /// Repeat for several users.
+    users: Vec<String>,
+    #[arg(long)]
+    /// Apply to the home of every user with a login shell. Needs root.
+    all_users: bool,
+
+    #[arg(short, long, default_value_t = 8)]
+    /// Number of targets applied at once.
+    jobs: usize,
+    #[arg(long)]
+    /// Only look this deep into the config house for `.d` directories, a
+    /// top-level one being at depth 1.
+    max_depth: Option<usize>,
+    #[arg(long)]
+    /// Skip `.d` directories shallower than this.
+    min_depth: Option<usize>,
 
     #[arg(long, default_value_t = Info)]
     log_level: log::Level,
+    #[arg(long, value_enum, default_value_t = Output::Text, global = true)]
+    /// Format of reports printed to stdout.
+    output: Output,
+    #[arg(long)]
+    /// Render targets from fragments only, instead of merging them into the
+    /// existing content. A `NO_SEED` file does so for its `.d` directory.
+    no_seed: bool,
+    #[arg(long)]
+    /// Remove keys of existing structured targets that no fragment sets, so
+    /// fragments fully define them.
+    prune: bool,
+    #[arg(long, requires = "prune")]
+    /// Comment out keys pruned from TOML and YAML targets with a `removed by
+    /// patch` note, rather than deleting them.
+    comment_pruned: bool,
+    #[arg(long)]
+    /// Record what changes in JSON targets as RFC 6902 patches under the
+    /// state directory.
+    json_patch: bool,
+    #[arg(long)]
+    /// Allow targets outside the target directory, reached with `..` or
+    /// absolute paths from rules, `matrix.toml` or `ALIASES`.
+    allow_outside_target: bool,
+    #[arg(long)]
+    /// Keep the previous content of changed targets under the state
+    /// directory.
+    backup: bool,
+    #[arg(long, env = "XDG_STATE_HOME")]
+    /// Where backups are kept, under `patch/`. Defaults to `~/.local/state`.
+    state: Option<PathBuf>,
+    #[arg(long)]
+    /// Comment which fragment each key or block of TOML and text targets
+    /// comes from.
+    annotate: bool,
+    #[arg(long)]
+    /// Ask which side wins where fragments change or remove what the
+    /// existing target has: per key path for structured targets, per hunk
+    /// for text. Targets are then applied one at a time.
+    resolve: bool,
+    #[arg(long)]
+    /// Send a desktop notification when a run changes targets or fails, so
+    /// runs in the background, like those of `install-timer`, are noticed.
+    notify: bool,
+    #[arg(long)]
+    /// Write node_exporter textfile metrics of each run to this `.prom`
+    /// file: targets managed, drifted and changed, and when the run ended
+    /// and whether it failed.
+    metrics_file: Option<PathBuf>,
+    #[arg(long)]
+    /// Print the time spent on discovery, and on parsing, merging and
+    /// writing each target, slowest first. With `--output json`, statistics
+    /// of targets get their timings in milliseconds.
+    timings: bool,
+    #[arg(long, value_name = "REV")]
+    /// Only apply targets with fragments that git sees changed since this
+    /// revision, like `HEAD~1`, counting uncommitted and untracked ones. A
+    /// changed `patch.toml` applies every target.
+    since: Option<String>,
+    #[arg(long)]
+    /// Print what changes in each target: key paths added, changed and
+    /// removed for structured targets, a line diff for text.
+    diff: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(skip)]
+    settings: Settings,
+    /// User and group ids written targets are given to.
+    #[arg(skip)]
+    owner: Option<(u32, u32)>,
+}
+
+/// Settings of the tool, read from `patch.toml` at the root of the config
+/// house.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Settings {
+    /// Rewrites of `.d` directory paths, relative to the config house, into
+    /// target paths. They are tried before the built-in rules.
+    rules: Vec<Rule>,
+    /// Suffix of directories holding fragments.
+    suffix: String,
+    /// Prefix standing for a leading `.` in target names.
+    dot: String,
+    /// Guess the format of targets without a known extension from their
+    /// first fragment.
+    sniff: bool,
+    /// Backups of changed targets.
+    backups: Backups,
+    /// Formats implemented by WebAssembly modules, relative to the config
+    /// house.
+    plugins: std::collections::BTreeMap<String, PathBuf>,
+    /// Extensions standing for a format, like `yml` for `yaml`. They are
+    /// tried before the built-in ones.
+    aliases: std::collections::BTreeMap<String, String>,
+    /// How vectors of EDN fragments merge.
+    vectors: Vectors,
+    /// What becomes of a target when one of its fragments fails to parse.
+    on_parse_error: OnParseError,
+    /// Patterns of targets kept readable by their owner only, relative to
+    /// the target directory, on top of built-in ones like `.ssh/*`.
+    private: Vec<String>,
+    /// Where a summary of runs changing targets or failing is sent.
+    webhook: Option<Webhook>,
+
+    #[serde(skip)]
+    builtin_rules: std::sync::OnceLock<Vec<Rule>>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            rules: vec![],
+            suffix: ".d".into(),
+            dot: "dot-".into(),
+            sniff: true,
+            backups: Default::default(),
+            plugins: Default::default(),
+            aliases: Default::default(),
+            vectors: Default::default(),
+            on_parse_error: Default::default(),
+            private: vec![],
+            webhook: None,
+            builtin_rules: Default::default(),
+        }
+    }
+}
+
+/// An endpoint told about runs, in the message format of a chat or as
+/// plain JSON.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Webhook {
+    url: String,
+    #[serde(default)]
+    format: WebhookFormat,
+    /// Sent as a bearer token, like the access token of a Matrix user.
+    token: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum WebhookFormat {
+    /// `summary`, `body`, `failed` and changed `targets`.
+    #[default]
+    Json,
+    /// An incoming webhook of Slack, or of anything taking its `text`.
+    Slack,
+    /// The `send/m.room.message` endpoint of a room, given without the
+    /// transaction id.
+    Matrix,
+}
+
+/// What becomes of a target when one of its fragments fails to parse, for
+/// adopting messy configs a fragment at a time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum OnParseError {
+    /// The target fails.
+    #[default]
+    Fail,
+    /// The fragment is left out.
+    Skip,
+    /// The target is written as the plain concatenation of its fragments,
+    /// leaving the existing one aside.
+    Text,
+}
+
+/// Retention of backups, per target.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Backups {
+    /// Back up targets without `--backup`.
+    enabled: bool,
+    /// Number of backups kept.
+    keep: usize,
+    /// Backups older than this many days are dropped.
+    days: Option<u64>,
+    /// Move copies of replaced targets to the desktop trash instead, where
+    /// they are restored like any deleted file. Retention is left to the trash.
+    trash: bool,
+}
+
+impl Default for Backups {
+    fn default() -> Self {
+        Backups { enabled: false, keep: 10, days: None, trash: false }
+    }
+}
+
+/// A regex rewrite of a path, with `$1` or `${name}` for captured groups.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    #[serde(deserialize_with = "deserialize_regex")]
+    pattern: regex::Regex,
+    replace: String,
+    #[serde(default)]
+    /// Skip the remaining rules once this one matches.
+    last: bool,
+}
+
+fn deserialize_regex<'de, D: serde::Deserializer<'de>>(d: D)
+    -> Result<regex::Regex, D::Error>
+{
+    use serde::Deserialize;
+    regex::Regex::new(&String::deserialize(d)?).map_err(serde::de::Error::custom)
+}
+
+impl Settings {
+    fn load(cli: &Cli) -> Result<Self> {
+        use anyhow::Context;
+
+        let paths = match &cli.config {
+            Some(path) => vec![path.clone()],
+            None => cli.directory.iter()
+                .map(|layer| layer.join("patch.toml"))
+                .filter(|path| path.exists())
+                .collect(),
+        };
+        // Keys of later layers replace those of earlier ones.
+        let mut table = toml::Table::new();
+        for path in paths {
+            let text = std::fs::read_to_string(&path).context(format!("Read {path:?}"))?;
+            let layer: toml::Table = toml::from_str(&text)
+                .context(format!("Parse {path:?}"))?;
+            table.extend(layer);
+        }
+        table.try_into().context("Parse settings")
+    }
+
+    /// Rules stripping the suffix and turning the dot token into `.`.
+    fn builtin_rules(&self) -> &[Rule] {
+        use regex::{escape, Regex};
+
+        self.builtin_rules.get_or_init(|| {
+            [
+                (format!("{}$", escape(&self.suffix)), ""),
+                (escape(&self.dot), "."),
+            ]
+            .map(|(pattern, replace)| Rule {
+                pattern: Regex::new(&pattern).expect("Escaped patterns are valid"),
+                replace: replace.into(),
+                last: false,
+            })
+            .into()
+        })
+    }
+
+    /// The format an extension stands for.
+    fn format(&self, extension: Option<String>) -> Option<String> {
+        const ALIASES: [(&str, &str); 6] = [
+            ("yml", "yaml"), ("jsonc", "json"), ("json5", "json"),
+            ("conf", "ini"), ("cfg", "ini"), ("tml", "toml"),
+        ];
+
+        let extension = extension?.to_lowercase();
+        let alias = self.aliases.get(&extension).map(String::as_str)
+            .or_else(|| ALIASES.iter().find(|(from, _)| *from == extension).map(|(_, to)| *to));
+        Some(alias.map_or(extension, str::to_string))
+    }
+
+    /// Whether a path in the config house names a `.d` directory.
+    fn is_directory(&self, path: &Path) -> bool {
+        path.to_string_lossy().ends_with(&self.suffix)
+    }
+
+    /// Maps a path relative to the config house to its path relative to the
+    /// target.
+    fn canonicalize(&self, relative: &Path) -> PathBuf {
+        let mut path = relative.to_string_lossy().trim().to_string();
+        for rule in self.rules.iter().chain(self.builtin_rules()) {
+            let matched = rule.pattern.is_match(&path);
+            path = rule.pattern.replace_all(&path, rule.replace.as_str()).into_owned();
+            if matched && rule.last {
+                break;
+            }
+        }
+        path.into()
+    }
+
+    /// Inverse of the built-in rules: maps a path relative to the target to
+    /// its `.d` directory in the config house.
+    fn uncanonicalize(&self, relative: &Path) -> PathBuf {
+        let mut path: PathBuf = relative.iter()
+            .map(|c| match c.to_string_lossy().strip_prefix('.') {
+                Some(rest) => format!("{}{rest}", self.dot),
+                None => c.to_string_lossy().into_owned(),
+            })
+            .collect();
+        path.as_mut_os_string().push(&self.suffix);
+        path
+    }
+}
+
+impl Cli {
+    /// Where the target directory is.
+    fn host(&self) -> Host {
+        match self.target.to_str().and_then(|t| t.strip_prefix("docker://")) {
+            Some(rest) => Host::Docker(
+                rest.split_once(':').map_or(rest, |(container, _)| container).into()
+            ),
+            None => Host::Local,
+        }
+    }
+
+    /// The target directory on its host, moved under `--root` if given.
+    fn target_root(&self) -> PathBuf {
+        let target = match self.target.to_str().and_then(|t| t.strip_prefix("docker://")) {
+            Some(rest) => PathBuf::from(rest.split_once(':').map_or("/", |(_, path)| path)),
+            None => self.target.clone(),
+        };
+        match &self.root {
+            Some(root) => root.join(target.strip_prefix("/").unwrap_or(&target)),
+            None => target,
+        }
+    }
+
+    /// Path of a file relative to the target directory, given with or without
+    /// `--root`.
+    fn relative<'a>(&self, file: &'a Path) -> Option<&'a Path> {
+        file.strip_prefix(self.target_root())
+            .or_else(|_| file.strip_prefix(&self.target))
+            .ok()
+    }
+
+    /// Patterns of private targets matching a target, built-in ones first.
+    fn private_patterns(&self, target: &Path) -> Vec<&str> {
+        let Some(relative) = self.relative(target).and_then(Path::to_str) else {
+            return vec![];
+        };
+        PRIVATE.into_iter()
+            .chain(self.settings.private.iter().map(String::as_str))
+            .filter(|pattern| glob_match(pattern, relative))
+            .collect()
+    }
+
+    /// Path of a target relative to the target directory, once `.` and `..`
+    /// are resolved, or `None` for one outside it. Paths are compared as
+    /// written, as targets may not exist yet.
+    fn contained(&self, target: &Path) -> Option<PathBuf> {
+        use std::path::Component;
+
+        fn normalize(path: &Path) -> Option<PathBuf> {
+            let mut normal = PathBuf::new();
+            for component in path.components() {
+                match component {
+                    Component::CurDir => {}
+                    Component::ParentDir => if !normal.pop() {
+                        return None;
+                    },
+                    component => normal.push(component),
+                }
+            }
+            Some(normal)
+        }
+
+        let (target, root) = normalize(target).zip(normalize(&self.target_root()))?;
+        target.strip_prefix(root).ok().map(Path::to_path_buf)
+    }
+
+    /// Fails for a target outside the target directory, unless
+    /// `--allow-outside-target`.
+    fn confine(&self, target: &Path) -> Result<()> {
+        if self.allow_outside_target || self.contained(target).is_some() {
+            return Ok(());
+        }
+        anyhow::bail!(Failure::Validation(format!(
+            "Target {target:?} is outside {:?}, pass `--allow-outside-target` to allow it",
+            self.target_root()
+        )))
+    }
+
+    /// Where backups and other state of the tool are kept.
+    fn state_dir(&self) -> PathBuf {
+        self.state.clone()
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+            .unwrap_or_default()
+            .join("patch")
+    }
+
+    /// The last layer of the config house, where new fragments go.
+    fn layer(&self) -> &Path {
+        self.directory.last().expect("Clap gives at least the default")
+    }
+
+    /// The layer of the config house a path is in.
+    fn layer_of(&self, path: &Path) -> Option<&Path> {
+        self.directory.iter()
+            .filter(|layer| path.starts_with(layer))
+            .max_by_key(|layer| layer.components().count())
+            .map(PathBuf::as_path)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+enum Output {
+    Text,
+    Json,
+    /// Annotations of GitHub Actions for findings of `lint`, `test`, `fmt`,
+    /// `dedupe` and `factor`, text otherwise.
+    Github,
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+enum Command {
+    /// Decompose an existing target into fragments of its `.d` directory.
+    Split(SplitArgs),
+    /// Check the config house for problems without touching any target.
+    Lint,
+    /// Rewrite JSON, TOML and YAML fragments the way targets are written.
+    Fmt(FmtArgs),
+    /// Remove keys of JSON, TOML and YAML fragments set to the value earlier
+    /// fragments already give them.
+    Dedupe(DedupeArgs),
+    /// Move keys several layers, like hosts, set to the same value into a
+    /// fragment of the first layer.
+    Factor(FactorArgs),
+    /// Check the environment the config house is applied in.
+    Doctor,
+    /// Scaffold the config house from existing files.
+    Init(InitArgs),
+    /// Create a fragment, numbered after the existing ones.
+    New(NewArgs),
+    /// Edit a fragment in `$EDITOR`, then apply its target.
+    Edit(EditArgs),
+    /// Print every `.d` directory with the target it renders to.
+    List,
+    /// Print how fragments feed targets.
+    Graph(GraphArgs),
+    /// Tell which fragments set the value at a key path of a target.
+    Why(WhyArgs),
+    /// Print the value at a key path of a target as it would be rendered.
+    Get(GetArgs),
+    /// Render every expected target and check it against expectations.
+    Test(TestArgs),
+    /// List key paths set to different values by several fragments.
+    Conflicts,
+    /// Render every target into another form, without writing targets.
+    Export(ExportArgs),
+    /// Write a shell script carrying every rendered target, which writes
+    /// them on machines without Rust.
+    Bake(BakeArgs),
+    /// Browse and restore backups of targets.
+    #[command(subcommand)]
+    Backups(BackupsCommand),
+    /// Manage git hooks of the repository holding the config house.
+    #[command(subcommand)]
+    Hook(HookCommand),
+    /// Put back what the last run changed in every target it wrote. Repeat
+    /// to undo earlier runs.
+    Undo,
+    /// Browse targets with their pending changes, and apply or skip them one
+    /// by one.
+    Ui,
+    /// Serve `Apply` and `Status` on the D-Bus session bus, emitting
+    /// `Changed` whenever an apply changes targets.
+    Serve,
+    /// Write a systemd user service and timer applying the config house
+    /// periodically with the current options.
+    InstallTimer(InstallTimerArgs),
+    /// Write an XDG autostart entry, or a launchd agent on macOS, applying
+    /// the config house at login with the current options.
+    InstallLogin(InstallLoginArgs),
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct SplitArgs {
+    /// Path to the target file.
+    file: PathBuf,
+    #[arg(long, value_enum, default_value_t = SplitBy::TopLevelKeys)]
+    /// How the target is cut into fragments.
+    by: SplitBy,
+    #[arg(long)]
+    /// Write into a non-empty `.d` directory, replacing same-named fragments.
+    force: bool,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct FmtArgs {
+    #[arg(long)]
+    /// Only print fragments that are not formatted, failing if any is.
+    check: bool,
+    #[arg(long)]
+    /// Sort keys of JSON and YAML fragments. TOML keys are always sorted.
+    sort: bool,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct DedupeArgs {
+    #[arg(long)]
+    /// Only print redundant keys, failing if there is any.
+    check: bool,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct FactorArgs {
+    #[arg(long, value_delimiter = ',', required = true)]
+    /// Layers to factor, each given with `--directory` too.
+    targets: Vec<PathBuf>,
+    #[arg(long)]
+    /// Only print shared keys, failing if there is any.
+    check: bool,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct InitArgs {
+    #[arg(long)]
+    /// Directory to walk for existing files. Defaults to the target.
+    from: Option<PathBuf>,
+    #[arg(long)]
+    /// Pick files to manage interactively instead of taking all of them.
+    select: bool,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct NewArgs {
+    /// Fragment path relative to the config house, like
+    /// `dot-config/nvim/init.lua.d/20-lsp`.
+    fragment: PathBuf,
+    #[arg(long)]
+    /// File to copy as the initial content, instead of an empty skeleton.
+    template: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct EditArgs {
+    /// A fragment, or a target whose last fragment is edited.
+    file: PathBuf,
+    #[arg(long, env = "EDITOR", default_value = "vi")]
+    /// Editor command, the fragment path is appended to it.
+    editor: String,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct GraphArgs {
+    #[arg(long)]
+    /// Print a Graphviz graph instead of an indented tree.
+    dot: bool,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct WhyArgs {
+    /// Path to the target file.
+    file: PathBuf,
+    /// Key path like `.editor.fontSize` or `.servers[0]."host.name"`.
+    path: KeyPath,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct GetArgs {
+    /// Path to the target file.
+    file: PathBuf,
+    /// Key path like `.editor.fontSize`, `editor.fontSize` or
+    /// `$.servers[0].host`.
+    path: KeyPath,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct TestArgs {
+    #[arg(long)]
+    /// Expectations of rendered targets. Defaults to `expectations.toml` in
+    /// the config house, which is optional with `--golden`.
+    expectations: Option<PathBuf>,
+    #[arg(long)]
+    /// Directory of expected copies of every target, relative to the target
+    /// directory, to compare rendered targets with.
+    golden: Option<PathBuf>,
+    #[arg(long, requires = "golden")]
+    /// Write rendered targets into the golden directory instead of comparing.
+    bless: bool,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct ExportArgs {
+    #[arg(long, value_enum)]
+    format: ExportFormat,
+    #[arg(long)]
+    /// Namespace of exported Kubernetes objects.
+    namespace: Option<String>,
+    #[arg(short = 'o', long = "out")]
+    /// File to write to. Defaults to stdout.
+    out: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct BakeArgs {
+    #[arg(short = 'o', long = "out")]
+    /// Script to write.
+    out: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+enum ExportFormat {
+    /// One Kubernetes ConfigMap manifest per target.
+    Configmap,
+    /// A tar archive of targets relative to the target directory, gzipped
+    /// when the output file ends with `.gz` or `.tgz`.
+    Tar,
+    /// A POSIX shell script writing every target into the directory it is
+    /// given, `$HOME` by default, with heredocs and `install`.
+    Sh,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct InstallTimerArgs {
+    #[arg(long, default_value = "1h")]
+    /// Time between runs, in systemd notation like `30min` or `1h`.
+    interval: String,
+    #[arg(long)]
+    /// Directory to write units to. Defaults to `~/.config/systemd/user`.
+    units: Option<PathBuf>,
+    #[arg(long)]
+    /// Only write the units, without enabling the timer.
+    no_enable: bool,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct InstallLoginArgs {
+    #[arg(long)]
+    /// Directory to write the entry to. Defaults to `~/.config/autostart`,
+    /// or `~/Library/LaunchAgents` on macOS.
+    dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+enum BackupsCommand {
+    /// Print backups, newest first.
+    List {
+        /// Path to a target file. Defaults to every backed up target.
+        file: Option<PathBuf>,
+    },
+    /// Write a backup back to its target.
+    Restore {
+        /// Path to the target file.
+        file: PathBuf,
+        #[arg(long)]
+        /// Backup to restore, as printed by `backups list`. Defaults to the
+        /// newest one.
+        at: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+enum HookCommand {
+    /// Install a pre-commit hook running `lint` and `fmt --check` on the
+    /// staged config house.
+    Install {
+        #[arg(long)]
+        /// Replace a pre-commit hook not written by this tool.
+        force: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+enum SplitBy {
+    /// One fragment per top-level key.
+    TopLevelKeys,
+    /// One fragment per table, with top-level scalars gathered in `root`.
+    /// Plain text is cut at blank lines.
+    Sections,
 }
 
 use anyhow::Result;
-fn main() -> Result<()> {
+
+/// Failures automation may tell apart, by exit code or by the `error` field
+/// of JSON output. Other errors exit with 1.
+#[derive(Debug)]
+enum Failure {
+    /// Finding `.d` directories and fragments.
+    Discovery(String),
+    /// Reading a fragment, target or marker file in its format.
+    Parse(String),
+    /// Fragments disagreeing, like overriding a final fragment.
+    Conflict(String),
+    /// Reading or writing files.
+    Io(String),
+    /// Lint, doctor or test finding problems.
+    Validation(String),
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Failure::*;
+        let (Discovery(message) | Parse(message) | Conflict(message) | Io(message)
+            | Validation(message)) = self;
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for Failure {}
+
+impl Failure {
+    /// Category and exit code of an error, judged by its outermost failure.
+    fn of(error: &anyhow::Error) -> (&'static str, u8) {
+        use Failure::*;
+        match error.downcast_ref::<Failure>() {
+            Some(Discovery(_)) => ("discovery", 3),
+            Some(Parse(_)) => ("parse", 4),
+            Some(Conflict(_)) => ("conflict", 5),
+            Some(Io(_)) => ("io", 6),
+            Some(Validation(_)) => ("validation", 7),
+            None if error.downcast_ref::<std::io::Error>().is_some() => ("io", 6),
+            None => ("other", 1),
+        }
+    }
+}
+
+/// Logs to stdout, coloring levels and targets on terminals. `NO_COLOR`
+/// turns colors off and `CLICOLOR_FORCE` on, like for other tools.
+struct Logger {
+    level: log::Level,
+    offset: time::UtcOffset,
+}
+
+impl Logger {
+    fn init(level: log::Level) {
+        use std::io::IsTerminal;
+
+        let env = |name| std::env::var(name).ok().filter(|v| !v.is_empty());
+        colored::control::set_override(colors(
+            env("NO_COLOR").is_some(),
+            env("CLICOLOR_FORCE").is_some_and(|v| v != "0"),
+            std::io::stdout().is_terminal(),
+        ));
+        // The local offset is only known while no other thread runs.
+        let offset = time::UtcOffset::current_local_offset()
+            .unwrap_or(time::UtcOffset::UTC);
+        log::set_max_level(level.to_level_filter());
+        log::set_boxed_logger(Box::new(Logger { level, offset }))
+            .expect("Logger is set once");
+    }
+
+    fn line(&self, record: &log::Record) -> String {
+        use colored::{Color, Colorize};
+        const TARGETS: [Color; 5] =
+            [Color::Blue, Color::Green, Color::Magenta, Color::Cyan, Color::Yellow];
+
+        let level = format!("{:<5}", record.level());
+        let level = match record.level() {
+            Error => level.red().bold(),
+            Warn => level.yellow().bold(),
+            Info => level.cyan(),
+            Debug => level.purple(),
+            Trace => level.normal(),
+        };
+        let target = record.target();
+        let hash = target.bytes().fold(0usize, |h, b| h.wrapping_mul(31) + b as usize);
+        let time = time::OffsetDateTime::now_utc().to_offset(self.offset)
+            .format(time::macros::format_description!(
+                "[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:3]"
+            ))
+            .unwrap_or_default();
+        format!("{} {level} [{}] {}",
+            time.dimmed(), target.color(TARGETS[hash % TARGETS.len()]), record.args())
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            println!("{}", self.line(record));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Whether to color output, `NO_COLOR` winning over `CLICOLOR_FORCE`, which
+/// wins over the terminal check.
+fn colors(no_color: bool, force: bool, terminal: bool) -> bool {
+    !no_color && (force || terminal)
+}
+
+fn main() -> std::process::ExitCode {
     use clap::Parser;
     let cli = Cli::parse();
 
-    use simple_logger::init_with_level;
-    init_with_level(cli.log_level)?;
+    Logger::init(cli.log_level);
     log::debug!("Starting logger in `{}` mode", cli.log_level);
 
-    start(cli)
+    let output = cli.output;
+    let Err(error) = start(cli) else {
+        return std::process::ExitCode::SUCCESS;
+    };
+    let (category, code) = Failure::of(&error);
+    match output {
+        Output::Text | Output::Github => eprintln!("Error: {error:?}"),
+        Output::Json => println!("{:#}", serde_json::json!({
+            "error": category,
+            "message": format!("{error:#}"),
+        })),
+    }
+    code.into()
 }
 
 /// Separated for test purpose.
-fn start(cli: Cli) -> Result<()> {
-    use anyhow::Context;
+fn start(mut cli: Cli) -> Result<()> {
+    cli.directory = cli.directory.iter().map(|layer| expand_path(layer)).collect::<Result<_>>()?;
+    cli.target = expand_path(&cli.target)?;
+    for path in [&mut cli.config, &mut cli.root, &mut cli.state].into_iter().flatten() {
+        *path = expand_path(path)?;
+    }
+    cli.settings = Settings::load(&cli)?;
+    for (format, path) in &cli.settings.plugins {
+        let path = expand_path(path)?;
+        let path = cli.directory.iter().rev()
+            .map(|layer| layer.join(&path))
+            .find(|path| path.exists())
+            .unwrap_or(path.clone());
+        Plugin::register(format, &path)?;
+    }
+
+    if cli.command.is_none() && (cli.all_users || !cli.users.is_empty()) {
+        return apply_users(cli);
+    }
+    match &cli.command {
+        Some(Command::Split(args)) => split(&cli, args),
+        Some(Command::Lint) => lint(&cli),
+        Some(Command::Fmt(args)) => fmt(&cli, args),
+        Some(Command::Dedupe(args)) => dedupe(&cli, args),
+        Some(Command::Factor(args)) => factor(&cli, args),
+        Some(Command::Doctor) => doctor(&cli),
+        Some(Command::Init(args)) => init(&cli, args),
+        Some(Command::New(args)) => new(&cli, args),
+        Some(Command::Edit(args)) => edit(&cli, args, std::io::stdin().lock()),
+        Some(Command::List) => list(&cli),
+        Some(Command::Graph(args)) => graph(&cli, args).map(|g| print!("{g}")),
+        Some(Command::Why(args)) => why(&cli, args),
+        Some(Command::Get(args)) => get(&cli, args).map(|v| println!("{v}")),
+        Some(Command::Test(args)) => test(&cli, args),
+        Some(Command::Conflicts) => conflicts(&cli),
+        Some(Command::Export(args)) => export(&cli, args),
+        Some(Command::Bake(args)) => bake(&cli, args),
+        Some(Command::Backups(command)) => backups(&cli, command),
+        Some(Command::Hook(HookCommand::Install { force })) => install_hook(&cli, *force),
+        Some(Command::Undo) => undo(&cli),
+        Some(Command::Ui) => ui(&cli),
+        Some(Command::Serve) => serve(cli),
+        Some(Command::InstallTimer(args)) => install_timer(&cli, args),
+        Some(Command::InstallLogin(args)) => install_login(&cli, args),
+        None => apply(&cli).map(drop),
+    }
+}
+
+/// Expands a leading `~` or `~user`, and `$VAR` or `${VAR}`, in a path, so
+/// paths written on one machine fit another.
+fn expand_path(path: &Path) -> Result<PathBuf> {
+    use anyhow::{bail, Context};
     use duct::cmd;
 
-    cmd!("find", &cli.directory).pipe(cmd!("grep", "-e", r"\.d$"))
-    .read()?
+    static VARIABLE: LazyLock<regex::Regex> = LazyLock::new(|| {
+        regex::Regex::new(r"\$(?:(\w+)|\{(\w+)\})").expect("Valid regex")
+    });
 
-    .lines()
-    .map(|directory| {
-        fn canonicalize(directory: &str) -> String {
-            directory
-            .trim()
-            .trim_end_matches(".d")
-            .replace("dot-", ".")
+    let Some(text) = path.to_str() else {
+        return Ok(path.into());
+    };
+    let mut unset = None;
+    let text = VARIABLE.replace_all(text, |captures: &regex::Captures| {
+        let name = captures.get(1).or(captures.get(2)).expect("One group matches").as_str();
+        std::env::var(name).unwrap_or_else(|_| {
+            unset = Some(name.to_string());
+            String::new()
+        })
+    });
+    if let Some(name) = unset {
+        bail!("`${name}` in {path:?} is not set");
+    }
+
+    let (head, rest) = text.split_once('/').unwrap_or((&text, ""));
+    let home = match head.strip_prefix('~') {
+        None => return Ok(text.as_ref().into()),
+        Some("") => std::env::var("HOME").context(format!("Expand `~` in {path:?}"))?,
+        Some(user) => cmd!("getent", "passwd", user).read()
+            .context(format!("Unknown user `{user}` in {path:?}"))?
+            .split(':').nth(5).unwrap_or_default().to_string(),
+    };
+    Ok(match rest {
+        "" => home.into(),
+        rest => Path::new(&home).join(rest),
+    })
+}
+
+/// Files from this size on are mapped into memory rather than read into a
+/// buffer.
+const MAP_SIZE: u64 = 1 << 20;
+
+/// Reads a text file. Large ones, like merged blocklists, are mapped so the
+/// text is only copied once validated.
+fn read_text(path: &Path) -> std::io::Result<String> {
+    let file = std::fs::File::open(path)?;
+    if file.metadata()?.len() < MAP_SIZE {
+        return std::io::read_to_string(file);
+    }
+    // SAFETY: The map is dropped before returning, and a config house is not
+    // expected to be truncated under a run.
+    let map = unsafe { memmap2::Mmap::map(&file)? };
+    std::str::from_utf8(&map)
+        .map(String::from)
+        .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))
+}
+
+/// Whether a file holds these bytes already, comparing large files through
+/// a map. Errors, like the file missing, count as not.
+fn same_content(path: &Path, bytes: &[u8]) -> bool {
+    use std::io::Read;
+
+    let compare = || -> std::io::Result<bool> {
+        let mut file = std::fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        if len != bytes.len() as u64 {
+            return Ok(false);
+        }
+        if len < MAP_SIZE {
+            let mut content = Vec::with_capacity(bytes.len());
+            file.read_to_end(&mut content)?;
+            return Ok(content == bytes);
         }
+        // SAFETY: As in `read_text`.
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        Ok(*map == *bytes)
+    };
+    compare().unwrap_or(false)
+}
 
-        fn replace_prefix(from: &Path, to: &Path, path: impl AsRef<Path>)
-            -> Result<PathBuf>
-        {
-            Ok(to.join(
-                path.as_ref().strip_prefix(from)
-                    .context("Strip prefix")?
-            ))
+/// Format of a target, judged by its extension. Extensions are lowercased,
+/// as files from case-insensitive filesystems carry odd casing.
+fn extension(path: &Path) -> Option<String> {
+    // The format of `config.json.gz` is the one of `config.json`.
+    let path = match Compression::of(path) {
+        Some(_) => Path::new(path.file_stem()?),
+        None => path,
+    };
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_lowercase)
+}
+
+/// Compression of a target, by its last extension. Targets are merged
+/// decompressed and compressed again on write.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn of(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "gz" => Some(Compression::Gzip),
+            "zst" => Some(Compression::Zstd),
+            _ => None,
         }
+    }
 
-        let target = replace_prefix(
-            &cli.directory, &cli.target, canonicalize(directory)
-        )
-        .context("Get target")?;
+    fn compress(self, text: &str) -> Result<Vec<u8>> {
+        use std::io::Write;
 
-        let format = target.extension()
-            .and_then(|e| e.to_str())
-            .map(str::to_string);
+        Ok(match self {
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(vec![], Default::default());
+                encoder.write_all(text.as_bytes())?;
+                encoder.finish()?
+            }
+            Compression::Zstd => zstd::encode_all(text.as_bytes(), 0)?,
+        })
+    }
 
-        return Ok((format, target, directory));
-    })
+    fn decompress(self, bytes: &[u8]) -> Result<String> {
+        use std::io::Read;
 
-    .map(|r| r.and_then(|(format, target, directory)| {
-        use std::fs::OpenOptions;
-        use std::iter::once;
+        let mut text = String::new();
+        match self {
+            Compression::Gzip => flate2::read::MultiGzDecoder::new(bytes).read_to_string(&mut text)?,
+            Compression::Zstd => zstd::Decoder::new(bytes)?.read_to_string(&mut text)?,
+        };
+        Ok(text)
+    }
+}
+
+/// The `.d` directories of a target across layers, in layer order, and the
+/// target they render to.
+#[derive(Debug, Clone)]
+struct Entry {
+    directories: Vec<PathBuf>,
+    target: PathBuf,
+    format: Option<String>,
+    host: Host,
+    /// Remove keys of the existing target that no fragment sets.
+    prune: bool,
+    /// Merge fragments into the existing target.
+    seed: bool,
+    /// Column keying the rows of CSV and TSV targets, from a `KEY` file.
+    key: Option<String>,
+    /// How vectors of EDN targets merge.
+    vectors: Vectors,
+    /// What becomes of the target when a fragment fails to parse.
+    on_parse_error: OnParseError,
+    /// User and group ids written targets are given to.
+    owner: Option<(u32, u32)>,
+    /// Format of the fragments, when the target is rendered in another one
+    /// as `RENDER_AS` lists.
+    fragment_format: Option<String>,
+    /// Values of the `matrix.toml` combination the target is rendered for,
+    /// standing for `{{name}}` in fragments.
+    variables: Vec<(String, String)>,
+}
 
-        log::trace!("Opening {target:?}");
-        let target =
-            OpenOptions::new().read(true).write(true).create(true)
-                .open(target)?;
+/// A `matrix.toml` of a `.d` directory, rendering a target per combination
+/// of the values of its variables.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Matrix {
+    /// Path of the targets under the target directory, with `{{name}}` for
+    /// variables.
+    target: String,
+    variables: std::collections::BTreeMap<String, Vec<String>>,
+}
 
-        let result =
-            once(target.try_clone())
-            .filter(|result| result.as_ref().is_ok_and(|file| {
-                file.metadata().is_ok_and(|m| m.len() > 0)
-            }))
-            .chain(
-                cmd!("ls", &directory).read()
-                    .context(format!("`ls` files in {directory}"))?
-                .lines()
+/// Where targets are read and written.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+enum Host {
+    Local,
+    /// A running container, reached with `docker exec`.
+    Docker(String),
+}
 
-                .filter(|s| !IGNORE_LIST.contains(s))
-                .map(|s| Path::new(directory).join(s))
+impl Host {
+    /// Content of a file, empty if it does not exist. Compressed files are
+    /// decompressed.
+    fn read(&self, path: &Path) -> Result<String> {
+        use anyhow::Context;
+        use duct::cmd;
 
-                .map(|path| {
-                    log::trace!("Opening {path:?}");
-                    OpenOptions::new().read(true).open(&path)
-                })
+        log::trace!("Opening {path:?} on {self:?}");
+        if let Some(compression) = Compression::of(path) {
+            let bytes = self.read_bytes(path)?;
+            if bytes.is_empty() {
+                return Ok(String::new());
+            }
+            return compression.decompress(&bytes)
+                .context(Failure::Io(format!("Decompress {path:?} on {self:?}")));
+        }
+        match self {
+            Host::Local if !path.exists() => Ok(String::new()),
+            Host::Local => read_text(path).context(Failure::Io(format!("Read {path:?}"))),
+            Host::Docker(container) => cmd!(
+                "docker", "exec", container,
+                "sh", "-c", r#"test ! -e "$1" || cat -- "$1""#, "sh", path
             )
-            // Maps IO error to anyhow error.
-            .map(|result| {
-                result.map_err(Into::into)
-            })
-            // Read content of opened files.
-            .map(|result| result.and_then(|mut file| {
-                use std::io::Read;
+            .read()
+            .context(Failure::Io(format!("Read {path:?} in container `{container}`"))),
+        }
+    }
 
-                let mut buf = String::new();
-                file.read_to_string(&mut buf)?;
+    fn read_bytes(&self, path: &Path) -> Result<Vec<u8>> {
+        use anyhow::Context;
+        use duct::cmd;
 
-                log::trace!("Reading {} bytes", buf.len());
-                Ok(buf)
-            }))
-            .map(|result| result.and_then(|text| {
-                Config::parse_dispatch(&format, text)
-            }))
-            // Concatenate with the Mereable trait.
-            .try_fold(Default::default(), Config::try_merge)?;
-
-        fn write_back(text: String, mut f: std::fs::File) -> Result<()> {
-            use std::io::Write;
-            // Write from file start.
-            f.rewind()?;
-            f.write_all(text.as_bytes())?;
-            // Truncate rest content.
-            let pos = f.stream_position()?;
-            f.set_len(pos)?;
-            Ok(())
+        match self {
+            Host::Local if !path.exists() => Ok(vec![]),
+            Host::Local => std::fs::read(path).context(Failure::Io(format!("Read {path:?}"))),
+            Host::Docker(container) => cmd!(
+                "docker", "exec", container,
+                "sh", "-c", r#"test ! -e "$1" || cat -- "$1""#, "sh", path
+            )
+            .stdout_capture()
+            .run()
+            .map(|output| output.stdout)
+            .context(Failure::Io(format!("Read {path:?} in container `{container}`"))),
+        }
+    }
+
+    /// Writes a file, compressed as its extension says.
+    fn write(&self, path: &Path, text: &str) -> Result<()> {
+        use anyhow::Context;
+
+        log::trace!("Opening {path:?} on {self:?}");
+        match Compression::of(path) {
+            Some(compression) => self.write_bytes(path, &compression.compress(text)
+                .context(Failure::Io(format!("Compress {path:?}")))?),
+            None => self.write_bytes(path, text.as_bytes()),
+        }
+    }
+
+    fn write_bytes(&self, path: &Path, bytes: &[u8]) -> Result<()> {
+        use anyhow::Context;
+        use duct::cmd;
+
+        match self {
+            Host::Local if same_content(path, bytes) => {
+                log::trace!("{path:?} is up to date");
+                Ok(())
+            }
+            Host::Local => {
+                use std::fs::OpenOptions;
+                use std::io::Write;
+
+                let mut f =
+                    OpenOptions::new().read(true).write(true).create(true)
+                        .truncate(false).open(path)?;
+                // Write from file start.
+                f.rewind()?;
+                f.write_all(bytes)?;
+                // Truncate rest content.
+                let pos = f.stream_position()?;
+                f.set_len(pos)?;
+                Ok(())
+            }
+            Host::Docker(container) => cmd!(
+                "docker", "exec", "-i", container,
+                "sh", "-c", r#"cat > "$1""#, "sh", path
+            )
+            .stdin_bytes(bytes)
+            .run()
+            .map(drop)
+            .context(Failure::Io(format!("Write {path:?} in container `{container}`"))),
+        }
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        use anyhow::Context;
+        use duct::cmd;
+
+        match self {
+            Host::Local => std::fs::remove_file(path)
+                .context(Failure::Io(format!("Remove {path:?}"))),
+            Host::Docker(container) => cmd!("docker", "exec", container, "rm", "-f", path)
+                .run()
+                .map(drop)
+                .context(Failure::Io(format!("Remove {path:?} in container `{container}`"))),
+        }
+    }
+
+    fn chown(&self, path: &Path, (uid, gid): (u32, u32)) -> Result<()> {
+        use anyhow::Context;
+        use duct::cmd;
+
+        match self {
+            Host::Local => std::os::unix::fs::chown(path, Some(uid), Some(gid))
+                .context(Failure::Io(format!("Give {path:?} to {uid}:{gid}"))),
+            Host::Docker(container) =>
+                cmd!("docker", "exec", container, "chown", format!("{uid}:{gid}"), path)
+                .run()
+                .map(drop)
+                .context(Failure::Io(
+                    format!("Give {path:?} to {uid}:{gid} in container `{container}`"))),
+        }
+    }
+
+    /// Makes a file readable and writable by its owner only.
+    fn restrict(&self, path: &Path) -> Result<()> {
+        use anyhow::Context;
+        use duct::cmd;
+        use std::os::unix::fs::PermissionsExt;
+
+        match self {
+            Host::Local => std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+                .context(Failure::Io(format!("Restrict {path:?}"))),
+            Host::Docker(container) => cmd!("docker", "exec", container, "chmod", "600", path)
+                .run()
+                .map(drop)
+                .context(Failure::Io(format!("Restrict {path:?} in container `{container}`"))),
+        }
+    }
+
+    /// Creates an empty file readable and writable by its owner only, so it
+    /// is never readable by others while being written.
+    fn create_private(&self, path: &Path) -> Result<()> {
+        use anyhow::Context;
+        use duct::cmd;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        match self {
+            Host::Local => std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600)
+                .open(path)
+                .map(drop)
+                .context(Failure::Io(format!("Create {path:?}"))),
+            Host::Docker(container) => cmd!(
+                "docker", "exec", container, "sh", "-c", r#"umask 077 && : > "$1""#, "sh", path
+            )
+            .run()
+            .map(drop)
+            .context(Failure::Io(format!("Create {path:?} in container `{container}`"))),
+        }
+    }
+
+    /// Permission bits of a file, none if it does not exist.
+    fn mode(&self, path: &Path) -> Result<Option<u32>> {
+        use anyhow::Context;
+        use duct::cmd;
+        use std::os::unix::fs::PermissionsExt;
+
+        match self {
+            Host::Local if !path.exists() => Ok(None),
+            Host::Local => Ok(Some(std::fs::metadata(path)
+                .context(Failure::Io(format!("Stat {path:?}")))?
+                .permissions().mode() & 0o777)),
+            Host::Docker(container) => {
+                let mode = cmd!(
+                    "docker", "exec", container,
+                    "sh", "-c", r#"test ! -e "$1" || stat -c %a -- "$1""#, "sh", path
+                )
+                .read()
+                .context(Failure::Io(format!("Stat {path:?} in container `{container}`")))?;
+                (!mode.is_empty()).then(|| u32::from_str_radix(mode.trim(), 8)).transpose()
+                    .context(Failure::Io(format!("Parse mode `{mode}` of {path:?}")))
+            }
+        }
+    }
+}
+
+/// Targets kept readable by their owner only, relative to the target
+/// directory. A directory holding them, like `.ssh`, is expected at `0700`.
+const PRIVATE: [&str; 8] = [
+    ".ssh/*", ".gnupg/**", ".netrc", ".git-credentials", ".pgpass", ".aws/credentials",
+    ".docker/config.json", ".kube/config",
+];
+
+/// Whether a path matches a pattern where `*` stands for any part of a
+/// component and `**` for any number of components.
+fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern = regex::escape(pattern).replace(r"\*\*", ".*").replace(r"\*", "[^/]*");
+    regex::Regex::new(&format!("^{pattern}$")).is_ok_and(|regex| regex.is_match(path))
+}
+
+impl Entry {
+    fn new(cli: &Cli, directory: &Path) -> Result<Self> {
+        use anyhow::Context;
+
+        let relative = cli.layer_of(directory)
+            .and_then(|layer| directory.strip_prefix(layer).ok())
+            .context("Strip prefix")?;
+        let target = cli.target_root().join(cli.settings.canonicalize(relative));
+        cli.confine(&target)?;
+        let mut format = cli.settings.format(extension(&target));
+        // Targets known by name rather than extension.
+        const NAMED: [(&str, &str); 17] = [
+            ("fish_variables", "fish"), ("i3/config", "i3config"), ("sway/config", "i3config"),
+            (".vimrc", "vim"), (".vim/vimrc", "vim"),
+            (".Xresources", "xresources"), (".Xdefaults", "xresources"),
+            ("mimeapps.list", "mimeapps"), ("fonts.conf", "fontconfig"),
+            (".fonts.conf", "fontconfig"), (".aws/config", "awsconfig"),
+            (".aws/credentials", "awsconfig"), (".kube/config", "kubeconfig"),
+            ("Brewfile", "brewfile"), (".Brewfile", "brewfile"),
+            ("requirements.txt", "requirements"), ("constraints.txt", "requirements"),
+        ];
+        if let Some((_, named)) = NAMED.iter().find(|(name, _)| target.ends_with(name)) {
+            format = Some(named.to_string());
         }
 
-        write_back(result.into(), target)
-    }))
-    .collect::<Result<()>>()?;
+        // A `FORMAT` file names the format of targets with no telling
+        // extension.
+        let marker = directory.join("FORMAT");
+        if marker.is_file() {
+            let forced = std::fs::read_to_string(&marker)
+                .context(format!("Read {marker:?}"))?
+                .trim()
+                .to_string();
+            format = cli.settings.format(Some(forced));
+        } else if cli.settings.sniff && (format.is_none() || !Config::supports(&format))
+            && let Some(first) = directory.is_dir().then(|| fragments(directory))
+                .transpose()?
+                .and_then(|fragments| fragments.into_iter().next())
+            && let Some(sniffed) = sniff(&read_text(&first)
+                .context(format!("Read {first:?}"))?)
+        {
+            log::debug!("Sniffed {sniffed} from {first:?}");
+            format = Some(sniffed);
+        }
+
+        // Specifiers of requirements combine, so those of the existing
+        // target would stay forever.
+        let seed = !cli.no_seed && !directory.join("NO_SEED").exists()
+            && format.as_deref() != Some("requirements");
+        Ok(Entry {
+            directories: vec![directory.into()],
+            target,
+            format,
+            host: cli.host(),
+            prune: cli.prune,
+            seed,
+            key: std::fs::read_to_string(directory.join("KEY")).ok()
+                .map(|key| key.trim().to_string()),
+            vectors: cli.settings.vectors,
+            on_parse_error: cli.settings.on_parse_error,
+            owner: cli.owner,
+            fragment_format: None,
+            variables: vec![],
+        })
+    }
+
+    /// The `.d` directory of the last layer having one, where fragments are
+    /// written.
+    fn directory(&self) -> &Path {
+        self.directories.last().expect("Entries have a directory")
+    }
+
+    /// Fragments of all layers in merge order. A fragment is dropped when a
+    /// later layer has one of the same name.
+    fn fragments(&self) -> Result<Vec<PathBuf>> {
+        let all: Vec<PathBuf> = self.directories.iter()
+            .map(|directory| fragments(directory))
+            .collect::<Result<Vec<_>>>()?
+            .concat();
+
+        Ok(
+            all.iter().enumerate()
+            .filter(|(i, fragment)| !all[i + 1..].iter()
+                .any(|later| self.relative(later) == self.relative(fragment)))
+            .map(|(_, fragment)| fragment.clone())
+            .collect()
+        )
+    }
+
+    /// Path of a fragment relative to its `.d` directory.
+    fn relative<'a>(&self, fragment: &'a Path) -> &'a Path {
+        self.directories.iter()
+            .find_map(|directory| fragment.strip_prefix(directory).ok())
+            .unwrap_or(fragment)
+    }
+
+    /// An entry per combination of the variables of a `matrix.toml`, or the
+    /// entry itself without one, and per path of an `ALIASES` file, kept in
+    /// sync from the same fragments. Targets named with another extension
+    /// take its format. Each is followed by an entry per format of a
+    /// `RENDER_AS` file, rendering the fragments into a target with that
+    /// extension instead. Such targets are not seeded, as fragments are in
+    /// another format.
+    fn expand(self, cli: &Cli) -> Result<Vec<Entry>> {
+        let aliases = marker_lines(&self.directories[0].join("ALIASES"));
+        let extensions = marker_lines(&self.directories[0].join("RENDER_AS"));
+        let mut expanded = vec![];
+        for entry in self.combinations(cli)? {
+            let aliases = aliases.iter()
+                .map(|alias| {
+                    let alias = substitute(alias, &entry.variables);
+                    // `~` is the target directory, the home applied to.
+                    let relative = match alias.strip_prefix('~') {
+                        Some(rest) if rest.is_empty() || rest.starts_with('/') =>
+                            expand_path(Path::new(rest.trim_start_matches('/')))?,
+                        _ => expand_path(Path::new(&alias))?,
+                    };
+                    let target = cli.target_root().join(relative);
+                    Ok(Entry {
+                        format: cli.settings.format(extension(&target)).or(entry.format.clone()),
+                        target,
+                        ..entry.clone()
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            for entry in once(entry).chain(aliases) {
+                let renderings: Vec<Entry> = extensions.iter()
+                    .map(|extension| Entry {
+                        target: entry.target.with_extension(extension),
+                        format: cli.settings.format(Some(extension.clone())),
+                        fragment_format: entry.format.clone(),
+                        seed: false,
+                        ..entry.clone()
+                    })
+                    .collect();
+                expanded.push(entry);
+                expanded.extend(renderings);
+            }
+        }
+        for entry in &expanded {
+            cli.confine(&entry.target)?;
+        }
+        Ok(expanded)
+    }
+
+    fn combinations(self, cli: &Cli) -> Result<Vec<Entry>> {
+        use anyhow::Context;
+
+        let file = self.directories[0].join("matrix.toml");
+        if !file.is_file() {
+            return Ok(vec![self]);
+        }
+        let matrix: Matrix = toml::from_str(&std::fs::read_to_string(&file)?)
+            .context(Failure::Parse(format!("Parse {file:?}")))?;
+        let combinations = matrix.variables.iter()
+            .fold(vec![vec![]], |combinations: Vec<Vec<(String, String)>>, (name, values)| {
+                combinations.iter()
+                .flat_map(|combination| values.iter().map(|value| {
+                    let mut combination = combination.clone();
+                    combination.push((name.clone(), value.clone()));
+                    combination
+                }))
+                .collect()
+            });
+        Ok(combinations.into_iter()
+            .map(|variables| {
+                let target = cli.target_root().join(substitute(&matrix.target, &variables));
+                Entry {
+                    format: cli.settings.format(extension(&target)).or(self.format.clone()),
+                    target,
+                    variables,
+                    ..self.clone()
+                }
+            })
+            .collect())
+    }
+
+    /// Parses a target or fragment in the format of the entry.
+    fn parse(&self, text: String) -> Result<Config> {
+        Ok(match Config::parse_dispatch(&self.format, text)? {
+            Config::Rows(rows) => Config::Rows(Rows { key: self.key.clone(), ..rows }),
+            Config::Edn(edn, _) => Config::Edn(edn, self.vectors),
+            config => config,
+        })
+    }
+
+    /// `PROTECTED` key paths of all layers.
+    fn protected(&self) -> Result<Vec<KeyPath>> {
+        Ok(
+            self.directories.iter()
+            .map(|directory| key_paths(directory, "PROTECTED"))
+            .collect::<Result<Vec<_>>>()?
+            .concat()
+        )
+    }
+
+    /// Key paths of `COERCE` files of all layers, each with the type its
+    /// values are made, like `.port number`.
+    fn coercions(&self) -> Result<Vec<(KeyPath, Coercion)>> {
+        use anyhow::{bail, Context};
+
+        let mut coercions = vec![];
+        for file in self.directories.iter().map(|directory| directory.join("COERCE")) {
+            for line in marker_lines(&file) {
+                let coercion = (|| {
+                    let Some((path, kind)) = line.rsplit_once(char::is_whitespace) else {
+                        bail!("Expect a key path and a type in `{line}`");
+                    };
+                    let kind = match kind {
+                        "number" => Coercion::Number,
+                        "boolean" => Coercion::Boolean,
+                        "string" => Coercion::String,
+                        kind => bail!("Unknown type `{kind}`, expect number, boolean or string"),
+                    };
+                    Ok((path.trim_end().parse()?, kind))
+                })().context(Failure::Parse(format!("In {file:?}")))?;
+                coercions.push(coercion);
+            }
+        }
+        Ok(coercions)
+    }
+
+    /// The JSON Schema a `SCHEMA` file names, relative to its `.d`
+    /// directory, of the last layer having one.
+    fn schema(&self) -> Result<Option<serde_json::Value>> {
+        use anyhow::Context;
+
+        let Some(path) = self.directories.iter().rev()
+            .find_map(|directory| Some(directory.join(marker_lines(&directory.join("SCHEMA"))
+                .into_iter().next()?)))
+        else {
+            return Ok(None);
+        };
+        let text = std::fs::read_to_string(&path)
+            .context(Failure::Io(format!("Read schema {path:?}")))?;
+        jsonc_parser::parse_to_serde_value(&text, &Default::default())
+            .map_err(anyhow::Error::from)
+            .and_then(|schema| schema.context("Empty schema"))
+            .context(Failure::Parse(format!("Parse schema {path:?}")))
+            .map(Some)
+    }
+
+    /// Key paths whose values are masked in diffs and reports: those of
+    /// `SENSITIVE` files of all layers, and every key set by a sensitive
+    /// fragment.
+    fn sensitive(&self) -> Result<Vec<KeyPath>> {
+        let mut paths = self.directories.iter()
+            .map(|directory| key_paths(directory, "SENSITIVE"))
+            .collect::<Result<Vec<_>>>()?
+            .concat();
+        if self.fragments()?.iter().any(|fragment| is_sensitive(fragment)) {
+            for Input { config, .. } in inputs(self)?.into_iter()
+                .filter(|input| is_sensitive(&input.source))
+            {
+                let tree = config.tree().unwrap_or_default();
+                paths.extend(KeyPath::leaves(&tree).into_iter().map(|(path, _)| path));
+            }
+        }
+        Ok(paths)
+    }
+}
+
+/// Guesses a format from the first meaningful line of a fragment: a JSON
+/// object, a TOML table header or a YAML document marker. The whole text has
+/// to parse in it.
+fn sniff(text: &str) -> Option<String> {
+    static TABLE: LazyLock<regex::Regex> = LazyLock::new(|| {
+        regex::Regex::new(r#"^\[\[?[\w.\-" ]+\]\]?$"#).expect("Valid regex")
+    });
+
+    let line = text.lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))?;
+    let format = if line.starts_with('{') {
+        "json"
+    } else if line.starts_with("---") {
+        "yaml"
+    } else if TABLE.is_match(line) {
+        "toml"
+    } else {
+        return None;
+    };
+
+    let format = Some(format.to_string());
+    Config::parse_dispatch(&format, text.into()).is_ok().then_some(format).flatten()
+}
+
+/// Finds every `.d` directory under the layers of the config house, grouped
+/// by target. Symlinks are followed, and loops are skipped with a warning.
+fn discover(cli: &Cli) -> Result<Vec<Entry>> {
+    use anyhow::Context;
+    use duct::cmd;
+
+    let mut entries: Vec<Entry> = vec![];
+    let depth = [("-mindepth", cli.min_depth), ("-maxdepth", cli.max_depth)]
+        .into_iter()
+        .filter_map(|(flag, depth)| Some([flag.to_owned(), depth?.to_string()]))
+        .flatten()
+        .collect::<Vec<_>>();
+    for layer in &cli.directory {
+        let args = ["-L".into(), layer.as_os_str().to_owned()].into_iter()
+            .chain(depth.iter().map(Into::into));
+        let output = cmd("find", args).stdout_capture().stderr_capture()
+            .unchecked().run()?;
+        // `find` fails on loops, but still lists everything else.
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        for line in stderr.lines() {
+            if !line.contains("loop") {
+                anyhow::bail!(Failure::Discovery(format!("`find` in {layer:?} failed: {stderr}")));
+            }
+            log::warn!("{line}");
+        }
+        let found = String::from_utf8(output.stdout)?;
+        for directory in found.lines()
+            .filter(|line| line.ends_with(&cli.settings.suffix))
+        {
+            let expanded = Entry::new(cli, Path::new(directory))
+                .and_then(|entry| entry.expand(cli))
+                .context(Failure::Discovery(format!("Discover {directory:?}")))?;
+            for entry in expanded {
+                match entries.iter_mut().find(|e| e.target == entry.target) {
+                    Some(existing) => {
+                        existing.directories.extend(entry.directories);
+                        existing.seed &= entry.seed;
+                    }
+                    None => entries.push(entry),
+                }
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Lists fragments of a `.d` directory in merge order. Fragments of a
+/// subdirectory take its place. Symlinked subdirectories are followed, unless
+/// they lead back to one being listed.
+fn fragments(directory: &Path) -> Result<Vec<PathBuf>> {
+    walk(directory, &mut vec![])
+}
+
+fn walk(directory: &Path, ancestors: &mut Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+    use anyhow::Context;
+    use duct::cmd;
+
+    let real = directory.canonicalize()
+        .context(format!("resolve {directory:?}"))?;
+    if ancestors.contains(&real) {
+        log::warn!("Skipping {directory:?}, it loops back to {real:?}");
+        return Ok(vec![]);
+    }
+    ancestors.push(real);
+    let found = cmd!("ls", directory).read()
+        .context(format!("`ls` files in {directory:?}"))?
+    .lines()
+
+    .filter(|s| !IGNORE_LIST.contains(s))
+    .map(|s| directory.join(s))
+    .map(|path| match path.is_dir() {
+        true => walk(&path, ancestors),
+        false => Ok(vec![path]),
+    })
+    .collect::<Result<Vec<_>>>()
+    .map(|nested| nested.concat());
+    ancestors.pop();
+    found
+}
+
+/// Applies the config house to the home of each user of `--user` or
+/// `--all-users` in turn, giving written targets to the user.
+fn apply_users(mut cli: Cli) -> Result<()> {
+    use anyhow::bail;
+    use duct::cmd;
+
+    if cli.all_users && cmd!("id", "-u").read()?.trim() != "0" {
+        bail!("`--all-users` needs root");
+    }
+    for User { name, home, owner } in users(&cli)? {
+        log::info!("Applying to {home:?} of `{name}`");
+        cli.target = home;
+        cli.owner = Some(owner);
+        apply(&cli)?;
+    }
+    Ok(())
+}
+
+struct User {
+    name: String,
+    home: PathBuf,
+    /// User and group ids.
+    owner: (u32, u32),
+}
+
+/// The users of `--user`, or every user with a login shell for
+/// `--all-users`, from `getent passwd`.
+fn users(cli: &Cli) -> Result<Vec<User>> {
+    use anyhow::Context;
+    use duct::cmd;
+
+    let passwd = match cli.all_users {
+        true => cmd!("getent", "passwd").read()?,
+        false => cmd("getent", once("passwd").chain(cli.users.iter().map(String::as_str)))
+            .read()
+            .context(format!("Unknown user among {:?}", cli.users))?,
+    };
+    passwd.lines()
+        .map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            let [name, _, uid, gid, _, home, shell] = fields[..] else {
+                anyhow::bail!("Malformed passwd entry `{line}`");
+            };
+            Ok((name, home, shell, uid.parse::<u32>()?, gid.parse::<u32>()?))
+        })
+        .filter(|user| !cli.all_users || user.as_ref().is_ok_and(|(_, _, shell, uid, _)| {
+            (1000..65534).contains(uid) && !shell.ends_with("nologin") && !shell.ends_with("false")
+        }))
+        .map(|user| user.map(|(name, home, _, uid, gid)| {
+            User { name: name.into(), home: home.into(), owner: (uid, gid) }
+        }))
+        .collect()
+}
+
+/// Applies every target. Statistics of each target are printed with
+/// `--output json`. Targets written before an error are still journaled for
+/// `undo`. Returns the targets changed.
+fn apply(cli: &Cli) -> Result<Vec<PathBuf>> {
+    // Drift is only known before targets are written again.
+    let managed = cli.metrics_file.as_ref()
+        .map(|_| statuses(cli))
+        .transpose()
+        .unwrap_or_else(|error| {
+            log::warn!("Counting drifted targets failed: {error:#}");
+            None
+        })
+        .map(|statuses| (statuses.len(), statuses.iter().filter(|s| s.drifted).count()));
+    let journal = std::sync::Mutex::default();
+    let discovery = std::time::Instant::now();
+    let stats = apply_entries(cli, &journal, discovery);
+    let journal = journal.into_inner().expect("No worker panics holding the journal");
+    if let Some((summary, body)) = notification(&journal, stats.as_ref().err()) {
+        if cli.notify {
+            notify(&summary, &body, stats.is_err());
+        }
+        if let Some(webhook) = &cli.settings.webhook {
+            post(webhook, &summary, &body, &journal, stats.is_err());
+        }
+    }
+    if let Some(path) = &cli.metrics_file {
+        write_metrics(path, &metrics(managed, journal.len(), stats.is_err())?)?;
+    }
+    audit(cli, "apply", &journal)?;
+    let changed = journal.iter().map(|revert| revert.target.clone()).collect();
+    record_run(cli, journal)?;
+    let (discovery, mut stats) = stats?;
+
+    if cli.output == Output::Json {
+        stats.sort_by(|a, b| a.target.cmp(&b.target));
+        println!("{:#}", serde_json::to_value(stats)?);
+    } else if cli.timings {
+        print_timings(discovery, stats);
+    }
+    Ok(changed)
+}
+
+/// Discovers and applies every target, returning how long discovery took
+/// since `start` and statistics of the targets. Targets are read and written
+/// on the blocking pool of a tokio runtime, `--jobs` at a time, so the
+/// latency of slow filesystems like NFS overlaps. The first error stops the
+/// remaining targets.
+fn apply_entries(cli: &Cli, journal: &std::sync::Mutex<Vec<Revert>>, start: std::time::Instant)
+    -> Result<(std::time::Duration, Vec<Stats>)>
+{
+    use std::sync::{Arc, Mutex};
+
+    let mut entries = discover(cli)?;
+    if let Some(revision) = &cli.since && let Some(changed) = changed_since(cli, revision)? {
+        let total = entries.len();
+        entries.retain(|entry| entry.directories.iter()
+            .map(|directory| directory.canonicalize().unwrap_or_else(|_| directory.clone()))
+            .any(|directory| changed.iter().any(|path| path.starts_with(&directory))));
+        log::debug!("{} of {total} targets changed since {revision}", entries.len());
+    }
+    let discovery = start.elapsed();
+    let jobs = if cli.resolve { 1 } else { cli.jobs.max(1) };
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .max_blocking_threads(jobs)
+        .build()?;
+    let shared = Arc::new((cli.clone(), Mutex::new(vec![])));
+    let stats = runtime.block_on(async {
+        let mut tasks = tokio::task::JoinSet::new();
+        for entry in entries {
+            let shared = shared.clone();
+            tasks.spawn_blocking(move || {
+                let (cli, journal) = &*shared;
+                apply_entry(cli, entry, journal)
+            });
+        }
+        let mut stats = vec![];
+        while let Some(result) = tasks.join_next().await {
+            match result.expect("Targets are applied without panicking") {
+                Ok(s) => stats.push(s),
+                Err(e) => {
+                    // Targets being written are finished, the others dropped.
+                    tasks.shutdown().await;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(stats)
+    });
+    let written = std::mem::take(&mut *shared.1.lock().expect("No target panics holding the journal"));
+    journal.lock().expect("No target panics holding the journal").extend(written);
+    Ok((discovery, stats?))
+}
+
+/// Files of the layers that git sees changed since a revision, including
+/// uncommitted and untracked ones, or `None` if `patch.toml` of a layer
+/// changed, as it may change every target.
+fn changed_since(cli: &Cli, revision: &str) -> Result<Option<Vec<PathBuf>>> {
+    use anyhow::Context;
+    use duct::cmd;
+
+    let mut changed = vec![];
+    for layer in &cli.directory {
+        let layer = layer.canonicalize().context(Failure::Io(format!("Resolve {layer:?}")))?;
+        let git = |args: &[&str]| cmd("git", ["-C".as_ref(), layer.as_os_str()].into_iter()
+            .chain(args.iter().map(|arg| arg.as_ref())))
+            .read()
+            .context(Failure::Discovery(format!("Find files of {layer:?} changed since {revision}")));
+        let diff = git(&["diff", "--name-only", "--no-renames", "--relative", revision, "--"])?;
+        let untracked = git(&["ls-files", "--others", "--exclude-standard"])?;
+        for path in diff.lines().chain(untracked.lines()).filter(|path| !path.is_empty()) {
+            if path == "patch.toml" {
+                return Ok(None);
+            }
+            changed.push(layer.join(path));
+        }
+    }
+    Ok(Some(changed))
+}
+
+/// Prints a table of the time spent on each target, slowest first, after
+/// the time discovery took.
+fn print_timings(discovery: std::time::Duration, mut stats: Vec<Stats>) {
+    let millis = |duration: std::time::Duration| format!("{:.1}ms", duration.as_secs_f64() * 1e3);
+
+    stats.sort_by_key(|stats| std::cmp::Reverse(stats.timings.unwrap_or_default().total()));
+    let rows: Vec<[String; 5]> = once(["TARGET", "PARSE", "MERGE", "WRITE", "TOTAL"].map(String::from))
+        .chain(once(["(discovery)".into(), "".into(), "".into(), "".into(), millis(discovery)]))
+        .chain(stats.iter().map(|stats| {
+            let Timings { parse, merge, write } = stats.timings.unwrap_or_default();
+            [stats.target.display().to_string(), millis(parse), millis(merge), millis(write),
+                millis(parse + merge + write)]
+        }))
+        .collect();
+    print_table(&rows);
+}
+
+/// Method, URL and JSON body telling a webhook about a run.
+fn webhook_request(webhook: &Webhook, summary: &str, body: &str, journal: &[Revert], failed: bool)
+    -> (&'static str, String, serde_json::Value)
+{
+    use std::time::SystemTime;
+
+    let text = format!("{summary}\n{body}");
+    match webhook.format {
+        WebhookFormat::Json => ("POST", webhook.url.clone(), serde_json::json!({
+            "summary": summary,
+            "body": body,
+            "failed": failed,
+            "targets": journal.iter().map(|revert| &revert.target).collect::<Vec<_>>(),
+        })),
+        WebhookFormat::Slack => ("POST", webhook.url.clone(), serde_json::json!({ "text": text })),
+        WebhookFormat::Matrix => {
+            // Matrix drops messages sent again with a transaction id.
+            let id = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default().as_nanos();
+            let url = format!("{}/{id}", webhook.url.trim_end_matches('/'));
+            ("PUT", url, serde_json::json!({ "msgtype": "m.text", "body": text }))
+        }
+    }
+}
+
+/// Sends a summary of a run to the webhook with `curl`. Failing to is only
+/// logged, as the run itself went through.
+fn post(webhook: &Webhook, summary: &str, body: &str, journal: &[Revert], failed: bool) {
+    use duct::cmd;
+
+    let (method, url, json) = webhook_request(webhook, summary, body, journal, failed);
+    // Options are given on stdin, keeping the token out of the process list.
+    let mut config = format!("url = {url:?}\ndata-binary = {:?}\n", json.to_string());
+    if let Some(token) = &webhook.token {
+        config += &format!("header = {:?}\n", format!("Authorization: Bearer {token}"));
+    }
+    log::debug!("Sending a summary of the run to {url}");
+    let sent = cmd!("curl", "--fail", "--silent", "--show-error", "--max-time", "30",
+        "--request", method, "--header", "Content-Type: application/json", "--config", "-")
+        .stdin_bytes(config)
+        .stdout_null()
+        .run();
+    if let Err(error) = sent {
+        log::warn!("Sending a summary of the run to {url} failed: {error}");
+    }
+}
+
+/// Metrics of a run in the Prometheus text format. The counts of managed
+/// and drifted targets are left out when they could not be told.
+fn metrics(managed: Option<(usize, usize)>, changed: usize, failed: bool) -> Result<String> {
+    use std::time::SystemTime;
+
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+    let mut metrics = vec![];
+    if let Some((managed, drifted)) = managed {
+        metrics.push(("targets", "Targets of the config house.", managed as u64));
+        metrics.push(("targets_drifted",
+            "Targets changed since the run that last wrote them.", drifted as u64));
+    }
+    metrics.extend([
+        ("targets_changed", "Targets the last run changed.", changed as u64),
+        ("last_run_timestamp_seconds", "When the last run ended.", now),
+        ("last_run_success", "Whether the last run went through.", u64::from(!failed)),
+    ]);
+    Ok(metrics.into_iter()
+        .map(|(name, help, value)| {
+            format!("# HELP patch_{name} {help}\n# TYPE patch_{name} gauge\npatch_{name} {value}\n")
+        })
+        .collect())
+}
+
+/// Replaces a metrics file at once, so the collector never reads half of it.
+fn write_metrics(path: &Path, metrics: &str) -> Result<()> {
+    use anyhow::Context;
+
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".tmp");
+    std::fs::write(&partial, metrics)
+        .and_then(|_| std::fs::rename(&partial, path))
+        .context(Failure::Io(format!("Write metrics to {path:?}")))
+}
+
+/// Summary and body of the notification for a run: its error, or the
+/// targets it changed. Runs changing nothing go unnoticed.
+fn notification(journal: &[Revert], error: Option<&anyhow::Error>) -> Option<(String, String)> {
+    const SHOWN: usize = 10;
+
+    if let Some(error) = error {
+        return Some(("Applying config patches failed".into(), format!("{error:#}")));
+    }
+    if journal.is_empty() {
+        return None;
+    }
+    let mut body: Vec<String> = journal.iter().take(SHOWN)
+        .map(|revert| revert.target.display().to_string())
+        .collect();
+    if journal.len() > SHOWN {
+        body.push(format!("and {} more", journal.len() - SHOWN));
+    }
+    Some((format!("Updated {} target(s)", journal.len()), body.join("\n")))
+}
+
+/// Shows a desktop notification. Failing to is only logged, as the run
+/// itself went through.
+fn notify(summary: &str, body: &str, failed: bool) {
+    let shown = notify_rust::Notification::new()
+        .appname("patch")
+        .summary(summary)
+        .body(body)
+        .icon(if failed { "dialog-error" } else { "dialog-information" })
+        .show();
+    if let Err(error) = shown {
+        log::warn!("Sending a desktop notification failed: {error}");
+    }
+}
+
+/// A parsed target or fragment.
+struct Input {
+    source: PathBuf,
+    config: Config,
+}
+
+/// Parses the non-empty target, unless it is not seeded, and its fragments,
+/// in merge order. An input failing to parse fails the target, is left out,
+/// or turns the target into plain text, as the entry says.
+fn inputs(entry: &Entry) -> Result<Vec<Input>> {
+    use anyhow::Context;
+
+    let Entry { target, host, .. } = entry;
+    let texts: Vec<(PathBuf, String)> = entry.seed
+    .then(|| host.read(target).map(|text| (target.clone(), text)))
+    .into_iter()
+    .filter(|result| result.as_ref().is_ok_and(|(_, text)| !text.is_empty()))
+    .chain(entry.fragments()?.into_iter().map(|source| {
+        log::trace!("Opening {source:?}");
+        let text = read_text(&source).context(format!("Read {source:?}"))?;
+        Ok((source, substitute(&text, &entry.variables)))
+    }))
+    .collect::<Result<_>>()?;
+
+    let mut inputs = vec![];
+    for (source, text) in &texts {
+        let error = match input(entry, source.clone(), text.clone()) {
+            Ok(input) => {
+                inputs.push(input);
+                continue;
+            }
+            Err(error) if !matches!(error.downcast_ref(), Some(Failure::Parse(_))) => {
+                return Err(error);
+            }
+            Err(error) => error,
+        };
+        // The target itself is only left aside as text, as when written so
+        // by an earlier run.
+        match entry.on_parse_error {
+            OnParseError::Fail => return Err(error),
+            OnParseError::Skip if source == target => return Err(error),
+            OnParseError::Skip => log::warn!("Leaving {source:?} out of {target:?}: {error:#}"),
+            OnParseError::Text => {
+                log::warn!("Writing {target:?} as plain text: {error:#}");
+                return Ok(texts.into_iter()
+                    .filter(|(source, _)| source != target)
+                    .map(|(source, text)| Input { source, config: Config::Text(text) })
+                    .collect());
+            }
+        }
+    }
+    Ok(inputs)
+}
+
+/// Parses the target or a fragment of an entry.
+fn input(entry: &Entry, source: PathBuf, text: String) -> Result<Input> {
+    use anyhow::Context;
+
+    log::trace!("Reading {} bytes", text.len());
+    if source == entry.target {
+        let config = entry.parse(text)
+            .context(Failure::Parse(format!("Parse {source:?}")))?;
+        return Ok(Input { source, config });
+    }
+    let config = match &entry.fragment_format {
+        Some(_) => Config::parse_dispatch(&entry.fragment_format, text)
+            .and_then(|config| Config::from_tree(&entry.format,
+                config.tree().unwrap_or_else(|| serde_json::json!({})))),
+        None => entry.parse(text),
+    };
+    // Parse errors may quote the text.
+    let config = match is_sensitive(&source) {
+        true => config.map_err(|_| anyhow::anyhow!(Failure::Parse(
+            format!("Parse {source:?}, details are hidden as it is sensitive")))),
+        false => config.context(Failure::Parse(format!("Parse {source:?}"))),
+    }?;
+    let scope = scope(entry, entry.relative(&source), &config)
+        .context(format!("Scope {source:?}"))?;
+    let config = config.scoped(&scope)?;
+    Ok(Input { source, config })
+}
+
+/// Meaningful lines of a marker file of a `.d` directory, none if it does not
+/// exist.
+fn marker_lines(path: &Path) -> Vec<String> {
+    std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+/// Replaces `{{name}}` with the value of each variable.
+fn substitute(text: &str, variables: &[(String, String)]) -> String {
+    variables.iter().fold(text.to_string(), |text, (name, value)| {
+        text.replace(&format!("{{{{{name}}}}}"), value)
+    })
+}
+
+/// Key path a fragment is nested under: a key per subdirectory of its `.d`
+/// directory, then its name if it holds a single value, like
+/// `editor.fontSize.json` holding `14`.
+fn scope(entry: &Entry, relative: &Path, config: &Config) -> Result<KeyPath> {
+    let mut keys: Vec<Key> = relative.parent().into_iter()
+        .flat_map(Path::iter)
+        .map(|c| Key::Name(c.to_string_lossy().into_owned()))
+        .collect();
+
+    if config.is_value() {
+        let name = relative.file_name().unwrap_or_default().to_string_lossy();
+        // Either the format or an alias of it, as the target is named.
+        let name = [entry.fragment_format.clone(), entry.format.clone(), extension(&entry.target)]
+            .into_iter()
+            .flatten()
+            .find_map(|ext| {
+                let stem = name.len().checked_sub(ext.len() + 1)?;
+                name.get(stem..)?.eq_ignore_ascii_case(&format!(".{ext}"))
+                    .then(|| &name[..stem])
+            })
+            .unwrap_or(&name);
+        let name = name.strip_suffix(".final").unwrap_or(name);
+        let unordered = name.trim_start_matches(|c: char| c.is_ascii_digit());
+        let name = match unordered.strip_prefix('-') {
+            Some(rest) if unordered.len() < name.len() => rest,
+            _ => name,
+        };
+        keys.extend(name.parse::<KeyPath>()?.0);
+    }
+    Ok(KeyPath(keys))
+}
+
+/// Concatenates inputs with the Mergeable trait. Keys of a final fragment
+/// are locked, and later inputs changing them are errors.
+fn render(inputs: Vec<Input>) -> Result<Config> {
+    let mut locked: Vec<(KeyPath, PathBuf)> = vec![];
+
+    inputs.into_iter()
+    .try_fold(Default::default(), |merged: Config, Input { source, config }| {
+        let locks: Vec<_> = is_final(&source)
+            .then(|| config.tree()).flatten()
+            .map(|tree| KeyPath::leaves(&tree).into_iter()
+                .map(|(path, _)| (path, source.clone()))
+                .collect())
+            .unwrap_or_default();
+
+        let before = (!locked.is_empty()).then(|| merged.tree()).flatten();
+        let merged = merged.try_merge(Ok(config))?;
+        if let (Some(before), Some(after)) = (before, merged.tree())
+            && let Some((path, lock)) = locked.iter()
+                .find(|(path, _)| path.get(&before) != path.get(&after))
+        {
+            anyhow::bail!(Failure::Conflict(
+                format!("{source:?} overrides `{path}` of final fragment {lock:?}")));
+        }
+
+        locked.extend(locks);
+        Ok(merged)
+    })
+}
+
+/// Renders a target, putting back the values its `PROTECTED` key paths have
+/// in the existing target. Paths the target lacks are left to fragments.
+/// When pruning, keys of the existing target no fragment sets are removed.
+fn render_entry(entry: &Entry, inputs: Vec<Input>) -> Result<Config> {
+    use anyhow::Context;
+
+    let protected = entry.protected()?;
+    let existing = match inputs.first().filter(|input| input.source == entry.target) {
+        Some(input) => Some(input.config.clone()),
+        // Unseeded targets are still read for the protected values.
+        None if !entry.seed && !protected.is_empty() => {
+            let text = entry.host.read(&entry.target)?;
+            (!text.is_empty()).then(|| entry.parse(text))
+                .transpose()
+                .context(Failure::Parse(format!("Parse {:?}", entry.target)))?
+        }
+        None => None,
+    };
+    clobbered(entry, &inputs)?;
+    let trees: Vec<_> = inputs.iter()
+        .filter(|input| entry.prune && input.source != entry.target)
+        .filter_map(|input| input.config.tree())
+        .collect();
+    let mut config = render(inputs)?;
+
+    if entry.prune && let Some(tree) = existing.as_ref().and_then(Config::tree) {
+        KeyPath::leaves(&tree).into_iter()
+            .filter(|(path, _)| trees.iter().all(|t| path.get(t).is_none()))
+            .try_for_each(|(path, _)| {
+                log::debug!("Pruning `{path}` of {:?}", entry.target);
+                config.remove(&path)
+            })?;
+    }
+
+    if let Some(existing) = existing && !protected.is_empty() {
+        protected.iter().try_for_each(|path| config.restore(&existing, path))?;
+    }
+
+    if let Some(schema) = entry.schema()? && let Some(tree) = config.tree() {
+        let defaults = schema_defaults(&schema, &schema, &tree, &KeyPath(vec![]));
+        if !defaults.is_empty() {
+            let mut filled = serde_json::json!({});
+            for (path, value) in &defaults {
+                log::debug!("Defaulting `{path}` of {:?}", entry.target);
+                path.set(&mut filled, Some(value.clone()), || serde_json::json!({}));
+            }
+            let filled = Config::from_tree(&entry.format, filled)
+                .context(format!("Fill defaults of {:?}", entry.target))?;
+            defaults.iter().try_for_each(|(path, _)| config.restore(&filled, path))?;
+        }
+    }
+
+    let coercions = entry.coercions()?;
+    if !coercions.is_empty() && let Some(mut tree) = config.tree() {
+        let mut changed = vec![];
+        for (path, coercion) in &coercions {
+            let Some(value) = path.get(&tree) else { continue };
+            if !value.is_object() && !value.is_array() && coercion.apply(value).is_none() {
+                log::warn!("Can't make `{path}` of {:?} a {coercion:?}: {value}", entry.target);
+            }
+            changed.extend(coercion.scalars(value, path));
+        }
+        if !changed.is_empty() {
+            for (path, value) in &changed {
+                log::debug!("Coercing `{path}` of {:?}", entry.target);
+                path.set(&mut tree, Some(value.clone()), || serde_json::json!({}));
+            }
+            let coerced = Config::from_tree(&entry.format, tree)
+                .context(format!("Coerce values of {:?}", entry.target))?;
+            changed.iter().try_for_each(|(path, _)| config.restore(&coerced, path))?;
+        }
+    }
+    Ok(config)
+}
+
+/// A type values at a key path of a `COERCE` file are made after merging.
+#[derive(Debug, Clone, Copy)]
+enum Coercion {
+    /// Numeric strings become numbers.
+    Number,
+    /// `true` and `false` strings become booleans.
+    Boolean,
+    /// Numbers and booleans become strings.
+    String,
+}
+
+impl Coercion {
+    /// The value a scalar becomes, if it changes.
+    fn apply(self, value: &serde_json::Value) -> Option<serde_json::Value> {
+        use serde_json::Value;
+
+        match (self, value) {
+            (Coercion::Number, Value::String(s)) => s.trim().parse::<i64>().map(Value::from).ok()
+                .or_else(|| serde_json::Number::from_f64(s.trim().parse().ok()?).map(Value::Number)),
+            (Coercion::Boolean, Value::String(s)) => match s.trim().to_lowercase().as_str() {
+                "true" => Some(Value::Bool(true)),
+                "false" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            (Coercion::String, Value::Number(n)) => Some(Value::String(n.to_string())),
+            (Coercion::String, Value::Bool(b)) => Some(Value::String(b.to_string())),
+            _ => None,
+        }
+    }
+
+    /// Every scalar at or under a key path changing, with its new value.
+    fn scalars(self, value: &serde_json::Value, path: &KeyPath)
+        -> Vec<(KeyPath, serde_json::Value)>
+    {
+        use serde_json::Value;
+
+        let child = |key| KeyPath(path.0.iter().cloned().chain(once(key)).collect());
+        match value {
+            Value::Object(map) => map.iter()
+                .flat_map(|(k, v)| self.scalars(v, &child(Key::Name(k.clone()))))
+                .collect(),
+            Value::Array(array) => array.iter().enumerate()
+                .flat_map(|(i, v)| self.scalars(v, &child(Key::Index(i))))
+                .collect(),
+            value => self.apply(value).map(|v| (path.clone(), v)).into_iter().collect(),
+        }
+    }
+}
+
+/// Values the `default`s of a JSON Schema give to properties missing from a
+/// tree, by key path. Objects the tree has, or is given, are walked into,
+/// following `allOf` and `$ref`s within the schema.
+fn schema_defaults(root: &serde_json::Value, schema: &serde_json::Value,
+    tree: &serde_json::Value, path: &KeyPath) -> Vec<(KeyPath, serde_json::Value)>
+{
+    fn resolve<'a>(root: &'a serde_json::Value, mut schema: &'a serde_json::Value)
+        -> &'a serde_json::Value
+    {
+        // Bounded, as references may loop.
+        for _ in 0..32 {
+            match schema.get("$ref").and_then(|r| r.as_str()?.strip_prefix('#')) {
+                Some(pointer) => schema = root.pointer(pointer).unwrap_or(&serde_json::Value::Null),
+                None => break,
+            }
+        }
+        schema
+    }
+
+    let schema = resolve(root, schema);
+    let mut defaults: Vec<_> = schema.get("allOf").and_then(|all| all.as_array())
+        .into_iter()
+        .flatten()
+        .flat_map(|schema| schema_defaults(root, schema, tree, path))
+        .collect();
+    let properties = schema.get("properties").and_then(|p| p.as_object());
+    for (name, property) in properties.into_iter().flatten() {
+        let property = resolve(root, property);
+        let path = KeyPath(path.0.iter().cloned().chain(once(Key::Name(name.clone()))).collect());
+        let value = match (tree.get(name), property.get("default")) {
+            (Some(value), _) => value,
+            (None, Some(default)) => {
+                defaults.push((path.clone(), default.clone()));
+                default
+            }
+            (None, None) => continue,
+        };
+        if value.is_object() {
+            defaults.extend(schema_defaults(root, property, value, &path));
+        }
+    }
+    defaults
+}
+
+/// Fails when a fragment of an AWS config changes a key of a profile that an
+/// earlier fragment set, or a fragment of a Brewfile gives an entry other
+/// arguments, rather than one clobbering the other silently.
+fn clobbered(entry: &Entry, inputs: &[Input]) -> Result<()> {
+    let mut set: std::collections::HashMap<(&str, &str), (&serde_json::Value, &Path)> =
+        Default::default();
+    let profiles = inputs.iter()
+        .filter(|input| input.source != entry.target)
+        .filter_map(|input| match &input.config {
+            Config::Ini(serde_json::Value::Object(sections), IniStyle::Aws) =>
+                Some(sections.iter().map(|section| (section, input.source.as_path()))),
+            _ => None,
+        })
+        .flatten();
+    for ((section, keys), source) in profiles {
+        let profile = section.strip_prefix("profile ").unwrap_or(section).trim();
+        for (key, value) in keys.as_object().into_iter().flatten() {
+            match set.insert((profile, key), (value, source)) {
+                Some((earlier, by)) if earlier != value => anyhow::bail!(Failure::Conflict(
+                    format!("{source:?} changes `{key}` of profile `{profile}` set by {by:?}"))),
+                _ => {}
+            }
+        }
+    }
+
+    let mut given: std::collections::HashMap<(&str, &str), (&BrewEntry, &Path)> =
+        Default::default();
+    let entries = inputs.iter()
+        .filter(|input| input.source != entry.target)
+        .filter_map(|input| match &input.config {
+            Config::Brewfile(brewfile) =>
+                Some(brewfile.entries.iter().map(|entry| (entry, input.source.as_path()))),
+            _ => None,
+        })
+        .flatten()
+        .filter(|(entry, _)| !entry.args.is_empty());
+    for (brew, source) in entries {
+        match given.insert((&brew.kind, &brew.name), (brew, source)) {
+            Some((earlier, by)) if earlier.args != brew.args => anyhow::bail!(Failure::Conflict(
+                format!("{source:?} has `{brew}` while {by:?} has `{earlier}`"))),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Key paths listed in a marker file of a `.d` directory, like `PROTECTED`,
+/// one per line. Empty lines and `#` comments are skipped.
+fn key_paths(directory: &Path, marker: &str) -> Result<Vec<KeyPath>> {
+    use anyhow::Context;
+
+    let file = directory.join(marker);
+    if !file.exists() {
+        return Ok(vec![]);
+    }
+    std::fs::read_to_string(&file)?
+    .lines()
+    .map(str::trim)
+    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    .map(|line| line.parse().context(Failure::Parse(format!("In {file:?}"))))
+    .collect()
+}
+
+/// Whether a fragment is named like `20-token.sensitive.json`.
+fn is_sensitive(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.split('.').skip(1).any(|s| s == "sensitive"))
+}
+
+/// The value at a key path as shown in diffs and reports, masked within a
+/// sensitive key path.
+fn redact(path: &KeyPath, value: &serde_json::Value, sensitive: &[KeyPath])
+    -> serde_json::Value
+{
+    const MASK: &str = "<redacted>";
+
+    if sensitive.iter().any(|s| path.0.starts_with(&s.0)) {
+        return MASK.into();
+    }
+    let mut value = value.clone();
+    for inner in sensitive.iter().filter(|s| s.0.starts_with(&path.0)) {
+        let inner = KeyPath(inner.0[path.0.len()..].to_vec());
+        if inner.get(&value).is_some() {
+            inner.set(&mut value, Some(MASK.into()), || serde_json::json!({}));
+        }
+    }
+    value
+}
+
+/// Whether a fragment is named like `10-security.final.json`.
+fn is_final(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.split('.').skip(1).any(|s| s == "final"))
+}
+
+/// Merges the fragments of one `.d` directory into its target, journaling
+/// the change.
+fn apply_entry(cli: &Cli, entry: Entry, journal: &std::sync::Mutex<Vec<Revert>>)
+    -> Result<Stats>
+{
+    use std::time::Instant;
+
+    let start = Instant::now();
+    let inputs = inputs(&entry)?;
+    let parsed = Instant::now();
+    let result = rendered_from(cli, &entry, inputs)?;
+    let merged = Instant::now();
+    let before = entry.host.read(&entry.target)?;
+    let result = match cli.resolve {
+        true => resolve(&entry, &before, result, std::io::stdin().lock(), std::io::stdout())?,
+        false => result,
+    };
+    if cli.diff {
+        print!("{}", diff(&entry, &before, &result)?);
+    }
+    let mut stats = Stats::new(&entry, &before, &result)?;
+    log::info!("{:?}: {} added, {} overridden, {} removed from {} fragment(s)",
+        stats.target, stats.added, stats.overridden, stats.removed, stats.fragments);
+    let writing = Instant::now();
+    let private = cli.private_patterns(&entry.target);
+    let redacted = !private.is_empty() || !entry.sensitive()?.is_empty();
+    if cli.backup || cli.settings.backups.enabled {
+        backup(cli, &entry, &result)?;
+    }
+    // Patches hold the values changed.
+    if cli.json_patch && !redacted {
+        record_patch(cli, &entry, &before, &result)?;
+    }
+    if !private.is_empty() {
+        guard_private(cli, &entry, &private)?;
+    }
+    entry.host.write(&entry.target, &result)?;
+    if let Some(owner) = entry.owner {
+        entry.host.chown(&entry.target, owner)?;
+    }
+    if before != result {
+        journal.lock().expect("No worker panics holding the journal").push(Revert {
+            host: entry.host.clone(),
+            target: entry.target.clone(),
+            before,
+            after: result,
+            redacted,
+        });
+    }
+    // Credentials are secrets.
+    if entry.format.as_deref() == Some("awsconfig")
+        && entry.target.file_name().is_some_and(|name| name == "credentials")
+    {
+        entry.host.restrict(&entry.target)?;
+    }
+    stats.timings = cli.timings.then(|| Timings {
+        parse: parsed - start,
+        merge: merged - parsed,
+        write: writing.elapsed(),
+    });
+    Ok(stats)
+}
+
+/// Creates a missing private target readable by its owner only, and warns
+/// about an existing one, or the directory a pattern names, that others may
+/// read, as changing modes of existing files is left to their owner.
+fn guard_private(cli: &Cli, entry: &Entry, patterns: &[&str]) -> Result<()> {
+    let Entry { target, host, .. } = entry;
+    match host.mode(target)? {
+        None => host.create_private(target)?,
+        Some(mode) if mode & 0o077 != 0 => log::warn!(
+            "{target:?} is private but has mode {mode:03o}, consider `chmod 600` on it"),
+        Some(_) => {}
+    }
+    let directories = patterns.iter()
+        .filter_map(|pattern| pattern.strip_suffix("/**").or_else(|| pattern.strip_suffix("/*")))
+        .filter(|directory| !directory.contains('*'))
+        .map(|directory| cli.target_root().join(directory));
+    for directory in directories {
+        if let Some(mode) = host.mode(&directory)? && mode & 0o077 != 0 {
+            log::warn!("{directory:?} holds private targets but has mode {mode:03o}, \
+                consider `chmod 700` on it");
+        }
+    }
+    Ok(())
+}
+
+/// A key path a target gains (`+`), changes (`~`) or loses (`-`).
+struct Change {
+    sign: char,
+    path: KeyPath,
+    value: String,
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}: {}", self.sign, self.path, self.value)
+    }
+}
+
+/// Key paths changing from the content of a target to its rendered text,
+/// or `None` for text targets.
+fn changes(entry: &Entry, before: &str, after: &str) -> Result<Option<Vec<Change>>> {
+    fn find<'a>(leaves: &[(KeyPath, &'a serde_json::Value)], path: &KeyPath)
+        -> Option<&'a serde_json::Value>
+    {
+        leaves.iter().find(|(p, _)| p == path).map(|(_, value)| *value)
+    }
+
+    // Text not parsing, like a target written as text with `on_parse_error`,
+    // is diffed as text.
+    let tree = |text: &str| match text.trim().is_empty() {
+        true => Some(serde_json::json!({})),
+        false => entry.parse(text.into()).ok()?.tree(),
+    };
+    let (Some(old), Some(new)) = (tree(before), tree(after)) else {
+        return Ok(None);
+    };
+    let sensitive = entry.sensitive()?;
+    let shown = |path, value| redact(path, value, &sensitive);
+    let (old, new) = (KeyPath::leaves(&old), KeyPath::leaves(&new));
+    Ok(Some(new.iter()
+        .filter_map(|(path, value)| match find(&old, path) {
+            Some(old) if old == *value => None,
+            Some(old) => Some(('~', path, format!("{} -> {}", shown(path, old), shown(path, value)))),
+            None => Some(('+', path, shown(path, value).to_string())),
+        })
+        .chain(old.iter()
+            .filter(|(path, _)| find(&new, path).is_none())
+            .map(|(path, value)| ('-', path, shown(path, value).to_string())))
+        .map(|(sign, path, value)| Change { sign, path: path.clone(), value })
+        .collect()))
+}
+
+/// Changes from the content of a target to its rendered text, one key path
+/// per line for structured targets and a unified diff for text. Empty when
+/// nothing changes.
+fn diff(entry: &Entry, before: &str, after: &str) -> Result<String> {
+    if before == after {
+        return Ok(String::new());
+    }
+    let header = format!("{}\n", entry.target.display());
+    let Some(changes) = changes(entry, before, after)? else {
+        if entry.fragments()?.iter().any(|fragment| is_sensitive(fragment)) {
+            return Ok(header + "<redacted>, from sensitive fragments\n");
+        }
+        let diff = similar::TextDiff::from_lines(before, after).unified_diff()
+            .to_string();
+        return Ok(header + &diff);
+    };
+    Ok(match changes.is_empty() {
+        true => String::new(),
+        false => header + &changes.iter().map(|c| format!("{c}\n")).collect::<String>(),
+    })
+}
+
+/// Asks, for each key path the rendered text changes or removes from the
+/// existing target, or each changed hunk of text targets, whether to take
+/// the fragments' side or keep the target's. `F` and `T` answer for every
+/// remaining one.
+fn resolve(
+    entry: &Entry,
+    before: &str,
+    after: String,
+    mut input: impl std::io::BufRead,
+    mut output: impl std::io::Write,
+) -> Result<String> {
+    let mut all = None;
+    let mut keep_target = |output: &mut dyn std::io::Write| -> Result<bool> {
+        if let Some(all) = all {
+            return Ok(all);
+        }
+        loop {
+            write!(output, "Take [f]ragments or keep [t]arget (F/T for all remaining)? ")?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                anyhow::bail!("No answer for {:?}", entry.target);
+            }
+            return Ok(match line.trim() {
+                "" | "f" => false,
+                "t" => true,
+                "F" => *all.insert(false),
+                "T" => *all.insert(true),
+                _ => continue,
+            });
+        }
+    };
+
+    if before.trim().is_empty() || before == after {
+        return Ok(after);
+    }
+    writeln!(output, "{}", entry.target.display())?;
+    let Some(changes) = changes(entry, before, &after)? else {
+        let sensitive = entry.fragments()?.iter().any(|fragment| is_sensitive(fragment));
+        let diff = similar::TextDiff::from_lines(before, &after);
+        let (old, new) = (diff.old_slices(), diff.new_slices());
+        let mut text = String::new();
+        for op in diff.ops() {
+            let (old, new) = (&old[op.old_range()], &new[op.new_range()]);
+            if op.tag() != similar::DiffTag::Equal {
+                match sensitive {
+                    true => writeln!(output, "<redacted>, from sensitive fragments")?,
+                    false => old.iter().map(|line| ('-', line))
+                        .chain(new.iter().map(|line| ('+', line)))
+                        .try_for_each(|(sign, line)| write!(output, "{sign} {line}"))?,
+                }
+                if keep_target(&mut output)? {
+                    text.extend(old.iter().copied());
+                    continue;
+                }
+            }
+            text.extend(new.iter().copied());
+        }
+        return Ok(text);
+    };
+
+    let mut kept = vec![];
+    for change in changes.into_iter().filter(|change| change.sign != '+') {
+        writeln!(output, "{change}")?;
+        if keep_target(&mut output)? {
+            kept.push(change.path);
+        }
+    }
+    if kept.is_empty() {
+        return Ok(after);
+    }
+    let existing = entry.parse(before.into())?;
+    let mut config = entry.parse(after)?;
+    kept.iter().try_for_each(|path| config.restore(&existing, path))?;
+    config.try_into()
+}
+
+/// What applying a target changed. Structured targets count key paths,
+/// text targets count lines.
+#[derive(Debug, Default, PartialEq, serde::Serialize)]
+struct Stats {
+    target: PathBuf,
+    fragments: usize,
+    added: usize,
+    overridden: usize,
+    removed: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timings: Option<Timings>,
+}
+
+/// Time spent on a target with `--timings`, in milliseconds once
+/// serialized.
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Serialize)]
+struct Timings {
+    /// Reading and parsing the existing target and fragments.
+    #[serde(serialize_with = "serialize_millis")]
+    parse: std::time::Duration,
+    /// Merging and rendering them.
+    #[serde(serialize_with = "serialize_millis")]
+    merge: std::time::Duration,
+    /// Backing up, writing and whatever follows.
+    #[serde(serialize_with = "serialize_millis")]
+    write: std::time::Duration,
+}
+
+impl Timings {
+    fn total(&self) -> std::time::Duration {
+        self.parse + self.merge + self.write
+    }
+}
+
+fn serialize_millis<S: serde::Serializer>(duration: &std::time::Duration, s: S)
+    -> Result<S::Ok, S::Error>
+{
+    s.serialize_f64(duration.as_secs_f64() * 1e3)
+}
+
+impl Stats {
+    fn new(entry: &Entry, before: &str, after: &str) -> Result<Self> {
+        use similar::ChangeTag;
+
+        let signs: Vec<char> = match changes(entry, before, after)? {
+            Some(changes) => changes.iter().map(|c| c.sign).collect(),
+            None => similar::TextDiff::from_lines(before, after).iter_all_changes()
+                .filter_map(|change| match change.tag() {
+                    ChangeTag::Insert => Some('+'),
+                    ChangeTag::Delete => Some('-'),
+                    ChangeTag::Equal => None,
+                })
+                .collect(),
+        };
+        let count = |sign| signs.iter().filter(|s| **s == sign).count();
+        Ok(Stats {
+            target: entry.target.clone(),
+            fragments: entry.fragments()?.len(),
+            added: count('+'),
+            overridden: count('~'),
+            removed: count('-'),
+            timings: None,
+        })
+    }
+}
+
+/// Text the target of an entry would be written with.
+fn rendered(cli: &Cli, entry: &Entry) -> Result<String> {
+    rendered_from(cli, entry, inputs(entry)?)
+}
+
+fn rendered_from(cli: &Cli, entry: &Entry, inputs: Vec<Input>) -> Result<String> {
+    let text = if cli.annotate {
+        annotated(entry, inputs)?
+    } else {
+        render_entry(entry, inputs)?.try_into()?
+    };
+    match cli.comment_pruned {
+        true => comment_pruned(entry, &entry.host.read(&entry.target)?, text),
+        false => Ok(text),
+    }
+}
+
+/// Starts the comments standing for keys pruned from a target.
+const PRUNED: &str = "# removed by patch: ";
+
+/// Writes the keys pruned from TOML and YAML targets back as comments, at
+/// the top of the deepest table of their path that is left. Comments of
+/// earlier runs are kept until a fragment sets their key again.
+fn comment_pruned(entry: &Entry, before: &str, text: String) -> Result<String> {
+    let separator = match entry.format.as_deref() {
+        Some("toml") => " = ",
+        Some("yaml") => ": ",
+        _ => return Ok(text),
+    };
+    let Some(tree) = entry.parse(text.clone())?.tree() else {
+        return Ok(text);
+    };
+
+    let earlier = before.lines()
+        .filter_map(|line| line.trim_start().strip_prefix(PRUNED)?.split_once(separator))
+        .filter_map(|(path, value)| Some((path.parse::<KeyPath>().ok()?, value.to_string())));
+    let mut pruned: Vec<(KeyPath, String)> = vec![];
+    for (path, value) in changes(entry, before, &text)?.into_iter().flatten()
+        .filter(|change| change.sign == '-')
+        .map(|change| (change.path, change.value))
+        .chain(earlier)
+    {
+        if path.get(&tree).is_none() && pruned.iter().all(|(p, _)| *p != path) {
+            pruned.push((path, value));
+        }
+    }
+
+    let lines: Vec<&str> = text.lines()
+        .filter(|line| !line.trim_start().starts_with(PRUNED))
+        .collect();
+    // Where a comment may go, for the table it is in: after a TOML table
+    // header, or before the first YAML key of a mapping.
+    let anchors: Vec<(Vec<String>, usize, &str)> = match separator {
+        " = " => once((vec![], 0, ""))
+            .chain(lines.iter().enumerate().filter_map(|(n, line)| {
+                let header = line.strip_prefix("[[").and_then(|l| l.strip_suffix("]]"))
+                    .or_else(|| line.strip_prefix('[')?.strip_suffix(']'))?;
+                let path: KeyPath = header.parse().ok()?;
+                Some((path.0.iter().map(Key::to_string).collect(), n + 1, ""))
+            }))
+            .collect(),
+        _ => yaml_nodes(&lines.join("\n")).0.into_iter()
+            .filter(|(n, ..)| !lines[*n].trim_start().starts_with('-'))
+            .map(|(n, mut keys, _, _)| {
+                keys.pop();
+                (keys, n, &lines[n][..lines[n].len() - lines[n].trim_start().len()])
+            })
+            .collect(),
+    };
+    let inserts: Vec<(usize, String)> = pruned.iter()
+        .map(|(path, value)| {
+            let names: Vec<String> = path.0.iter().map(Key::to_string).collect();
+            // The first of the deepest, as `max_by_key` gives the last.
+            let (n, indent) = anchors.iter().rev()
+                .filter(|(keys, _, _)| keys.len() < names.len() && names.starts_with(keys))
+                .max_by_key(|(keys, _, _)| keys.len())
+                .map_or((lines.len(), ""), |(_, n, indent)| (*n, *indent));
+            (n, format!("{indent}{PRUNED}{path}{separator}{value}"))
+        })
+        .collect();
+
+    Ok((0..=lines.len())
+        .flat_map(|n| inserts.iter().filter(move |(i, _)| *i == n).map(|(_, line)| line.as_str())
+            .chain(lines.get(n).copied()))
+        .map(|line| format!("{line}\n"))
+        .collect())
+}
+
+/// Renders inputs with a comment before each key, table or text fragment
+/// naming the fragment it comes from. Formats without comments are left
+/// as is.
+fn annotated(entry: &Entry, inputs: Vec<Input>) -> Result<String> {
+    use toml_edit::{DocumentMut, Item, Table};
+
+    fn comment(sources: &[&Path]) -> String {
+        let sources: Vec<_> = sources.iter().map(|s| s.display().to_string()).collect();
+        format!("# from {}\n", sources.join(", "))
+    }
+
+    let fragments: Vec<_> = inputs.iter()
+        .filter(|input| input.source != entry.target)
+        .map(|input| (input.source.clone(), input.config.tree()))
+        .collect();
+    let texts: Vec<_> = inputs.iter()
+        .filter_map(|Input { source, config }| match config {
+            Config::Text(text) if *source == entry.target => Some(text.clone()),
+            Config::Text(text) => Some(comment(&[source]) + text),
+            _ => None,
+        })
+        .collect();
+
+    match render_entry(entry, inputs)? {
+        Config::Text(_) => Ok(texts.join("\n")),
+
+        Config::Toml(toml) => {
+            fn walk(table: &mut Table, path: &KeyPath,
+                fragments: &[(PathBuf, Option<serde_json::Value>)])
+            {
+                for (mut key, item) in table.iter_mut() {
+                    let mut path = path.clone();
+                    path.0.push(Key::Name(key.get().to_string()));
+                    let sources: Vec<&Path> = fragments.iter()
+                        .filter(|(_, tree)| tree.as_ref()
+                            .is_some_and(|tree| path.get(tree).is_some()))
+                        .map(|(source, _)| source.as_path())
+                        .collect();
+                    let Some(last) = sources.last() else { continue };
+
+                    match item {
+                        Item::Table(table) => {
+                            table.decor_mut().set_prefix(format!("\n{}", comment(&sources)));
+                            walk(table, &path, fragments);
+                        }
+                        Item::ArrayOfTables(array) => array.iter_mut()
+                            .for_each(|table| table.decor_mut()
+                                .set_prefix(format!("\n{}", comment(&[last])))),
+                        _ => key.leaf_decor_mut().set_prefix(comment(&[last])),
+                    }
+                }
+            }
+
+            let mut document: DocumentMut = toml.to_string().parse()?;
+            walk(document.as_table_mut(), &KeyPath(vec![]), &fragments);
+            Ok(document.to_string().trim_start().to_string())
+        }
+
+        config => config.try_into(),
+    }
+}
+
+/// The entry of a target file given on the command line.
+fn entry_of(cli: &Cli, file: &Path) -> Result<Entry> {
+    use anyhow::Context;
+
+    let file = std::path::absolute(file)?;
+    let relative = cli.relative(&file)
+        .context(format!("{file:?} is not under {:?}", cli.target_root()))?;
+    let target = cli.target_root().join(relative);
+
+    // Rules may map any directory here, so look for it before falling back
+    // to the inverse of the built-in ones.
+    match discover(cli)?.into_iter().find(|entry| entry.target == target) {
+        Some(entry) => Ok(entry),
+        None => Entry::new(cli, &cli.layer().join(cli.settings.uncanonicalize(relative))),
+    }
+}
+
+/// Writes each part of an existing target as a fragment of its `.d`
+/// directory. Parts are numbered in steps of 10 to leave room for overrides.
+fn split(cli: &Cli, args: &SplitArgs) -> Result<()> {
+    use anyhow::{bail, Context};
+
+    let entry = entry_of(cli, &args.file)?;
+    let Entry { target, format, host, .. } = &entry;
+    let directory = entry.directory();
+    let text = host.read(target)?;
+    let parts = Config::parse_dispatch(format, text)?.split(args.by)?;
+
+    if !args.force && directory.read_dir().is_ok_and(|mut d| d.next().is_some()) {
+        bail!("{directory:?} is not empty, pass `--force` to write anyway");
+    }
+    std::fs::create_dir_all(directory)?;
+
+    let width = (parts.len().saturating_sub(1) * 10).to_string().len().max(2);
+    parts.into_iter().enumerate().try_for_each(|(i, (name, config))| {
+        let name: String = name.chars()
+            .map(|c| if c.is_alphanumeric() || "-_.".contains(c) { c } else { '_' })
+            .collect();
+        let suffix = format.as_ref().map(|f| format!(".{f}")).unwrap_or_default();
+        let path = directory.join(format!("{:0width$}-{name}{suffix}", i * 10));
+
+        let mut text: String = config.try_into()?;
+        if matches!(format.as_deref(), Some("json" | "hjson")) {
+            text.push('\n');
+        }
+
+        log::info!("Writing {path:?}");
+        std::fs::write(&path, text).context(format!("Write {path:?}"))
+    })
+}
+
+/// Copies existing files under the target into `00-base` fragments of new
+/// `.d` directories.
+fn init(cli: &Cli, args: &InitArgs) -> Result<()> {
+    use anyhow::Context;
+    use duct::cmd;
+
+    let from = std::path::absolute(args.from.clone().unwrap_or(cli.target_root()))?;
+    let managed: std::collections::HashSet<_> = discover(cli)?.into_iter()
+        .map(|entry| entry.target)
+        .collect();
+    let candidates: Vec<(PathBuf, PathBuf, String)> =
+        cmd!("find", &from, "-type", "f").read()?
+        .lines()
+        .map(PathBuf::from)
+        .filter_map(|file| {
+            let directory = cli.layer().join(
+                cli.settings.uncanonicalize(cli.relative(&file)?)
+            );
+            if managed.contains(&file) || directory.exists() {
+                log::debug!("Skipping {file:?}, already managed");
+                return None;
+            }
+            // Binary files are no configs.
+            let text = std::fs::read_to_string(&file).ok()?;
+            Some((file, directory, text))
+        })
+        .collect();
+
+    if candidates.is_empty() {
+        anyhow::bail!("No unmanaged text file found under {from:?}");
+    }
+
+    let chosen = if args.select {
+        let files: Vec<_> = candidates.iter().map(|(f, ..)| f.as_path()).collect();
+        select(&files, std::io::stdin().lock(), std::io::stdout())?
+    } else {
+        (0..candidates.len()).collect()
+    };
+
+    candidates.into_iter().enumerate()
+    .filter(|(i, _)| chosen.contains(i))
+    .try_for_each(|(_, (file, directory, text))| {
+        let suffix = extension(&file).map(|f| format!(".{f}")).unwrap_or_default();
+        let fragment = directory.join(format!("00-base{suffix}"));
+
+        log::info!("Writing {fragment:?}");
+        std::fs::create_dir_all(&directory)?;
+        std::fs::write(&fragment, text).context(format!("Write {fragment:?}"))
+    })
+}
+
+/// Asks which of the files to take, answered with indices and ranges like
+/// `1 3 5-7`.
+fn select(
+    files: &[&Path],
+    mut input: impl std::io::BufRead,
+    mut output: impl std::io::Write,
+) -> Result<Vec<usize>> {
+    use anyhow::{bail, Context};
+
+    files.iter().enumerate().try_for_each(|(i, file)| {
+        writeln!(output, "{:>4}  {}", i + 1, file.display())
+    })?;
+    write!(output, "Select files to manage (e.g. `1 3 5-7`): ")?;
+    output.flush()?;
+
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+
+    let mut chosen = vec![];
+    for word in line.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|w| !w.is_empty())
+    {
+        let (start, end) = word.split_once('-').unwrap_or((word, word));
+        let parse = |n: &str| n.parse::<usize>()
+            .context(format!("Invalid selection `{word}`"));
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start == 0 || end > files.len() || start > end {
+            bail!("Selection `{word}` is out of range");
+        }
+        chosen.extend(start - 1..end);
+    }
+    Ok(chosen)
+}
+
+/// Creates a fragment with the extension of its target. A name without a
+/// numeric prefix is put after the existing fragments.
+fn new(cli: &Cli, args: &NewArgs) -> Result<()> {
+    use anyhow::{bail, Context};
+
+    let layer = cli.layer_of(&args.fragment).unwrap_or(cli.layer());
+    let fragment = args.fragment.strip_prefix(layer)
+        .unwrap_or(&args.fragment);
+    let (Some(directory), Some(name)) = (fragment.parent(), fragment.file_name())
+    else {
+        bail!("{fragment:?} is not a fragment path");
+    };
+    if !cli.settings.is_directory(directory) {
+        bail!("{directory:?} is not a `.d` directory");
+    }
+    let directory = layer.join(directory);
+    let format = Entry::new(cli, &directory)?.format;
+    let mut name = name.to_string_lossy().into_owned();
+
+    if !name.starts_with(|c: char| c.is_ascii_digit()) {
+        let last = directory.exists().then(|| fragments(&directory))
+            .transpose()?
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|path| {
+                let name = path.file_name()?.to_str()?;
+                name.split(|c: char| !c.is_ascii_digit()).next()?.parse().ok()
+            })
+            .max();
+        let next = last.map_or(0, |n: u64| (n / 10 + 1) * 10);
+        name = format!("{next:02}-{name}");
+    }
+    if let Some(format) = &format
+        && extension(Path::new(&name)).as_ref() != Some(format)
+    {
+        name = format!("{name}.{format}");
+    }
+
+    let path = directory.join(name);
+    if path.exists() {
+        bail!("{path:?} already exists");
+    }
+    let content = match &args.template {
+        Some(template) => std::fs::read_to_string(template)
+            .context(format!("Read template {template:?}"))?,
+        None => Config::skeleton(&format).to_string(),
+    };
+
+    std::fs::create_dir_all(&directory)?;
+    std::fs::write(&path, content).context(format!("Write {path:?}"))?;
+    println!("{}", path.display());
+    Ok(())
+}
+
+/// Opens a fragment in the editor until it parses, then applies only the
+/// target it belongs to. Given up edits are reverted.
+fn edit(cli: &Cli, args: &EditArgs, mut answers: impl std::io::BufRead)
+    -> Result<()>
+{
+    use anyhow::{bail, Context};
+    use duct::cmd;
+
+    let file = std::path::absolute(&args.file)?;
+    let layers = cli.directory.iter()
+        .map(std::path::absolute)
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let fragment = if layers.iter().any(|layer| file.starts_with(layer)) {
+        file
+    } else {
+        cli.relative(&file)
+            .context(format!("{file:?} is neither a fragment nor a target"))?;
+        let fragment = entry_of(cli, &file)?.fragments()?
+            .pop()
+            .context(format!("{file:?} has no fragment, create one with `new`"))?;
+        std::path::absolute(fragment)?
+    };
+    // Every target the fragment feeds, with the fragment as its entry names it.
+    let entries: Vec<(Entry, PathBuf)> = discover(cli)?.into_iter()
+        .filter_map(|entry| {
+            let source = entry.directories.iter().find_map(|directory| {
+                let relative = fragment.strip_prefix(std::path::absolute(directory).ok()?).ok()?;
+                Some(directory.join(relative))
+            })?;
+            Some((entry, source))
+        })
+        .collect();
+    if entries.is_empty() {
+        bail!("{fragment:?} is not in a `.d` directory");
+    }
+    let original = std::fs::read_to_string(&fragment)
+        .context(format!("Read {fragment:?}"))?;
+
+    let mut words = args.editor.split_whitespace();
+    let editor = words.next().context("Editor command is empty")?;
+    let editor_args: Vec<_> = words.collect();
+
+    loop {
+        cmd(editor, editor_args.iter().copied().map(Into::into)
+            .chain(once(fragment.clone().into_os_string())))
+            .run()
+            .context(format!("Run `{}`", args.editor))?;
+
+        let text = std::fs::read_to_string(&fragment)?;
+        let Some(error) = entries.iter()
+            .find_map(|(entry, source)| input(entry, source.clone(),
+                substitute(&text, &entry.variables)).err())
+        else {
+            break;
+        };
+
+        eprintln!("{fragment:?} does not parse: {error:#}");
+        eprint!("Edit again? [Y/n] ");
+        let mut answer = String::new();
+        if answers.read_line(&mut answer)? == 0
+            || answer.trim().eq_ignore_ascii_case("n")
+        {
+            std::fs::write(&fragment, original)?;
+            bail!("Edit given up, {fragment:?} is reverted");
+        }
+    }
+
+    let journal = Default::default();
+    for (entry, _) in entries {
+        apply_entry(cli, entry, &journal)?;
+    }
+    let journal = journal.into_inner().expect("No other thread holds the journal");
+    audit(cli, "edit", &journal)?;
+    record_run(cli, journal)
+}
+
+/// A row printed by [`list`].
+struct Listing {
+    entry: Entry,
+    fragments: usize,
+}
+
+fn list(cli: &Cli) -> Result<()> {
+    let listings = listings(cli)?;
+
+    match cli.output {
+        Output::Json => println!("{:#}", serde_json::Value::from_iter(
+            listings.iter().map(|Listing { entry, fragments }| serde_json::json!({
+                "directories": entry.directories,
+                "target": entry.target,
+                "format": entry.format.as_deref().unwrap_or("text"),
+                "fragments": fragments,
+            }))
+        )),
+        Output::Text | Output::Github => {
+            let rows: Vec<[String; 4]> = once([
+                "DIRECTORY", "TARGET", "FORMAT", "FRAGMENTS"
+            ].map(String::from))
+            .chain(listings.iter().map(|Listing { entry, fragments }| [
+                entry.directories.iter()
+                    .map(|d| d.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(","),
+                entry.target.display().to_string(),
+                entry.format.clone().unwrap_or("text".into()),
+                fragments.to_string(),
+            ]))
+            .collect();
+            print_table(&rows);
+        }
+    }
+    Ok(())
+}
+
+/// Prints rows with columns aligned.
+fn print_table<const N: usize>(rows: &[[String; N]]) {
+    let widths = (0..N).map(|i| {
+        rows.iter().map(|row| row[i].chars().count()).max().unwrap_or(0)
+    })
+    .collect::<Vec<_>>();
+    for row in rows {
+        let line = row.iter().zip(&widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+}
+
+fn listings(cli: &Cli) -> Result<Vec<Listing>> {
+    discover(cli)?.into_iter()
+    .map(|entry| Ok(Listing {
+        fragments: entry.fragments()?.len(),
+        entry,
+    }))
+    .collect()
+}
+
+/// Renders fragment to target edges. Fragments linking to a shared snippet
+/// get an extra edge from the snippet.
+fn graph(cli: &Cli, args: &GraphArgs) -> Result<String> {
+    use std::fmt::Write;
+
+    fn quote(path: &Path) -> String {
+        format!("{:?}", path.display().to_string())
+    }
+
+    let mut out = String::new();
+    if args.dot {
+        writeln!(out, "digraph patch {{")?;
+        writeln!(out, "    rankdir=LR;")?;
+    }
+
+    for entry in discover(cli)? {
+        let target = &entry.target;
+        if args.dot {
+            writeln!(out, "    {} [shape=box];", quote(target))?;
+        } else {
+            writeln!(out, "{}", target.display())?;
+        }
+
+        for fragment in entry.fragments()? {
+            let snippet = fragment.is_symlink()
+                .then(|| std::fs::canonicalize(&fragment).ok())
+                .flatten();
+
+            if args.dot {
+                writeln!(out, "    {} -> {};", quote(&fragment), quote(target))?;
+                if let Some(snippet) = snippet {
+                    writeln!(out, "    {} [shape=note];", quote(&snippet))?;
+                    writeln!(out, "    {} -> {} [style=dashed];",
+                        quote(&snippet), quote(&fragment))?;
+                }
+            } else {
+                write!(out, "    {}", fragment.display())?;
+                if let Some(snippet) = snippet {
+                    write!(out, " -> {}", snippet.display())?;
+                }
+                writeln!(out)?;
+            }
+        }
+    }
+
+    if args.dot {
+        writeln!(out, "}}")?;
+    }
+    Ok(out)
+}
+
+/// How an input took part in the value at a key path.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    /// Its value was replaced by a later input.
+    Overridden,
+    /// Its table was merged with others into the final value.
+    Merged,
+    /// Its value is the final value.
+    Set,
+    /// It removed the value.
+    Deleted,
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Role::Overridden => "overridden",
+            Role::Merged => "merged",
+            Role::Set => "set",
+            Role::Deleted => "deleted",
+        })
+    }
+}
+
+fn why(cli: &Cli, args: &WhyArgs) -> Result<()> {
+    let entry = entry_of(cli, &args.file)?;
+    let (value, history) = provenance(&entry, &args.path)?;
+    let sensitive = entry.sensitive()?;
+    let value = value.map(|value| redact(&args.path, &value, &sensitive));
+    let history: Vec<_> = history.into_iter()
+        .map(|(source, value, role)| {
+            (source, value.map(|value| redact(&args.path, &value, &sensitive)), role)
+        })
+        .collect();
+
+    match cli.output {
+        Output::Json => println!("{:#}", serde_json::json!({
+            "target": entry.target,
+            "path": args.path.to_string(),
+            "value": value,
+            "sources": history.iter().map(|(source, value, role)| {
+                serde_json::json!({
+                    "source": source,
+                    "value": value,
+                    "role": role.to_string(),
+                })
+            })
+            .collect::<Vec<_>>(),
+        })),
+        Output::Text | Output::Github => {
+            match &value {
+                Some(value) => println!("{} = {value}", args.path),
+                None => println!("{} is not set", args.path),
+            }
+            for (source, value, role) in &history {
+                let value = value.as_ref().map(|v| format!(" ({v})")).unwrap_or_default();
+                let existing = if *source == entry.target { " (existing target)" } else { "" };
+                println!("  {role:>10}  {}{existing}{value}", source.display());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Renders a target in memory and formats the value at a key path. Strings
+/// are printed as is in text output.
+fn get(cli: &Cli, args: &GetArgs) -> Result<String> {
+    use anyhow::Context;
+
+    let entry = entry_of(cli, &args.file)?;
+    let tree = render_entry(&entry, inputs(&entry)?)?.tree()
+        .context(format!("{:?} is plain text and has no keys", entry.target))?;
+    let value = args.path.get(&tree)
+        .context(format!("`{}` is not set in {:?}", args.path, entry.target))?;
+
+    Ok(match (cli.output, value) {
+        (Output::Text | Output::Github, serde_json::Value::String(s)) => s.clone(),
+        (Output::Text | Output::Github, value) => value.to_string(),
+        (Output::Json, value) => format!("{value:#}"),
+    })
+}
+
+/// Replays the merge of a target, keeping every input which changed the
+/// value at `path` along with its own value there.
+#[allow(clippy::type_complexity)]
+fn provenance(entry: &Entry, path: &KeyPath)
+    -> Result<(Option<serde_json::Value>, Vec<(PathBuf, Option<serde_json::Value>, Role)>)>
+{
+    use anyhow::Context;
+
+    let mut merged = Config::None;
+    let mut history = vec![];
+    for Input { source, config } in inputs(entry)? {
+        let tree = config.tree().context("Plain text has no keys")?;
+        let own = path.get(&tree).cloned();
+
+        let before = merged.tree().and_then(|t| path.get(&t).cloned());
+        merged = merged.try_merge(Ok(config))?;
+        let after = merged.tree().and_then(|t| path.get(&t).cloned());
+
+        if own.is_some() || before != after {
+            history.push((source, own, after));
+        }
+    }
+
+    let value = merged.tree().and_then(|t| path.get(&t).cloned());
+    let last = history.len().saturating_sub(1);
+    let history = history.into_iter().enumerate()
+        .map(|(i, (source, own, after))| {
+            let role = match (&value, &own) {
+                _ if i == last && after.is_none() => Role::Deleted,
+                _ if i == last => Role::Set,
+                (Some(v), Some(o)) if v.is_object() && o.is_object() => Role::Merged,
+                _ => Role::Overridden,
+            };
+            (source, own, role)
+        })
+        .collect();
+
+    Ok((value, history))
+}
+
+/// Values given to one key path by different fragments.
+type Conflict = (KeyPath, Vec<(PathBuf, serde_json::Value)>);
+
+fn conflicts(cli: &Cli) -> Result<()> {
+    let mut report = vec![];
+    for entry in discover(cli)? {
+        let sensitive = entry.sensitive()?;
+        let conflicts: Vec<Conflict> = target_conflicts(&entry)?.into_iter()
+            .map(|(path, values)| {
+                let values = values.into_iter()
+                    .map(|(source, value)| (source, redact(&path, &value, &sensitive)))
+                    .collect();
+                (path, values)
+            })
+            .collect();
+        if !conflicts.is_empty() {
+            report.push((entry.target, conflicts));
+        }
+    }
+
+    match cli.output {
+        Output::Json => println!("{:#}", serde_json::Value::from_iter(
+            report.iter().map(|(target, conflicts)| serde_json::json!({
+                "target": target,
+                "conflicts": conflicts.iter().map(|(path, values)| serde_json::json!({
+                    "path": path.to_string(),
+                    "values": values.iter().map(|(source, value)| serde_json::json!({
+                        "source": source,
+                        "value": value,
+                    }))
+                    .collect::<Vec<_>>(),
+                }))
+                .collect::<Vec<_>>(),
+            }))
+        )),
+        Output::Text | Output::Github => for (target, conflicts) in &report {
+            println!("{}", target.display());
+            for (path, values) in conflicts {
+                println!("  {path}");
+                for (source, value) in values {
+                    println!("    {value}  {}", source.display());
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Key paths of a target which fragments disagree on. A value also
+/// conflicts with a table another fragment puts in its place.
+fn target_conflicts(entry: &Entry) -> Result<Vec<Conflict>> {
+    use std::collections::BTreeMap;
+
+    let fragments: Vec<_> = inputs(entry)?.into_iter()
+        .filter(|input| input.source != entry.target)
+        .filter_map(|input| Some((input.source, input.config.tree()?)))
+        .collect();
+
+    let mut paths: BTreeMap<KeyPath, Vec<(PathBuf, serde_json::Value)>> =
+        BTreeMap::new();
+    for (source, tree) in &fragments {
+        for (path, value) in KeyPath::leaves(tree) {
+            paths.entry(path).or_default().push((source.clone(), value.clone()));
+        }
+    }
+    for (path, values) in paths.iter_mut() {
+        for (source, tree) in &fragments {
+            if let Some(table) = path.get(tree).filter(|v| v.is_object()) {
+                values.push((source.clone(), table.clone()));
+            }
+        }
+        values.sort_by_key(|(source, _)| {
+            fragments.iter().position(|(s, _)| s == source)
+        });
+    }
+
+    Ok(paths.into_iter()
+        .filter(|(_, values)| values.len() > 1
+            && values.iter().any(|(_, v)| *v != values[0].1))
+        .collect())
+}
+
+fn export(cli: &Cli, args: &ExportArgs) -> Result<()> {
+    use anyhow::Context;
+    use std::io::Write;
+
+    let targets = rendered_targets(cli)?;
+    let bytes = match args.format {
+        ExportFormat::Configmap => configmaps(&targets, args.namespace.as_deref())?,
+        ExportFormat::Tar => {
+            let gzip = args.out.as_ref().and_then(|out| out.file_name())
+                .is_some_and(|name| {
+                    let name = name.to_string_lossy();
+                    name.ends_with(".gz") || name.ends_with(".tgz")
+                });
+            tarball(&targets, gzip)?
+        }
+        ExportFormat::Sh => install_script(&targets).into_bytes(),
+    };
+
+    match &args.out {
+        Some(path) => std::fs::write(path, bytes).context(format!("Write {path:?}")),
+        None => Ok(std::io::stdout().write_all(&bytes)?),
+    }
+}
+
+/// Renders every target for export, with the mode of the existing one.
+/// Targets outside the target directory, as `--allow-outside-target` lets
+/// through, have no place in it and are skipped with a warning.
+fn rendered_targets(cli: &Cli) -> Result<Vec<Rendered>> {
+    discover(cli)?.into_iter()
+        .filter_map(|entry| {
+            let Some(relative) = cli.contained(&entry.target) else {
+                log::warn!("Leaving {:?} out of the export, it is outside {:?}",
+                    entry.target, cli.target_root());
+                return None;
+            };
+            Some((entry, relative))
+        })
+        .map(|(entry, relative)| {
+            use std::os::unix::fs::PermissionsExt;
+
+            let text = rendered(cli, &entry)?;
+            let mode = (entry.host == Host::Local)
+                .then(|| entry.target.metadata().ok())
+                .flatten()
+                .map_or(0o644, |m| m.permissions().mode() & 0o7777);
+            Ok(Rendered { relative, text, mode })
+        })
+        .collect()
+}
+
+/// Writes a POSIX shell script extracting every rendered target into the
+/// directory it is given, `$HOME` by default, from a gzipped tar archive
+/// appended to it. It only needs `sed`, `tail`, `gzip` and `tar`.
+fn bake(cli: &Cli, args: &BakeArgs) -> Result<()> {
+    use anyhow::Context;
+    use std::os::unix::fs::PermissionsExt;
+
+    let targets = rendered_targets(cli)?;
+    let mut script = format!(r#"#!/bin/sh
+# Writes {} target(s) baked by patch.rs into the directory given, $HOME by
+# default, from the archive below.
+set -eu
+target=${{1:-$HOME}}
+mkdir -p "$target"
+line=$(sed -n '/^__ARCHIVE__$/{{=;q;}}' "$0")
+tail -n +"$((line + 1))" "$0" | gzip -dc | tar -xpvf - -C "$target"
+exit 0
+__ARCHIVE__
+"#, targets.len()).into_bytes();
+    script.extend(tarball(&targets, true)?);
+
+    let out = &args.out;
+    std::fs::write(out, script).context(Failure::Io(format!("Write {out:?}")))?;
+    std::fs::set_permissions(out, std::fs::Permissions::from_mode(0o755))
+        .context(Failure::Io(format!("Make {out:?} executable")))?;
+    log::info!("Baked {} target(s) into {out:?}", targets.len());
+    Ok(())
+}
+
+/// A target rendered for export.
+struct Rendered {
+    /// Path relative to the target directory.
+    relative: PathBuf,
+    text: String,
+    /// Mode of the existing target, or `0o644`.
+    mode: u32,
+}
+
+fn tarball(targets: &[Rendered], gzip: bool) -> Result<Vec<u8>> {
+    use std::time::SystemTime;
+
+    let mtime = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+    let mut builder = tar::Builder::new(vec![]);
+    for Rendered { relative, text, mode } in targets {
+        let bytes = match Compression::of(relative) {
+            Some(compression) => compression.compress(text)?,
+            None => text.as_bytes().to_vec(),
+        };
+        let mut header = tar::Header::new_gnu();
+        header.set_size(bytes.len() as u64);
+        header.set_mode(*mode);
+        header.set_mtime(mtime);
+        builder.append_data(&mut header, relative, &bytes[..])?;
+    }
+    let archive = builder.into_inner()?;
+
+    if !gzip {
+        return Ok(archive);
+    }
+    use std::io::Write;
+    let mut encoder = flate2::write::GzEncoder::new(vec![], Default::default());
+    encoder.write_all(&archive)?;
+    Ok(encoder.finish()?)
+}
+
+/// A shell script writing each target from a heredoc into a temporary file,
+/// then installing it with its mode. Compressed targets are piped through
+/// `gzip` or `zstd` on the way.
+fn install_script(targets: &[Rendered]) -> String {
+    let mut script = format!(r#"#!/bin/sh
+# Writes {} target(s) rendered by patch.rs into the directory given, $HOME by
+# default.
+set -eu
+target=${{1:-$HOME}}
+mkdir -p "$target"
+tmp=$(mktemp)
+trap 'rm -f "$tmp"' EXIT
+"#, targets.len());
+
+    for Rendered { relative, text, mode } in targets {
+        let mut delimiter = "PATCH_EOF".to_string();
+        while text.lines().any(|line| line == delimiter) {
+            delimiter.push('_');
+        }
+        let compress = match Compression::of(relative) {
+            Some(Compression::Gzip) => " | gzip -c",
+            Some(Compression::Zstd) => " | zstd -q -c",
+            None => "",
+        };
+        script.push_str(&match text.ends_with('\n') || text.is_empty() {
+            true => format!("\ncat <<'{delimiter}'{compress} > \"$tmp\"\n{text}{delimiter}\n"),
+            // Command substitution drops the newline the heredoc ends with.
+            false => format!("\nprintf '%s' \"$(cat <<'{delimiter}'\n{text}\n{delimiter}\n\
+                )\"{compress} > \"$tmp\"\n"),
+        });
+        let path = sh_quote(&relative.to_string_lossy());
+        if let Some(parent) = relative.parent().filter(|p| !p.as_os_str().is_empty()) {
+            script.push_str(&format!("mkdir -p \"$target\"/{}\n",
+                sh_quote(&parent.to_string_lossy())));
+        }
+        script.push_str(&format!("install -m {mode:o} \"$tmp\" \"$target\"/{path}\n"));
+    }
+    script
+}
+
+/// Quotes a word for POSIX shells.
+fn sh_quote(word: &str) -> String {
+    format!("'{}'", word.replace('\'', r"'\''"))
+}
+
+/// Marks hooks written by `hook install`, which it may replace.
+const HOOK_MARKER: &str = "# Written by `patch.rs hook install`.";
+
+/// Writes a pre-commit hook into the repository of the first layer. It
+/// checks out the index into a temporary directory and runs `lint` and
+/// `fmt --check` on the layers there, so what is checked is what gets
+/// committed. Commits touching no layer are let through.
+fn install_hook(cli: &Cli, force: bool) -> Result<()> {
+    use anyhow::Context;
+    use duct::cmd;
+    use std::os::unix::fs::PermissionsExt;
+
+    let first = cli.directory.first().context("No config house directory")?;
+    let git = |args: &[&str]| cmd("git", ["-C".as_ref(), first.as_os_str()].into_iter()
+        .chain(args.iter().map(|arg| arg.as_ref())))
+        .read()
+        .context(Failure::Discovery(format!("Find the git repository of {first:?}")));
+    let toplevel = PathBuf::from(git(&["rev-parse", "--show-toplevel"])?);
+    let hooks = PathBuf::from(git(&["rev-parse", "--path-format=absolute", "--git-path", "hooks"])?);
+
+    let program = match option_env!("CARGO_MANIFEST_PATH") {
+        Some(script) => PathBuf::from(script),
+        None => std::env::current_exe()?,
+    };
+    let mut options = vec![];
+    let mut layers = vec![];
+    for layer in &cli.directory {
+        // Git gives the top level with symlinks resolved.
+        let layer = std::fs::canonicalize(layer).or_else(|_| std::path::absolute(layer))?;
+        // Layers of the repository are checked as staged, others as they are.
+        match layer.strip_prefix(&toplevel) {
+            Ok(relative) => {
+                let relative = relative.to_string_lossy();
+                layers.push(sh_quote(if relative.is_empty() { "." } else { &relative }));
+                options.push(format!("--directory \"$tmp\"/{}", sh_quote(&relative)));
+            }
+            Err(_) => options.push(format!("--directory {}", sh_quote(&layer.to_string_lossy()))),
+        }
+    }
+    if let Some(config) = &cli.config {
+        options.push(format!("--config {}",
+            sh_quote(&std::path::absolute(config)?.to_string_lossy())));
+    }
+    options.push(format!("--target {}", sh_quote(&cli.target.to_string_lossy())));
+    let patch = format!("{} {}", sh_quote(&program.to_string_lossy()), options.join(" "));
+    let hook = format!(r#"#!/bin/sh
+{HOOK_MARKER}
+# Checks the staged config house with `lint` and `fmt --check`.
+set -eu
+cd "$(git rev-parse --show-toplevel)"
+git diff --cached --quiet -- {layers} && exit 0
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+git checkout-index --all --prefix="$tmp/"
+{patch} lint
+{patch} fmt --check
+"#, layers = layers.join(" "));
+
+    let path = hooks.join("pre-commit");
+    if path.exists() && !force
+        && !std::fs::read_to_string(&path).unwrap_or_default().contains(HOOK_MARKER)
+    {
+        anyhow::bail!(Failure::Validation(
+            format!("{path:?} exists and was not written by this tool, pass `--force` to replace it")));
+    }
+    std::fs::create_dir_all(&hooks)?;
+    log::info!("Writing {path:?}");
+    std::fs::write(&path, hook).context(Failure::Io(format!("Write {path:?}")))?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))
+        .context(Failure::Io(format!("Make {path:?} executable")))
+}
+
+/// ConfigMap manifests keyed by file name, one per target, named after the
+/// target path.
+fn configmaps(targets: &[Rendered], namespace: Option<&str>) -> Result<Vec<u8>> {
+    let documents = targets.iter()
+        .map(|Rendered { relative, text, .. }| {
+            let name: String = relative.to_string_lossy().to_lowercase()
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+                .collect();
+            let name = name.split('-').filter(|s| !s.is_empty())
+                .collect::<Vec<_>>()
+                .join("-");
+            let key = relative.file_name().unwrap_or_default().to_string_lossy();
+
+            let mut metadata = serde_json::json!({
+                "name": name,
+                "annotations": {"patch/target": relative},
+            });
+            if let Some(namespace) = namespace {
+                metadata["namespace"] = namespace.into();
+            }
+            serde_yaml::to_string(&serde_json::json!({
+                "apiVersion": "v1",
+                "kind": "ConfigMap",
+                "metadata": metadata,
+                "data": {key: text},
+            }))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(documents.join("---\n").into_bytes())
+}
+
+/// Directory of the state directory holding the `kind` of records of a
+/// target, like its backups, named after its host and path.
+fn target_state_dir(cli: &Cli, kind: &str, entry: &Entry) -> PathBuf {
+    let host = match &entry.host {
+        Host::Local => "local".into(),
+        Host::Docker(container) => format!("docker/{container}"),
+    };
+    let target = entry.target.strip_prefix("/").unwrap_or(&entry.target);
+    cli.state_dir().join(kind).join(host).join(target)
+}
+
+/// Saves the existing target when it is about to change, named after the
+/// time in milliseconds, then drops backups beyond the retention. Local
+/// targets are reflinked where the filesystem supports it, like btrfs, XFS
+/// or APFS, and copied elsewhere, with the mode of the target. Backups are
+/// kept in a directory readable by the owner only.
+fn backup(cli: &Cli, entry: &Entry, result: &str) -> Result<()> {
+    use anyhow::Context;
+    use std::time::SystemTime;
+
+    let existing = entry.host.read(&entry.target)?;
+    if existing.is_empty() || existing == result {
+        return Ok(());
+    }
+    if cli.settings.backups.trash {
+        match entry.host {
+            Host::Local => return trash_copy(&entry.target),
+            Host::Docker(_) => log::warn!(
+                "{:?}: Containers have no trash, backing up to the state directory", entry.target),
+        }
+    }
+    let directory = target_state_dir(cli, "backups", entry);
+    create_private_dir(&directory)?;
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    let path = directory.join(now.as_millis().to_string());
+    log::info!("Backing up {:?} to {path:?}", entry.target);
+    // Copy-on-write filesystems share the blocks until the target changes.
+    // Compressed targets are kept decompressed, as restored.
+    match entry.host {
+        Host::Local if Compression::of(&entry.target).is_none() =>
+            reflink_copy::reflink_or_copy(&entry.target, &path).map(drop)
+                .context(Failure::Io(format!("Write {path:?}")))?,
+        _ => write_private(&path, existing, entry.host.mode(&entry.target)?.unwrap_or(0o600))?,
+    }
+    prune_records(cli, &directory, now)
+}
+
+/// Moves a copy of a target to the desktop trash, leaving the target, or the
+/// symlink to it, in place to be written through. The copy is made in a
+/// scratch directory beside the target, on the same filesystem as the trash
+/// it goes to.
+fn trash_copy(target: &Path) -> Result<()> {
+    use anyhow::Context;
+
+    let name = target.file_name().context(format!("{target:?} has no file name"))?;
+    let scratch = target.with_file_name(
+        format!(".{}.trash-{}", name.to_string_lossy(), std::process::id()));
+    std::fs::create_dir(&scratch).context(Failure::Io(format!("Create {scratch:?}")))?;
+    let copy = scratch.join(name);
+    log::info!("Moving a copy of {target:?} to the trash");
+    let trashed = std::fs::copy(target, &copy).map_err(anyhow::Error::from)
+        .and_then(|_| trash::delete(&copy).map_err(Into::into))
+        .context(Failure::Io(format!("Move a copy of {target:?} to the trash")));
+    std::fs::remove_dir_all(&scratch).context(Failure::Io(format!("Remove {scratch:?}")))?;
+    trashed
+}
+
+/// Saves the change of a JSON target as an RFC 6902 patch, named after the
+/// time in milliseconds and kept like backups.
+fn record_patch(cli: &Cli, entry: &Entry, before: &str, after: &str) -> Result<()> {
+    use std::time::SystemTime;
+
+    let json = |text: &str| match text.trim().is_empty() {
+        true => Ok(Config::Json(serde_json::json!({}))),
+        false => entry.parse(text.into()),
+    };
+    let (Config::Json(before), Config::Json(after)) = (json(before)?, json(after)?) else {
+        return Ok(());
+    };
+    let patch = json_patch::diff(&before, &after);
+    if patch.0.is_empty() {
+        return Ok(());
+    }
+    let directory = target_state_dir(cli, "patches", entry);
+    create_private_dir(&directory)?;
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    let path = directory.join(format!("{}.json", now.as_millis()));
+    log::info!("Recording the change of {:?} to {path:?}", entry.target);
+    write_private(&path, format!("{:#}\n", serde_json::to_value(&patch)?), 0o600)?;
+    prune_records(cli, &directory, now)
+}
+
+/// What a run wrote to a target, and what the target held before. An empty
+/// target did not exist.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Revert {
+    host: Host,
+    target: PathBuf,
+    before: String,
+    after: String,
+    /// A private or sensitive target, saved as SHA-256 hashes of its
+    /// contents, so it can't be undone.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    redacted: bool,
+}
+
+impl Revert {
+    /// Whether a target still holds what the run wrote.
+    fn wrote(&self, text: &str) -> bool {
+        match self.redacted {
+            true => self.after == fingerprint(text),
+            false => self.after == text,
+        }
+    }
+}
+
+/// SHA-256 of a text in hex, or nothing for the empty text of a missing
+/// target.
+fn fingerprint(text: &str) -> String {
+    use sha2::Digest;
+
+    match text.is_empty() {
+        true => String::new(),
+        false => format!("{:x}", sha2::Sha256::digest(text)),
+    }
+}
+
+/// Creates a directory of the state directory, readable by its owner only.
+fn create_private_dir(directory: &Path) -> Result<()> {
+    use anyhow::Context;
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    std::fs::DirBuilder::new().recursive(true).mode(0o700).create(directory)
+        .and_then(|_| std::fs::set_permissions(directory, std::fs::Permissions::from_mode(0o700)))
+        .context(Failure::Io(format!("Create {directory:?}")))
+}
+
+/// Writes a new file of the state directory with a mode, like `0o600` for
+/// one readable by its owner only.
+fn write_private(path: &Path, contents: impl AsRef<[u8]>, mode: u32) -> Result<()> {
+    use anyhow::Context;
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    std::fs::OpenOptions::new().write(true).create(true).truncate(true).mode(mode).open(path)
+        .and_then(|mut file| file.write_all(contents.as_ref()))
+        .context(Failure::Io(format!("Write {path:?}")))
+}
+
+/// Drops records of a directory named after the time in milliseconds, like
+/// backups, beyond the retention of backups.
+fn prune_records(cli: &Cli, directory: &Path, now: std::time::Duration) -> Result<()> {
+    use anyhow::Context;
+    use std::time::Duration;
+
+    let mut records: Vec<(Duration, PathBuf)> = directory.read_dir()?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let time = path.file_stem()?.to_str()?.parse().ok()?;
+            Some((Duration::from_millis(time), path))
+        })
+        .collect();
+    records.sort();
+
+    let Backups { keep, days, .. } = cli.settings.backups;
+    let max_age = days.map(|days| Duration::from_secs(days * 24 * 60 * 60));
+    records.into_iter().rev().enumerate()
+    .filter(|(i, (time, _))| *i >= keep
+        || max_age.is_some_and(|age| now.saturating_sub(*time) > age))
+    .try_for_each(|(_, (_, path))| {
+        log::debug!("Dropping {path:?}");
+        std::fs::remove_file(&path).context(Failure::Io(format!("Remove {path:?}")))
+    })
+}
+
+/// Saves the changes of a run under `runs/` of the state directory, named
+/// after the time in milliseconds like backups, readable by its owner only.
+/// Private and sensitive targets are saved as hashes. Runs changing nothing
+/// are not saved, and runs beyond the retention of backups are dropped.
+fn record_run(cli: &Cli, journal: Vec<Revert>) -> Result<()> {
+    use std::time::SystemTime;
+
+    if journal.is_empty() {
+        return Ok(());
+    }
+    let journal: Vec<_> = journal.into_iter()
+        .map(|revert| match revert.redacted {
+            true => Revert {
+                before: fingerprint(&revert.before),
+                after: fingerprint(&revert.after),
+                ..revert
+            },
+            false => revert,
+        })
+        .collect();
+    let directory = cli.state_dir().join("runs");
+    create_private_dir(&directory)?;
+    // Runs within a millisecond still go in order.
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    let path = (now.as_millis()..).map(|time| directory.join(time.to_string()))
+        .find(|path| !path.exists())
+        .expect("Some time is free");
+    write_private(&path, serde_json::to_string(&journal)?, 0o600)?;
+    prune_records(cli, &directory, now)
+}
+
+/// Appends a line for a run to `audit.jsonl` of the state directory: when it
+/// ran, its arguments, and the SHA-256 of each changed target before and
+/// after, `null` for none. Lines are never rewritten.
+fn audit(cli: &Cli, command: &str, journal: &[Revert]) -> Result<()> {
+    use anyhow::Context;
+    use std::io::Write;
+
+    let hash = |text: &str| Some(fingerprint(text)).filter(|hash| !hash.is_empty());
+    let time = time::OffsetDateTime::now_utc()
+        .format(&time::format_description::well_known::Rfc3339)?;
+    let mut line = serde_json::json!({
+        "time": time,
+        "command": command,
+        "args": std::env::args_os().skip(1)
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect::<Vec<_>>(),
+        "targets": journal.iter().map(|Revert { host, target, before, after, .. }| serde_json::json!({
+            "host": host,
+            "target": target,
+            "before": hash(before),
+            "after": hash(after),
+        }))
+        .collect::<Vec<_>>(),
+    })
+    .to_string();
+    line.push('\n');
+
+    let path = cli.state_dir().join("audit.jsonl");
+    std::fs::create_dir_all(cli.state_dir())?;
+    std::fs::OpenOptions::new().append(true).create(true).open(&path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .context(Failure::Io(format!("Append to {path:?}")))
+}
+
+/// Reverts the last saved run, newest change first, then drops it. Targets
+/// changed since, and private or sensitive ones, are left as they are.
+fn undo(cli: &Cli) -> Result<()> {
+    use anyhow::Context;
+
+    let directory = cli.state_dir().join("runs");
+    let Some(last) = backup_times(&directory)?.pop() else {
+        anyhow::bail!("No run to undo in {directory:?}");
+    };
+    let path = directory.join(last.as_millis().to_string());
+    let journal: Vec<Revert> = serde_json::from_slice(&std::fs::read(&path)?)
+        .context(Failure::Parse(format!("Parse {path:?}")))?;
+    let mut reverted = vec![];
+    for revert in journal.into_iter().rev() {
+        if revert.redacted {
+            log::warn!("{:?} is private or sensitive, so the run kept no content to revert it to",
+                revert.target);
+            continue;
+        }
+        let Revert { host, target, before, after, .. } = revert;
+        if host.read(&target)? != after {
+            log::warn!("{target:?} changed since the run, leaving it");
+            continue;
+        }
+        log::info!("Reverting {target:?}");
+        match before.is_empty() {
+            true => host.remove(&target)?,
+            false => host.write(&target, &before)?,
+        }
+        reverted.push(Revert { host, target, before: after, after: before, redacted: false });
+    }
+    audit(cli, "undo", &reverted)?;
+    std::fs::remove_file(&path).context(Failure::Io(format!("Remove {path:?}")))
+}
+
+/// A target as the dashboard shows it.
+struct Status {
+    entry: Entry,
+    /// When a saved run last changed the target.
+    applied: Option<std::time::Duration>,
+    /// Whether the target changed since that run.
+    drifted: bool,
+    /// What applying the target would change, as `--diff` prints it.
+    diff: Result<String>,
+    skipped: bool,
+}
+
+impl Status {
+    fn new(cli: &Cli, entry: Entry, runs: &[(std::time::Duration, Vec<Revert>)]) -> Self {
+        let last = runs.iter().rev()
+            .find_map(|(time, journal)| journal.iter().rev()
+                .find(|revert| revert.target == entry.target && revert.host == entry.host)
+                .map(|revert| (*time, revert)));
+        let before = entry.host.read(&entry.target);
+        let drifted = last.zip(before.as_ref().ok())
+            .is_some_and(|((_, revert), before)| !revert.wrote(before));
+        let diff = before.and_then(|before| diff(&entry, &before, &rendered(cli, &entry)?));
+        Status { applied: last.map(|(time, _)| time), drifted, diff, skipped: false, entry }
+    }
+
+    /// `fail`, `drift` for a target changed since it was last applied,
+    /// `ok` or `pending`.
+    fn state(&self) -> &'static str {
+        match &self.diff {
+            Err(_) => "fail",
+            Ok(_) if self.drifted => "drift",
+            Ok(diff) if diff.is_empty() => "ok",
+            Ok(_) => "pending",
+        }
+    }
+}
+
+/// Every target with when it was last applied, whether it drifted since,
+/// and its pending changes. Targets failing to render show the error.
+fn statuses(cli: &Cli) -> Result<Vec<Status>> {
+    use anyhow::Context;
+
+    let directory = cli.state_dir().join("runs");
+    let runs = backup_times(&directory)?.into_iter()
+        .map(|time| {
+            let path = directory.join(time.as_millis().to_string());
+            let journal = serde_json::from_slice(&std::fs::read(&path)?)
+                .context(Failure::Parse(format!("Parse {path:?}")))?;
+            Ok((time, journal))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(discover(cli)?.into_iter().map(|entry| Status::new(cli, entry, &runs)).collect())
+}
+
+/// Lists targets with their state next to the pending diff of the selected
+/// one. `a` applies the selected target, `A` every pending one not skipped,
+/// `s` skips or unskips it.
+fn ui(cli: &Cli) -> Result<()> {
+    use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+    use ratatui::widgets::ListState;
+    use std::sync::Mutex;
+    use std::time::SystemTime;
+
+    if cli.diff || cli.resolve {
+        anyhow::bail!("`ui` shows diffs itself, drop `--diff` and `--resolve`");
+    }
+    let mut statuses = statuses(cli)?;
+    let mut list = ListState::default().with_selected(Some(0));
+    let mut scroll = 0;
+    let mut message = String::new();
+    let journal = Mutex::default();
+
+    // Logs would scribble over the screen.
+    let level = log::max_level();
+    log::set_max_level(log::LevelFilter::Off);
+    let mut terminal = ratatui::init();
+    let result = (|| -> Result<()> {
+        loop {
+            terminal.draw(|frame| draw_ui(frame, &statuses, &mut list, scroll, &message))?;
+            let Event::Key(key) = event::read()? else { continue };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            let selected = list.selected().unwrap_or_default().min(statuses.len().saturating_sub(1));
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Down | KeyCode::Char('j') => { list.select_next(); scroll = 0 }
+                KeyCode::Up | KeyCode::Char('k') => { list.select_previous(); scroll = 0 }
+                KeyCode::PageDown => scroll += 10,
+                KeyCode::PageUp => scroll = scroll.saturating_sub(10),
+                KeyCode::Char('s') if !statuses.is_empty() => {
+                    statuses[selected].skipped ^= true;
+                    list.select_next();
+                }
+                KeyCode::Char(key @ ('a' | 'A')) => {
+                    let chosen: Vec<usize> = match key {
+                        'a' => vec![selected],
+                        _ => (0..statuses.len())
+                            .filter(|i| statuses[*i].diff.as_ref().is_ok_and(|d| !d.is_empty()))
+                            .collect(),
+                    };
+                    let chosen: Vec<usize> = chosen.into_iter()
+                        .filter(|i| statuses.get(*i).is_some_and(|status| !status.skipped))
+                        .collect();
+                    if chosen.is_empty() {
+                        message = "Nothing to apply".into();
+                    }
+                    let mut applied = 0;
+                    for i in chosen {
+                        let entry = statuses[i].entry.clone();
+                        if let Err(error) = apply_entry(cli, entry.clone(), &journal) {
+                            message = format!("{:?}: {error:#}", entry.target);
+                            break;
+                        }
+                        applied += 1;
+                        message = format!("Applied {applied} target(s)");
+                        // The run is saved on quitting.
+                        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+                        statuses[i] = Status { applied: Some(now), ..Status::new(cli, entry, &[]) };
+                    }
+                }
+                _ => {}
+            }
+        }
+    })();
+    ratatui::restore();
+    log::set_max_level(level);
+    let journal = journal.into_inner().expect("No worker panics holding the journal");
+    audit(cli, "ui", &journal)?;
+    record_run(cli, journal)?;
+    result
+}
+
+fn draw_ui(
+    frame: &mut ratatui::Frame,
+    statuses: &[Status],
+    list: &mut ratatui::widgets::ListState,
+    scroll: u16,
+    message: &str,
+) {
+    use ratatui::layout::{Constraint, Layout};
+    use ratatui::style::{Color, Style, Stylize};
+    use ratatui::widgets::{Block, List, ListItem, Paragraph, Wrap};
+    use std::time::SystemTime;
+
+    let [main, footer] = Layout::vertical([Constraint::Fill(1), Constraint::Length(1)])
+        .areas(frame.area());
+    let [left, right] = Layout::horizontal([Constraint::Percentage(40), Constraint::Fill(1)])
+        .areas(main);
+
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    let items = statuses.iter().map(|status| {
+        let (mark, color) = match status.state() {
+            _ if status.skipped => ("skip", Color::DarkGray),
+            "fail" => ("fail", Color::Red),
+            "drift" => ("drift", Color::Yellow),
+            "ok" => ("ok", Color::Green),
+            state => (state, Color::Cyan),
+        };
+        let applied = status.applied.map_or("never".into(), |time| ago(now.saturating_sub(time)));
+        ListItem::new(format!("{mark:<8}{applied:>6}  {}", status.entry.target.display()))
+            .style(Style::new().fg(color))
+    });
+    frame.render_stateful_widget(
+        List::new(items)
+            .block(Block::bordered().title("Targets"))
+            .highlight_style(Style::new().reversed()),
+        left,
+        list,
+    );
+
+    let detail = match list.selected().and_then(|i| statuses.get(i)).map(|s| &s.diff) {
+        Some(Ok(diff)) if diff.is_empty() => "Nothing to change".into(),
+        Some(Ok(diff)) => diff.clone(),
+        Some(Err(error)) => format!("{error:#}"),
+        None => String::new(),
+    };
+    frame.render_widget(
+        Paragraph::new(detail)
+            .block(Block::bordered().title("Pending changes"))
+            .wrap(Wrap { trim: false })
+            .scroll((scroll, 0)),
+        right,
+    );
+    frame.render_widget(
+        Paragraph::new(format!(
+            "a apply  A apply all  s skip  j/k move  PgUp/PgDn scroll  q quit  {message}"
+        )),
+        footer,
+    );
+}
+
+/// Registers [`Service`] on the session bus, then serves it until killed.
+fn serve(cli: Cli) -> Result<()> {
+    let _connection = zbus::blocking::connection::Builder::session()?
+        .name(Service::NAME)?
+        .serve_at(Service::PATH, Service { cli: std::sync::Arc::new(cli.into()) })?
+        .build()?;
+    log::info!("Serving `{}` on the session bus", Service::NAME);
+    loop {
+        std::thread::park();
+    }
+}
+
+/// The tool on D-Bus, so widgets and scripts apply and watch the config
+/// house without spawning it. Methods run on the blocking thread pool, so
+/// an apply doesn't hold up the bus, one at a time, so applies of several
+/// callers don't write the same targets together.
+struct Service {
+    cli: std::sync::Arc<std::sync::Mutex<Cli>>,
+}
+
+impl Service {
+    const NAME: &str = "org.patch.Patch";
+    const PATH: &str = "/org/patch/Patch";
+
+    /// Runs a method once earlier ones are done, with `patch.toml` loaded
+    /// again as it may have been edited since the service started.
+    async fn call<T: Send + 'static>(&self, method: impl FnOnce(&Cli) -> Result<T> + Send + 'static)
+        -> zbus::fdo::Result<T>
+    {
+        let cli = self.cli.clone();
+        blocking::unblock(move || {
+            let mut cli = cli.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+            let settings = Settings::load(&cli)?;
+            cli.settings = settings;
+            method(&cli)
+        })
+        .await
+        .map_err(|error| zbus::fdo::Error::Failed(format!("{error:#}")))
+    }
+}
+
+/// Every target as a JSON array of its path, state and when it was last
+/// applied in milliseconds.
+fn status_report(cli: &Cli) -> Result<serde_json::Value> {
+    Ok(statuses(cli)?.iter()
+        .map(|status| serde_json::json!({
+            "target": status.entry.target,
+            "state": status.state(),
+            "applied": status.applied.map(|time| time.as_millis() as u64),
+        }))
+        .collect())
+}
+
+#[zbus::interface(name = "org.patch.Patch")]
+impl Service {
+    /// Applies every target, returning those which changed.
+    async fn apply(
+        &self,
+        #[zbus(signal_emitter)] emitter: zbus::object_server::SignalEmitter<'_>,
+    ) -> zbus::fdo::Result<Vec<String>> {
+        let changed: Vec<String> = self.call(apply).await?
+            .iter()
+            .map(|target| target.display().to_string())
+            .collect();
+        if !changed.is_empty() {
+            Self::changed(&emitter, changed.clone()).await?;
+        }
+        Ok(changed)
+    }
+
+    /// [`status_report`] as JSON text.
+    async fn status(&self) -> zbus::fdo::Result<String> {
+        self.call(status_report).await.map(|status| status.to_string())
+    }
+
+    /// Targets an apply changed.
+    #[zbus(signal)]
+    async fn changed(emitter: &zbus::object_server::SignalEmitter<'_>, targets: Vec<String>)
+        -> zbus::Result<()>;
+}
+
+/// A duration in its largest whole unit, like `3d` or `5m`.
+fn ago(duration: std::time::Duration) -> String {
+    let age = duration.as_secs();
+    [(86400, "d"), (3600, "h"), (60, "m"), (1, "s")].iter()
+        .find(|(unit, _)| age >= *unit)
+        .map_or("0s".into(), |(unit, suffix)| format!("{}{suffix}", age / unit))
+}
+
+/// Times of the backups in a directory, oldest first.
+fn backup_times(directory: &Path) -> Result<Vec<std::time::Duration>> {
+    if !directory.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut times: Vec<_> = directory.read_dir()?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .map(std::time::Duration::from_millis)
+        .collect();
+    times.sort();
+    Ok(times)
+}
+
+fn backups(cli: &Cli, command: &BackupsCommand) -> Result<()> {
+    use anyhow::Context;
+    use duct::cmd;
+    use std::time::SystemTime;
+
+    match command {
+        BackupsCommand::List { file } => {
+            let root = cli.state_dir().join("backups");
+            let directories: Vec<PathBuf> = match file {
+                Some(file) => vec![target_state_dir(cli, "backups", &entry_of(cli, file)?)],
+                None if root.is_dir() => cmd!("find", &root, "-type", "f").read()?
+                    .lines()
+                    .filter_map(|path| Path::new(path).parent().map(Path::to_path_buf))
+                    .collect::<std::collections::BTreeSet<_>>()
+                    .into_iter()
+                    .collect(),
+                None => vec![],
+            };
+
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+            let mut rows = vec![];
+            for directory in directories {
+                // Undo the naming of `target_state_dir`.
+                let relative = directory.strip_prefix(&root)?;
+                let mut components = relative.iter();
+                let target = match components.next().and_then(|c| c.to_str()) {
+                    Some("docker") => {
+                        let container = components.next().unwrap_or_default();
+                        let path = Path::new("/").join(components.as_path());
+                        format!("docker://{}:{}", container.display(), path.display())
+                    }
+                    _ => Path::new("/").join(components.as_path()).display().to_string(),
+                };
+                for time in backup_times(&directory)?.into_iter().rev() {
+                    let age = ago(now.saturating_sub(time));
+                    rows.push([time.as_millis().to_string(), age, target.clone()]);
+                }
+            }
+
+            match cli.output {
+                Output::Json => println!("{:#}", serde_json::Value::from_iter(
+                    rows.iter().map(|[backup, age, target]| serde_json::json!({
+                        "backup": backup,
+                        "age": age,
+                        "target": target,
+                    }))
+                )),
+                Output::Text | Output::Github => print_table(&once(["BACKUP", "AGE", "TARGET"].map(String::from))
+                    .chain(rows)
+                    .collect::<Vec<_>>()),
+            }
+            Ok(())
+        }
+        BackupsCommand::Restore { file, at } => {
+            let entry = entry_of(cli, file)?;
+            let directory = target_state_dir(cli, "backups", &entry);
+            let at = match at {
+                Some(at) => at.clone(),
+                None => backup_times(&directory)?.last()
+                    .context(format!("{:?} has no backup", entry.target))?
+                    .as_millis()
+                    .to_string(),
+            };
+            let path = directory.join(&at);
+            let text = std::fs::read_to_string(&path)
+                .context(format!("Read backup {path:?}"))?;
+
+            if cli.backup || cli.settings.backups.enabled {
+                backup(cli, &entry, &text)?;
+            }
+            log::info!("Restoring {:?} from {path:?}", entry.target);
+            let before = entry.host.read(&entry.target)?;
+            entry.host.write(&entry.target, &text)?;
+            // Journaled like a run, so `undo` takes the restore back.
+            let journal: Vec<_> = (before != text).then(|| Ok::<_, anyhow::Error>(Revert {
+                host: entry.host.clone(),
+                target: entry.target.clone(),
+                redacted: !cli.private_patterns(&entry.target).is_empty()
+                    || !entry.sensitive()?.is_empty(),
+                before,
+                after: text,
+            }))
+            .transpose()?
+            .into_iter()
+            .collect();
+            audit(cli, "restore", &journal)?;
+            record_run(cli, journal)
+        }
+    }
+}
+
+/// The program and options applying the config house as this run does,
+/// for units and login entries running it later. Paths are made absolute,
+/// and every option choosing targets or confining them is kept.
+fn invocation(cli: &Cli) -> Result<Vec<std::ffi::OsString>> {
+    use clap::ValueEnum;
+
+    // The script itself, rather than the binary cargo caches for it.
+    let program = match option_env!("CARGO_MANIFEST_PATH") {
+        Some(script) => PathBuf::from(script),
+        None => std::env::current_exe()?,
+    };
+    let mut words: Vec<std::ffi::OsString> = vec![program.into()];
+    for layer in &cli.directory {
+        words.extend(["--directory".into(), std::path::absolute(layer)?.into()]);
+    }
+    let target = match cli.target.to_str().is_some_and(|t| t.starts_with("docker://")) {
+        true => cli.target.clone(),
+        false => std::path::absolute(&cli.target)?,
+    };
+    words.extend(["--target".into(), target.into()]);
+    if let Some(config) = &cli.config {
+        words.extend(["--config".into(), std::path::absolute(config)?.into()]);
+    }
+    if let Some(root) = &cli.root {
+        words.extend(["--root".into(), std::path::absolute(root)?.into()]);
+    }
+    if let Some(state) = &cli.state {
+        words.extend(["--state".into(), std::path::absolute(state)?.into()]);
+    }
+    if let Some(metrics) = &cli.metrics_file {
+        words.extend(["--metrics-file".into(), std::path::absolute(metrics)?.into()]);
+    }
+    for user in &cli.users {
+        words.extend(["--user".into(), user.into()]);
+    }
+    if let Some(depth) = cli.max_depth {
+        words.extend(["--max-depth".into(), depth.to_string().into()]);
+    }
+    if let Some(depth) = cli.min_depth {
+        words.extend(["--min-depth".into(), depth.to_string().into()]);
+    }
+    if let Some(revision) = &cli.since {
+        words.extend(["--since".into(), revision.into()]);
+    }
+    if let Some(output) = cli.output.to_possible_value() {
+        words.extend(["--output".into(), output.get_name().into()]);
+    }
+    words.extend(["--jobs".into(), cli.jobs.to_string().into()]);
+    words.extend(["--log-level".into(), cli.log_level.to_string().into()]);
+    let flags = [
+        (cli.all_users, "--all-users"), (cli.allow_outside_target, "--allow-outside-target"),
+        (cli.no_seed, "--no-seed"), (cli.prune, "--prune"),
+        (cli.comment_pruned, "--comment-pruned"), (cli.json_patch, "--json-patch"),
+        (cli.backup, "--backup"), (cli.annotate, "--annotate"), (cli.notify, "--notify"),
+    ];
+    words.extend(flags.into_iter().filter(|(on, _)| *on).map(|(_, flag)| flag.into()));
+    Ok(words)
+}
+
+/// Writes `patch.service` running the tool with the options of this run, and
+/// `patch.timer` starting it every interval, then enables the timer.
+fn install_timer(cli: &Cli, args: &InstallTimerArgs) -> Result<()> {
+    use anyhow::Context;
+    use duct::cmd;
+
+    /// Quotes a word for a unit, where `%` starts a specifier, with the
+    /// C-like escapes systemd reads in double quotes.
+    fn quote(word: &str) -> String {
+        let word = word.replace('%', "%%");
+        let special = |c: char| c.is_whitespace() || c.is_control() || "\"'\\;".contains(c);
+        if !word.is_empty() && !word.contains(special) {
+            return word;
+        }
+        let escaped: String = word.chars()
+            .map(|c| match c {
+                '"' | '\\' => format!("\\{c}"),
+                '\n' => "\\n".into(),
+                '\t' => "\\t".into(),
+                c if c.is_control() => format!("\\u{:04x}", c as u32),
+                c => c.into(),
+            })
+            .collect();
+        format!("\"{escaped}\"")
+    }
+
+    let words = invocation(cli)?;
+    // `ExecStart` also expands `$` into variables.
+    let exec: Vec<String> = words.iter()
+        .map(|word| quote(&word.to_string_lossy().replace('$', "$$")))
+        .collect();
+    let path = std::env::var("PATH").unwrap_or_default();
+    let service = format!(
+        "[Unit]\nDescription=Apply config patches\n\n\
+        [Service]\nType=oneshot\nEnvironment={}\nExecStart={}\n",
+        quote(&format!("PATH={path}")), exec.join(" "),
+    );
+    let timer = format!(
+        "[Unit]\nDescription=Apply config patches every {0}\n\n\
+        [Timer]\nOnStartupSec=1min\nOnUnitActiveSec={0}\n\n\
+        [Install]\nWantedBy=timers.target\n",
+        args.interval,
+    );
+
+    let units = match &args.units {
+        Some(units) => units.clone(),
+        None => std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+            .context("Neither `XDG_CONFIG_HOME` nor `HOME` is set")?
+            .join("systemd/user"),
+    };
+    std::fs::create_dir_all(&units)?;
+    for (name, text) in [("patch.service", service), ("patch.timer", timer)] {
+        let path = units.join(name);
+        log::info!("Writing {path:?}");
+        std::fs::write(&path, text).context(format!("Write {path:?}"))?;
+    }
+
+    if !args.no_enable {
+        cmd!("systemctl", "--user", "daemon-reload").run()?;
+        cmd!("systemctl", "--user", "enable", "--now", "patch.timer").run()?;
+    }
+    Ok(())
+}
+
+/// Writes `patch.desktop` to the XDG autostart directory, or `patch.plist`
+/// to the launchd agents on macOS, running the tool with the options of this
+/// run at login.
+fn install_login(cli: &Cli, args: &InstallLoginArgs) -> Result<()> {
+    use anyhow::Context;
+
+    let path = std::env::var("PATH").unwrap_or_default();
+    let words = invocation(cli)?;
+    let home = || std::env::var_os("HOME").map(PathBuf::from).context("`HOME` is not set");
+    let (directory, name, text) = if cfg!(target_os = "macos") {
+        let directory = match &args.dir {
+            Some(dir) => dir.clone(),
+            None => home()?.join("Library/LaunchAgents"),
+        };
+        (directory, "patch.plist", launch_agent(&words, &path))
+    } else {
+        let directory = match &args.dir {
+            Some(dir) => dir.clone(),
+            None => std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+                .map_or_else(|| home().map(|home| home.join(".config")), Ok)?
+                .join("autostart"),
+        };
+        (directory, "patch.desktop", autostart_entry(&words, &path))
+    };
+
+    std::fs::create_dir_all(&directory)?;
+    let path = directory.join(name);
+    log::info!("Writing {path:?}");
+    std::fs::write(&path, text).context(Failure::Io(format!("Write {path:?}")))
+}
+
+/// An XDG autostart entry running a command with `PATH` set.
+fn autostart_entry(words: &[std::ffi::OsString], path: &str) -> String {
+    /// Quotes a word for `Exec`, whose quoted words escape `"`, `` ` ``, `$`
+    /// and `\`, and whose value escapes `\` once more.
+    fn quote(word: &std::ffi::OsStr) -> String {
+        let word = word.to_string_lossy().replace('%', "%%");
+        let reserved = |c: char| c.is_whitespace() || "\"'\\><~|&;$*?#()`".contains(c);
+        if !word.is_empty() && !word.contains(reserved) {
+            return word;
+        }
+        let escaped: String = word.chars()
+            .flat_map(|c| "\"`$\\".contains(c).then_some('\\').into_iter().chain(once(c)))
+            .collect();
+        format!("\"{}\"", escaped.replace('\\', "\\\\"))
+    }
+
+    let exec: Vec<String> = ["env".as_ref(), format!("PATH={path}").as_ref()].into_iter()
+        .chain(words.iter().map(|word| word.as_os_str()))
+        .map(quote)
+        .collect();
+    format!(
+        "[Desktop Entry]\nType=Application\nName=Apply config patches\n\
+        Exec={}\nTerminal=false\nNoDisplay=true\nX-GNOME-Autostart-enabled=true\n",
+        exec.join(" "),
+    )
+}
+
+/// A launchd agent plist running a command with `PATH` set once loaded,
+/// which is at login.
+fn launch_agent(words: &[std::ffi::OsString], path: &str) -> String {
+    let escape = |text: &str| text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+    let arguments: String = words.iter()
+        .map(|word| format!("        <string>{}</string>\n", escape(&word.to_string_lossy())))
+        .collect();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+        <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \
+        \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+        <plist version=\"1.0\">\n<dict>\n\
+        \x20   <key>Label</key>\n    <string>patch</string>\n\
+        \x20   <key>ProgramArguments</key>\n    <array>\n{arguments}    </array>\n\
+        \x20   <key>EnvironmentVariables</key>\n    <dict>\n\
+        \x20       <key>PATH</key>\n        <string>{}</string>\n    </dict>\n\
+        \x20   <key>RunAtLoad</key>\n    <true/>\n\
+        </dict>\n</plist>\n",
+        escape(path),
+    )
+}
+
+/// What a rendered target should hold: the value, or only the presence, at
+/// a key path, or a match of a regex for plain text.
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Expectation {
+    /// Path relative to the target directory.
+    target: PathBuf,
+    path: Option<String>,
+    value: Option<toml::Value>,
+    /// Regex searched in the rendered text, with `^` and `$` matching at
+    /// line breaks.
+    matches: Option<String>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Expectations {
+    expect: Vec<Expectation>,
+}
+
+fn test(cli: &Cli, args: &TestArgs) -> Result<()> {
+    let failures = failures(cli, args)?;
+    let expectations = args.expectations.clone()
+        .unwrap_or(cli.layer().join("expectations.toml"));
+    failures.iter().for_each(|f| match (cli.output, &args.golden) {
+        // Targets are no files of the config house, their expectations are.
+        (Output::Github, Some(golden)) if f.kind == "golden" =>
+            println!("{}", annotation("error", &golden.join(&f.path), &f.message)),
+        (Output::Github, _) => println!("{}",
+            annotation("error", &expectations, &format!("{}: {}", f.path.display(), f.message))),
+        _ => println!("{f}"),
+    });
+
+    if !failures.is_empty() {
+        anyhow::bail!(Failure::Validation(format!("{} expectation(s) failed", failures.len())));
+    }
+    Ok(())
+}
+
+/// Renders the target of each expectation and reports the ones which do
+/// not hold, then targets which differ from their golden copies.
+fn failures(cli: &Cli, args: &TestArgs) -> Result<Vec<Problem>> {
+    use anyhow::{bail, Context};
+
+    let path = args.expectations.clone()
+        .unwrap_or(cli.layer().join("expectations.toml"));
+    let Expectations { expect } = if args.golden.is_some() && args.expectations.is_none()
+        && !path.exists()
+    {
+        Default::default()
+    } else {
+        let text = std::fs::read_to_string(&path).context(format!("Read {path:?}"))?;
+        toml::from_str(&text).context(format!("Parse {path:?}"))?
+    };
+
+    let mut failures = vec![];
+    for Expectation { target, path, value, matches } in expect {
+        let entry = entry_of(cli, &cli.target_root().join(&target))?;
+        let config = render_entry(&entry, inputs(&entry)?)?;
+        let mut fail = |message| failures.push(Problem {
+            path: target.clone(), kind: "expectation", message,
+        });
+
+        if let Some(path) = path {
+            let path: KeyPath = path.parse()?;
+            let tree = config.tree()
+                .context(format!("{target:?} is plain text and has no keys"))?;
+            let expected = value.map(serde_json::to_value).transpose()?;
+            match (path.get(&tree), expected) {
+                (None, _) => fail(format!("`{path}` is not set")),
+                (Some(actual), Some(expected)) if *actual != expected =>
+                    fail(format!("`{path}` is {actual}, expected {expected}")),
+                _ => {}
+            }
+        } else if let Some(pattern) = matches {
+            let regex = regex::Regex::new(&format!("(?m){pattern}"))?;
+            if !regex.is_match(&String::try_from(config)?) {
+                fail(format!("does not match `{pattern}`"));
+            }
+        } else {
+            bail!("Expectation of {target:?} has neither `path` nor `matches`");
+        }
+    }
+
+    let Some(golden) = &args.golden else {
+        return Ok(failures);
+    };
+    for entry in discover(cli)? {
+        let text = rendered(cli, &entry)?;
+        let relative = entry.target.strip_prefix(cli.target_root())
+            .unwrap_or(&entry.target)
+            .to_path_buf();
+        let copy = golden.join(&relative);
+
+        if args.bless {
+            log::info!("Blessing {copy:?}");
+            std::fs::create_dir_all(copy.parent().unwrap_or(golden))?;
+            std::fs::write(&copy, text).context(format!("Write {copy:?}"))?;
+            continue;
+        }
+        let expected = match std::fs::read_to_string(&copy) {
+            Ok(expected) => expected,
+            Err(_) => {
+                failures.push(Problem {
+                    path: relative, kind: "golden", message: format!("no copy at {copy:?}"),
+                });
+                continue;
+            }
+        };
+        if expected != text {
+            let diff = similar::TextDiff::from_lines(&expected, &text)
+                .unified_diff()
+                .header(&copy.display().to_string(), &entry.target.display().to_string())
+                .to_string();
+            failures.push(Problem {
+                path: relative, kind: "golden",
+                message: format!("differs from the golden copy\n{}", diff.trim_end()),
+            });
+        }
+    }
+    Ok(failures)
+}
+
+/// A workflow command of GitHub Actions annotating a file, relative to the
+/// working directory like the checkout, with a message.
+fn annotation(level: &str, file: &Path, message: &str) -> String {
+    let escape = |text: &str, property: bool| {
+        let text = text.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A");
+        match property {
+            true => text.replace(':', "%3A").replace(',', "%2C"),
+            false => text,
+        }
+    };
+    let file = std::env::current_dir().ok()
+        .and_then(|directory| file.strip_prefix(directory).ok())
+        .unwrap_or(file);
+    format!("::{level} file={}::{}", escape(&file.to_string_lossy(), true), escape(message, false))
+}
+
+/// Findings fail checks, and are only told otherwise.
+fn level(check: bool) -> &'static str {
+    if check { "error" } else { "notice" }
+}
+
+/// A problem in the config house, found by [`lint`].
+struct Problem {
+    path: PathBuf,
+    kind: &'static str,
+    message: String,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}: {}", self.path.display(), self.kind, self.message)
+    }
+}
+
+fn lint(cli: &Cli) -> Result<()> {
+    let problems = problems(cli)?;
+    problems.iter().for_each(|p| match cli.output {
+        Output::Github => println!("{}", annotation("error", &p.path, &format!("{}: {}", p.kind, p.message))),
+        _ => println!("{p}"),
+    });
+
+    if !problems.is_empty() {
+        anyhow::bail!(Failure::Validation(format!("Found {} problem(s)", problems.len())));
+    }
+    Ok(())
+}
+
+/// Rewrites fragments the way their target is written, printing the ones
+/// that change. Fragments with comments the rewrite would drop, and lone
+/// values, are left as they are.
+fn fmt(cli: &Cli, args: &FmtArgs) -> Result<()> {
+    use anyhow::Context;
+    use serde_json::Value;
+
+    fn sorted(value: Value) -> Value {
+        match value {
+            Value::Object(map) => {
+                let mut entries: Vec<_> = map.into_iter().map(|(k, v)| (k, sorted(v))).collect();
+                entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+                Value::Object(entries.into_iter().collect())
+            }
+            Value::Array(array) => Value::Array(array.into_iter().map(sorted).collect()),
+            value => value,
+        }
+    }
+
+    let mut unformatted = 0;
+    for entry in discover(cli)? {
+        // Renderings in another format share the fragments of their entry.
+        let Some(format @ ("json" | "toml" | "yaml")) = entry.format.as_deref()
+            .filter(|_| entry.fragment_format.is_none())
+        else {
+            continue;
+        };
+        for fragment in entry.fragments()? {
+            let text = std::fs::read_to_string(&fragment)
+                .context(Failure::Io(format!("Read {fragment:?}")))?;
+            if drops_comments(format, &text) {
+                log::warn!("Skipping {fragment:?}, formatting would drop its comments");
+                continue;
+            }
+            let config = Config::parse_dispatch(&entry.format, text.clone())
+                .context(Failure::Parse(format!("Parse {fragment:?}")))?;
+            if config.is_value() {
+                continue;
+            }
+            let mut formatted: String = match config {
+                Config::Json(value) if args.sort => Config::Json(sorted(value)),
+                Config::Yaml(value, comments) if args.sort => Config::Yaml(sorted(value), comments),
+                config => config,
+            }.try_into()?;
+            if format == "json" {
+                formatted.push('\n');
+            }
+            if formatted == text {
+                continue;
+            }
+
+            match cli.output {
+                Output::Github => println!("{}", annotation(level(args.check), &fragment,
+                    "Not formatted the way `fmt` writes it")),
+                _ => println!("{}", fragment.display()),
+            }
+            unformatted += 1;
+            if !args.check {
+                std::fs::write(&fragment, formatted)
+                    .context(Failure::Io(format!("Write {fragment:?}")))?;
+            }
+        }
+    }
+    if args.check && unformatted > 0 {
+        anyhow::bail!(Failure::Validation(format!("{unformatted} fragment(s) are not formatted")));
+    }
+    Ok(())
+}
+
+/// Whether rewriting a JSON, TOML or YAML fragment drops comments of it.
+/// Conservative: a `#` that may be in a string still counts.
+fn drops_comments(format: &str, text: &str) -> bool {
+    match format {
+        "json" => serde_json::from_str::<serde_json::Value>(text).is_err(),
+        "toml" => text.lines().any(|line| line.contains('#')),
+        _ => text.lines().any(|line| line.contains(" #") && !line.trim_start().starts_with('#')),
+    }
+}
+
+/// Rewrites a fragment without key paths of its scoped tree, or removes it
+/// when they are `all` it sets.
+fn drop_keys(entry: &Entry, source: &Path, text: String, paths: &[KeyPath], all: bool)
+    -> Result<()>
+{
+    use anyhow::Context;
+
+    if all {
+        log::info!("Removing {source:?}, nothing is left in it");
+        return std::fs::remove_file(source).context(Failure::Io(format!("Remove {source:?}")));
+    }
+    // Paths are within the fragment, under its scope.
+    let mut config = entry.parse(text)?;
+    let scope = scope(entry, entry.relative(source), &config)?;
+    paths.iter()
+        .try_for_each(|path| config.remove(&KeyPath(path.0[scope.0.len()..].to_vec())))?;
+    let mut text: String = config.try_into()?;
+    if entry.format.as_deref() == Some("json") {
+        text.push('\n');
+    }
+    std::fs::write(source, text).context(Failure::Io(format!("Write {source:?}")))
+}
+
+/// Removes keys of fragments whose value is the one earlier fragments
+/// already merge into, printing each, and fragments left with nothing.
+/// Final fragments, and ones with comments the rewrite would drop, are only
+/// printed.
+fn dedupe(cli: &Cli, args: &DedupeArgs) -> Result<()> {
+    use anyhow::Context;
+
+    let mut found = 0;
+    for entry in discover(cli)? {
+        let Some(format @ ("json" | "toml" | "yaml")) = entry.format.as_deref()
+            .filter(|_| entry.fragment_format.is_none())
+        else {
+            continue;
+        };
+        let mut merged = Config::None;
+        for Input { source, config } in inputs(&entry)? {
+            if source == entry.target {
+                continue;
+            }
+            let earlier = merged.tree().unwrap_or_else(|| serde_json::json!({}));
+            let leaves = config.tree().map(|tree| KeyPath::leaves(&tree).into_iter()
+                .map(|(path, value)| (path.get(&earlier) == Some(value), path))
+                .collect::<Vec<_>>())
+                .unwrap_or_default();
+            merged = merged.try_merge(Ok(config))?;
+
+            let redundant: Vec<KeyPath> = leaves.iter()
+                .filter(|(redundant, _)| *redundant)
+                .map(|(_, path)| path.clone())
+                .collect();
+            if redundant.is_empty() {
+                continue;
+            }
+            redundant.iter().for_each(|path| match cli.output {
+                Output::Github => println!("{}", annotation(level(args.check), &source,
+                    &format!("`{path}` is set to the value earlier fragments give it"))),
+                _ => println!("{}: {path}", source.display()),
+            });
+            found += redundant.len();
+
+            let text = std::fs::read_to_string(&source)
+                .context(Failure::Io(format!("Read {source:?}")))?;
+            if args.check {
+                continue;
+            }
+            if is_final(&source) || drops_comments(format, &text) {
+                log::warn!("Leaving {source:?}, it is final or has comments");
+                continue;
+            }
+            drop_keys(&entry, &source, text, &redundant, redundant.len() == leaves.len())?;
+        }
+    }
+    if args.check && found > 0 {
+        anyhow::bail!(Failure::Validation(format!("Found {found} redundant key(s)")));
+    }
+    Ok(())
+}
+
+/// Moves keys every `--targets` layer sets to the same value into a
+/// `00-common` fragment of the first layer, printing each. Keys other
+/// layers set, or a final or commented fragment of a target layer sets,
+/// stay where they are.
+fn factor(cli: &Cli, args: &FactorArgs) -> Result<()> {
+    use anyhow::{bail, Context};
+    use serde_json::{json, Value};
+
+    let absolute = |path: &Path| std::path::absolute(path).unwrap_or(path.into());
+    let targets: Vec<PathBuf> = args.targets.iter().map(|target| absolute(target)).collect();
+    let base = cli.directory.first().expect("Clap gives at least the default");
+    if targets.len() < 2 {
+        bail!("Give at least two layers to factor");
+    }
+    if let Some(target) = targets.iter()
+        .find(|target| !cli.directory.iter().any(|layer| absolute(layer) == **target))
+    {
+        bail!("{target:?} is not a layer given with `--directory`");
+    }
+    if targets.contains(&absolute(base)) {
+        bail!("Shared keys go to the first layer {base:?}, so it cannot be factored");
+    }
+
+    let mut found = 0;
+    for entry in discover(cli)? {
+        let Some(format @ ("json" | "toml" | "yaml")) = entry.format.as_deref()
+            .filter(|_| entry.fragment_format.is_none())
+        else {
+            continue;
+        };
+        let (ours, others): (Vec<_>, Vec<_>) = entry.directories.iter()
+            .partition(|directory| cli.layer_of(directory)
+                .is_some_and(|layer| targets.contains(&absolute(layer))));
+        if ours.len() < targets.len() {
+            continue;
+        }
+        let inputs = |directory: &PathBuf| inputs(&Entry {
+            directories: vec![directory.clone()],
+            seed: false,
+            ..entry.clone()
+        });
+        let tree = |directory: &PathBuf| -> Result<Value> {
+            Ok(render(inputs(directory)?)?.tree().unwrap_or_else(|| json!({})))
+        };
+        let trees = ours.iter().map(|d| tree(d)).collect::<Result<Vec<_>>>()?;
+        let elsewhere = others.iter().map(|d| tree(d)).collect::<Result<Vec<_>>>()?;
+        let mut fragments = vec![];
+        for directory in &ours {
+            for Input { source, config } in inputs(directory)? {
+                let text = std::fs::read_to_string(&source)
+                    .context(Failure::Io(format!("Read {source:?}")))?;
+                let fixed = is_final(&source) || drops_comments(format, &text);
+                fragments.push((source, config.tree().unwrap_or_else(|| json!({})), text, fixed));
+            }
+        }
+
+        let shared: Vec<(KeyPath, &Value)> = KeyPath::leaves(&trees[0]).into_iter()
+            .filter(|(path, value)| trees[1..].iter().all(|tree| path.get(tree) == Some(value))
+                && elsewhere.iter().all(|tree| path.get(tree).is_none())
+                && fragments.iter().all(|(_, tree, _, fixed)| !fixed || path.get(tree).is_none()))
+            .collect();
+        if shared.is_empty() {
+            continue;
+        }
+        for (path, _) in &shared {
+            match cli.output {
+                Output::Github => fragments.iter()
+                    .filter(|(_, tree, ..)| path.get(tree).is_some())
+                    .for_each(|(source, ..)| println!("{}", annotation(level(args.check), source,
+                        &format!("`{path}` is set to the same value by every layer")))),
+                _ => println!("{}: {path}", entry.target.display()),
+            }
+        }
+        found += shared.len();
+        if args.check {
+            continue;
+        }
+
+        // The first layer takes the keys in `00-common`, before its own
+        // fragments.
+        let relative = ours[0].strip_prefix(cli.layer_of(ours[0]).expect("Partitioned by layer"))?;
+        let directory = base.join(relative);
+        let common = directory.join(format!("00-common.{format}"));
+        let mut value = json!({});
+        shared.iter().for_each(|(path, v)| path.set(&mut value, Some((*v).clone()), || json!({})));
+        let config = Config::from_tree(&entry.format, value)?;
+        let config = match common.exists() {
+            true => entry.parse(std::fs::read_to_string(&common)?)?.try_merge(Ok(config))?,
+            false => config,
+        };
+        let mut text: String = config.try_into()?;
+        if format == "json" {
+            text.push('\n');
+        }
+        log::info!("Writing {common:?}");
+        std::fs::create_dir_all(&directory)?;
+        std::fs::write(&common, text).context(Failure::Io(format!("Write {common:?}")))?;
+
+        for (source, tree, text, _) in fragments {
+            let moved: Vec<KeyPath> = shared.iter()
+                .map(|(path, _)| path.clone())
+                .filter(|path| path.get(&tree).is_some())
+                .collect();
+            if !moved.is_empty() {
+                let all = moved.len() == KeyPath::leaves(&tree).len();
+                drop_keys(&entry, &source, text, &moved, all)?;
+            }
+        }
+    }
+    if args.check && found > 0 {
+        bail!(Failure::Validation(format!("Found {found} shared key(s)")));
+    }
+    Ok(())
+}
+
+/// Parses every fragment and checks the layout of the config house.
+fn problems(cli: &Cli) -> Result<Vec<Problem>> {
+    let mut problems = vec![];
+    let mut report = |path: &Path, kind, message: String| {
+        problems.push(Problem { path: path.to_path_buf(), kind, message })
+    };
+
+    let directories = discover(cli)?.into_iter()
+    .filter(|entry| entry.fragment_format.is_none())
+    .flat_map(|entry| {
+        let format = entry.format;
+        entry.directories.into_iter().map(move |directory| (directory, format.clone()))
+    });
+    for (directory, format) in directories {
+        let relative = cli.layer_of(&directory)
+            .and_then(|layer| directory.strip_prefix(layer).ok());
+        if relative.is_some_and(|relative| {
+            relative.iter().any(|c| c.to_string_lossy().starts_with('.'))
+        }) {
+            report(&directory, "naming", format!(
+                "hidden path component, name it with `{}` instead", cli.settings.dot
+            ));
+        }
+        if !directory.is_dir() {
+            report(&directory, "naming", "not a directory".into());
+            continue;
+        }
+        if !Config::supports(&format) {
+            report(&directory, "format", format!("unknown format {format:?}"));
+            continue;
+        }
+
+        let fragments = fragments(&directory)?;
+        if fragments.is_empty() {
+            report(&directory, "empty",
+                "no fragments, the target is never changed".into());
+        }
+
+        for fragment in &fragments {
+            if let (Some(format), Some(ext)) = (&format, extension(fragment))
+                && cli.settings.format(Some(ext.clone())).as_ref() != Some(format)
+            {
+                report(fragment, "naming",
+                    format!("`.{ext}` fragment of a `{format}` target"));
+            }
+
+            let text = std::fs::read_to_string(fragment)?;
+            if format.as_deref() == Some("json") {
+                duplicate_keys(&text).into_iter().for_each(|key| {
+                    report(fragment, "duplicate", format!("key `{key}`"))
+                });
+            }
+            if let Err(e) = Config::parse_dispatch(&format, text) {
+                report(fragment, "parse", format!("{e:#}"));
+            }
+        }
+
+        // Fragments are merged in name order, which only agrees with the
+        // order of numeric prefixes when they are distinct and equally wide.
+        let numbered: Vec<(u64, &Path)> = fragments.iter()
+            .filter_map(|path| {
+                let name = path.file_name()?.to_str()?;
+                let digits = name.split(|c: char| !c.is_ascii_digit()).next()?;
+                Some((digits.parse().ok()?, path.as_path()))
+            })
+            .collect();
+        numbered.windows(2).for_each(|pair| {
+            let [(a, first), (b, second)] = pair else { unreachable!() };
+            if a >= b {
+                report(second, "ordering", format!(
+                    "merged after {:?} although its prefix is {}",
+                    first.file_name().unwrap_or_default(),
+                    if a == b { "the same" } else { "smaller" },
+                ));
+            }
+        });
+    }
+
+    Ok(problems)
+}
+
+/// Key paths which appear more than once in the same JSON object.
+fn duplicate_keys(text: &str) -> Vec<String> {
+    use jsonc_parser::{ast::Value, parse_to_ast};
+
+    fn walk(value: &Value, path: &str, found: &mut Vec<String>) {
+        match value {
+            Value::Object(object) => {
+                let mut seen = std::collections::HashSet::new();
+                for property in &object.properties {
+                    let name = property.name.clone().into_string();
+                    let path = format!("{path}.{name}");
+                    walk(&property.value, &path, found);
+                    if !seen.insert(name) {
+                        found.push(path);
+                    }
+                }
+            }
+            Value::Array(array) => array.elements.iter().enumerate()
+                .for_each(|(i, v)| walk(v, &format!("{path}[{i}]"), found)),
+            _ => {}
+        }
+    }
+
+    let mut found = vec![];
+    if let Ok(Some(value)) = parse_to_ast(text, &Default::default(), &Default::default())
+        .map(|result| result.value)
+    {
+        walk(&value, "", &mut found);
+    }
+    found
+}
+
+/// An issue with the environment, found by [`doctor`].
+struct Diagnosis {
+    subject: String,
+    message: String,
+    hint: String,
+}
+
+fn doctor(cli: &Cli) -> Result<()> {
+    let diagnoses = diagnose(cli)?;
+    diagnoses.iter().for_each(|Diagnosis { subject, message, hint }| {
+        println!("{subject}: {message}\n    hint: {hint}");
+    });
+
+    if !diagnoses.is_empty() {
+        anyhow::bail!(Failure::Validation(format!("Found {} issue(s)", diagnoses.len())));
+    }
+    println!("No issues found");
+    Ok(())
+}
+
+/// Finds whatever would make applying fail or behave surprisingly.
+fn diagnose(cli: &Cli) -> Result<Vec<Diagnosis>> {
+    use duct::cmd;
+    use std::time::{Duration, SystemTime};
+
+    let mut diagnoses = vec![];
+    let mut report = |subject: &dyn std::fmt::Debug, message: &str, hint: String| {
+        diagnoses.push(Diagnosis {
+            subject: format!("{subject:?}"),
+            message: message.into(),
+            hint,
+        })
+    };
+
+    fn on_path(tool: &str) -> bool {
+        std::env::var_os("PATH").is_some_and(|paths| {
+            std::env::split_paths(&paths).any(|p| p.join(tool).is_file())
+        })
+    }
+    fn writable(path: &Path) -> bool {
+        cmd!("test", "-w", path).unchecked().run()
+            .is_ok_and(|output| output.status.success())
+    }
+
+    for tool in ["find", "grep", "ls"] {
+        if !on_path(tool) {
+            report(&tool, "not found in `PATH`",
+                format!("install `{tool}`, it is used to read the config house"));
+        }
+    }
+
+    let locale = ["LC_ALL", "LC_CTYPE", "LANG"].into_iter()
+        .find_map(|var| std::env::var(var).ok().filter(|v| !v.is_empty()))
+        .unwrap_or_default();
+    if !locale.to_lowercase().replace('-', "").contains("utf8") {
+        report(&locale, "locale is not UTF-8",
+            "set `LANG` to a UTF-8 locale, `ls` may mangle file names".into());
+    }
+
+    let now = SystemTime::now();
+    let mut tools_wanted = std::collections::BTreeSet::new();
+
+    for entry in discover(cli)? {
+        let fragments = entry.fragments()?;
+        let Entry { target, host, .. } = entry;
+        if host != Host::Local {
+            continue;
+        }
+        match target.parent() {
+            Some(parent) if !parent.is_dir() => report(&target,
+                "parent directory is missing",
+                format!("run `mkdir -p {parent:?}`")),
+            Some(parent) if !target.exists() && !writable(parent) => report(
+                &target, "cannot be created",
+                format!("make {parent:?} writable")),
+            _ if target.exists() && !writable(&target) => report(&target,
+                "not writable", format!("run `chmod u+w {target:?}`")),
+            _ => {}
+        }
+        if target.is_symlink() {
+            report(&target, "is a symlink, writes go to its destination",
+                "remove the link, or manage the file with stow only".into());
+        }
+
+        for fragment in fragments {
+            let name = fragment.file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default();
+
+            // Editors leave these next to files being edited, and they would
+            // be merged like any other fragment.
+            if name.ends_with('~') || name.starts_with('#') && name.ends_with('#') {
+                report(&fragment, "looks like an editor backup or lock file",
+                    "close the editor, or delete the file if it is stale".into());
+            }
+            if [".gpg", ".asc"].iter().any(|ext| name.ends_with(ext)) {
+                tools_wanted.insert("gpg");
+            }
+            if name.contains(".sops.") {
+                tools_wanted.insert("sops");
+            }
+
+            let modified = fragment.metadata().and_then(|m| m.modified());
+            if modified.is_ok_and(|m| m > now + Duration::from_secs(60)) {
+                report(&fragment, "modified in the future",
+                    "check the system clock, e.g. with `timedatectl`".into());
+            }
+        }
+    }
+
+    for tool in tools_wanted {
+        if !on_path(tool) {
+            report(&tool, "not found in `PATH` while fragments need it",
+                format!("install `{tool}`"));
+        }
+    }
+
+    Ok(diagnoses)
+}
+
+/// A path into a structured config, written like `.editor.fontSize` or
+/// `.servers[0]."host.name"`. A lone `.` is the root.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct KeyPath(Vec<Key>);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Key {
+    Name(String),
+    Index(usize),
+}
+
+impl std::str::FromStr for KeyPath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        use anyhow::{bail, Context};
+
+        let mut keys = vec![];
+        let rest = s.strip_prefix('$').unwrap_or(s);
+        let mut rest = rest.strip_prefix('.').unwrap_or(rest);
+        while !rest.is_empty() {
+            if let Some(r) = rest.strip_prefix('[') {
+                let (index, r) = r.split_once(']')
+                    .context(format!("Unclosed `[` in `{s}`"))?;
+                keys.push(Key::Index(index.parse()
+                    .context(format!("Invalid index `{index}` in `{s}`"))?));
+                rest = r;
+            } else if let Some(r) = rest.strip_prefix('"') {
+                let (name, r) = r.split_once('"')
+                    .context(format!("Unclosed `\"` in `{s}`"))?;
+                keys.push(Key::Name(name.into()));
+                rest = r;
+            } else {
+                let end = rest.find(['.', '[']).unwrap_or(rest.len());
+                if end == 0 {
+                    bail!("Empty key in `{s}`");
+                }
+                keys.push(Key::Name(rest[..end].into()));
+                rest = &rest[end..];
+            }
+
+            if let Some(r) = rest.strip_prefix('.') {
+                if r.is_empty() {
+                    bail!("Trailing `.` in `{s}`");
+                }
+                rest = r;
+            } else if !rest.is_empty() && !rest.starts_with('[') {
+                bail!("Expect `.` or `[` before `{rest}` in `{s}`");
+            }
+        }
+        Ok(KeyPath(keys))
+    }
+}
+
+impl std::fmt::Display for KeyPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return f.write_str(".");
+        }
+        self.0.iter().try_for_each(|key| match key {
+            Key::Index(i) => write!(f, "[{i}]"),
+            Key::Name(name) if name.is_empty()
+                || name.contains(|c: char| !c.is_alphanumeric() && !"-_".contains(c))
+                => write!(f, ".{name:?}"),
+            Key::Name(name) => write!(f, ".{name}"),
+        })
+    }
+}
+
+impl KeyPath {
+    /// Every non-table value in a tree with its path. Empty tables, which
+    /// merge as no-ops, are left out.
+    fn leaves(tree: &serde_json::Value) -> Vec<(KeyPath, &serde_json::Value)> {
+        fn walk<'a>(value: &'a serde_json::Value, path: &mut Vec<Key>,
+            leaves: &mut Vec<(KeyPath, &'a serde_json::Value)>)
+        {
+            match value {
+                serde_json::Value::Object(map) => map.iter().for_each(|(k, v)| {
+                    path.push(Key::Name(k.clone()));
+                    walk(v, path, leaves);
+                    path.pop();
+                }),
+                value => leaves.push((KeyPath(path.clone()), value)),
+            }
+        }
+
+        let mut leaves = vec![];
+        walk(tree, &mut vec![], &mut leaves);
+        leaves
+    }
+
+    fn get<'a>(&self, value: &'a serde_json::Value) -> Option<&'a serde_json::Value> {
+        self.0.iter().try_fold(value, |value, key| match key {
+            Key::Name(name) => value.get(name),
+            Key::Index(i) => value.get(i),
+        })
+    }
+
+    fn node<'a, N: Node>(&self, root: &'a N) -> Option<&'a N> {
+        self.0.iter().try_fold(root, |node, key| node.child(key))
+    }
+
+    /// Sets or, given `None`, removes the value at the path. Missing tables
+    /// on the way are created.
+    fn set<N: Node>(&self, root: &mut N, value: Option<N>, table: impl Fn() -> N) {
+        let Some((last, parents)) = self.0.split_last() else {
+            if let Some(value) = value {
+                *root = value;
+            }
+            return;
+        };
+
+        let mut node = root;
+        for key in parents {
+            if node.child(key).is_none() {
+                if value.is_none() {
+                    return;
+                }
+                node.set_child(key, table());
+            }
+            node = node.child_mut(key).expect("Child was just set");
+        }
+        match value {
+            Some(value) => node.set_child(last, value),
+            None => { node.remove_child(last); }
+        }
+    }
+}
+
+/// A value of a structured format, walked by key paths.
+trait Node: Clone {
+    fn child(&self, key: &Key) -> Option<&Self>;
+    fn child_mut(&mut self, key: &Key) -> Option<&mut Self>;
+    /// Sets a child of a table or array, replacing anything else by a table.
+    fn set_child(&mut self, key: &Key, value: Self);
+    fn remove_child(&mut self, key: &Key) -> Option<Self>;
+}
+
+impl Node for serde_json::Value {
+    fn child(&self, key: &Key) -> Option<&Self> {
+        match key {
+            Key::Name(name) => self.get(name),
+            Key::Index(i) => self.get(i),
+        }
+    }
+
+    fn child_mut(&mut self, key: &Key) -> Option<&mut Self> {
+        match key {
+            Key::Name(name) => self.get_mut(name),
+            Key::Index(i) => self.get_mut(i),
+        }
+    }
+
+    fn set_child(&mut self, key: &Key, value: Self) {
+        use serde_json::Value::{Array, Object};
+        match (self, key) {
+            (Array(array), Key::Index(i)) if *i < array.len() => array[*i] = value,
+            (Object(map), key) => { map.insert(key.to_string(), value); }
+            (this, key) => {
+                *this = Object(Default::default());
+                this.set_child(key, value);
+            }
+        }
+    }
+
+    fn remove_child(&mut self, key: &Key) -> Option<Self> {
+        use serde_json::Value::{Array, Object};
+        match (self, key) {
+            (Array(array), Key::Index(i)) if *i < array.len() => Some(array.remove(*i)),
+            (Object(map), key) => map.shift_remove(&key.to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl Node for toml::Value {
+    fn child(&self, key: &Key) -> Option<&Self> {
+        match key {
+            Key::Name(name) => self.get(name),
+            Key::Index(i) => self.get(i),
+        }
+    }
+
+    fn child_mut(&mut self, key: &Key) -> Option<&mut Self> {
+        match key {
+            Key::Name(name) => self.get_mut(name),
+            Key::Index(i) => self.get_mut(i),
+        }
+    }
+
+    fn set_child(&mut self, key: &Key, value: Self) {
+        use toml::Value::{Array, Table};
+        match (self, key) {
+            (Array(array), Key::Index(i)) if *i < array.len() => array[*i] = value,
+            (Table(table), key) => { table.insert(key.to_string(), value); }
+            (this, key) => {
+                *this = Table(Default::default());
+                this.set_child(key, value);
+            }
+        }
+    }
+
+    fn remove_child(&mut self, key: &Key) -> Option<Self> {
+        use toml::Value::{Array, Table};
+        match (self, key) {
+            (Array(array), Key::Index(i)) if *i < array.len() => Some(array.remove(*i)),
+            (Table(table), key) => table.remove(&key.to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Key::Name(name) => f.write_str(name),
+            Key::Index(i) => write!(f, "{i}"),
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+enum Config {
+    Json(serde_json::Value),
+    Toml(TomlConfig),
+    /// A tree with the comments before its keys.
+    Yaml(serde_json::Value, YamlComments),
+    /// A YAML kubeconfig, whose lists of named clusters, contexts and users
+    /// merge entry by entry.
+    Kubeconfig(serde_json::Value),
+    /// Sections as tables of strings, after the keys before any section.
+    Ini(serde_json::Value, IniStyle),
+    Lines(KeyedLines),
+    Rows(Rows),
+    /// A value with the way its vectors merge.
+    Edn(Edn, Vectors),
+    Lua(LuaConfig),
+    Fontconfig(Fontconfig),
+    Registry(Registry),
+    Brewfile(Brewfile),
+    Requirements(Requirements),
+    /// A Markdown document: its front matter as a YAML config, and its body.
+    FrontMatter(Box<Config>, String),
+    /// A format of a [`Plugin`], as the JSON tree it parses into.
+    Plugin { format: String, value: serde_json::Value },
+    Text(String),
+    #[default] None,
+}
+
+/// Text whose lines are keyed by what they set, like a variable, so a later
+/// line replaces the earlier one with the same key.
+#[derive(Clone, Default)]
+struct KeyedLines {
+    dialect: Dialect,
+    lines: Vec<String>,
+}
+
+/// Rows of a CSV or TSV file, keyed by a column, so a later row replaces the
+/// earlier one with the same key. The key is a column name from the header
+/// row, or a column number counted from 1, and defaults to the first column.
+#[derive(Clone, Default)]
+struct Rows {
+    delimiter: u8,
+    key: Option<String>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Rows {
+    fn parse(text: &str, delimiter: u8) -> Result<Self> {
+        let rows = csv::ReaderBuilder::new()
+            .delimiter(delimiter)
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(text.as_bytes())
+            .records()
+            .map(|record| Ok(record?.iter().map(String::from).collect()))
+            .collect::<Result<_>>()?;
+        Ok(Rows { delimiter, key: None, rows })
+    }
+
+    /// Index of the key column.
+    fn column(&self) -> usize {
+        let Some(key) = &self.key else { return 0 };
+        match key.parse::<usize>() {
+            Ok(number) => number.saturating_sub(1),
+            Err(_) => self.rows.first()
+                .and_then(|header| header.iter().position(|name| name == key))
+                .unwrap_or(0),
+        }
+    }
+
+    fn key<'a>(&self, row: &'a [String]) -> Option<&'a str> {
+        row.get(self.column()).map(String::as_str)
+    }
+
+    fn position(&self, key: &str) -> Option<usize> {
+        self.rows.iter().position(|row| self.key(row) == Some(key))
+    }
+}
+
+impl std::fmt::Display for Rows {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(self.delimiter)
+            .flexible(true)
+            .from_writer(vec![]);
+        self.rows.iter().try_for_each(|row| writer.write_record(row))
+            .map_err(|_| std::fmt::Error)?;
+        let bytes = writer.into_inner().map_err(|_| std::fmt::Error)?;
+        f.write_str(&String::from_utf8_lossy(&bytes))
+    }
+}
+
+/// Conventions of INI-like formats.
+#[derive(Clone, Copy, Default)]
+enum IniStyle {
+    /// `key = value` lines.
+    #[default]
+    Plain,
+    /// freedesktop `.desktop` entries: `key=value` lines, where list values,
+    /// like `Categories` and `MimeType` in any locale, end with `;`.
+    Desktop,
+    /// `mimeapps.list`: `type=value` lines of `;` lists of applications,
+    /// which merge by appending those not listed yet.
+    Mimeapps,
+    /// AWS `config` and `credentials`, where fragments may not change keys
+    /// of a profile another fragment set.
+    Aws,
+}
+
+#[derive(Clone, Copy, Default)]
+enum Dialect {
+    /// Fish scripts and `fish_variables`, keyed by the variable of `set` and
+    /// `SETUVAR` lines.
+    #[default]
+    Fish,
+    /// i3 and sway configs, keyed by the variable of `set`, the keys of
+    /// `bindsym` and `bindcode`, and the command of `exec`. Indented lines,
+    /// like those in `mode` blocks, are not keyed.
+    I3,
+    /// Vim scripts, keyed by the option of `set` lines setting a single
+    /// one, with `set nonumber` keyed like `set number`, and the variable of
+    /// `let` lines. Indented lines, like those in functions, are not keyed.
+    Vim,
+    /// X resources, keyed by the resource of `Resource.path: value` lines,
+    /// and by the whole line for `#include` and other directives.
+    Xresources,
+}
+
+impl Dialect {
+    /// The key and value a line sets.
+    fn key(self, line: &str) -> Option<(&str, &str)> {
+        /// Skips words starting with `-`, then splits at the next word.
+        fn after_flags(mut rest: &str) -> Option<(&str, &str)> {
+            rest = rest.trim_start();
+            while rest.starts_with('-') {
+                rest = rest.split_once(char::is_whitespace)?.1.trim_start();
+            }
+            Some(rest.split_once(char::is_whitespace).unwrap_or((rest, "")))
+        }
+
+        match self {
+            Dialect::Fish => {
+                if let Some(rest) = line.strip_prefix("SETUVAR ") {
+                    let rest = rest.trim_start();
+                    return rest.strip_prefix("--export ").unwrap_or(rest)
+                        .trim_start()
+                        .split_once(':');
+                }
+                after_flags(line.strip_prefix("set ")?)
+            }
+            Dialect::I3 => {
+                let (word, rest) = line.split_once(' ')?;
+                match word {
+                    "set" => {
+                        let rest = rest.trim_start();
+                        let name = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                        let key = line.len() - rest.len() + name;
+                        Some((&line[..key], rest[name..].trim()))
+                    }
+                    "bindsym" | "bindcode" => {
+                        let (_, command) = after_flags(rest)?;
+                        let key = line[..line.len() - command.len()].trim_end();
+                        Some((key, command.trim()))
+                    }
+                    "exec" | "exec_always" => Some((line.trim_end(), "")),
+                    _ => None,
+                }
+            }
+            Dialect::Xresources => match line.starts_with('#') {
+                true => Some((line.trim_end(), "")),
+                false if line.starts_with('!') => None,
+                false => line.split_once(':').map(|(key, value)| (key.trim(), value.trim())),
+            },
+            Dialect::Vim => {
+                let (word, rest) = line.split_once(char::is_whitespace)?;
+                let rest = rest.trim_start();
+                match word {
+                    "set" | "se" => {
+                        let end = rest.find(|c: char| !c.is_ascii_alphanumeric())
+                            .unwrap_or(rest.len());
+                        let (name, after) = rest.split_at(end);
+                        let value = match after.chars().next() {
+                            Some('=' | ':') => &after[1..],
+                            None => after,
+                            Some(c) if c.is_whitespace() => {
+                                let after = after.trim_start();
+                                // More options on the line are not keyed.
+                                (after.is_empty() || after.starts_with('"')).then_some("")?
+                            }
+                            Some(_) => return None,
+                        };
+                        let name = name.strip_prefix("no").unwrap_or(name);
+                        (!name.is_empty()).then_some((name, value.trim()))
+                    }
+                    "let" => {
+                        let end = rest.find(|c: char| c.is_whitespace() || c == '=')?;
+                        let (name, after) = rest.split_at(end);
+                        let value = after.trim_start().strip_prefix('=')?;
+                        (!value.starts_with('=')).then_some((name, value.trim()))
+                    }
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    /// What comment lines start with.
+    fn comment(self) -> char {
+        match self {
+            Dialect::Fish | Dialect::I3 => '#',
+            Dialect::Vim => '"',
+            Dialect::Xresources => '!',
+        }
+    }
+}
+
+impl KeyedLines {
+    fn position(&self, key: &str) -> Option<usize> {
+        self.lines.iter()
+            .position(|line| self.dialect.key(line).is_some_and(|(k, _)| k == key))
+    }
+}
+
+impl std::fmt::Display for KeyedLines {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.lines.iter().try_for_each(|line| writeln!(f, "{line}"))
+    }
+}
+
+#[derive(Clone)]
+struct TomlConfig {
+    value: toml::Value,
+}
+
+impl Default for TomlConfig {
+    fn default() -> Self {
+        TomlConfig {
+            value: toml::Value::Table(Default::default()),
+        }
+    }
+}
+
+impl std::fmt::Display for TomlConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&toml::to_string_pretty(&self.value)
+            .expect("Serialize a toml::Value should not fail"))
+    }
+}
+
+impl Config {
+    fn supports(format: &Option<String>) -> bool {
+        matches!(format.as_deref(),
+            Some("json" | "hjson" | "toml" | "yaml" | "ini" | "edn" | "lua" | "fish" | "i3config"
+                | "vim" | "xresources" | "desktop" | "mimeapps"
+                | "awsconfig" | "kubeconfig" | "fontconfig" | "reg" | "brewfile" | "requirements"
+                | "frontmatter" | "csv" | "tsv" | "text") | None)
+            || format.as_deref().is_some_and(Plugin::exists)
+    }
+
+    /// Makes the value at `path` the same as in `other`, if `other` has one.
+    fn restore(&mut self, other: &Config, path: &KeyPath) -> Result<()> {
+        use Config::*;
+        match (self, other) {
+            (FrontMatter(yaml, _), FrontMatter(other, _)) => yaml.restore(other, path)?,
+            (Json(value), Json(other)) | (Yaml(value, _), Yaml(other, _))
+            | (Kubeconfig(value), Kubeconfig(other))
+            | (Ini(value, _), Ini(other, _))
+            | (Plugin { value, .. }, Plugin { value: other, .. }) => {
+                if let Some(v) = path.node(other) {
+                    path.set(value, Some(v.clone()), || serde_json::json!({}))
+                }
+            }
+            (Toml(toml), Toml(other)) => if let Some(v) = path.node(&other.value) {
+                path.set(&mut toml.value, Some(v.clone()),
+                    || toml::Value::Table(Default::default()))
+            },
+            (Edn(edn, _), Edn(other, _)) => if let Some(v) = path.node(other) {
+                path.set(edn, Some(v.clone()), || crate::Edn::Map(vec![]))
+            },
+            (Lua(lua), Lua(other)) => if let Some(v) = path.node(&other.table) {
+                path.set(&mut lua.table, Some(v.clone()), Default::default)
+            },
+            (Lines(lines), Lines(other)) => if let [Key::Name(name)] = &path.0[..]
+                && let Some(line) = other.position(name).map(|i| other.lines[i].clone())
+            {
+                match lines.position(name) {
+                    Some(i) => lines.lines[i] = line,
+                    _ => lines.lines.push(line),
+                }
+            },
+            (Fontconfig(rules), Fontconfig(other)) => if let [Key::Name(name)] = &path.0[..]
+                && let Some(rule) = other.position(name).map(|i| other.rules[i].clone())
+            {
+                match rules.position(name) {
+                    Some(i) => rules.rules[i] = rule,
+                    _ => rules.rules.push(rule),
+                }
+            },
+            (Registry(registry), Registry(other)) => if let [Key::Name(name)] = &path.0[..]
+                && let Some(key) = other.position(name).map(|i| other.keys[i].clone())
+            {
+                match registry.position(name) {
+                    Some(i) => registry.keys[i] = key,
+                    _ => registry.keys.push(key),
+                }
+            },
+            (Rows(rows), Rows(other)) => if let [Key::Name(name)] = &path.0[..]
+                && let Some(row) = other.position(name).map(|i| other.rows[i].clone())
+            {
+                match rows.position(name) {
+                    Some(i) => rows.rows[i] = row,
+                    _ => rows.rows.push(row),
+                }
+            },
+            (Brewfile(brewfile), Brewfile(other)) => {
+                let (kind, name) = crate::Brewfile::key(path);
+                let entries: Vec<_> = other.entries.iter()
+                    .filter(|entry| entry.kind == kind
+                        && name.as_ref().is_none_or(|name| entry.name == *name))
+                    .cloned()
+                    .collect();
+                entries.into_iter().for_each(|entry| brewfile.insert(entry));
+            }
+            (Requirements(ours), Requirements(other)) => if let [Key::Name(key)] = &path.0[..]
+                && let Some(requirement) = other.position(key).map(|i| other.requirements[i].clone())
+            {
+                match ours.position(key) {
+                    Some(i) => ours.requirements[i] = requirement,
+                    _ => ours.requirements.push(requirement),
+                }
+            },
+            (Text(_), _) => anyhow::bail!("Plain text has no keys"),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Removes the value at `path`, if any.
+    fn remove(&mut self, path: &KeyPath) -> Result<()> {
+        match self {
+            Config::FrontMatter(yaml, _) => yaml.remove(path)?,
+            Config::Json(value) | Config::Yaml(value, _) | Config::Ini(value, _)
+            | Config::Kubeconfig(value) | Config::Plugin { value, .. } =>
+                path.set(value, None, || serde_json::json!({})),
+            Config::Toml(toml) => path.set(&mut toml.value, None,
+                || toml::Value::Table(Default::default())),
+            Config::Edn(edn, _) => path.set(edn, None, || Edn::Map(vec![])),
+            Config::Lua(lua) => path.set(&mut lua.table, None, Default::default),
+            Config::Lines(lines) => if let [Key::Name(name)] = &path.0[..] {
+                let dialect = lines.dialect;
+                lines.lines.retain(|line| dialect.key(line).is_none_or(|(k, _)| k != name));
+            },
+            Config::Rows(rows) => if let [Key::Name(name)] = &path.0[..]
+                && let Some(i) = rows.position(name)
+            {
+                rows.rows.remove(i);
+            },
+            Config::Fontconfig(rules) => if let [Key::Name(name)] = &path.0[..]
+                && let Some(i) = rules.position(name)
+            {
+                rules.rules.remove(i);
+            },
+            Config::Registry(registry) => if let [Key::Name(name)] = &path.0[..] {
+                registry.keys.retain(|key| !key.path.eq_ignore_ascii_case(name));
+            },
+            Config::Brewfile(brewfile) => {
+                let (kind, name) = Brewfile::key(path);
+                brewfile.entries.retain(|entry| entry.kind != kind
+                    || name.as_ref().is_some_and(|name| entry.name != *name));
+            }
+            Config::Requirements(requirements) => if let [Key::Name(key)] = &path.0[..]
+                && let Some(i) = requirements.position(key)
+            {
+                requirements.requirements.remove(i);
+            },
+            Config::Text(_) => anyhow::bail!("Plain text has no keys"),
+            Config::None => {}
+        }
+        Ok(())
+    }
+
+    /// Whether the config is a single value rather than a table.
+    fn is_value(&self) -> bool {
+        match self {
+            Config::FrontMatter(yaml, _) => yaml.is_value(),
+            Config::Json(value) | Config::Yaml(value, _) | Config::Ini(value, _)
+            | Config::Kubeconfig(value) | Config::Plugin { value, .. } => !value.is_object(),
+            Config::Toml(toml) => !toml.value.is_table(),
+            Config::Edn(edn, _) => !matches!(edn, Edn::Map(_)),
+            Config::Lua(lua) => !matches!(lua.table, Lua::Table(_)),
+            Config::Lines(_) | Config::Rows(_) | Config::Fontconfig(_) | Config::Registry(_)
+            | Config::Brewfile(_) | Config::Requirements(_) | Config::Text(_)
+            | Config::None => false,
+        }
+    }
+
+    /// Nests the config under a key path.
+    fn scoped(self, path: &KeyPath) -> Result<Self> {
+        use Config::*;
+
+        if path.0.is_empty() {
+            return Ok(self);
+        }
+        let nest = |value| {
+            let mut root = serde_json::json!({});
+            path.set(&mut root, Some(value), || serde_json::json!({}));
+            root
+        };
+        Ok(match self {
+            FrontMatter(yaml, body) => FrontMatter(Box::new(yaml.scoped(path)?), body),
+            Json(value) => Json(nest(value)),
+            Yaml(value, comments) => Yaml(nest(value), YamlComments {
+                nodes: comments.nodes.into_iter()
+                    .map(|(keys, before, inline)| (
+                        path.0.iter().map(Key::to_string).chain(keys).collect(),
+                        before,
+                        inline,
+                    ))
+                    .collect(),
+                trailing: comments.trailing,
+            }),
+            Ini(value, style) => Ini(nest(value), style),
+            Kubeconfig(value) => Kubeconfig(nest(value)),
+            Plugin { format, value } => Plugin { format, value: nest(value) },
+            Toml(toml) => {
+                let table = || toml::Value::Table(Default::default());
+                let mut root = table();
+                path.set(&mut root, Some(toml.value), table);
+                Toml(TomlConfig { value: root })
+            }
+            Edn(edn, vectors) => {
+                let mut root = crate::Edn::Map(vec![]);
+                path.set(&mut root, Some(edn), || crate::Edn::Map(vec![]));
+                Edn(root, vectors)
+            }
+            Lua(mut lua) => {
+                let mut root = Default::default();
+                path.set(&mut root, Some(lua.table), Default::default);
+                lua.table = root;
+                Lua(lua)
+            }
+            Lines(_) => anyhow::bail!("Keyed lines have no tables to nest `{path}` in"),
+            Rows(_) => anyhow::bail!("Rows have no tables to nest `{path}` in"),
+            Fontconfig(_) => anyhow::bail!("Fontconfig rules have no tables to nest `{path}` in"),
+            Registry(_) => anyhow::bail!("Registry keys have no tables to nest `{path}` in"),
+            Brewfile(_) => anyhow::bail!("Brewfile entries have no tables to nest `{path}` in"),
+            Requirements(_) => anyhow::bail!("Requirements have no tables to nest `{path}` in"),
+            Text(_) => anyhow::bail!("Plain text has no keys to nest `{path}` in"),
+            None => None,
+        })
+    }
+
+    /// The config as a JSON tree to be walked by key paths. Plain text has
+    /// none.
+    fn tree(&self) -> Option<serde_json::Value> {
+        fn toml_to_json(value: &toml::Value) -> serde_json::Value {
+            use toml::Value::*;
+            match value {
+                String(s) => s.clone().into(),
+                Integer(i) => (*i).into(),
+                Float(f) => (*f).into(),
+                Boolean(b) => (*b).into(),
+                Datetime(d) => d.to_string().into(),
+                Array(array) => array.iter().map(toml_to_json).collect(),
+                Table(table) => table.iter()
+                    .map(|(k, v)| (k.clone(), toml_to_json(v)))
+                    .collect::<serde_json::Map<_, _>>()
+                    .into(),
+            }
+        }
+
+        match self {
+            Config::FrontMatter(yaml, _) => yaml.tree(),
+            Config::Json(value) | Config::Yaml(value, _) | Config::Ini(value, _)
+            | Config::Kubeconfig(value) | Config::Plugin { value, .. } => Some(value.clone()),
+            Config::Toml(toml) => Some(toml_to_json(&toml.value)),
+            Config::Edn(edn, _) => Some(edn.tree()),
+            Config::Lua(lua) => Some(lua.table.tree()),
+            Config::Lines(lines) => Some(lines.lines.iter()
+                .filter_map(|line| lines.dialect.key(line))
+                .map(|(name, value)| (name.to_string(), value.into()))
+                .collect::<serde_json::Map<_, _>>()
+                .into()),
+            Config::Fontconfig(rules) => Some(rules.rules.iter()
+                .map(|(key, rule)| (key.clone(), rule.as_str().into()))
+                .collect::<serde_json::Map<_, _>>()
+                .into()),
+            Config::Registry(registry) => Some(registry.keys.iter()
+                .map(|key| (key.path.clone(), match key.delete {
+                    true => serde_json::Value::Null,
+                    false => key.values.iter()
+                        .map(|(name, data)| (name.clone(), data.as_str().into()))
+                        .collect::<serde_json::Map<_, _>>()
+                        .into(),
+                }))
+                .collect::<serde_json::Map<_, _>>()
+                .into()),
+            Config::Rows(rows) => Some(rows.rows.iter()
+                .filter_map(|row| Some((rows.key(row)?.to_string(), row.clone().into())))
+                .collect::<serde_json::Map<_, _>>()
+                .into()),
+            // Arguments by name under each kind, or by kind for lines
+            // without a name.
+            Config::Brewfile(brewfile) => {
+                let mut tree = serde_json::Map::new();
+                for entry in &brewfile.entries {
+                    let args = serde_json::Value::from(entry.args.as_str());
+                    let node = tree.entry(entry.kind.clone());
+                    match (entry.name.is_empty(), node.or_insert_with(|| serde_json::json!({}))) {
+                        (true, node) => *node = args,
+                        (false, serde_json::Value::Object(names)) => {
+                            names.insert(entry.name.clone(), args);
+                        }
+                        _ => {}
+                    }
+                }
+                Some(tree.into())
+            }
+            Config::Requirements(requirements) => Some(requirements.requirements.iter()
+                .map(|requirement| (requirement.key(), requirement.to_string().into()))
+                .collect::<serde_json::Map<_, _>>()
+                .into()),
+            Config::Text(_) | Config::None => None,
+        }
+    }
+
+    /// A JSON, TOML or YAML config holding a tree.
+    fn from_tree(format: &Option<String>, tree: serde_json::Value) -> Result<Self> {
+        Ok(match format.as_deref() {
+            Some("json") => Config::Json(tree),
+            Some("yaml") => Config::Yaml(tree, Default::default()),
+            Some("toml") => Config::Toml(TomlConfig { value: toml::Value::try_from(&tree)? }),
+            _ => anyhow::bail!("Cannot write {format:?} from other formats"),
+        })
+    }
+
+    /// Smallest fragment text that parses in the format.
+    fn skeleton(format: &Option<String>) -> &'static str {
+        match format.as_deref() {
+            Some("json" | "hjson") => "{\n}\n",
+            Some("yaml" | "kubeconfig") => "{}\n",
+            _ => "",
+        }
+    }
+
+    fn parse_dispatch(format: &Option<String>, text: String) -> Result<Self> {
+        use anyhow::{bail, Context};
+        use jsonc_parser::parse_to_serde_value;
+
+        match format.as_deref() {
+            Some(format) if Plugin::exists(format) => Ok(Config::Plugin {
+                format: format.into(),
+                value: serde_json::from_slice(&Plugin::call(format, "parse", &[text.as_bytes()])?)
+                    .context(format!("Plugin `{format}` parsed into invalid JSON"))?,
+            }),
+            Some("json") => Ok(Config::Json(
+                parse_to_serde_value(&text, &Default::default())?
+                    .context(format!("Possible empty json: `{text}`"))?
+            )),
+            Some("toml") => Ok(Config::Toml(TomlConfig {
+                value: toml::from_str(&text)
+                    .or_else(|e| {
+                        // A lone value, scoped by the fragment name.
+                        toml::from_str::<toml::Table>(&format!("value = {text}"))
+                            .ok()
+                            .and_then(|mut table| table.remove("value"))
+                            .ok_or(e)
+                    })
+                    .context(format!("Possible empty toml: `{text}`"))?,
+            })),
+            Some("ini") => Ok(Config::Ini(parse_ini(&text)?, IniStyle::Plain)),
+            Some("desktop") => Ok(Config::Ini(parse_ini(&text)?, IniStyle::Desktop)),
+            Some("mimeapps") => Ok(Config::Ini(parse_ini(&text)?, IniStyle::Mimeapps)),
+            Some("awsconfig") => Ok(Config::Ini(parse_ini(&text)?, IniStyle::Aws)),
+            Some(format @ ("fish" | "i3config" | "vim" | "xresources")) => {
+                let dialect = match format {
+                    "fish" => Dialect::Fish,
+                    "vim" => Dialect::Vim,
+                    "xresources" => Dialect::Xresources,
+                    _ => Dialect::I3,
+                };
+                Ok(Config::Lines(KeyedLines {
+                    dialect,
+                    lines: text.lines().map(String::from).collect(),
+                }))
+            }
+            // Hjson is written back as JSON, which is valid Hjson.
+            Some("hjson") => Ok(Config::Json(deser_hjson::from_str(&text)?)),
+            Some("edn") => Ok(Config::Edn(Edn::parse(&text)?, Default::default())),
+            Some("lua") => Ok(Config::Lua(LuaConfig::parse(&text)?)),
+            Some("fontconfig") => Ok(Config::Fontconfig(Fontconfig::parse(&text)?)),
+            Some("reg") => Ok(Config::Registry(Registry::parse(&text)?)),
+            Some("brewfile") => Ok(Config::Brewfile(Brewfile::parse(&text)?)),
+            Some("requirements") => Ok(Config::Requirements(Requirements::parse(&text)?)),
+            Some("frontmatter") => {
+                // The block between `---` lines opening the document.
+                let block = text.strip_prefix("---\n").and_then(|rest| {
+                    let mut end = 0;
+                    for line in rest.split_inclusive('\n') {
+                        if line.trim_end() == "---" {
+                            return Some((&rest[..end], &rest[end + line.len()..]));
+                        }
+                        end += line.len();
+                    }
+                    None
+                });
+                let (yaml, body) = block.unwrap_or(("", &text));
+                let yaml = match serde_yaml::from_str(yaml)? {
+                    serde_json::Value::Null => Config::Yaml(serde_json::json!({}), Default::default()),
+                    value => Config::Yaml(value, YamlComments::parse(yaml)),
+                };
+                Ok(Config::FrontMatter(Box::new(yaml), body.into()))
+            }
+            Some("csv") => Ok(Config::Rows(Rows::parse(&text, b',')?)),
+            Some("tsv") => Ok(Config::Rows(Rows::parse(&text, b'\t')?)),
+            Some("kubeconfig") => Ok(Config::Kubeconfig(serde_yaml::from_str(&text)?)),
+            Some("yaml") => match serde_yaml::from_str(&text)? {
+                serde_json::Value::Null => bail!("Possible empty yaml: `{text}`"),
+                value => Ok(Config::Yaml(value, YamlComments::parse(&text))),
+            },
+            Some("text") | None => Ok(Config::Text(text)),
+            _ => bail!("Unsupported format: {format:?}"),
+        }
+    }
+
+    /// Cuts a config into named parts which merge back into itself.
+    fn split(self, by: SplitBy) -> Result<Vec<(String, Self)>> {
+        use anyhow::bail;
+        use Config::*;
+
+        /// Groups entries into parts. For sections, entries that are not
+        /// tables are gathered in a leading `root` part.
+        fn group<V>(
+            entries: impl IntoIterator<Item = (String, V)>,
+            by: SplitBy,
+            is_table: impl Fn(&V) -> bool,
+        ) -> Vec<(String, Vec<(String, V)>)> {
+            let (root, tables): (Vec<_>, Vec<_>) = entries.into_iter()
+                .partition(|(_, v)| by == SplitBy::Sections && !is_table(v));
+
+            (!root.is_empty()).then(|| ("root".to_string(), root))
+            .into_iter()
+            .chain(tables.into_iter().map(|(k, v)| (k.clone(), vec![(k, v)])))
+            .collect()
+        }
+
+        fn objects(
+            map: serde_json::Map<String, serde_json::Value>,
+            by: SplitBy,
+            wrap: impl Fn(serde_json::Value) -> Config,
+        ) -> Vec<(String, Config)> {
+            group(map, by, serde_json::Value::is_object)
+            .into_iter()
+            .map(|(name, part)| {
+                (name, wrap(serde_json::Value::Object(part.into_iter().collect())))
+            })
+            .collect()
+        }
+
+        Ok(match self {
+            Json(serde_json::Value::Object(map)) => objects(map, by, Json),
+            Yaml(serde_json::Value::Object(map), comments) =>
+                objects(map, by, |value| Yaml(value, comments.clone())),
+            Ini(serde_json::Value::Object(map), style) =>
+                objects(map, by, |value| Ini(value, style)),
+            Kubeconfig(serde_json::Value::Object(map)) => objects(map, by, Kubeconfig),
+            Plugin { format, value: serde_json::Value::Object(map) } =>
+                objects(map, by, |value| Plugin { format: format.clone(), value }),
+            Toml(TomlConfig { value: toml::Value::Table(table) }) =>
+                group(table, by, toml::Value::is_table)
+                .into_iter()
+                .map(|(name, part)| {
+                    (name, Toml(TomlConfig {
+                        value: toml::Value::Table(part.into_iter().collect()),
+                    }))
+                })
+                .collect(),
+            // Text fragments are joined by a newline, so each part but the
+            // last keeps one of the two newlines it was cut at.
+            Text(text) if by == SplitBy::Sections => {
+                let parts: Vec<_> = text.split("\n\n").collect();
+                let last = parts.len() - 1;
+                parts.into_iter().enumerate()
+                .map(|(i, part)| {
+                    let mut part = part.to_string();
+                    if i < last {
+                        part.push('\n');
+                    }
+                    ("section".to_string(), Text(part))
+                })
+                .collect()
+            }
+            Text(_) => bail!("Plain text has no keys, try `--by sections`"),
+            None => bail!("Nothing to split"),
+            _ => bail!("Only a table can be split"),
+        })
+    }
+
+    fn try_merge(self, other: Result<Self>) -> Result<Self> {
+        use anyhow::{bail, Context};
+        use Config::*;
+
+        match (self, other?) {
+            (None, other) => Ok(other),
+            (Json(a), Json(b)) => Ok(Json(a.merge(b))),
+            (Toml(a), Toml(b)) => Ok(Toml(a.merge(b))),
+            (Yaml(a, comments), Yaml(b, later)) => {
+                let comments = comments.merge(later, &b);
+                Ok(Yaml(a.merge(b), comments))
+            }
+            (Ini(a, IniStyle::Mimeapps), Ini(b, _)) =>
+                Ok(Ini(merge_lists(a, b), IniStyle::Mimeapps)),
+            (Ini(a, style), Ini(b, _)) => Ok(Ini(a.merge(b), style)),
+            (Kubeconfig(a), Kubeconfig(b)) => Ok(Kubeconfig(merge_kubeconfig(a, b))),
+            (Lines(a), Lines(b)) => Ok(Lines(a.merge(b))),
+            (Rows(a), Rows(b)) => Ok(Rows(a.merge(b))),
+            (Edn(a, vectors), Edn(b, _)) => Ok(Edn(a.merge(b, vectors), vectors)),
+            (Lua(a), Lua(b)) => Ok(Lua(a.merge(b))),
+            (Fontconfig(a), Fontconfig(b)) => Ok(Fontconfig(a.merge(b))),
+            (Registry(a), Registry(b)) => Ok(Registry(a.merge(b))),
+            (Plugin { format, value: a }, Plugin { value: b, .. }) => {
+                let merged = crate::Plugin::call(&format, "merge",
+                    &[a.to_string().as_bytes(), b.to_string().as_bytes()])?;
+                let value = serde_json::from_slice(&merged)
+                    .context(format!("Plugin `{format}` merged into invalid JSON"))?;
+                Ok(Plugin { format, value })
+            }
+            (Brewfile(a), Brewfile(b)) => Ok(Brewfile(a.merge(b))),
+            (Requirements(a), Requirements(b)) => Ok(Requirements(a.combine(b)?)),
+            // Bodies join like text, and an empty one leaves the other alone.
+            (FrontMatter(a, body), FrontMatter(b, later)) => Ok(FrontMatter(
+                Box::new(a.try_merge(Ok(*b))?),
+                match (body.trim().is_empty(), later.trim().is_empty()) {
+                    (true, _) => later,
+                    (_, true) => body,
+                    _ => body.merge(later),
+                },
+            )),
+            (Text(a), Text(b)) => Ok(Text(a.merge(b))),
+            _ => bail!(crate::Failure::Conflict("Cannot merge different types".into())),
+        }
+    }
+}
+
+/// A value of EDN, the data notation of Clojure. Scalars keep their source
+/// text, like `:mvn/version`, `1.5M` or a string with its escapes.
+#[derive(Debug, Clone, PartialEq)]
+enum Edn {
+    Scalar(String),
+    List(Vec<Edn>),
+    Vector(Vec<Edn>),
+    Set(Vec<Edn>),
+    Map(Vec<(Edn, Edn)>),
+    /// A tagged element, like `#inst "2024-01-01"`.
+    Tagged(String, Box<Edn>),
+}
+
+/// How EDN vectors of a later fragment merge into earlier ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Vectors {
+    /// The later vector replaces the earlier one.
+    #[default]
+    Replace,
+    /// Elements of the later vector are appended.
+    Append,
+    /// Elements of the later vector missing from the earlier one are
+    /// appended.
+    Union,
+}
+
+impl Edn {
+    /// Parses a single EDN element. `;` comments, commas and elements after
+    /// `#_` are skipped.
+    fn parse(text: &str) -> Result<Self> {
+        use anyhow::{bail, Context};
+
+        fn skip(rest: &mut &str) -> Result<()> {
+            loop {
+                *rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+                if rest.starts_with(';') {
+                    *rest = rest.find('\n').map_or("", |i| &rest[i..]);
+                } else if let Some(r) = rest.strip_prefix("#_") {
+                    *rest = r;
+                    element(rest)?;
+                } else {
+                    return Ok(());
+                }
+            }
+        }
+
+        fn token<'a>(rest: &mut &'a str) -> &'a str {
+            let end = rest.find(|c: char| c.is_whitespace() || "()[]{}\",;".contains(c))
+                .unwrap_or(rest.len());
+            let (token, r) = rest.split_at(end);
+            *rest = r;
+            token
+        }
+
+        fn elements(rest: &mut &str, close: char) -> Result<Vec<Edn>> {
+            let mut elements = vec![];
+            loop {
+                skip(rest)?;
+                if let Some(r) = rest.strip_prefix(close) {
+                    *rest = r;
+                    return Ok(elements);
+                }
+                if rest.is_empty() {
+                    bail!("Expect `{close}` before the end");
+                }
+                elements.push(element(rest)?);
+            }
+        }
+
+        fn element(rest: &mut &str) -> Result<Edn> {
+            skip(rest)?;
+            let mut chars = rest.chars();
+            let Some(first) = chars.next() else { bail!("Expect an element before the end") };
+            let after = chars.as_str();
+            Ok(match first {
+                '(' => { *rest = after; Edn::List(elements(rest, ')')?) }
+                '[' => { *rest = after; Edn::Vector(elements(rest, ']')?) }
+                '{' => {
+                    *rest = after;
+                    let elements = elements(rest, '}')?;
+                    if elements.len() % 2 == 1 {
+                        bail!("Map has a key without a value");
+                    }
+                    Edn::Map(elements.chunks(2)
+                        .map(|pair| (pair[0].clone(), pair[1].clone()))
+                        .collect())
+                }
+                '#' if after.starts_with('{') => {
+                    *rest = &after[1..];
+                    Edn::Set(elements(rest, '}')?)
+                }
+                '#' => {
+                    *rest = after;
+                    let tag = token(rest);
+                    Edn::Tagged(tag.into(), Box::new(element(rest)?))
+                }
+                '"' => {
+                    let mut escaped = false;
+                    let end = after.char_indices()
+                        .find(|&(_, c)| {
+                            let closing = c == '"' && !escaped;
+                            escaped = c == '\\' && !escaped;
+                            closing
+                        })
+                        .map(|(i, _)| i + 2)
+                        .context("Unclosed string")?;
+                    let (string, r) = rest.split_at(end);
+                    *rest = r;
+                    Edn::Scalar(string.into())
+                }
+                ')' | ']' | '}' => bail!("Unexpected `{first}`"),
+                // A character literal, like `\a` or `\newline`.
+                '\\' => {
+                    let len = first.len_utf8() + after.chars().next()
+                        .context("Expect a character after `\\`")?.len_utf8();
+                    let (head, r) = rest.split_at(len);
+                    *rest = r;
+                    Edn::Scalar(format!("{head}{}", token(rest)))
+                }
+                _ => Edn::Scalar(token(rest).into()),
+            })
+        }
+
+        let mut rest = text;
+        let edn = element(&mut rest).context("Parse EDN")?;
+        skip(&mut rest)?;
+        if !rest.is_empty() {
+            bail!("Expect a single EDN element, found more at `{}`",
+                rest.lines().next().unwrap_or_default());
+        }
+        Ok(edn)
+    }
+
+    /// Maps merge key by key, sets merge into their union, and vectors by
+    /// the strategy. Anything else is replaced.
+    fn merge(self, other: Self, vectors: Vectors) -> Self {
+        use Edn::*;
+        match (self, other) {
+            (Map(mut a), Map(b)) => {
+                for (key, value) in b {
+                    match a.iter().position(|(k, _)| *k == key) {
+                        Some(i) => {
+                            let earlier = std::mem::replace(&mut a[i].1, Map(vec![]));
+                            a[i].1 = earlier.merge(value, vectors);
+                        }
+                        None => a.push((key, value)),
+                    }
+                }
+                Map(a)
+            }
+            (Set(a), Set(b)) => Set(union(a, b)),
+            (Vector(mut a), Vector(b)) => match vectors {
+                Vectors::Replace => Vector(b),
+                Vectors::Append => { a.extend(b); Vector(a) }
+                Vectors::Union => Vector(union(a, b)),
+            },
+            (_, other) => other,
+        }
+    }
+
+    /// Text of the element, with one entry of a map per line.
+    fn write(&self, indent: usize) -> String {
+        fn join(elements: &[Edn], indent: usize) -> String {
+            elements.iter().map(|e| e.write(indent)).collect::<Vec<_>>().join(" ")
+        }
+
+        match self {
+            Edn::Scalar(text) => text.clone(),
+            Edn::List(elements) => format!("({})", join(elements, indent + 1)),
+            Edn::Vector(elements) => format!("[{}]", join(elements, indent + 1)),
+            Edn::Set(elements) => format!("#{{{}}}", join(elements, indent + 2)),
+            Edn::Map(entries) => {
+                let entries: Vec<_> = entries.iter()
+                    .map(|(key, value)| {
+                        let key = key.write(indent + 1);
+                        let value = value.write(indent + 2 + key.len());
+                        format!("{key} {value}")
+                    })
+                    .collect();
+                format!("{{{}}}", entries.join(&format!("\n{}", " ".repeat(indent + 1))))
+            }
+            Edn::Tagged(tag, element) => format!("#{tag} {}", element.write(indent)),
+        }
+    }
+
+    /// The element as a JSON tree, with map keys as their text.
+    fn tree(&self) -> serde_json::Value {
+        use serde_json::Value;
+        match self {
+            Edn::Scalar(text) => match text.as_str() {
+                "nil" => Value::Null,
+                "true" => true.into(),
+                "false" => false.into(),
+                text => text.parse::<i64>().map(Value::from)
+                    .or_else(|_| text.parse::<f64>().map(Value::from))
+                    .unwrap_or_else(|_| serde_json::from_str(text)
+                        .unwrap_or_else(|_| text.into())),
+            },
+            Edn::List(elements) | Edn::Vector(elements) | Edn::Set(elements) =>
+                elements.iter().map(Edn::tree).collect(),
+            Edn::Map(entries) => entries.iter()
+                .map(|(key, value)| (key.write(0), value.tree()))
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+            Edn::Tagged(..) => self.write(0).into(),
+        }
+    }
+}
+
+fn union(mut a: Vec<Edn>, b: Vec<Edn>) -> Vec<Edn> {
+    for element in b {
+        if !a.contains(&element) {
+            a.push(element);
+        }
+    }
+    a
+}
+
+impl std::fmt::Display for Edn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.write(0))
+    }
+}
+
+impl Node for Edn {
+    fn child(&self, key: &Key) -> Option<&Self> {
+        match (self, key) {
+            (Edn::Map(entries), Key::Name(name)) => entries.iter()
+                .find(|(k, _)| k.write(0) == *name).map(|(_, v)| v),
+            (Edn::Vector(elements) | Edn::List(elements), Key::Index(i)) => elements.get(*i),
+            _ => None,
+        }
+    }
+
+    fn child_mut(&mut self, key: &Key) -> Option<&mut Self> {
+        match (self, key) {
+            (Edn::Map(entries), Key::Name(name)) => entries.iter_mut()
+                .find(|(k, _)| k.write(0) == *name).map(|(_, v)| v),
+            (Edn::Vector(elements) | Edn::List(elements), Key::Index(i)) =>
+                elements.get_mut(*i),
+            _ => None,
+        }
+    }
+
+    fn set_child(&mut self, key: &Key, value: Self) {
+        match (self, key) {
+            (Edn::Vector(elements) | Edn::List(elements), Key::Index(i))
+                if *i < elements.len() => elements[*i] = value,
+            (Edn::Map(entries), key) => {
+                let name = key.to_string();
+                match entries.iter_mut().find(|(k, _)| k.write(0) == name) {
+                    Some((_, v)) => *v = value,
+                    None => entries.push((Edn::Scalar(name), value)),
+                }
+            }
+            (this, key) => {
+                *this = Edn::Map(vec![]);
+                this.set_child(key, value);
+            }
+        }
+    }
+
+    fn remove_child(&mut self, key: &Key) -> Option<Self> {
+        match (self, key) {
+            (Edn::Vector(elements) | Edn::List(elements), Key::Index(i))
+                if *i < elements.len() => Some(elements.remove(*i)),
+            (Edn::Map(entries), key) => {
+                let name = key.to_string();
+                let i = entries.iter().position(|(k, _)| k.write(0) == name)?;
+                Some(entries.remove(i).1)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A declarative Lua config: lines before its `return`, like
+/// `local wezterm = require 'wezterm'`, and the table it returns.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct LuaConfig {
+    preamble: Vec<String>,
+    table: Lua,
+}
+
+/// A Lua table constructor, or any other expression as its source text.
+#[derive(Debug, Clone, PartialEq)]
+enum Lua {
+    Expression(String),
+    /// Fields in order, positional ones without a key. Keys are names, the
+    /// content of `["string"]` keys, or other `[expression]` keys as written.
+    Table(Vec<(Option<String>, Lua)>),
+}
+
+impl Default for Lua {
+    fn default() -> Self {
+        Lua::Table(vec![])
+    }
+}
+
+/// Length of the string literal or comment at the start of Lua source, or 0.
+fn lua_skip(s: &str) -> usize {
+    /// Length of a long bracket like `[==[ … ]==]` at the start, if any.
+    fn long(s: &str) -> Option<usize> {
+        let level = s.strip_prefix('[')?.find(|c| c != '=')?;
+        (s[1 + level..].starts_with('[')).then_some(())?;
+        let close = format!("]{}]", "=".repeat(level));
+        Some(s.find(&close).map_or(s.len(), |i| i + close.len()))
+    }
+
+    if let Some(rest) = s.strip_prefix("--") {
+        return 2 + long(rest).unwrap_or_else(|| rest.find('\n').unwrap_or(rest.len()));
+    }
+    if let Some(quote) = s.chars().next().filter(|c| *c == '"' || *c == '\'') {
+        let mut escaped = false;
+        return s[1..].char_indices()
+            .find(|&(_, c)| {
+                let closing = c == quote && !escaped;
+                escaped = c == '\\' && !escaped;
+                closing
+            })
+            .map_or(s.len(), |(i, _)| i + 2);
+    }
+    long(s).unwrap_or(0)
+}
+
+impl LuaConfig {
+    fn parse(text: &str) -> Result<Self> {
+        use anyhow::{bail, Context};
+
+        /// Skips whitespace and comments.
+        fn blank(rest: &mut &str) {
+            loop {
+                *rest = rest.trim_start();
+                match rest.starts_with("--") {
+                    true => *rest = &rest[lua_skip(rest)..],
+                    false => return,
+                }
+            }
+        }
+
+        /// Source text up to a `,` or `;` or closing bracket out of any
+        /// nesting, without comments.
+        fn expression(rest: &mut &str) -> Result<String> {
+            let mut depth = 0;
+            let mut text = String::new();
+            loop {
+                let skip = lua_skip(rest);
+                if rest.starts_with("--") {
+                    *rest = &rest[skip..];
+                    continue;
+                }
+                if skip > 0 {
+                    text.push_str(&rest[..skip]);
+                    *rest = &rest[skip..];
+                    continue;
+                }
+                let Some(c) = rest.chars().next() else { break };
+                match c {
+                    '(' | '{' | '[' => depth += 1,
+                    ')' | '}' | ']' if depth == 0 => break,
+                    ')' | '}' | ']' => depth -= 1,
+                    ',' | ';' if depth == 0 => break,
+                    _ => {}
+                }
+                text.push(c);
+                *rest = &rest[c.len_utf8()..];
+            }
+            let text = text.trim().to_string();
+            if text.is_empty() {
+                bail!("Expect a value at `{}`", rest.lines().next().unwrap_or_default());
+            }
+            Ok(text)
+        }
+
+        fn value(rest: &mut &str) -> Result<Lua> {
+            blank(rest);
+            let Some(after) = rest.strip_prefix('{') else {
+                return Ok(Lua::Expression(expression(rest)?));
+            };
+            *rest = after;
+            let mut fields = vec![];
+            loop {
+                blank(rest);
+                if let Some(after) = rest.strip_prefix('}') {
+                    *rest = after;
+                    return Ok(Lua::Table(fields));
+                }
+                if rest.is_empty() {
+                    bail!("Expect `}}` before the end");
+                }
+                fields.push(field(rest)?);
+                blank(rest);
+                *rest = rest.strip_prefix([',', ';']).unwrap_or(rest);
+            }
+        }
+
+        fn field(rest: &mut &str) -> Result<(Option<String>, Lua)> {
+            let name = rest.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(rest.len());
+            let after = rest[name..].trim_start();
+            if name > 0 && after.starts_with('=') && !after.starts_with("==") {
+                let key = rest[..name].to_string();
+                *rest = &after[1..];
+                return Ok((Some(key), value(rest)?));
+            }
+            if rest.starts_with('[') && lua_skip(rest) == 0 {
+                *rest = &rest[1..];
+                let key = expression(rest)?;
+                *rest = rest.strip_prefix(']').context("Expect `]` after a key")?;
+                blank(rest);
+                *rest = rest.strip_prefix('=').context("Expect `=` after a key")?;
+                let key = match serde_json::from_str::<String>(&key) {
+                    Ok(name) if key.starts_with('"') => name,
+                    _ => format!("[{key}]"),
+                };
+                return Ok((Some(key), value(rest)?));
+            }
+            Ok((None, value(rest)?))
+        }
+
+        // Find the `return` of the chunk, outside strings and comments.
+        let mut at = 0;
+        let start = loop {
+            let rest = &text[at..];
+            let Some(c) = rest.chars().next() else {
+                bail!("Expect a `return` of a table");
+            };
+            let word_start = at == 0 || !text[..at].ends_with(|c: char| c.is_alphanumeric() || c == '_');
+            if word_start && rest.starts_with("return")
+                && !rest[6..].starts_with(|c: char| c.is_alphanumeric() || c == '_')
+            {
+                break at;
+            }
+            at += lua_skip(rest).max(c.len_utf8());
+        };
+
+        let preamble = text[..start].lines()
+            .map(str::trim_end)
+            .filter(|line| !line.trim().is_empty())
+            .map(String::from)
+            .collect();
+        let mut rest = &text[start + 6..];
+        let table = value(&mut rest).context("Parse the returned value")?;
+        blank(&mut rest);
+        if !rest.is_empty() {
+            bail!("Expect nothing after the returned value, found `{}`",
+                rest.lines().next().unwrap_or_default());
+        }
+        if !matches!(table, Lua::Table(_)) {
+            bail!("Expect a table to be returned");
+        }
+        Ok(LuaConfig { preamble, table })
+    }
+}
+
+impl Lua {
+    /// Named fields merge key by key. Positional fields of the later table,
+    /// if any, replace those of the earlier one, so lists are replaced.
+    fn merge(self, other: Self) -> Self {
+        let (mut a, b) = match (self, other) {
+            (Lua::Table(a), Lua::Table(b)) => (a, b),
+            (_, other) => return other,
+        };
+        if b.iter().any(|(key, _)| key.is_none()) {
+            a.retain(|(key, _)| key.is_some());
+        }
+        for (key, value) in b {
+            let earlier = key.as_ref()
+                .and_then(|k| a.iter().position(|(key, _)| key.as_ref() == Some(k)));
+            match earlier {
+                Some(i) => {
+                    let earlier = std::mem::take(&mut a[i].1);
+                    a[i].1 = earlier.merge(value);
+                }
+                None => a.push((key, value)),
+            }
+        }
+        Lua::Table(a)
+    }
+
+    /// Source text, with a field per line, or lists of expressions inline
+    /// when short.
+    fn write(&self, indent: usize) -> String {
+        const KEYWORDS: [&str; 22] = [
+            "and", "break", "do", "else", "elseif", "end", "false", "for", "function",
+            "goto", "if", "in", "local", "nil", "not", "or", "repeat", "return", "then",
+            "true", "until", "while",
+        ];
+
+        let fields = match self {
+            Lua::Expression(text) => return text.clone(),
+            Lua::Table(fields) if fields.is_empty() => return "{}".into(),
+            Lua::Table(fields) => fields,
+        };
+        if fields.iter().all(|(key, value)| key.is_none() && matches!(value, Lua::Expression(_))) {
+            let inline = format!("{{ {} }}", fields.iter()
+                .map(|(_, value)| value.write(indent))
+                .collect::<Vec<_>>()
+                .join(", "));
+            if indent * 2 + inline.len() <= 80 {
+                return inline;
+            }
+        }
+
+        let pad = "  ".repeat(indent + 1);
+        let lines: String = fields.iter()
+            .map(|(key, value)| {
+                let key = match key {
+                    None => String::new(),
+                    Some(key) if key.starts_with('[') => format!("{key} = "),
+                    Some(key) if !key.starts_with(|c: char| c.is_ascii_digit())
+                        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                        && !KEYWORDS.contains(&key.as_str()) => format!("{key} = "),
+                    Some(key) => format!("[{}] = ", serde_json::Value::from(key.as_str())),
+                };
+                format!("{pad}{key}{},\n", value.write(indent + 1))
+            })
+            .collect();
+        format!("{{\n{lines}{}}}", "  ".repeat(indent))
+    }
+
+    /// The value as a JSON tree. Tables of positional fields only are arrays,
+    /// and other positional fields are keyed by their index from 1.
+    fn tree(&self) -> serde_json::Value {
+        use serde_json::Value;
+        match self {
+            Lua::Expression(text) => match text.as_str() {
+                "nil" => Value::Null,
+                "true" => true.into(),
+                "false" => false.into(),
+                text if text.starts_with(['"', '\'']) && text.len() > 1
+                    && text.ends_with(&text[..1]) => text[1..text.len() - 1].into(),
+                text => text.parse::<i64>().map(Value::from)
+                    .or_else(|_| text.parse::<f64>().map(Value::from))
+                    .unwrap_or_else(|_| text.into()),
+            },
+            Lua::Table(fields) if fields.iter().all(|(key, _)| key.is_none())
+                && !fields.is_empty() => fields.iter().map(|(_, v)| v.tree()).collect(),
+            Lua::Table(fields) => {
+                let mut index = 0;
+                fields.iter()
+                    .map(|(key, value)| (key.clone().unwrap_or_else(|| {
+                        index += 1;
+                        index.to_string()
+                    }), value.tree()))
+                    .collect::<serde_json::Map<_, _>>()
+                    .into()
+            }
+        }
+    }
+
+    /// Index of the field at a key, positional fields counted from 0.
+    fn field(&self, key: &Key) -> Option<usize> {
+        let Lua::Table(fields) = self else { return None };
+        match key {
+            Key::Name(name) => fields.iter().position(|(k, _)| k.as_ref() == Some(name)),
+            Key::Index(i) => fields.iter().enumerate()
+                .filter(|(_, (k, _))| k.is_none())
+                .nth(*i)
+                .map(|(position, _)| position),
+        }
+    }
+}
+
+impl Node for Lua {
+    fn child(&self, key: &Key) -> Option<&Self> {
+        let (Lua::Table(fields), Some(i)) = (self, self.field(key)) else { return None };
+        Some(&fields[i].1)
+    }
+
+    fn child_mut(&mut self, key: &Key) -> Option<&mut Self> {
+        let i = self.field(key)?;
+        let Lua::Table(fields) = self else { return None };
+        Some(&mut fields[i].1)
+    }
+
+    fn set_child(&mut self, key: &Key, value: Self) {
+        match (self.field(key), &mut *self) {
+            (Some(i), Lua::Table(fields)) => fields[i].1 = value,
+            (_, Lua::Table(fields)) => fields.push((Some(key.to_string()), value)),
+            (_, this) => {
+                *this = Lua::Table(vec![]);
+                this.set_child(key, value);
+            }
+        }
+    }
+
+    fn remove_child(&mut self, key: &Key) -> Option<Self> {
+        let i = self.field(key)?;
+        let Lua::Table(fields) = self else { return None };
+        Some(fields.remove(i).1)
+    }
+}
+
+impl std::fmt::Display for LuaConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.preamble.is_empty() {
+            writeln!(f, "{}\n", self.preamble.join("\n"))?;
+        }
+        writeln!(f, "return {}", self.table.write(0))
+    }
+}
+
+/// A fontconfig file: the text before `<fontconfig>`, like the XML
+/// declaration and DOCTYPE, and its top-level elements as written, keyed by
+/// what they select. Rules of a later fragment with the same selector
+/// replace the earlier ones, and others are appended.
+#[derive(Clone, Default)]
+struct Fontconfig {
+    head: String,
+    rules: Vec<(String, String)>,
+}
+
+impl Fontconfig {
+    fn parse(text: &str) -> Result<Self> {
+        use anyhow::{bail, Context};
+
+        let options = roxmltree::ParsingOptions { allow_dtd: true, ..Default::default() };
+        let document = roxmltree::Document::parse_with_options(text, options)
+            .context("Parse fontconfig XML")?;
+        let root = document.root_element();
+        if root.tag_name().name() != "fontconfig" {
+            bail!("Expect a `<fontconfig>` root, found `<{}>`", root.tag_name().name());
+        }
+
+        let collapse = |node: roxmltree::Node| text[node.range()]
+            .split_whitespace().collect::<Vec<_>>().join(" ");
+        let rules = root.children()
+            .filter(|node| node.is_element() || node.is_comment())
+            .map(|node| {
+                let children = |name| node.children()
+                    .filter(move |child| child.has_tag_name(name));
+                let key = match node.tag_name().name() {
+                    "match" => format!("match {} {}",
+                        node.attribute("target").unwrap_or("pattern"),
+                        children("test").map(collapse).collect::<Vec<_>>().join(" ")),
+                    "alias" => format!("alias {}", children("family").next()
+                        .and_then(|family| family.text()).unwrap_or_default().trim()),
+                    _ => collapse(node),
+                };
+                (key, text[node.range()].to_string())
+            })
+            .collect();
+        Ok(Fontconfig { head: text[..root.range().start].to_string(), rules })
+    }
+
+    fn position(&self, key: &str) -> Option<usize> {
+        self.rules.iter().position(|(k, _)| k == key)
+    }
+}
+
+impl std::fmt::Display for Fontconfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}<fontconfig>", self.head)?;
+        self.rules.iter().try_for_each(|(_, rule)| writeln!(f, "  {rule}"))?;
+        writeln!(f, "</fontconfig>")
+    }
+}
+
+/// A Windows `.reg` file: its header line, and its keys with their values
+/// in order. `[-key]` deletes a key with its subkeys, and `"name"=-` a value.
+/// Names of keys and values are matched ignoring case, like the registry
+/// does, and comments are dropped.
+#[derive(Clone, Default)]
+struct Registry {
+    head: String,
+    keys: Vec<RegistryKey>,
+}
+
+#[derive(Clone)]
+struct RegistryKey {
+    path: String,
+    delete: bool,
+    /// Values by their quoted name, or `@` for the default value, with their
+    /// data as written after the `=`.
+    values: Vec<(String, String)>,
+}
+
+impl Registry {
+    fn parse(text: &str) -> Result<Self> {
+        use anyhow::{bail, Context};
+
+        let mut lines = text.trim_start_matches('\u{feff}').lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with(';'));
+        let head = lines.next().unwrap_or_default().to_string();
+        if head != "Windows Registry Editor Version 5.00" && head != "REGEDIT4" {
+            bail!("Expect a `Windows Registry Editor Version 5.00` header, found `{head}`");
+        }
+
+        let mut keys: Vec<RegistryKey> = vec![];
+        let mut pending = String::new();
+        for line in lines {
+            // Data, like long `hex:` lists, goes on after a trailing `\`.
+            pending.push_str(line);
+            if let Some(rest) = pending.strip_suffix('\\') {
+                pending = rest.to_string();
+                continue;
+            }
+            let line = std::mem::take(&mut pending);
+            if let Some(path) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                let (path, delete) = path.strip_prefix('-').map_or((path, false), |p| (p, true));
+                keys.push(RegistryKey { path: path.into(), delete, values: vec![] });
+                continue;
+            }
+            let key = keys.last_mut().context(format!("Value `{line}` before any key"))?;
+            let split = match line.starts_with('@') {
+                true => Some(1),
+                // The closing quote of the name, past escaped ones.
+                false => line.char_indices().skip(1)
+                    .scan(false, |escaped, (i, c)| {
+                        let end = c == '"' && !*escaped;
+                        *escaped = c == '\\' && !*escaped;
+                        Some((i, end))
+                    })
+                    .find(|(_, end)| *end)
+                    .map(|(i, _)| i + 1),
+            };
+            match split.map(|i| line.split_at(i)) {
+                Some((name, data)) if data.starts_with('=') =>
+                    key.values.push((name.into(), data[1..].into())),
+                _ => bail!("Expect `\"name\"=data` in key `{}`, found `{line}`", key.path),
+            }
+        }
+        Ok(Registry { head, keys })
+    }
+
+    /// The last key with a path, since a deleted key may be created again.
+    fn position(&self, path: &str) -> Option<usize> {
+        self.keys.iter().rposition(|key| key.path.eq_ignore_ascii_case(path))
+    }
+}
+
+impl std::fmt::Display for Registry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.head)?;
+        self.keys.iter().try_for_each(|key| {
+            writeln!(f, "\n[{}{}]", if key.delete { "-" } else { "" }, key.path)?;
+            key.values.iter().try_for_each(|(name, data)| writeln!(f, "{name}={data}"))
+        })
+    }
+}
+
+/// A Homebrew `Brewfile`: `tap`, `brew`, `cask`, `mas` and other entries,
+/// keyed by their kind and quoted name, with the arguments after the name as
+/// written. Lines with no name, like `cask_args`, are keyed by their kind.
+/// Comments are dropped, and conditionals are not supported.
+#[derive(Clone, Default)]
+struct Brewfile {
+    entries: Vec<BrewEntry>,
+}
+
+#[derive(Clone)]
+struct BrewEntry {
+    kind: String,
+    /// Empty for lines with no name.
+    name: String,
+    /// What follows the name and its comma, like `args: ["HEAD"]`.
+    args: String,
+}
+
+impl Brewfile {
+    fn parse(text: &str) -> Result<Self> {
+        use anyhow::{bail, Context};
+
+        let mut brewfile = Brewfile::default();
+        let lines = text.lines().map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+        for line in lines {
+            let (kind, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+            if matches!(kind, "if" | "unless" | "else" | "elsif" | "end") || rest.ends_with(" do")
+                || !kind.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            {
+                bail!("Expect `kind \"name\", args` entries, found `{line}`");
+            }
+            let (name, args) = match rest.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let end = rest[1..].find(quote)
+                        .context(format!("Unclosed name in `{line}`"))? + 1;
+                    let args = rest[end + 1..].trim_start();
+                    let args = match args.strip_prefix(',') {
+                        Some(args) => args.trim(),
+                        None if args.is_empty() || args.starts_with('#') => "",
+                        None => bail!("Expect a `,` after the name in `{line}`"),
+                    };
+                    (&rest[1..end], args)
+                }
+                _ => ("", rest),
+            };
+            let entry = BrewEntry { kind: kind.into(), name: name.into(), args: args.into() };
+            if let Some(earlier) = brewfile.position(kind, name).map(|i| &brewfile.entries[i])
+                && !earlier.args.is_empty() && !args.is_empty() && earlier.args != args
+            {
+                bail!("`{earlier}` and `{entry}` conflict");
+            }
+            brewfile.insert(entry);
+        }
+        Ok(brewfile)
+    }
+
+    fn position(&self, kind: &str, name: &str) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.kind == kind && entry.name == name)
+    }
+
+    /// Adds an entry, or gives the arguments to the one already there unless
+    /// it has none.
+    fn insert(&mut self, entry: BrewEntry) {
+        match self.position(&entry.kind, &entry.name) {
+            Some(_) if entry.args.is_empty() => {}
+            Some(i) => self.entries[i].args = entry.args,
+            None => self.entries.push(entry),
+        }
+    }
+
+    /// The kind, and name if any, a key path of the tree stands for.
+    fn key(path: &KeyPath) -> (String, Option<String>) {
+        let mut keys = path.0.iter().map(Key::to_string);
+        (keys.next().unwrap_or_default(), keys.next())
+    }
+}
+
+impl std::fmt::Display for BrewEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.name.is_empty(), self.args.is_empty()) {
+            (true, true) => write!(f, "{}", self.kind),
+            (true, false) => write!(f, "{} {}", self.kind, self.args),
+            (false, true) => write!(f, "{} \"{}\"", self.kind, self.name),
+            (false, false) => write!(f, "{} \"{}\", {}", self.kind, self.name, self.args),
+        }
+    }
+}
+
+/// Taps come first, as later entries may need them.
+impl std::fmt::Display for Brewfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (taps, rest): (Vec<_>, Vec<_>) = self.entries.iter()
+            .partition(|entry| entry.kind == "tap");
+        taps.into_iter().chain(rest).try_for_each(|entry| writeln!(f, "{entry}"))
+    }
+}
+
+/// A pip requirements or constraints file: option lines, like `-r` or
+/// `--index-url`, kept once each, then requirements by normalized package
+/// name and environment marker. Specifiers of the same requirement combine,
+/// and two different pins of it are an error. Comments are dropped.
+#[derive(Clone, Default)]
+struct Requirements {
+    options: Vec<String>,
+    requirements: Vec<Requirement>,
+}
+
+#[derive(Clone)]
+struct Requirement {
+    /// As first written.
+    name: String,
+    extras: Vec<String>,
+    /// Clauses, like `>=2` and `<3`, or a `@ url` reference.
+    specifiers: Vec<String>,
+    marker: Option<String>,
+    /// Options of the line, like `--hash`, which the last line giving any
+    /// decides.
+    options: String,
+}
+
+impl Requirements {
+    fn parse(text: &str) -> Result<Self> {
+        use anyhow::{bail, Context};
+
+        let mut requirements = Requirements::default();
+        let mut pending = String::new();
+        for line in text.lines() {
+            // Lines go on after a trailing `\`, like long `--hash` lists.
+            pending.push_str(line.trim());
+            if let Some(rest) = pending.strip_suffix('\\') {
+                pending = format!("{} ", rest.trim_end());
+                continue;
+            }
+            let line = std::mem::take(&mut pending);
+            // Comments start a line or follow a space.
+            let line = match line.find(" #") {
+                Some(i) => &line[..i],
+                None if line.starts_with('#') => "",
+                None => &line,
+            }.trim();
+            if line.is_empty() {
+                continue;
+            }
+            // Options, editables, and paths or URLs with no name.
+            if line.starts_with(['-', '.', '/']) || line.contains("://") && !line.contains(" @ ") {
+                if !requirements.options.iter().any(|option| option == line) {
+                    requirements.options.push(line.into());
+                }
+                continue;
+            }
+
+            let (line, options) = line.split_once(" --").map_or((line, ""), |(l, o)| (l, o));
+            let (line, marker) = line.split_once(';')
+                .map_or((line, None), |(l, m)| (l, Some(m.trim().to_string())));
+            let end = line.find(|c: char| !(c.is_ascii_alphanumeric() || "-_.".contains(c)))
+                .unwrap_or(line.len());
+            let (name, rest) = line.split_at(end);
+            if name.is_empty() {
+                bail!("Expect a package name in `{line}`");
+            }
+            let rest = rest.trim();
+            let (extras, rest) = match rest.strip_prefix('[') {
+                Some(rest) => {
+                    let (extras, rest) = rest.split_once(']')
+                        .context(format!("Unclosed extras in `{line}`"))?;
+                    (extras.split(',').map(|e| e.trim().to_string()).collect(), rest.trim())
+                }
+                None => (vec![], rest),
+            };
+            let specifiers = match rest.strip_prefix('@') {
+                Some(url) => vec![format!("@ {}", url.trim())],
+                None => rest.split(',')
+                    .map(|clause| clause.split_whitespace().collect::<String>())
+                    .filter(|clause| !clause.is_empty())
+                    .collect(),
+            };
+            let options = match options {
+                "" => String::new(),
+                options => format!("--{options}"),
+            };
+            let name = name.into();
+            requirements.add(Requirement { name, extras, specifiers, marker, options })?;
+        }
+        Ok(requirements)
+    }
+
+    fn position(&self, key: &str) -> Option<usize> {
+        self.requirements.iter().position(|requirement| requirement.key() == key)
+    }
+
+    /// Adds a requirement, or combines it with the one already there.
+    fn add(&mut self, requirement: Requirement) -> Result<()> {
+        let Some(i) = self.position(&requirement.key()) else {
+            self.requirements.push(requirement);
+            return Ok(());
+        };
+        let earlier = &mut self.requirements[i];
+        for extra in requirement.extras {
+            if !earlier.extras.contains(&extra) {
+                earlier.extras.push(extra);
+            }
+        }
+        for clause in requirement.specifiers {
+            if !earlier.specifiers.contains(&clause) {
+                earlier.specifiers.push(clause);
+            }
+        }
+        if !requirement.options.is_empty() {
+            earlier.options = requirement.options;
+        }
+        let pins: Vec<_> = earlier.specifiers.iter()
+            .filter(|clause| clause.starts_with("==") || clause.starts_with('@'))
+            .collect();
+        let reference = earlier.specifiers.iter().any(|clause| clause.starts_with('@'));
+        if pins.len() > 1 || reference && earlier.specifiers.len() > 1 {
+            anyhow::bail!(Failure::Conflict(format!("`{}` is required as `{}`",
+                earlier.name, earlier.specifiers.join("` and `"))));
+        }
+        Ok(())
+    }
+
+    fn combine(mut self, other: Self) -> Result<Self> {
+        for option in other.options {
+            if !self.options.contains(&option) {
+                self.options.push(option);
+            }
+        }
+        other.requirements.into_iter().try_for_each(|requirement| self.add(requirement))?;
+        Ok(self)
+    }
+}
+
+impl Requirement {
+    /// The name normalized as package indexes do, with the marker if any.
+    fn key(&self) -> String {
+        static SEPARATORS: LazyLock<regex::Regex> = LazyLock::new(|| {
+            regex::Regex::new("[-_.]+").expect("Valid regex")
+        });
+        let name = SEPARATORS.replace_all(&self.name.to_lowercase(), "-").into_owned();
+        match &self.marker {
+            Some(marker) => format!("{name}; {marker}"),
+            None => name,
+        }
+    }
+}
+
+impl std::fmt::Display for Requirement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        if !self.extras.is_empty() {
+            write!(f, "[{}]", self.extras.join(","))?;
+        }
+        let reference = self.specifiers.iter().any(|clause| clause.starts_with('@'));
+        match reference {
+            true => write!(f, " {}", self.specifiers.join(","))?,
+            false => write!(f, "{}", self.specifiers.join(","))?,
+        }
+        if let Some(marker) = &self.marker {
+            // A space keeps the `;` out of a URL.
+            write!(f, "{}; {marker}", if reference { " " } else { "" })?;
+        }
+        if !self.options.is_empty() {
+            write!(f, " {}", self.options)?;
+        }
+        Ok(())
+    }
+}
+
+/// Options come first, as pip reads indexes before resolving requirements.
+impl std::fmt::Display for Requirements {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.options.iter().try_for_each(|option| writeln!(f, "{option}"))?;
+        self.requirements.iter().try_for_each(|requirement| writeln!(f, "{requirement}"))
+    }
+}
+
+/// Comments of a YAML document: those on the lines before a key or sequence
+/// item and the one ending its line, by the path to it, then those after
+/// the last one.
+#[derive(Clone, Default)]
+struct YamlComments {
+    nodes: Vec<(Vec<String>, Vec<String>, Option<String>)>,
+    trailing: Vec<String>,
+}
+
+impl YamlComments {
+    fn parse(text: &str) -> Self {
+        let (nodes, trailing) = yaml_nodes(text);
+        let nodes = nodes.into_iter()
+            .filter(|(_, _, before, inline)| !before.is_empty() || inline.is_some())
+            .map(|(_, keys, before, inline)| (keys, before, inline))
+            .collect();
+        YamlComments { nodes, trailing }
+    }
+
+    /// Comments of a later fragment replace those of the same node. Those
+    /// within a sequence the later tree replaces are dropped with it.
+    fn merge(mut self, later: Self, tree: &serde_json::Value) -> Self {
+        let replaced = |keys: &[String]| (0..keys.len()).any(|n| keys[..n].iter()
+            .try_fold(tree, |value, key| match value {
+                serde_json::Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+                value => value.get(key),
+            })
+            .is_some_and(serde_json::Value::is_array));
+        self.nodes.retain(|(keys, ..)| !replaced(keys) && later.nodes.iter().all(|(k, ..)| k != keys));
+        self.nodes.extend(later.nodes);
+        if !later.trailing.is_empty() {
+            self.trailing = later.trailing;
+        }
+        self
+    }
+}
+
+/// Walks the keys of YAML block mappings and the items of block sequences,
+/// giving each line holding one with the path to it, where items are keyed
+/// by index, the comment lines right before it and the comment ending it.
+/// Comment lines after the last one come separately.
+#[allow(clippy::type_complexity)]
+fn yaml_nodes(text: &str) -> (Vec<(usize, Vec<String>, Vec<String>, Option<String>)>, Vec<String>) {
+    // Indents of keys and items on the way, and whether each is an item.
+    let mut stack: Vec<(usize, String, bool)> = vec![];
+    let mut comments = vec![];
+    let mut nodes = vec![];
+
+    for (n, line) in text.lines().enumerate() {
+        let content = line.trim_start();
+        let mut indent = line.len() - content.len();
+        if content.starts_with('#') {
+            comments.push(content.to_string());
+            continue;
+        }
+        if content.is_empty() || content.starts_with("---") {
+            continue;
+        }
+        let before = std::mem::take(&mut comments);
+        let (mut content, inline) = split_yaml_comment(content);
+
+        let mut found = false;
+        if let Some(rest) = content.strip_prefix("- ").or((content == "-").then_some("")) {
+            while stack.last().is_some_and(|(i, _, _)| *i > indent) {
+                stack.pop();
+            }
+            // An item at the indent of the last one follows it.
+            let index = match stack.last() {
+                Some((i, index, true)) if *i == indent => {
+                    let next = index.parse::<usize>().unwrap_or_default() + 1;
+                    stack.pop();
+                    next
+                }
+                _ => 0,
+            };
+            stack.push((indent, index.to_string(), true));
+            found = true;
+            let rest = rest.trim_start();
+            indent += content.len() - rest.len();
+            content = rest;
+        }
+        let key = content.split_once(": ").map_or(content.strip_suffix(':'), |(k, _)| Some(k));
+        if let Some(key) = key {
+            while stack.last().is_some_and(|(i, _, _)| *i >= indent) {
+                stack.pop();
+            }
+            stack.push((indent, key.trim_matches(['"', '\'']).to_string(), false));
+            found = true;
+        }
+        if found {
+            nodes.push((n, stack.iter().map(|(_, k, _)| k.clone()).collect(), before, inline));
+        }
+    }
+    (nodes, comments)
+}
+
+/// Cuts the comment off the end of a YAML line, where `#` follows a space
+/// outside quotes.
+fn split_yaml_comment(content: &str) -> (&str, Option<String>) {
+    let mut quote = None;
+    let mut previous = ' ';
+    let mut chars = content.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (Some('"'), '\\') => { chars.next(); }
+            (Some(q), c) if c == q => quote = None,
+            (None, '"' | '\'') if previous.is_whitespace() || "[{,".contains(previous) =>
+                quote = Some(c),
+            (None, '#') if previous.is_whitespace() =>
+                return (content[..i].trim_end(), Some(content[i..].to_string())),
+            _ => {}
+        }
+        previous = c;
+    }
+    (content, None)
+}
+
+/// Puts comments back around their keys and items in serialized YAML.
+fn with_yaml_comments(text: &str, comments: &YamlComments) -> String {
+    let (nodes, _) = yaml_nodes(text);
+    let found: std::collections::HashMap<usize, _> = nodes.iter()
+        .filter_map(|(n, keys, _, _)| Some((*n, comments.nodes.iter().find(|(k, ..)| k == keys)?)))
+        .collect();
+
+    text.lines().enumerate()
+        .flat_map(|(n, line)| {
+            let indent = &line[..line.len() - line.trim_start().len()];
+            let node = found.get(&n);
+            let line = match node.and_then(|(_, _, inline)| inline.as_ref()) {
+                Some(comment) => format!("{line}  {comment}\n"),
+                None => format!("{line}\n"),
+            };
+            node.into_iter().flat_map(|(_, before, _)| before.iter())
+                .map(move |comment| format!("{indent}{comment}\n"))
+                .chain(once(line))
+        })
+        .chain(comments.trailing.iter().map(|comment| format!("{comment}\n")))
+        .collect()
+}
+
+/// Parses INI into a table of sections, after the keys before any section.
+/// Keys without a value are `true`, and `;` or `#` start comment lines.
+fn parse_ini(text: &str) -> Result<serde_json::Value> {
+    use anyhow::Context;
+    use serde_json::{Map, Value};
+
+    let mut root = Map::new();
+    let mut section: Option<String> = None;
+    for (n, line) in text.lines().enumerate().map(|(n, line)| (n + 1, line.trim())) {
+        if line.is_empty() || line.starts_with([';', '#']) {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[') {
+            let name = name.strip_suffix(']')
+                .context(format!("Unclosed section header at line {n}"))?;
+            root.entry(name).or_insert_with(|| Value::Object(Map::new()));
+            section = Some(name.into());
+            continue;
+        }
+
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), Value::from(value.trim())),
+            None => (line, Value::Bool(true)),
+        };
+        let table = match &section {
+            Some(name) => root.get_mut(name).and_then(Value::as_object_mut)
+                .context(format!("Section `{name}` is also a key"))?,
+            None => &mut root,
+        };
+        table.insert(key.into(), value);
+    }
+    Ok(Value::Object(root))
+}
+
+/// Merges trees of `;` lists, appending items of the later list that the
+/// earlier one lacks.
+fn merge_lists(a: serde_json::Value, b: serde_json::Value) -> serde_json::Value {
+    use serde_json::Value;
+    match (a, b) {
+        (Value::Object(mut a), Value::Object(b)) => {
+            for (key, value) in b {
+                match a.get_mut(&key) {
+                    Some(earlier) => *earlier = merge_lists(earlier.take(), value),
+                    None => { a.insert(key, value); }
+                }
+            }
+            Value::Object(a)
+        }
+        (Value::String(a), Value::String(b)) => {
+            let mut items: Vec<&str> = a.split(';').filter(|s| !s.is_empty()).collect();
+            for item in b.split(';').filter(|s| !s.is_empty()) {
+                if !items.contains(&item) {
+                    items.push(item);
+                }
+            }
+            Value::String(items.join(";") + ";")
+        }
+        (_, b) => b,
+    }
+}
+
+/// Merges kubeconfigs like `KUBECONFIG` does: clusters, contexts and users
+/// are matched by name, and the first `current-context` set is kept, so the
+/// one `kubectl` switched to survives later fragments.
+fn merge_kubeconfig(a: serde_json::Value, b: serde_json::Value) -> serde_json::Value {
+    use serde_json::Value;
+    let (mut a, b) = match (a, b) {
+        (Value::Object(a), Value::Object(b)) => (a, b),
+        (a, b) => return a.merge(b),
+    };
+    for (key, value) in b {
+        match (key.as_str(), a.get_mut(&key), value) {
+            ("current-context", Some(Value::String(earlier)), _) if !earlier.is_empty() => {}
+            ("clusters" | "contexts" | "users", Some(Value::Array(earlier)), Value::Array(later)) =>
+                for item in later {
+                    match earlier.iter().position(|e| e.get("name") == item.get("name")) {
+                        Some(i) => earlier[i] = earlier[i].take().merge(item),
+                        None => earlier.push(item),
+                    }
+                },
+            (_, Some(earlier), value) => *earlier = earlier.take().merge(value),
+            (_, None, value) => { a.insert(key, value); }
+        }
+    }
+    Value::Object(a)
+}
+
+fn ini_string(value: &serde_json::Value, style: IniStyle) -> String {
+    use serde_json::Value;
+
+    const LISTS: [&str; 7] =
+        ["Actions", "Categories", "Implements", "Keywords", "MimeType", "NotShowIn", "OnlyShowIn"];
+
+    let line = |key: &str, value: &Value| {
+        let value = match value {
+            Value::Bool(true) => return format!("{key}\n"),
+            Value::String(s) => s.clone(),
+            value => value.to_string(),
+        };
+        match style {
+            IniStyle::Plain | IniStyle::Aws => format!("{key} = {value}\n"),
+            IniStyle::Mimeapps if !value.is_empty() && !value.ends_with(';') =>
+                format!("{key}={value};\n"),
+            IniStyle::Desktop if LISTS.contains(&key.split('[').next().unwrap_or(key))
+                && !value.is_empty() && !value.ends_with(';') => format!("{key}={value};\n"),
+            IniStyle::Desktop | IniStyle::Mimeapps => format!("{key}={value}\n"),
+        }
+    };
+
+    let Some(map) = value.as_object() else {
+        return line("value", value);
+    };
+    let (sections, keys): (Vec<_>, Vec<_>) = map.iter().partition(|(_, v)| v.is_object());
+    let mut blocks: Vec<String> = vec![];
+    if !keys.is_empty() {
+        blocks.push(keys.iter().map(|(k, v)| line(k, v)).collect());
+    }
+    blocks.extend(sections.iter().map(|(name, table)| {
+        let lines: String = table.as_object().into_iter().flatten()
+            .map(|(k, v)| line(k, v))
+            .collect();
+        format!("[{name}]\n{lines}")
+    }));
+    blocks.join("\n")
+}
+
+/// A format implemented by a WebAssembly module. It exports `memory`,
+/// `alloc(len) -> ptr` for inputs, and functions taking `(ptr, len)` pairs
+/// and returning `ptr << 32 | len` of their output:
+///
+/// - `parse(text)` gives a JSON tree,
+/// - `merge(tree, tree)` gives the JSON tree of the second merged into the
+///   first,
+/// - `serialize(tree)` gives text.
+struct Plugin {
+    store: wasmi::Store<()>,
+    instance: wasmi::Instance,
+}
+
+type Plugins = std::sync::Mutex<std::collections::HashMap<String, Plugin>>;
+static PLUGINS: LazyLock<Plugins> = LazyLock::new(Default::default);
+
+impl Plugin {
+    fn register(format: &str, path: &Path) -> Result<()> {
+        use anyhow::Context;
+
+        let bytes = std::fs::read(path).context(format!("Read plugin {path:?}"))?;
+        let engine = wasmi::Engine::default();
+        let module = wasmi::Module::new(&engine, &bytes)
+            .context(format!("Load plugin {path:?}"))?;
+        let mut store = wasmi::Store::new(&engine, ());
+        let instance = wasmi::Linker::new(&engine)
+            .instantiate(&mut store, &module)?
+            .start(&mut store)?;
+
+        log::debug!("Loaded plugin {path:?} for `{format}`");
+        PLUGINS.lock().expect("Plugins do not panic")
+            .insert(format.into(), Plugin { store, instance });
+        Ok(())
+    }
+
+    fn exists(format: &str) -> bool {
+        PLUGINS.lock().expect("Plugins do not panic").contains_key(format)
+    }
+
+    /// Calls a function of the plugin of a format with byte strings.
+    fn call(format: &str, name: &str, inputs: &[&[u8]]) -> Result<Vec<u8>> {
+        use anyhow::Context;
+        use wasmi::Val;
+
+        let mut plugins = PLUGINS.lock().expect("Plugins do not panic");
+        let Plugin { store, instance } = plugins.get_mut(format)
+            .context(format!("No plugin for `{format}`"))?;
+        let context = || format!("Call `{name}` of plugin `{format}`");
+
+        let memory = instance.get_memory(&*store, "memory").context("No `memory` export")?;
+        let alloc = instance.get_typed_func::<i32, i32>(&*store, "alloc")?;
+        let mut params = vec![];
+        for input in inputs {
+            let ptr = alloc.call(&mut *store, input.len() as i32).with_context(context)?;
+            memory.write(&mut *store, ptr as usize, input).with_context(context)?;
+            params.extend([Val::I32(ptr), Val::I32(input.len() as i32)]);
+        }
+
+        let mut results = [Val::I64(0)];
+        instance.get_func(&*store, name)
+            .context(format!("No `{name}` export"))?
+            .call(&mut *store, &params, &mut results)
+            .with_context(context)?;
+        let packed = results[0].i64().context("Expect an `i64` result")? as u64;
+        let mut output = vec![0; (packed & 0xffff_ffff) as usize];
+        memory.read(&*store, (packed >> 32) as usize, &mut output).with_context(context)?;
+        Ok(output)
+    }
+}
+
+impl TryFrom<Config> for String {
+    type Error = anyhow::Error;
+
+    fn try_from(config: Config) -> Result<Self> {
+        use Config::*;
+        use serde_json::to_string_pretty;
+        Ok(match config {
+            Json(json) => to_string_pretty(&json)
+                .expect("Serialize a serde_json::Value should not fail"),
+            Toml(toml) => toml.to_string(),
+            Yaml(yaml, comments) => {
+                let text = serde_yaml::to_string(&yaml)
+                    .expect("Serialize a serde_json::Value should not fail");
+                with_yaml_comments(&text, &comments)
+            }
+            Ini(ini, style) => ini_string(&ini, style),
+            Kubeconfig(value) => serde_yaml::to_string(&value)
+                .expect("Serialize a serde_json::Value should not fail"),
+            Lines(lines) => lines.to_string(),
+            Rows(rows) => rows.to_string(),
+            Edn(edn, _) => edn.to_string(),
+            Lua(lua) => lua.to_string(),
+            Fontconfig(rules) => rules.to_string(),
+            Registry(registry) => registry.to_string(),
+            Brewfile(brewfile) => brewfile.to_string(),
+            Requirements(requirements) => requirements.to_string(),
+            FrontMatter(yaml, body) => match yaml.tree() {
+                Some(serde_json::Value::Object(map)) if map.is_empty() => body,
+                _ => format!("---\n{}---\n{body}", String::try_from(*yaml)?),
+            },
+            Plugin { format, value } => String::from_utf8(
+                crate::Plugin::call(&format, "serialize", &[value.to_string().as_bytes()])?
+            )?,
+            Text(text) => text,
+            None => String::new(),
+        })
+    }
+}
+
+trait Mergeable: Default + ToString {
+    fn merge(self, other: Self) -> Self;
+}
+
+impl Mergeable for serde_json::Value {
+    fn merge(mut self, other: Self) -> Self {
+        use json_patch::merge;
+        merge(&mut self, &other);
+        self
+    }
+}
+
+impl Mergeable for String {
+    fn merge(mut self, other: Self) -> Self {
+        self.push('\n');
+        self.push_str(&other);
+        self
+    }
+}
+
+/// The head of the first fontconfig file is kept.
+impl Mergeable for Fontconfig {
+    fn merge(mut self, other: Self) -> Self {
+        if self.head.trim().is_empty() {
+            self.head = other.head;
+        }
+        for (key, rule) in other.rules {
+            match self.position(&key) {
+                Some(i) => self.rules[i] = (key, rule),
+                None => self.rules.push((key, rule)),
+            }
+        }
+        self
+    }
+}
+
+/// Later arguments of an entry replace earlier ones, unless it has none.
+impl Mergeable for Brewfile {
+    fn merge(mut self, other: Self) -> Self {
+        other.entries.into_iter().for_each(|entry| self.insert(entry));
+        self
+    }
+}
+
+/// A later deleted key drops the earlier ones under it. Values of a later
+/// key replace the ones of the same name, unless the key was deleted in
+/// between, and the header of the first file is kept.
+impl Mergeable for Registry {
+    fn merge(mut self, other: Self) -> Self {
+        if self.head.is_empty() {
+            self.head = other.head;
+        }
+        for key in other.keys {
+            if key.delete {
+                let under = format!("{}\\", key.path.to_lowercase());
+                self.keys.retain(|k| !k.path.eq_ignore_ascii_case(&key.path)
+                    && !k.path.to_lowercase().starts_with(&under));
+                self.keys.push(key);
+                continue;
+            }
+            match self.position(&key.path).map(|i| &mut self.keys[i]) {
+                Some(earlier) if !earlier.delete => for (name, data) in key.values {
+                    match earlier.values.iter().position(|(n, _)| n.eq_ignore_ascii_case(&name)) {
+                        Some(i) => earlier.values[i] = (name, data),
+                        None => earlier.values.push((name, data)),
+                    }
+                },
+                _ => self.keys.push(key),
+            }
+        }
+        self
+    }
+}
+
+/// Lines before the `return` are kept once, and returned tables merge
+/// field by field.
+impl Mergeable for LuaConfig {
+    fn merge(mut self, other: Self) -> Self {
+        for line in other.preamble {
+            if !self.preamble.contains(&line) {
+                self.preamble.push(line);
+            }
+        }
+        self.table = self.table.merge(other.table);
+        self
+    }
+}
+
+/// Rows replace the earlier row with the same key, and new ones are
+/// appended.
+impl Mergeable for Rows {
+    fn merge(mut self, other: Self) -> Self {
+        for row in other.rows {
+            match self.key(&row).and_then(|key| self.position(key)) {
+                Some(i) => self.rows[i] = row,
+                None => self.rows.push(row),
+            }
+        }
+        self
+    }
+}
+
+/// Keyed lines replace the earlier line with the same key. Repeated
+/// comments, like the header of `fish_variables`, are kept once.
+impl Mergeable for KeyedLines {
+    fn merge(mut self, other: Self) -> Self {
+        for line in other.lines {
+            let key = self.dialect.key(&line).map(|(key, _)| key.to_string());
+            match key.and_then(|key| self.position(&key)) {
+                Some(i) => self.lines[i] = line,
+                None if line.starts_with(self.dialect.comment())
+                    && self.lines.contains(&line) => {}
+                None => self.lines.push(line),
+            }
+        }
+        self
+    }
+}
+
+impl Mergeable for TomlConfig {
+    fn merge(self, other: Self) -> Self {
+        fn merge_values(a: toml::Value, b: toml::Value) -> toml::Value {
+            match (a, b) {
+                (toml::Value::Table(mut left), toml::Value::Table(right)) => {
+                    for (k, v) in right {
+                        let merged = if let Some(existing) = left.remove(&k) {
+                            merge_values(existing, v)
+                        } else {
+                            v
+                        };
+                        left.insert(k, merged);
+                    }
+                    toml::Value::Table(left)
+                }
+                (_, b) => b,
+            }
+        }
+
+        TomlConfig {
+            value: merge_values(self.value, other.value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Test code are mostly AI-generated.
+    use super::*;
+    use std::io::Write;
+    use tempfile::{tempdir, tempdir_in, Builder, TempDir};
+
+    struct TestEnv {
+        _root: TempDir,
+        patch_dir: TempDir,
+        target_dir: TempDir,
+    }
+
+    impl TestEnv {
+        fn new() -> Self {
+            let root = tempdir().unwrap();
+            let patch_dir = tempdir_in(root.path()).unwrap();
+            let target_dir = tempdir_in(root.path()).unwrap();
+            
+            TestEnv {
+                _root: root,
+                patch_dir,
+                target_dir,
+            }
+        }
+
+        fn create_patch_dir(&self, name: &str) -> PathBuf {
+            let d = self.patch_dir.path().join(name);
+            std::fs::create_dir_all(&d).unwrap();
+            d
+        }
+
+        fn write_patch_file(&self, dir: &Path, prefix: &str, content: &[u8]) {
+            let temp_file = Builder::new().prefix(prefix).tempfile_in(dir).unwrap();
+            let (mut file, _) = temp_file.keep().unwrap();
+            file.write_all(content).unwrap();
+        }
+
+        fn write_named_patch_file(&self, dir: &Path, name: &str, content: &[u8]) {
+            let mut file = std::fs::File::create(dir.join(name)).unwrap();
+            file.write_all(content).unwrap();
+        }
+
+        fn cli(&self, args: &[&str]) -> Cli {
+            use clap::Parser;
+
+            let directory = self.patch_dir.path().to_str().unwrap();
+            let target = self.target_dir.path().to_str().unwrap();
+            // Keep run journals out of the real state directory.
+            let state = self._root.path().join("state");
+            let state = ["--state", state.to_str().unwrap()];
+            Cli::parse_from(
+                ["patch", "--directory", directory, "--target", target,
+                    "--log-level", "error"]
+                .iter()
+                .chain(state.iter().filter(|_| !args.contains(&"--state")))
+                .chain(args)
+            )
+        }
+
+        fn run_patch(&self) {
+            self.run(&[]).unwrap();
+        }
+
+        fn run(&self, args: &[&str]) -> Result<()> {
+            super::start(self.cli(args))
+        }
+
+        fn write_target_file(&self, name: &str, content: &[u8]) -> PathBuf {
+            let path = self.target_dir.path().join(name);
+            std::fs::write(&path, content).unwrap();
+            path
+        }
+
+        fn read_target_file(&self, name: &str) -> String {
+            std::fs::read_to_string(self.target_dir.path().join(name)).unwrap()
+        }
+
+        fn read_target_json(&self, name: &str) -> serde_json::Value {
+            serde_json::from_str(&self.read_target_file(name)).unwrap()
+        }
+
+        fn read_target_toml(&self, name: &str) -> toml::Value {
+            toml::from_str(&self.read_target_file(name)).unwrap()
+        }
+    }
+
+    #[test]
+    fn argparse_test() {
+        use std::env;
+        use clap::Parser;
+
+        let root = tempdir().unwrap();
+        let home = root.path().join("home");
+        std::fs::create_dir_all(&home).unwrap();
+        unsafe {
+            env::set_var("HOME", &home);
+        }
+
+        let cli = Cli::parse_from(["patch"]);
+        assert_eq!(cli.directory, vec![PathBuf::from("patches")]);
+        assert_eq!(cli.target, home);
+        assert_eq!(cli.log_level, log::Level::Info);
+    }
+
+    #[test]
+    fn plain_text_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-foo.d");
+
+        env.write_patch_file(&d, "000", b"hello");
+        env.write_patch_file(&d, "001", b"world");
+        env.run_patch();
+
+        let result = env.read_target_file(".foo");
+        assert!(result.contains("hello"));
+        assert!(result.contains("world"));
+    }
+
+    #[test]
+    fn json_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-bar.json.d");
+
+        env.write_patch_file(&d, "000", br#"{"foo": "bar"}"#);
+        env.write_patch_file(&d, "001", br#"{"baz": "qux"}"#);
+        env.run_patch();
+
+        let result = env.read_target_json(".bar.json");
+        assert_eq!(result["foo"], "bar");
+        assert_eq!(result["baz"], "qux");
+    }
+
+    #[test]
+    fn json_merge_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-baz.json.d");
+
+        env.write_patch_file(&d, "000", br#"{"a":1,"nested":{"x":1}}"#);
+        env.write_patch_file(&d, "001", br#"{"b":2,"nested":{"y":2}}"#);
+        env.run_patch();
+
+        let result = env.read_target_json(".baz.json");
+        assert_eq!(result["a"], 1);
+        assert_eq!(result["b"], 2);
+        assert_eq!(result["nested"]["x"], 1);
+        assert_eq!(result["nested"]["y"], 2);
+    }
+
+    #[test]
+    fn jsonc_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-jsonc.json.d");
+
+        env.write_patch_file(&d, "000", b"{\n  // line comment\n  \"foo\": 1,\n}\n");
+        env.write_patch_file(&d, "001", b"{\n  /* block comment */\n  \"bar\": 2\n}\n");
+        env.run_patch();
+
+        let result = env.read_target_json(".jsonc.json");
+        assert_eq!(result["foo"], 1);
+        assert_eq!(result["bar"], 2);
+    }
+
+    #[test]
+    fn toml_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-toml.toml.d");
+
+        env.write_named_patch_file(&d, "000.toml", b"foo = \"bar\"\n[nested]\nx = 1\n");
+        env.write_named_patch_file(&d, "001.toml", b"baz = \"qux\"\n[nested]\ny = 2\n");
+        env.run_patch();
+
+        let result = env.read_target_toml(".toml.toml");
+        assert_eq!(result["foo"].as_str().unwrap(), "bar");
+        assert_eq!(result["baz"].as_str().unwrap(), "qux");
+        assert_eq!(result["nested"]["x"].as_integer().unwrap(), 1);
+        assert_eq!(result["nested"]["y"].as_integer().unwrap(), 2);
+    }
+
+    #[test]
+    fn ignore_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-ignore.d");
+
+        env.write_named_patch_file(&d, "000", b"keep-this");
+        env.write_named_patch_file(&d, "AGENTS.md", b"secret");
+        env.write_named_patch_file(&d, "README.md", b"topline");
+        env.run_patch();
+
+        let result = env.read_target_file(".ignore");
+        assert!(result.contains("keep-this"));
+        assert!(!result.contains("secret"));
+        assert!(!result.contains("topline"));
+    }
+
+    #[test]
+    fn split_test() {
+        let env = TestEnv::new();
+        let file = env.write_target_file(
+            ".split.json", br#"{"a":1,"b":{"c":2},"d":[3]}"#
+        );
+        env.run(&["split", file.to_str().unwrap()]).unwrap();
+
+        let d = env.patch_dir.path().join("dot-split.json.d");
+        let mut names: Vec<_> = std::fs::read_dir(&d).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["00-a.json", "10-b.json", "20-d.json"]);
+
+        // Splitting twice needs `--force`.
+        assert!(env.run(&["split", file.to_str().unwrap()]).is_err());
+
+        std::fs::remove_file(&file).unwrap();
+        env.run_patch();
+        let result = env.read_target_json(".split.json");
+        assert_eq!(result, serde_json::json!({"a":1,"b":{"c":2},"d":[3]}));
+    }
+
+    #[test]
+    fn split_sections_test() {
+        let env = TestEnv::new();
+        let toml = env.write_target_file(
+            ".split.toml", b"x = 1\ny = 2\n[t]\nz = 3\n"
+        );
+        let text = env.write_target_file(".split", b"one\n\ntwo\n\n\nthree\n");
+        env.run(&["split", "--by", "sections", toml.to_str().unwrap()]).unwrap();
+        env.run(&["split", "--by", "sections", text.to_str().unwrap()]).unwrap();
+
+        let d = env.patch_dir.path().join("dot-split.toml.d");
+        let root = std::fs::read_to_string(d.join("00-root.toml")).unwrap();
+        assert!(root.contains("x = 1") && root.contains("y = 2"));
+        assert!(d.join("10-t.toml").exists());
+
+        std::fs::remove_file(&toml).unwrap();
+        std::fs::remove_file(&text).unwrap();
+        env.run_patch();
+        assert_eq!(env.read_target_toml(".split.toml")["t"]["z"].as_integer(), Some(3));
+        assert_eq!(env.read_target_file(".split"), "one\n\ntwo\n\n\nthree\n");
+    }
+
+    #[test]
+    fn fmt_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-tidy.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"b": 1,  "a": [1,2]}"#);
+        env.write_named_patch_file(&d, "10-b.json", b"// keep\n{\"c\": 1}");
+        env.write_named_patch_file(&d, "20-editor.fontSize.json", b"14");
+        let t = env.create_patch_dir("dot-tidy.toml.d");
+        env.write_named_patch_file(&t, "00-a.toml", b"b=1\na   = 2\n");
+        let (messy, commented, lone, toml) = (d.join("00-a.json"), d.join("10-b.json"),
+            d.join("20-editor.fontSize.json"), t.join("00-a.toml"));
+
+        assert!(env.run(&["fmt", "--check"]).is_err());
+        assert_eq!(std::fs::read_to_string(&toml).unwrap(), "b=1\na   = 2\n");
+
+        env.run(&["fmt", "--sort"]).unwrap();
+        assert_eq!(std::fs::read_to_string(&messy).unwrap(),
+            "{\n  \"a\": [\n    1,\n    2\n  ],\n  \"b\": 1\n}\n");
+        assert_eq!(std::fs::read_to_string(&toml).unwrap(), "a = 2\nb = 1\n");
+        assert_eq!(std::fs::read(&commented).unwrap(), b"// keep\n{\"c\": 1}");
+        assert_eq!(std::fs::read(&lone).unwrap(), b"14");
+        env.run(&["fmt", "--check"]).unwrap();
+    }
+
+    #[test]
+    fn dedupe_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-dedupe.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"a": 1, "b": {"c": 2, "d": [3]}}"#);
+        env.write_named_patch_file(&d, "10-b.json", br#"{"a": 1, "b": {"c": 4, "d": [3]}}"#);
+        env.write_named_patch_file(&d, "20-a.json", b"1");
+        env.write_named_patch_file(&d, "30-c.final.json", br#"{"a": 1}"#);
+        env.run_patch();
+        let rendered = env.read_target_json(".dedupe.json");
+
+        assert!(env.run(&["dedupe", "--check"]).is_err());
+        env.run(&["dedupe"]).unwrap();
+        assert_eq!(std::fs::read_to_string(d.join("10-b.json")).unwrap(),
+            "{\n  \"b\": {\n    \"c\": 4\n  }\n}\n");
+        assert!(!d.join("20-a.json").exists());
+        assert!(d.join("30-c.final.json").exists());
+        env.run_patch();
+        assert_eq!(env.read_target_json(".dedupe.json"), rendered);
+    }
+
+    #[test]
+    fn factor_test() {
+        let env = TestEnv::new();
+        let hosts = tempdir_in(env._root.path()).unwrap();
+        let write = |path: &str, text: &[u8]| {
+            let path = hosts.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text).unwrap();
+        };
+        write("a/dot-shared.json.d/10-a.json", br#"{"font": 12, "theme": "dark", "a": 1}"#);
+        write("a/dot-shared.json.d/20-editor.json", br#"{"editor": {"tabs": 4}}"#);
+        write("b/dot-shared.json.d/10-b.json",
+            br#"{"font": 12, "theme": "light", "editor": {"tabs": 4}}"#);
+        let d = env.create_patch_dir("dot-shared.json.d");
+        env.write_named_patch_file(&d, "50-base.json", br#"{"theme": "dark"}"#);
+
+        let (a, b) = (hosts.path().join("a"), hosts.path().join("b"));
+        let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+        let targets = format!("{a},{b}");
+        let render = |host| {
+            env.run(&["-d", host, "--no-seed"]).unwrap();
+            env.read_target_json(".shared.json")
+        };
+        let before = [render(a), render(b)];
+
+        assert!(env.run(&["-d", a, "-d", b, "factor", "--targets", &targets, "--check"]).is_err());
+        env.run(&["-d", a, "-d", b, "factor", "--targets", &targets]).unwrap();
+        assert_eq!(std::fs::read_to_string(d.join("00-common.json")).unwrap(),
+            "{\n  \"font\": 12,\n  \"editor\": {\n    \"tabs\": 4\n  }\n}\n");
+        assert!(!hosts.path().join("a/dot-shared.json.d/20-editor.json").exists());
+        assert_eq!(std::fs::read_to_string(hosts.path().join("b/dot-shared.json.d/10-b.json"))
+            .unwrap(), "{\n  \"theme\": \"light\",\n  \"editor\": {}\n}\n");
+        assert_eq!([render(a), render(b)], before);
+    }
+
+    #[test]
+    fn lint_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-good.json.d");
+        env.write_named_patch_file(&d, "10-a.json", br#"{"a": 1}"#);
+        env.write_named_patch_file(&d, "20-b.json", br#"{"b": 2}"#);
+        env.run(&["lint"]).unwrap();
+
+        let d = env.create_patch_dir("dot-bad.json.d");
+        env.write_named_patch_file(&d, "10-a.json", br#"{"a": {"x": 1, "x": 2}}"#);
+        env.write_named_patch_file(&d, "10-b.toml", b"b = 2");
+        env.write_named_patch_file(&d, "9-c.json", b"{");
+        env.create_patch_dir("dot-empty.d");
+        env.create_patch_dir("dot-kdl.kdl.d");
+        assert!(env.run(&["lint"]).is_err());
+
+        let problems = problems(&env.cli(&[])).unwrap();
+        let kinds = |name: &str| -> Vec<&str> {
+            problems.iter()
+                .filter(|p| p.path.ends_with(name))
+                .map(|p| p.kind)
+                .collect()
+        };
+        assert_eq!(kinds("10-a.json"), ["duplicate"]);
+        assert_eq!(kinds("10-b.toml"), ["naming", "parse", "ordering"]);
+        assert_eq!(kinds("9-c.json"), ["parse", "ordering"]);
+        assert_eq!(kinds("dot-empty.d"), ["empty"]);
+        assert_eq!(kinds("dot-kdl.kdl.d"), ["format"]);
+
+        // Nothing is written.
+        assert!(!env.target_dir.path().join(".good.json").exists());
+    }
+
+    #[test]
+    fn doctor_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-config/app.json.d");
+        env.write_named_patch_file(&d, "10-a.json", br#"{"a": 1}"#);
+        env.write_named_patch_file(&d, "10-a.json~", b"");
+        env.write_named_patch_file(&d, "20-b.json.gpg", b"");
+
+        let diagnoses = diagnose(&env.cli(&[])).unwrap();
+        let messages: Vec<_> = diagnoses.iter()
+            .map(|d| d.message.as_str())
+            .collect();
+        assert!(messages.contains(&"parent directory is missing"));
+        assert!(messages.contains(&"looks like an editor backup or lock file"));
+
+        std::fs::create_dir(env.target_dir.path().join(".config")).unwrap();
+        let diagnoses = diagnose(&env.cli(&[])).unwrap();
+        assert!(diagnoses.iter().all(|d| d.message != "parent directory is missing"));
+    }
+
+    #[test]
+    fn init_test() {
+        let env = TestEnv::new();
+        std::fs::create_dir_all(env.target_dir.path().join(".config/b")).unwrap();
+        env.write_target_file(".config/a.json", br#"{"a": 1}"#);
+        env.write_target_file(".config/b/c", b"plain");
+        env.write_target_file(".config/bin", &[0xff, 0xfe]);
+        env.write_target_file(".outside", b"not walked");
+
+        let from = env.target_dir.path().join(".config");
+        env.run(&["init", "--from", from.to_str().unwrap()]).unwrap();
+
+        let d = env.patch_dir.path();
+        assert_eq!(
+            std::fs::read_to_string(d.join("dot-config/a.json.d/00-base.json")).unwrap(),
+            r#"{"a": 1}"#,
+        );
+        assert!(d.join("dot-config/b/c.d/00-base").exists());
+        assert!(!d.join("dot-config/bin.d").exists());
+        assert!(!d.join("dot-outside.d").exists());
+
+        // Applying right after changes nothing.
+        env.run_patch();
+        assert_eq!(env.read_target_json(".config/a.json")["a"], 1);
+    }
+
+    #[test]
+    fn select_test() {
+        let files = [Path::new("a"), Path::new("b"), Path::new("c"), Path::new("d")];
+        let chosen = select(&files, &b"1, 3-4\n"[..], std::io::sink()).unwrap();
+        assert_eq!(chosen, [0, 2, 3]);
+        assert!(select(&files, &b"5\n"[..], std::io::sink()).is_err());
+        assert!(select(&files, &b"\n"[..], std::io::sink()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn new_test() {
+        let env = TestEnv::new();
+        let new = |fragment: &str| env.run(&["new", fragment]);
+
+        new("dot-new.json.d/base").unwrap();
+        new("dot-new.json.d/35-mid.json").unwrap();
+        new("dot-new.json.d/extra").unwrap();
+        new("dot-new.d/note").unwrap();
+        assert!(new("dot-new.json.d/00-base").is_err());
+        assert!(new("dot-new.json/base").is_err());
+
+        let d = env.patch_dir.path();
+        assert!(d.join("dot-new.json.d/00-base.json").exists());
+        assert!(d.join("dot-new.json.d/35-mid.json").exists());
+        assert!(d.join("dot-new.json.d/40-extra.json").exists());
+        assert!(d.join("dot-new.d/00-note").exists());
+
+        // Skeletons parse.
+        env.run_patch();
+        assert_eq!(env.read_target_json(".new.json"), serde_json::json!({}));
+    }
+
+    #[test]
+    fn edit_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-edit.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"a": 1}"#);
+        env.write_named_patch_file(&d, "10-b.json", br#"{"b": 1}"#);
+        let edit = |file: &Path, editor: &str, input: &[u8]| {
+            let cli = env.cli(&["edit", "--editor", editor, file.to_str().unwrap()]);
+            let Some(Command::Edit(args)) = &cli.command else { unreachable!() };
+            edit(&cli, args, input)
+        };
+
+        // A target opens its last fragment.
+        let target = env.target_dir.path().join(".edit.json");
+        edit(&target, "sed -i s/1/2/", b"").unwrap();
+        assert_eq!(env.read_target_json(".edit.json"), serde_json::json!({"a": 1, "b": 2}));
+
+        edit(&d.join("00-a.json"), "sed -i s/1/3/", b"").unwrap();
+        assert_eq!(env.read_target_json(".edit.json")["a"], 3);
+
+        // Broken edits are reverted when given up.
+        assert!(edit(&d.join("00-a.json"), "sed -i s/3/{/", b"n\n").is_err());
+        assert_eq!(std::fs::read_to_string(d.join("00-a.json")).unwrap(), r#"{"a": 3}"#);
+
+        // Fragments of scoped subdirectories keep their scope.
+        let t = env.create_patch_dir("dot-edit.toml.d/tool");
+        env.write_named_patch_file(&t, "00-a.toml", b"key = 1");
+        edit(&t.join("00-a.toml"), "sed -i s/1/2/", b"").unwrap();
+        assert_eq!(env.read_target_toml(".edit.toml")["tool"]["key"].as_integer(), Some(2));
+
+        // Fragments of the first of several layers, given relative to a
+        // relative layer, render with the later layers.
+        let host = tempdir_in(env._root.path()).unwrap();
+        let over = host.path().join("dot-edit.json.d");
+        std::fs::create_dir_all(&over).unwrap();
+        env.write_named_patch_file(&over, "20-c.json", br#"{"c": 1}"#);
+        let up = std::env::current_dir().unwrap().components().skip(1).map(|_| "..")
+            .collect::<PathBuf>();
+        let relative = |path: &Path| up.join(path.strip_prefix("/").unwrap());
+        let base = relative(env.patch_dir.path());
+        let fragment = base.join("dot-edit.json.d/00-a.json");
+        let cli = env.cli(&["-d", base.to_str().unwrap(), "-d", host.path().to_str().unwrap(),
+            "edit", "--editor", "sed -i s/3/4/", fragment.to_str().unwrap()]);
+        let Some(Command::Edit(args)) = &cli.command else { unreachable!() };
+        super::edit(&cli, args, &b""[..]).unwrap();
+        assert_eq!(env.read_target_json(".edit.json"), serde_json::json!({"a": 4, "b": 2, "c": 1}));
+    }
+
+    #[test]
+    fn list_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-config/list.toml.d");
+        env.write_named_patch_file(&d, "00-a.toml", b"a = 1");
+        env.write_named_patch_file(&d, "10-b.toml", b"b = 1");
+        env.write_named_patch_file(&d, "README.md", b"ignored");
+        env.create_patch_dir("dot-list.d");
+
+        env.run(&["list"]).unwrap();
+        env.run(&["--output", "json", "list"]).unwrap();
+
+        let mut listings = listings(&env.cli(&[])).unwrap();
+        listings.sort_by(|a, b| a.entry.target.cmp(&b.entry.target));
+        let summary: Vec<_> = listings.iter()
+            .map(|l| (l.entry.target.strip_prefix(env.target_dir.path()).unwrap(),
+                l.entry.format.as_deref(), l.fragments))
+            .collect();
+        assert_eq!(summary, [
+            (Path::new(".config/list.toml"), Some("toml"), 2),
+            (Path::new(".list"), None, 0),
+        ]);
+        // Listing writes nothing.
+        assert!(!env.target_dir.path().join(".list").exists());
+    }
+
+    #[test]
+    fn graph_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-graph.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"a": 1}"#);
+        let shared = env.patch_dir.path().join("shared.json");
+        std::fs::write(&shared, br#"{"s": 1}"#).unwrap();
+        std::os::unix::fs::symlink(&shared, d.join("10-shared.json")).unwrap();
+
+        let cli = env.cli(&["graph", "--dot"]);
+        let Some(Command::Graph(args)) = &cli.command else { unreachable!() };
+        let dot = graph(&cli, args).unwrap();
+
+        let target = env.target_dir.path().join(".graph.json");
+        let quote = |p: &Path| format!("{:?}", p.display().to_string());
+        assert!(dot.starts_with("digraph patch {"));
+        assert!(dot.contains(&format!("{} -> {};",
+            quote(&d.join("00-a.json")), quote(&target))));
+        assert!(dot.contains(&format!("{} -> {} [style=dashed];",
+            quote(&shared.canonicalize().unwrap()), quote(&d.join("10-shared.json")))));
+    }
+
+    #[test]
+    fn key_path_test() {
+        let path: KeyPath = r#".a.b[1]."c.d""#.parse().unwrap();
+        assert_eq!(path.0, [
+            Key::Name("a".into()), Key::Name("b".into()),
+            Key::Index(1), Key::Name("c.d".into()),
+        ]);
+        assert_eq!(path.to_string(), r#".a.b[1]."c.d""#);
+        assert_eq!("a".parse::<KeyPath>().unwrap().to_string(), ".a");
+        assert_eq!(".".parse::<KeyPath>().unwrap(), KeyPath(vec![]));
+        for invalid in ["a..b", "a.", "a[x]", "a[1", r#""a"b"#] {
+            assert!(invalid.parse::<KeyPath>().is_err(), "{invalid}");
+        }
+
+        let value = serde_json::json!({"a": {"b": [0, {"c.d": 5}]}});
+        assert_eq!(path.get(&value), Some(&serde_json::json!(5)));
+    }
+
+    #[test]
+    fn why_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-why.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"editor": {"fontSize": 12, "x": 1}}"#);
+        env.write_named_patch_file(&d, "10-b.json", br#"{"editor": {"fontSize": 14}}"#);
+        env.write_named_patch_file(&d, "20-c.json", br#"{"other": 1}"#);
+        let target = env.write_target_file(".why.json", br#"{"editor": {"fontSize": 11}}"#);
+
+        let entry = entry_of(&env.cli(&[]), &target).unwrap();
+        let (value, history) = provenance(&entry, &".editor.fontSize".parse().unwrap()).unwrap();
+        assert_eq!(value, Some(serde_json::json!(14)));
+        let roles: Vec<_> = history.iter()
+            .map(|(source, _, role)| (source.file_name().unwrap().to_str().unwrap(), *role))
+            .collect();
+        assert_eq!(roles, [
+            (".why.json", Role::Overridden),
+            ("00-a.json", Role::Overridden),
+            ("10-b.json", Role::Set),
+        ]);
+
+        let (_, history) = provenance(&entry, &".editor".parse().unwrap()).unwrap();
+        assert_eq!(history.iter().map(|h| h.2).collect::<Vec<_>>(),
+            [Role::Merged, Role::Merged, Role::Set]);
+
+        env.run(&["why", target.to_str().unwrap(), ".editor.fontSize"]).unwrap();
+    }
+
+    #[test]
+    fn annotate_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-note.toml.d");
+        env.write_named_patch_file(&d, "00-a.toml", b"x = 1\n[t]\ny = 1\n");
+        env.write_named_patch_file(&d, "10-b.toml", b"[t]\nz = 2\n");
+        let t = env.create_patch_dir("dot-note.d");
+        env.write_named_patch_file(&t, "00-a", b"alias a=b");
+        env.run(&["--annotate"]).unwrap();
+
+        let a = d.join("00-a.toml").display().to_string();
+        let b = d.join("10-b.toml").display().to_string();
+        let toml = env.read_target_file(".note.toml");
+        assert!(toml.contains(&format!("# from {a}\nx = 1\n")), "{toml}");
+        assert!(toml.contains(&format!("# from {a}, {b}\n[t]\n")), "{toml}");
+        assert!(toml.contains(&format!("# from {b}\nz = 2\n")), "{toml}");
+        assert_eq!(env.read_target_toml(".note.toml")["t"]["z"].as_integer(), Some(2));
+
+        let text = env.read_target_file(".note");
+        assert_eq!(text, format!("# from {}\nalias a=b", t.join("00-a").display()));
+    }
+
+    #[test]
+    fn conflicts_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-conflict.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"a": 1, "b": 1, "c": 1, "t": {"x": 1}}"#);
+        env.write_named_patch_file(&d, "10-b.json", br#"{"a": 2, "b": 1, "c": {"d": 1}, "t": {"y": 1}}"#);
+        env.write_target_file(".conflict.json", br#"{"b": 5}"#);
+
+        let entry = Entry::new(&env.cli(&[]), &d).unwrap();
+        let conflicts = target_conflicts(&entry).unwrap();
+        let paths: Vec<_> = conflicts.iter().map(|(p, _)| p.to_string()).collect();
+        assert_eq!(paths, [".a", ".c"]);
+        let sources: Vec<_> = conflicts[0].1.iter()
+            .map(|(s, v)| (s.file_name().unwrap().to_str().unwrap(), v.clone()))
+            .collect();
+        assert_eq!(sources, [("00-a.json", 1.into()), ("10-b.json", 2.into())]);
+
+        env.run(&["conflicts"]).unwrap();
+        assert_eq!(env.read_target_json(".conflict.json"), serde_json::json!({"b": 5}));
+    }
+
+    #[test]
+    fn final_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-final.json.d");
+        env.write_named_patch_file(&d, "10-lock.final.json", br#"{"a": 1, "t": {"x": 1}}"#);
+        env.write_named_patch_file(&d, "20-same.json", br#"{"a": 1, "b": 2, "t": {"y": 2}}"#);
+        env.run_patch();
+        assert_eq!(env.read_target_json(".final.json"),
+            serde_json::json!({"a": 1, "b": 2, "t": {"x": 1, "y": 2}}));
+
+        env.write_named_patch_file(&d, "30-override.json", br#"{"a": 2}"#);
+        let error = env.run(&[]).unwrap_err().to_string();
+        assert!(error.contains("overrides `.a`"), "{error}");
+
+        env.write_named_patch_file(&d, "30-override.json", br#"{"t": 0}"#);
+        let error = env.run(&[]).unwrap_err().to_string();
+        assert!(error.contains("overrides `.t.x`"), "{error}");
+
+        assert!(is_final(Path::new("a/10-x.final.toml")));
+        assert!(is_final(Path::new("10-x.final")));
+        assert!(!is_final(Path::new("final.json")));
+    }
+
+    #[test]
+    fn protected_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-protect.json.d");
+        env.write_named_patch_file(&d, "00-a.json",
+            br#"{"id": "fragment", "auth": {"token": null, "user": "me"}, "new": 1}"#);
+        env.write_named_patch_file(&d, "PROTECTED", b"# generated\n.id\n.auth.token\n.new\n");
+        env.write_target_file(".protect.json",
+            br#"{"id": "machine", "auth": {"token": "secret"}}"#);
+        env.run_patch();
+
+        assert_eq!(env.read_target_json(".protect.json"), serde_json::json!({
+            "id": "machine",
+            "auth": {"token": "secret", "user": "me"},
+            "new": 1,
+        }));
+
+        let t = env.create_patch_dir("dot-protect.toml.d");
+        env.write_named_patch_file(&t, "00-a.toml", b"[s]\nid = 1\n");
+        env.write_named_patch_file(&t, "PROTECTED", b".s.id\n");
+        env.write_target_file(".protect.toml", b"[s]\nid = 2\n");
+        env.run_patch();
+        assert_eq!(env.read_target_toml(".protect.toml")["s"]["id"].as_integer(), Some(2));
+    }
+
+    #[test]
+    fn root_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-rooted.d");
+        env.write_named_patch_file(&d, "00-a", b"rooted");
+
+        let home = env.target_dir.path().join("home/user");
+        std::fs::create_dir_all(&home).unwrap();
+        let mut cli = env.cli(&["--root", env.target_dir.path().to_str().unwrap()]);
+        cli.target = "/home/user".into();
+        assert_eq!(cli.target_root(), home);
+        start(cli).unwrap();
+
+        assert_eq!(std::fs::read_to_string(home.join(".rooted")).unwrap(), "rooted");
+    }
+
+    #[test]
+    fn docker_target_test() {
+        let env = TestEnv::new();
+        let mut cli = env.cli(&[]);
+        cli.target = "docker://dev:/root".into();
+        assert_eq!(cli.host(), Host::Docker("dev".into()));
+        assert_eq!(cli.target_root(), Path::new("/root"));
+
+        let d = env.create_patch_dir("dot-docker.d");
+        let entry = Entry::new(&cli, &d).unwrap();
+        assert_eq!(entry.target, Path::new("/root/.docker"));
+        assert_eq!(entry.host, Host::Docker("dev".into()));
+
+        cli.target = env.target_dir.path().into();
+        assert_eq!(cli.host(), Host::Local);
+        let missing = env.target_dir.path().join("missing");
+        assert_eq!(Host::Local.read(&missing).unwrap(), "");
+        Host::Local.write(&missing, "x").unwrap();
+        assert_eq!(Host::Local.read(&missing).unwrap(), "x");
+    }
+
+    #[test]
+    fn export_configmap_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-config/app/settings.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"a": 1}"#);
+        let d = env.create_patch_dir("dot-app.d");
+        env.write_named_patch_file(&d, "00-a", b"line");
+
+        let out = env.target_dir.path().join("configmaps.yaml");
+        env.run(&["export", "--format", "configmap", "--namespace", "x",
+            "-o", out.to_str().unwrap()]).unwrap();
+
+        let text = std::fs::read_to_string(&out).unwrap();
+        let mut maps: Vec<serde_json::Value> = text.split("---\n")
+            .map(|document| serde_yaml::from_str(document).unwrap())
+            .collect();
+        maps.sort_by_key(|m| m["metadata"]["name"].to_string());
+
+        assert_eq!(maps[0]["metadata"]["name"], "app");
+        assert_eq!(maps[0]["data"][".app"], "line");
+        assert_eq!(maps[1]["kind"], "ConfigMap");
+        assert_eq!(maps[1]["metadata"]["name"], "config-app-settings-json");
+        assert_eq!(maps[1]["metadata"]["namespace"], "x");
+        let data: serde_json::Value =
+            serde_json::from_str(maps[1]["data"]["settings.json"].as_str().unwrap()).unwrap();
+        assert_eq!(data["a"], 1);
+
+        // Targets are not written.
+        assert!(!env.target_dir.path().join(".app").exists());
+    }
+
+    #[test]
+    fn export_tar_test() {
+        use std::io::Read;
+        use std::os::unix::fs::PermissionsExt;
+
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-config/app.toml.d");
+        env.write_named_patch_file(&d, "00-a.toml", b"a = 1\n");
+        let d = env.create_patch_dir("dot-secret.d");
+        env.write_named_patch_file(&d, "00-a", b"token");
+        std::fs::create_dir(env.target_dir.path().join(".config")).unwrap();
+        let secret = env.write_target_file(".secret", b"");
+        std::fs::set_permissions(&secret, PermissionsExt::from_mode(0o600)).unwrap();
+
+        let out = env.patch_dir.path().join("configs.tar.gz");
+        env.run(&["export", "--format", "tar", "-o", out.to_str().unwrap()]).unwrap();
+
+        let file = std::fs::File::open(&out).unwrap();
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(file));
+        let mut entries: Vec<(String, u32, String)> = archive.entries().unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut text = String::new();
+                entry.read_to_string(&mut text).unwrap();
+                (entry.path().unwrap().display().to_string(),
+                    entry.header().mode().unwrap(), text)
+            })
+            .collect();
+        entries.sort();
+        assert_eq!(entries, [
+            (".config/app.toml".into(), 0o644, "a = 1\n".into()),
+            (".secret".into(), 0o600, "token".into()),
+        ]);
+    }
+
+    #[test]
+    fn rules_test() {
+        let env = TestEnv::new();
+        std::fs::write(env.patch_dir.path().join("patch.toml"), br#"
+            [[rules]]
+            pattern = '^AppData/(.*)$'
+            replace = 'AppData/Roaming/$1'
+
+            [[rules]]
+            pattern = '^verbatim/(.*)\.d$'
+            replace = 'dot-$1'
+            last = true
+        "#).unwrap();
+
+        let d = env.create_patch_dir("AppData/app/dot-conf.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"a": 1}"#);
+        let v = env.create_patch_dir("verbatim/name.d");
+        env.write_named_patch_file(&v, "00-a", b"kept");
+        std::fs::create_dir_all(env.target_dir.path().join("AppData/Roaming/app")).unwrap();
+        env.run_patch();
+
+        assert_eq!(env.read_target_json("AppData/Roaming/app/.conf.json")["a"], 1);
+        assert_eq!(env.read_target_file("dot-name"), "kept");
+
+        // Targets given on the command line are found through the rules.
+        let mut cli = env.cli(&[]);
+        cli.settings = Settings::load(&cli).unwrap();
+        let entry = entry_of(&cli, &env.target_dir.path().join("dot-name")).unwrap();
+        assert_eq!(entry.directories, vec![v]);
+    }
+
+    #[test]
+    fn suffix_test() {
+        let env = TestEnv::new();
+        std::fs::write(env.patch_dir.path().join("patch.toml"),
+            b"suffix = '.frag'\ndot = '_'\n").unwrap();
+        let d = env.create_patch_dir("_frag.json.frag");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"a": 1}"#);
+        env.create_patch_dir("_ignored.d");
+        env.run_patch();
+
+        assert_eq!(env.read_target_json(".frag.json")["a"], 1);
+        assert!(!env.target_dir.path().join(".ignored").exists());
+
+        env.run(&["new", "_frag.json.frag/b"]).unwrap();
+        assert!(d.join("10-b.json").exists());
+    }
+
+    #[test]
+    fn layers_test() {
+        let env = TestEnv::new();
+        let host = tempdir_in(env._root.path()).unwrap();
+        let layer = host.path().to_str().unwrap();
+
+        let base = env.create_patch_dir("dot-layered.json.d");
+        env.write_named_patch_file(&base, "00-a.json", br#"{"a": 1, "b": 1}"#);
+        env.write_named_patch_file(&base, "10-b.json", br#"{"b": 2, "c": 1}"#);
+        let over = host.path().join("dot-layered.json.d");
+        std::fs::create_dir_all(&over).unwrap();
+        env.write_named_patch_file(&over, "10-b.json", br#"{"b": 3}"#);
+        env.write_named_patch_file(&over, "20-c.json", br#"{"d": 1}"#);
+        env.run(&["--directory", layer]).unwrap();
+
+        // The host layer replaces `10-b.json` of the base layer.
+        let json = env.read_target_json(".layered.json");
+        assert_eq!(json, serde_json::json!({"a": 1, "b": 3, "d": 1}));
+
+        // New fragments go to the last layer.
+        env.run(&["--directory", layer, "new", "dot-other.d/note"]).unwrap();
+        assert!(host.path().join("dot-other.d/00-note").exists());
+    }
+
+    #[test]
+    fn scope_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-scoped.json.d");
+        env.write_named_patch_file(&d, "00-base.json", br#"{"editor": {"tabSize": 4}}"#);
+        env.write_named_patch_file(&d, "10-editor.fontSize.json", b"14");
+        let t = env.create_patch_dir("dot-scoped.toml.d/tool/nested");
+        env.write_named_patch_file(&t, "00-a.toml", b"key = 1");
+        env.write_named_patch_file(&t, "10-b.toml", b"\"text\"");
+        env.run_patch();
+
+        let json = env.read_target_json(".scoped.json");
+        assert_eq!(json, serde_json::json!({"editor": {"tabSize": 4, "fontSize": 14}}));
+        let toml = env.read_target_toml(".scoped.toml");
+        assert_eq!(toml["tool"]["nested"]["key"].as_integer(), Some(1));
+        assert_eq!(toml["tool"]["nested"]["b"].as_str(), Some("text"));
+    }
+
+    #[test]
+    fn sniff_test() {
+        let env = TestEnv::new();
+        let j = env.create_patch_dir("dot-jrc.d");
+        env.write_named_patch_file(&j, "00-a", br#"{"a": 1, "b": 1}"#);
+        env.write_named_patch_file(&j, "10-b", br#"{"b": 2}"#);
+        let y = env.create_patch_dir("dot-yrc.d");
+        env.write_named_patch_file(&y, "00-a", b"---\na: 1\nb: [1]\n");
+        env.write_named_patch_file(&y, "10-b", b"b: [2]\n");
+        let t = env.create_patch_dir("dot-trc.d");
+        env.write_named_patch_file(&t, "00-a", b"# comment\n[a]\nb = 1\n");
+        env.write_named_patch_file(&t, "10-b", b"[a]\nc = 2\n");
+        // Not TOML, so it stays text.
+        let g = env.create_patch_dir("dot-gitconfig.d");
+        env.write_named_patch_file(&g, "00-a", b"[user]\n\tname = me");
+        env.write_named_patch_file(&g, "10-b", b"[core]\n\teditor = vi");
+        env.run_patch();
+
+        assert_eq!(env.read_target_json(".jrc"), serde_json::json!({"a": 1, "b": 2}));
+        assert_eq!(env.read_target_file(".yrc"), "a: 1\nb:\n- 2\n");
+        assert_eq!(env.read_target_toml(".trc")["a"]["c"].as_integer(), Some(2));
+        assert_eq!(env.read_target_file(".gitconfig"),
+            "[user]\n\tname = me\n[core]\n\teditor = vi");
+
+        std::fs::write(env.patch_dir.path().join("patch.toml"), b"sniff = false").unwrap();
+        std::fs::remove_file(env.target_dir.path().join(".jrc")).unwrap();
+        env.run_patch();
+        assert_eq!(env.read_target_file(".jrc"), "{\"a\": 1, \"b\": 1}\n{\"b\": 2}");
+    }
+
+    #[test]
+    fn format_marker_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-rc.d");
+        env.write_named_patch_file(&d, "FORMAT", b"toml\n");
+        env.write_named_patch_file(&d, "00-a", b"a = 1\nb = 1");
+        env.write_named_patch_file(&d, "10-b", b"b = 2");
+        env.run_patch();
+
+        let toml = env.read_target_toml(".rc");
+        assert_eq!(toml["a"].as_integer(), Some(1));
+        assert_eq!(toml["b"].as_integer(), Some(2));
+    }
+
+    #[test]
+    fn alias_test() {
+        let env = TestEnv::new();
+        std::fs::write(env.patch_dir.path().join("patch.toml"),
+            b"[aliases]\nrc = 'toml'\n").unwrap();
+        let y = env.create_patch_dir("dot-app.yml.d");
+        env.write_named_patch_file(&y, "00-a.yml", b"a: 1\nb: 1\n");
+        env.write_named_patch_file(&y, "10-b.yml", b"b: 2\n");
+        let i = env.create_patch_dir("tool.conf.d");
+        env.write_named_patch_file(&i, "00-a.conf", b"top = 1\n[core]\n; comment\nx = 1\ny = 1\n");
+        env.write_named_patch_file(&i, "10-b.conf", b"[core]\ny = 2\nflag\n");
+        let t = env.create_patch_dir("tool.rc.d");
+        env.write_named_patch_file(&t, "00-a.rc", b"a = 1");
+        env.write_named_patch_file(&t, "10-b.rc", b"a = 2");
+        env.run_patch();
+
+        assert_eq!(env.read_target_file(".app.yml"), "a: 1\nb: 2\n");
+        assert_eq!(env.read_target_file("tool.conf"),
+            "top = 1\n\n[core]\nx = 1\ny = 2\nflag\n");
+        assert_eq!(env.read_target_toml("tool.rc")["a"].as_integer(), Some(2));
+        assert!(problems(&{
+            let mut cli = env.cli(&[]);
+            cli.settings = Settings::load(&cli).unwrap();
+            cli
+        }).unwrap().is_empty());
+    }
+
+    #[test]
+    fn prune_test() {
+        let env = TestEnv::new();
+        env.write_target_file(".pruned.json",
+            br#"{"kept": 0, "stale": 1, "nested": {"a": 0, "old": 1}, "token": "x"}"#);
+        let d = env.create_patch_dir("dot-pruned.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"kept": 1, "nested": {"a": 1}}"#);
+        env.write_named_patch_file(&d, "PROTECTED", b".token");
+        env.write_target_file(".pruned", b"old");
+        let t = env.create_patch_dir("dot-pruned.d");
+        env.write_named_patch_file(&t, "00-a", b"new");
+
+        env.run_patch();
+        assert_eq!(env.read_target_json(".pruned.json")["stale"], 1);
+
+        env.run(&["--prune"]).unwrap();
+        assert_eq!(env.read_target_json(".pruned.json"),
+            serde_json::json!({"kept": 1, "nested": {"a": 1}, "token": "x"}));
+        // Plain text has no keys to prune.
+        assert!(env.read_target_file(".pruned").starts_with("old\nnew"));
+    }
+
+    #[test]
+    fn comment_pruned_test() {
+        let env = TestEnv::new();
+        env.write_target_file(".pruned.toml", b"stale = 1\nkept = 0\n\n[nested]\na = 0\nold = \"x\"\n");
+        let d = env.create_patch_dir("dot-pruned.toml.d");
+        env.write_named_patch_file(&d, "00-a.toml", b"kept = 1\n\n[nested]\na = 1\n");
+        env.write_target_file(".pruned.yaml", b"stale: 1\nnested:\n  a: 0\n  old: x\n");
+        let y = env.create_patch_dir("dot-pruned.yaml.d");
+        env.write_named_patch_file(&y, "00-a.yaml", b"nested:\n  a: 1\n");
+
+        env.run(&["--prune", "--comment-pruned"]).unwrap();
+        let toml = "# removed by patch: .stale = 1\nkept = 1\n\n[nested]\n\
+            # removed by patch: .nested.old = \"x\"\na = 1\n";
+        let yaml = [
+            "# removed by patch: .stale: 1",
+            "nested:",
+            "  # removed by patch: .nested.old: \"x\"",
+            "  a: 1\n",
+        ].join("\n");
+        assert_eq!(env.read_target_file(".pruned.toml"), toml);
+        assert_eq!(env.read_target_file(".pruned.yaml"), yaml);
+
+        // Comments stay once, until a fragment sets their key again.
+        env.run(&["--prune", "--comment-pruned"]).unwrap();
+        assert_eq!(env.read_target_file(".pruned.toml"), toml);
+        assert_eq!(env.read_target_file(".pruned.yaml"), yaml);
+        env.write_named_patch_file(&d, "10-b.toml", b"stale = 2\n");
+        env.run(&["--prune", "--comment-pruned"]).unwrap();
+        assert!(!env.read_target_file(".pruned.toml").contains(".stale"));
+    }
+
+    #[test]
+    fn no_seed_test() {
+        let env = TestEnv::new();
+        env.write_target_file(".seeded.json", br#"{"stale": 1, "token": "x"}"#);
+        let d = env.create_patch_dir("dot-seeded.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"a": 1, "token": "y"}"#);
+        env.write_named_patch_file(&d, "PROTECTED", b".token");
+        env.write_target_file(".unseeded", b"old");
+        let t = env.create_patch_dir("dot-unseeded.d");
+        env.write_named_patch_file(&t, "00-a", b"new");
+        env.write_named_patch_file(&t, "NO_SEED", b"");
+
+        env.run_patch();
+        assert_eq!(env.read_target_file(".unseeded"), "new");
+        assert_eq!(env.read_target_json(".seeded.json")["stale"], 1);
+
+        env.run(&["--no-seed"]).unwrap();
+        assert_eq!(env.read_target_json(".seeded.json"),
+            serde_json::json!({"a": 1, "token": "x"}));
+    }
+
+    #[test]
+    fn backups_test() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let env = TestEnv::new();
+        let state = tempdir_in(env._root.path()).unwrap();
+        let state = state.path().to_str().unwrap();
+        std::fs::write(env.patch_dir.path().join("patch.toml"),
+            b"[backups]\nkeep = 2\n").unwrap();
+        let target = env.write_target_file(".backed.json", br#"{"v": 0}"#);
+        let d = env.create_patch_dir("dot-backed.json.d");
+
+        for v in 1..=3 {
+            env.write_named_patch_file(&d, "00-a.json", format!(r#"{{"v": {v}}}"#).as_bytes());
+            std::thread::sleep(std::time::Duration::from_millis(2));
+            env.run(&["--backup", "--state", state]).unwrap();
+        }
+        // Unchanged targets are not backed up.
+        env.run(&["--backup", "--state", state]).unwrap();
+
+        let mut cli = env.cli(&["--state", state]);
+        cli.settings = Settings::load(&cli).unwrap();
+        let entry = entry_of(&cli, &target).unwrap();
+        assert_eq!(backup_times(&target_state_dir(&cli, "backups", &entry)).unwrap().len(), 2);
+
+        let target = target.to_str().unwrap();
+        env.run(&["--state", state, "backups", "restore", target]).unwrap();
+        assert_eq!(env.read_target_json(".backed.json")["v"], 2);
+        env.run(&["--state", state, "backups", "list"]).unwrap();
+
+        // Backups of secrets are as private as they are.
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        let secret = env.write_target_file(".secret.json.gz",
+            &Compression::Gzip.compress(r#"{"v": 0}"#).unwrap());
+        std::fs::set_permissions(&secret, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let d = env.create_patch_dir("dot-secret.json.gz.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"v": 1}"#);
+        env.run(&["--backup", "--state", state]).unwrap();
+        let directory = target_state_dir(&cli, "backups", &entry_of(&cli, &secret).unwrap());
+        assert_eq!(mode(&directory), 0o700);
+        let backup = directory.read_dir().unwrap().next().unwrap().unwrap().path();
+        assert_eq!(mode(&backup), 0o600);
+    }
+
+    #[test]
+    fn undo_test() {
+        let env = TestEnv::new();
+        env.write_target_file(".undone.json", br#"{"v": 0}"#);
+        let d = env.create_patch_dir("dot-undone.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"v": 1}"#);
+        let n = env.create_patch_dir("dot-new.json.d");
+        env.write_named_patch_file(&n, "00-a.json", br#"{"new": true}"#);
+        env.run_patch();
+        env.write_named_patch_file(&d, "00-a.json", br#"{"v": 2}"#);
+        env.run_patch();
+        // Runs changing nothing are not undone.
+        env.run_patch();
+
+        env.run(&["undo"]).unwrap();
+        assert_eq!(env.read_target_json(".undone.json")["v"], 1);
+        env.run(&["undo"]).unwrap();
+        assert_eq!(env.read_target_json(".undone.json")["v"], 0);
+        assert!(!env.target_dir.path().join(".new.json").exists());
+        assert!(env.run(&["undo"]).is_err());
+
+        // Targets changed since are left alone.
+        env.run_patch();
+        env.write_target_file(".undone.json", br#"{"v": 3}"#);
+        env.run(&["undo"]).unwrap();
+        assert_eq!(env.read_target_json(".undone.json")["v"], 3);
+        assert!(!env.target_dir.path().join(".new.json").exists());
+    }
+
+    #[test]
+    fn json_patch_test() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let env = TestEnv::new();
+        let state = tempdir_in(env._root.path()).unwrap();
+        let target = env.write_target_file(".patched.json", br#"{"v": 0, "old": 1}"#);
+        let d = env.create_patch_dir("dot-patched.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"v": 1, "new": [2]}"#);
+
+        let state = state.path().to_str().unwrap();
+        env.run(&["--prune", "--json-patch", "--state", state]).unwrap();
+        // Unchanged targets record nothing.
+        env.run(&["--prune", "--json-patch", "--state", state]).unwrap();
+
+        let cli = env.cli(&["--state", state]);
+        let directory = target_state_dir(&cli, "patches", &entry_of(&cli, &target).unwrap());
+        let patches: Vec<_> = directory.read_dir().unwrap().collect();
+        assert_eq!(patches.len(), 1);
+        let patch: json_patch::Patch = serde_json::from_slice(
+            &std::fs::read(patches[0].as_ref().unwrap().path()).unwrap()).unwrap();
+        let mut value = serde_json::json!({"v": 0, "old": 1});
+        json_patch::patch(&mut value, &patch).unwrap();
+        assert_eq!(value, env.read_target_json(".patched.json"));
+
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&directory), 0o700);
+        assert_eq!(mode(&patches[0].as_ref().unwrap().path()), 0o600);
+
+        // Sensitive targets record nothing.
+        let secret = env.write_target_file(".secret.json", br#"{"token": "a"}"#);
+        let d = env.create_patch_dir("dot-secret.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"token": "b"}"#);
+        env.write_named_patch_file(&d, "SENSITIVE", b".token\n");
+        env.run(&["--json-patch", "--state", state]).unwrap();
+        assert!(!target_state_dir(&cli, "patches", &entry_of(&cli, &secret).unwrap()).exists());
+    }
+
+    #[test]
+    fn jobs_test() {
+        let env = TestEnv::new();
+        for i in 0..20 {
+            let d = env.create_patch_dir(&format!("dot-many{i}.json.d"));
+            env.write_named_patch_file(&d, "00-a.json", format!(r#"{{"i": {i}}}"#).as_bytes());
+        }
+        env.run(&["--jobs", "4"]).unwrap();
+        (0..20).for_each(|i| assert_eq!(env.read_target_json(&format!(".many{i}.json"))["i"], i));
+
+        let d = env.create_patch_dir("dot-broken.json.d");
+        env.write_named_patch_file(&d, "00-a.json", b"{");
+        assert!(env.run(&["--jobs", "4"]).is_err());
+    }
+
+    #[test]
+    fn install_timer_test() {
+        let env = TestEnv::new();
+        let units = tempdir_in(env._root.path()).unwrap();
+        let config = env._root.path().join("my \"config\"\t%.toml");
+        std::fs::write(&config, "").unwrap();
+        env.run(&["--prune", "--max-depth", "2", "--allow-outside-target", "--since", "HEAD",
+            "--config", config.to_str().unwrap(), "install-timer", "--interval", "30min",
+            "--no-enable", "--units", units.path().to_str().unwrap()]).unwrap();
+
+        let service = std::fs::read_to_string(units.path().join("patch.service")).unwrap();
+        let exec = service.lines().find_map(|l| l.strip_prefix("ExecStart=")).unwrap();
+        assert!(exec.contains(&format!("--directory {}", env.patch_dir.path().display())));
+        assert!(exec.contains(&format!(r#"--config "{}/my \"config\"\t%%.toml""#,
+            env._root.path().display())), "{exec}");
+        for option in ["--max-depth 2", "--since HEAD", "--output text", "--allow-outside-target"] {
+            assert!(exec.contains(option), "{option} in {exec}");
+        }
+        assert!(exec.ends_with("--prune"));
+        let timer = std::fs::read_to_string(units.path().join("patch.timer")).unwrap();
+        assert!(timer.contains("OnUnitActiveSec=30min\n"));
+    }
+
+    #[test]
+    fn plugin_test() {
+        // Parses and serializes JSON as is, and merges by taking the last.
+        let wasm = wat::parse_str(r#"
+            (module
+                (memory (export "memory") 1)
+                (global $next (mut i32) (i32.const 0))
+                (func (export "alloc") (param $len i32) (result i32)
+                    (global.get $next)
+                    (global.set $next (i32.add (global.get $next) (local.get $len))))
+                (func $pack (param $ptr i32) (param $len i32) (result i64)
+                    (i64.or
+                        (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+                        (i64.extend_i32_u (local.get $len))))
+                (func (export "parse") (param i32 i32) (result i64)
+                    (call $pack (local.get 0) (local.get 1)))
+                (func (export "merge") (param i32 i32 i32 i32) (result i64)
+                    (call $pack (local.get 2) (local.get 3)))
+                (func (export "serialize") (param i32 i32) (result i64)
+                    (call $pack (local.get 0) (local.get 1))))
+        "#).unwrap();
+
+        let env = TestEnv::new();
+        std::fs::create_dir_all(env.patch_dir.path().join("plugins")).unwrap();
+        std::fs::write(env.patch_dir.path().join("plugins/last.wasm"), wasm).unwrap();
+        std::fs::write(env.patch_dir.path().join("patch.toml"),
+            b"[plugins]\nlast = 'plugins/last.wasm'\n").unwrap();
+        let d = env.create_patch_dir("dot-app.last.d");
+        env.write_named_patch_file(&d, "00-a.last", br#"{"a": 1}"#);
+        env.write_named_patch_file(&d, "10-b.last", br#"{"b": 2}"#);
+        env.run_patch();
+
+        assert_eq!(env.read_target_file(".app.last"), r#"{"b":2}"#);
+    }
+
+    #[test]
+    fn get_test() {
+        let env = TestEnv::new();
+        env.write_target_file(".got.toml", b"kept = true\n");
+        let d = env.create_patch_dir("dot-got.toml.d");
+        env.write_named_patch_file(&d, "00-a.toml", b"[server]\nhost = 'a'\nports = [1, 2]");
+        env.write_named_patch_file(&d, "10-b.toml", b"[server]\nhost = 'b'");
+
+        let get = |path: &str, output: &str| {
+            let cli = env.cli(&["--output", output]);
+            let file = env.target_dir.path().join(".got.toml");
+            get(&cli, &GetArgs { file, path: path.parse().unwrap() })
+        };
+        assert_eq!(get(".server.host", "text").unwrap(), "b");
+        assert_eq!(get("$.server.ports[1]", "text").unwrap(), "2");
+        assert_eq!(get("server.host", "json").unwrap(), r#""b""#);
+        assert_eq!(get(".kept", "text").unwrap(), "true");
+        assert!(get(".missing", "text").is_err());
+        // Nothing is written.
+        assert_eq!(env.read_target_file(".got.toml"), "kept = true\n");
+    }
+
+    #[test]
+    fn expectations_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-expected.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"editor": {"fontSize": 14}}"#);
+        let t = env.create_patch_dir("dot-expectedrc.d");
+        env.write_named_patch_file(&t, "00-a", b"export EDITOR=vi\n");
+        std::fs::write(env.patch_dir.path().join("expectations.toml"), br#"
+            [[expect]]
+            target = ".expected.json"
+            path = ".editor.fontSize"
+            value = 14
+
+            [[expect]]
+            target = ".expected.json"
+            path = ".editor"
+
+            [[expect]]
+            target = ".expectedrc"
+            matches = '^export EDITOR=vi$'
+        "#).unwrap();
+        env.run(&["test"]).unwrap();
+
+        env.write_named_patch_file(&d, "10-b.json", br#"{"editor": {"fontSize": 12}}"#);
+        let args = TestArgs { expectations: None, golden: None, bless: false };
+        let failures = failures(&env.cli(&[]), &args).unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].message, "`.editor.fontSize` is 12, expected 14");
+        assert!(env.run(&["test"]).is_err());
+        assert!(!env.target_dir.path().join(".expected.json").exists());
+    }
+
+    #[test]
+    fn golden_test() {
+        let env = TestEnv::new();
+        let golden = tempdir_in(env._root.path()).unwrap();
+        let golden_dir = golden.path().to_str().unwrap();
+        let d = env.create_patch_dir("dot-config/gold.toml.d");
+        env.write_named_patch_file(&d, "00-a.toml", b"a = 1");
+
+        assert!(env.run(&["test", "--golden", golden_dir]).is_err());
+        env.run(&["test", "--golden", golden_dir, "--bless"]).unwrap();
+        assert_eq!(std::fs::read_to_string(golden.path().join(".config/gold.toml")).unwrap(),
+            "a = 1\n");
+        env.run(&["test", "--golden", golden_dir]).unwrap();
+
+        env.write_named_patch_file(&d, "10-b.toml", b"a = 2");
+        let args = TestArgs { expectations: None, golden: Some(golden.path().into()), bless: false };
+        let failures = failures(&env.cli(&[]), &args).unwrap();
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].message.contains("-a = 1\n+a = 2"));
+        assert!(!env.target_dir.path().join(".config/gold.toml").exists());
+    }
+
+    #[test]
+    fn fish_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-config/fish/config.fish.d");
+        env.write_named_patch_file(&d, "00-base.fish",
+            b"set -gx EDITOR vi\nset -U fish_greeting hi\nalias ll 'ls -l'\n");
+        env.write_named_patch_file(&d, "10-host.fish", b"set -g -x EDITOR nvim\n");
+        let v = env.create_patch_dir("dot-config/fish/fish_variables.d");
+        let header = "# This file contains fish universal variable definitions.\n# VERSION: 3.0\n";
+        env.write_named_patch_file(&v, "00-a",
+            format!("{header}SETUVAR fish_greeting:hi\nSETUVAR --export PAGER:less\n").as_bytes());
+        env.write_named_patch_file(&v, "10-b",
+            format!("{header}SETUVAR --export PAGER:most\n").as_bytes());
+        std::fs::create_dir_all(env.target_dir.path().join(".config/fish")).unwrap();
+        env.run_patch();
+
+        assert_eq!(env.read_target_file(".config/fish/config.fish"),
+            "set -g -x EDITOR nvim\nset -U fish_greeting hi\nalias ll 'ls -l'\n");
+        assert_eq!(env.read_target_file(".config/fish/fish_variables"),
+            format!("{header}SETUVAR fish_greeting:hi\nSETUVAR --export PAGER:most\n"));
+    }
+
+    #[test]
+    fn yaml_comments_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-commented.yaml.d");
+        env.write_named_patch_file(&d, "00-a.yaml",
+            b"# Window\nwindow:\n  # Pixels\n  width: 1\n  height: 1\nfont: mono\n");
+        env.write_named_patch_file(&d, "10-b.yaml",
+            b"window:\n  # Wider\n  width: 2\n# Keys\nkeys: [a]\n");
+        env.run_patch();
+
+        assert_eq!(env.read_target_file(".commented.yaml"), "\
+            # Window\nwindow:\n  # Wider\n  width: 2\n  height: 1\nfont: mono\n\
+            # Keys\nkeys:\n- a\n");
+
+        // Comments ending lines, on sequence items and after the last key.
+        let d = env.create_patch_dir("dot-inline.yaml.d");
+        env.write_named_patch_file(&d, "00-a.yaml", b"\
+            url: \"http://x/#a\"  # quoted\nport: 80 # web\n\
+            hosts:\n  # Primary\n  - a # first\n  - name: b  # second\n    port: 2\n\
+            # end\n");
+        env.write_named_patch_file(&d, "10-b.yaml", b"port: 8080  # proxy\n");
+        env.write_named_patch_file(&d, "20-c.yaml", b"tags:\n- x # old\n");
+        env.write_named_patch_file(&d, "30-d.yaml", b"tags:\n- y\n");
+        env.run_patch();
+
+        assert_eq!(env.read_target_file(".inline.yaml"), "\
+            url: http://x/#a  # quoted\nport: 8080  # proxy\n\
+            hosts:\n# Primary\n- a  # first\n- name: b  # second\n  port: 2\n\
+            tags:\n- y\n# end\n");
+    }
+
+    #[test]
+    fn i3config_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-config/i3/config.d");
+        env.write_named_patch_file(&d, "00-base", b"\
+            set $mod Mod4\n\
+            bindsym $mod+Return exec xterm\n\
+            bindsym --release $mod+x kill\n\
+            exec --no-startup-id nm-applet\n\
+            mode \"resize\" {\n    bindsym h resize shrink width 10 px\n}\n");
+        env.write_named_patch_file(&d, "10-host", b"\
+            set $mod Mod1\n\
+            bindsym $mod+Return exec alacritty\n\
+            exec --no-startup-id nm-applet\n\
+            mode \"resize\" {\n    bindsym h resize shrink width 20 px\n}\n");
+        std::fs::create_dir_all(env.target_dir.path().join(".config/i3")).unwrap();
+        env.run_patch();
+
+        assert_eq!(env.read_target_file(".config/i3/config"), "\
+            set $mod Mod1\n\
+            bindsym $mod+Return exec alacritty\n\
+            bindsym --release $mod+x kill\n\
+            exec --no-startup-id nm-applet\n\
+            mode \"resize\" {\n    bindsym h resize shrink width 10 px\n}\n\
+            mode \"resize\" {\n    bindsym h resize shrink width 20 px\n}\n");
+    }
+
+    #[test]
+    fn depth_test() {
+        let env = TestEnv::new();
+        let top = env.create_patch_dir("top.d");
+        env.write_named_patch_file(&top, "a", b"top\n");
+        let nested = env.create_patch_dir("deep/nested.d");
+        env.write_named_patch_file(&nested, "a", b"nested\n");
+        std::fs::create_dir_all(env.target_dir.path().join("deep")).unwrap();
+
+        env.run(&["--max-depth", "1"]).unwrap();
+        assert_eq!(env.read_target_file("top"), "top\n");
+        assert!(!env.target_dir.path().join("deep/nested").exists());
+
+        env.run(&["--min-depth", "2"]).unwrap();
+        assert_eq!(env.read_target_file("deep/nested"), "nested\n");
+    }
+
+    #[test]
+    fn symlink_test() {
+        use std::os::unix::fs::symlink;
+
+        let env = TestEnv::new();
+        let shared = tempdir().unwrap();
+        let linked = shared.path().join("linked.d");
+        std::fs::create_dir(&linked).unwrap();
+        std::fs::write(linked.join("a"), b"shared\n").unwrap();
+        symlink(&linked, linked.join("again")).unwrap();
+        symlink(shared.path(), env.patch_dir.path().join("shared")).unwrap();
+
+        let own = env.create_patch_dir("own.d");
+        symlink(linked.join("a"), own.join("a")).unwrap();
+        std::fs::create_dir(env.target_dir.path().join("shared")).unwrap();
+        env.run_patch();
+
+        assert_eq!(env.read_target_file("shared/linked"), "shared\n");
+        assert_eq!(env.read_target_file("own"), "shared\n");
+    }
+
+    #[test]
+    fn extension_case_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("settings.JSON.d");
+        env.write_named_patch_file(&d, "00-a.Json", br#"{"a": 1}"#);
+        env.write_named_patch_file(&d, "10-port.JSON", b"8080");
+        env.run_patch();
+
+        let json = env.read_target_json("settings.JSON");
+        assert_eq!(json["a"], 1);
+        assert_eq!(json["port"], 8080);
+    }
+
+    #[test]
+    fn failure_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("broken.json.d");
+        env.write_named_patch_file(&d, "a.json", b"{");
+        let error = env.run(&[]).unwrap_err();
+        assert_eq!(Failure::of(&error), ("parse", 4));
+
+        env.write_named_patch_file(&d, "a.json", br#"{"a": 1}"#);
+        env.write_named_patch_file(&d, "00-lock.final.json", br#"{"a": 0}"#);
+        let error = env.run(&[]).unwrap_err();
+        assert_eq!(Failure::of(&error), ("conflict", 5));
+
+        env.write_named_patch_file(&d, "b.toml", br#"{"b": 2}"#);
+        let error = env.run(&["lint"]).unwrap_err();
+        assert_eq!(Failure::of(&error).0, "validation");
+    }
+
+    #[test]
+    fn logger_test() {
+        assert!(!colors(true, true, true));
+        assert!(colors(false, true, false));
+        assert!(colors(false, false, true));
+        assert!(!colors(false, false, false));
+
+        colored::control::set_override(false);
+        let logger = Logger { level: Info, offset: time::UtcOffset::UTC };
+        let line = logger.line(&log::Record::builder()
+            .level(Warn)
+            .target("patch")
+            .args(format_args!("Skipping"))
+            .build());
+        assert!(line.ends_with(" WARN  [patch] Skipping"), "{line}");
+    }
+
+    #[test]
+    fn diff_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-editor.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"editor": {"fontSize": 14}}"#);
+        let target = env.write_target_file(".editor.json",
+            br#"{"editor": {"fontSize": 12, "wrap": true}}"#);
+        let entry = entry_of(&env.cli(&[]), &target).unwrap();
+
+        let before = r#"{"editor": {"fontSize": 12, "wrap": true}}"#;
+        let after = r#"{"editor": {"fontSize": 14, "tabSize": 4}}"#;
+        assert_eq!(diff(&entry, before, after).unwrap(), format!("{}\n\
+            ~ .editor.fontSize: 12 -> 14\n\
+            + .editor.tabSize: 4\n\
+            - .editor.wrap: true\n", target.display()));
+        // Formatting alone is no change.
+        assert_eq!(diff(&entry, before, &before.replace(' ', "")).unwrap(), "");
+    }
+
+    #[test]
+    fn stats_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-editor.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"editor": {"fontSize": 14}}"#);
+        env.write_named_patch_file(&d, "10-b.json", br#"{"editor": {"tabSize": 4}}"#);
+        let target = env.write_target_file(".editor.json", b"");
+        let entry = entry_of(&env.cli(&[]), &target).unwrap();
+
+        let before = r#"{"editor": {"fontSize": 12, "wrap": true}}"#;
+        let after = r#"{"editor": {"fontSize": 14, "tabSize": 4}}"#;
+        assert_eq!(Stats::new(&entry, before, after).unwrap(), Stats {
+            target: target.clone(), fragments: 2, added: 1, overridden: 1, removed: 1, timings: None,
+        });
+
+        let d = env.create_patch_dir("notes.d");
+        env.write_named_patch_file(&d, "a", b"one\n");
+        let target = env.write_target_file("notes", b"");
+        let entry = entry_of(&env.cli(&[]), &target).unwrap();
+        let stats = Stats::new(&entry, "one\ntwo\n", "one\nthree\nfour\n").unwrap();
+        assert_eq!((stats.added, stats.overridden, stats.removed), (2, 0, 1));
+    }
+
+    #[test]
+    fn csv_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("contacts.csv.d");
+        env.write_named_patch_file(&d, "KEY", b"email\n");
+        env.write_named_patch_file(&d, "00-base", b"\
+            name,email\n\
+            Ann,ann@example.com\n\
+            Bob,bob@example.com\n");
+        env.write_named_patch_file(&d, "10-update", b"\
+            name,email\n\
+            \"Bob, Jr.\",bob@example.com\n\
+            Cy,cy@example.com\n");
+        env.run_patch();
+        assert_eq!(env.read_target_file("contacts.csv"), "\
+            name,email\n\
+            Ann,ann@example.com\n\
+            \"Bob, Jr.\",bob@example.com\n\
+            Cy,cy@example.com\n");
+
+        let d = env.create_patch_dir("words.tsv.d");
+        env.write_named_patch_file(&d, "00-a", b"cat\tnoun\nrun\tverb\n");
+        env.write_named_patch_file(&d, "10-b", b"run\tnoun\n");
+        env.run_patch();
+        assert_eq!(env.read_target_file("words.tsv"), "cat\tnoun\nrun\tnoun\n");
+    }
+
+    #[test]
+    fn hjson_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("app.hjson.d");
+        env.write_named_patch_file(&d, "00-a.hjson", b"\
+            {\n\
+              # Comments and missing commas are fine.\n\
+              name: app\n\
+              motd:\n\
+                '''\n\
+                Hello\n\
+                World\n\
+                '''\n\
+              ports: [80, 443]\n\
+            }\n");
+        env.write_named_patch_file(&d, "10-b.hjson", b"{\n  name: other\n}\n");
+        env.run_patch();
+
+        let json = env.read_target_json("app.hjson");
+        assert_eq!(json["name"], "other");
+        assert_eq!(json["motd"], "Hello\nWorld");
+        assert_eq!(json["ports"], serde_json::json!([80, 443]));
+    }
+
+    #[test]
+    fn edn_test() {
+        let env = TestEnv::new();
+        std::fs::write(env.patch_dir.path().join("patch.toml"), "vectors = \"union\"\n")
+            .unwrap();
+        let d = env.create_patch_dir("dot-clojure/deps.edn.d");
+        env.write_named_patch_file(&d, "00-base.edn", br#"
+            ;; Shared deps
+            {:paths ["src"]
+             :deps {org.clojure/clojure {:mvn/version "1.11.1"}}
+             :aliases {:test {:extra-paths ["test"]}}}
+        "#);
+        env.write_named_patch_file(&d, "10-host.edn", br#"
+            {:paths ["src" "resources"],
+             :deps {org.clojure/clojure {:mvn/version "1.12.0"}
+                    #_#_ignored/dep {:mvn/version "0"}
+                    cider/nrepl {:mvn/version "1.0" :exclusions #{a/b}}}}
+        "#);
+        std::fs::create_dir_all(env.target_dir.path().join(".clojure")).unwrap();
+        env.run_patch();
+
+        assert_eq!(env.read_target_file(".clojure/deps.edn"), "\
+            {:paths [\"src\" \"resources\"]\n \
+             :deps {org.clojure/clojure {:mvn/version \"1.12.0\"}\n        \
+                    cider/nrepl {:mvn/version \"1.0\"\n                     \
+                                 :exclusions #{a/b}}}\n \
+             :aliases {:test {:extra-paths [\"test\"]}}}\n");
+        assert!(Edn::parse("{:a}").is_err());
+        assert!(Edn::parse("[1 2").is_err());
+    }
+
+    #[test]
+    fn lua_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-wezterm.lua.d");
+        env.write_named_patch_file(&d, "00-base.lua", br##"
+local wezterm = require 'wezterm'
+
+-- Shared settings
+return {
+  font = wezterm.font("JetBrains Mono", { weight = "Bold" }),
+  font_size = 12.0,
+  colors = { foreground = "#ffffff", background = "#000000" },
+  ["window-title"] = [[a, b]],
+  keys = { "a", "b" },
+}
+"##);
+        env.write_named_patch_file(&d, "10-host.lua", br##"
+local wezterm = require 'wezterm'
+local act = wezterm.action
+return {
+  font_size = 14.0; --[[ larger on this host ]]
+  colors = { background = '#111111' },
+  keys = { { key = "t", action = act.SpawnTab "CurrentPaneDomain" } },
+}
+"##);
+        env.run_patch();
+
+        assert_eq!(env.read_target_file(".wezterm.lua"), "\
+local wezterm = require 'wezterm'
+-- Shared settings
+local act = wezterm.action
+
+return {
+  font = wezterm.font(\"JetBrains Mono\", { weight = \"Bold\" }),
+  font_size = 14.0,
+  colors = {
+    foreground = \"#ffffff\",
+    background = '#111111',
+  },
+  [\"window-title\"] = [[a, b]],
+  keys = {
+    {
+      key = \"t\",
+      action = act.SpawnTab \"CurrentPaneDomain\",
+    },
+  },
+}
+");
+        assert!(LuaConfig::parse("local x = 1").is_err());
+        assert!(LuaConfig::parse("return { a = 1").is_err());
+    }
+
+    #[test]
+    fn vim_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-vimrc.d");
+        env.write_named_patch_file(&d, "00-base", b"\
+            set number\n\
+            set tabstop=8\n\
+            set path+=**\n\
+            let g:mapleader = ','\n\
+            function! Trim()\n\
+            \x20 let save = winsaveview()\n\
+            endfunction\n");
+        env.write_named_patch_file(&d, "10-host", b"\
+            set nonumber\n\
+            set tabstop=4 \" narrower\n\
+            set path+=**\n\
+            let g:mapleader=' '\n\
+            let g:count == 1\n");
+        env.run_patch();
+
+        assert_eq!(env.read_target_file(".vimrc"), "\
+            set nonumber\n\
+            set tabstop=4 \" narrower\n\
+            set path+=**\n\
+            let g:mapleader=' '\n\
+            function! Trim()\n\
+            \x20 let save = winsaveview()\n\
+            endfunction\n\
+            set path+=**\n\
+            let g:count == 1\n");
+    }
+
+    #[test]
+    fn xresources_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-Xresources.d");
+        env.write_named_patch_file(&d, "00-colors", b"\
+            ! Colors\n\
+            #include \".Xresources.d/base\"\n\
+            *background: #000000\n\
+            *foreground:\t#ffffff\n");
+        env.write_named_patch_file(&d, "10-urxvt", b"\
+            ! Colors\n\
+            #include \".Xresources.d/base\"\n\
+            *background:  #111111\n\
+            URxvt.font: xft:monospace:size=10\n");
+        env.run_patch();
+
+        assert_eq!(env.read_target_file(".Xresources"), "\
+            ! Colors\n\
+            #include \".Xresources.d/base\"\n\
+            *background:  #111111\n\
+            *foreground:\t#ffffff\n\
+            URxvt.font: xft:monospace:size=10\n");
+    }
+
+    #[test]
+    fn desktop_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-local/share/applications/editor.desktop.d");
+        env.write_named_patch_file(&d, "00-exported.desktop", b"\
+            [Desktop Entry]\n\
+            Type=Application\n\
+            Name=Editor\n\
+            Name[de]=Bearbeiter\n\
+            Exec=editor %F\n\
+            Categories=Development;TextEditor;\n\
+            \n\
+            [Desktop Action new-window]\n\
+            Name=New Window\n\
+            Exec=editor --new-window\n");
+        env.write_named_patch_file(&d, "10-override.desktop", b"\
+            [Desktop Entry]\n\
+            Exec=editor --wayland %F\n\
+            Name[de]=Editor\n\
+            Categories=Development;Utility\n");
+        std::fs::create_dir_all(env.target_dir.path().join(".local/share/applications"))
+            .unwrap();
+        env.run_patch();
+
+        assert_eq!(env.read_target_file(".local/share/applications/editor.desktop"), "\
+            [Desktop Entry]\n\
+            Type=Application\n\
+            Name=Editor\n\
+            Name[de]=Editor\n\
+            Exec=editor --wayland %F\n\
+            Categories=Development;Utility;\n\
+            \n\
+            [Desktop Action new-window]\n\
+            Name=New Window\n\
+            Exec=editor --new-window\n");
+    }
+
+    #[test]
+    fn mimeapps_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-config/mimeapps.list.d");
+        env.write_named_patch_file(&d, "00-base", b"\
+            [Default Applications]\n\
+            text/plain=editor.desktop\n\
+            text/html=firefox.desktop;\n\
+            \n\
+            [Added Associations]\n\
+            image/png=viewer.desktop;\n");
+        env.write_named_patch_file(&d, "10-host", b"\
+            [Added Associations]\n\
+            image/png=gimp.desktop;viewer.desktop;\n\
+            image/svg+xml=inkscape.desktop\n");
+        std::fs::create_dir_all(env.target_dir.path().join(".config")).unwrap();
+        env.run_patch();
+
+        assert_eq!(env.read_target_file(".config/mimeapps.list"), "\
+            [Default Applications]\n\
+            text/plain=editor.desktop;\n\
+            text/html=firefox.desktop;\n\
+            \n\
+            [Added Associations]\n\
+            image/png=viewer.desktop;gimp.desktop;\n\
+            image/svg+xml=inkscape.desktop;\n");
+    }
+
+    #[test]
+    fn fontconfig_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-config/fontconfig/fonts.conf.d");
+        env.write_named_patch_file(&d, "00-base", br#"<?xml version="1.0"?>
+<!DOCTYPE fontconfig SYSTEM "urn:fontconfig:fonts.dtd">
+<fontconfig>
+  <dir>~/.fonts</dir>
+  <alias>
+    <family>monospace</family>
+    <prefer><family>DejaVu Sans Mono</family></prefer>
+  </alias>
+  <match target="font">
+    <edit name="antialias" mode="assign"><bool>true</bool></edit>
+  </match>
+</fontconfig>
+"#);
+        env.write_named_patch_file(&d, "10-host", br#"<fontconfig>
+  <!-- Host fonts -->
+  <dir>~/.fonts</dir>
+  <alias>
+    <family>monospace</family>
+    <prefer><family>Iosevka</family></prefer>
+  </alias>
+  <match target="pattern">
+    <test name="family"><string>Helvetica</string></test>
+    <edit name="family" mode="assign"><string>Arial</string></edit>
+  </match>
+</fontconfig>
+"#);
+        std::fs::create_dir_all(env.target_dir.path().join(".config/fontconfig")).unwrap();
+        env.run_patch();
+
+        assert_eq!(env.read_target_file(".config/fontconfig/fonts.conf"), r#"<?xml version="1.0"?>
+<!DOCTYPE fontconfig SYSTEM "urn:fontconfig:fonts.dtd">
+<fontconfig>
+  <dir>~/.fonts</dir>
+  <alias>
+    <family>monospace</family>
+    <prefer><family>Iosevka</family></prefer>
+  </alias>
+  <match target="font">
+    <edit name="antialias" mode="assign"><bool>true</bool></edit>
+  </match>
+  <!-- Host fonts -->
+  <match target="pattern">
+    <test name="family"><string>Helvetica</string></test>
+    <edit name="family" mode="assign"><string>Arial</string></edit>
+  </match>
+</fontconfig>
+"#);
+    }
+
+    #[test]
+    fn awsconfig_test() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let env = TestEnv::new();
+        std::fs::create_dir(env.target_dir.path().join(".aws")).unwrap();
+        let d = env.create_patch_dir("dot-aws/credentials.d");
+        env.write_named_patch_file(&d, "00-work", b"[work]\naws_access_key_id = A\n");
+        env.write_named_patch_file(&d, "10-home", b"[home]\naws_access_key_id = B\n");
+        let c = env.create_patch_dir("dot-aws/config.d");
+        env.write_named_patch_file(&c, "00-work", b"[profile work]\nregion = eu-west-1\n");
+        env.write_named_patch_file(&c, "10-more", b"[profile work]\nregion = eu-west-1\noutput = json\n");
+        env.run_patch();
+
+        assert_eq!(env.read_target_file(".aws/config"),
+            "[profile work]\nregion = eu-west-1\noutput = json\n");
+        let credentials = env.target_dir.path().join(".aws/credentials");
+        assert_eq!(std::fs::read_to_string(&credentials).unwrap(),
+            "[work]\naws_access_key_id = A\n\n[home]\naws_access_key_id = B\n");
+        let mode = std::fs::metadata(&credentials).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        env.write_named_patch_file(&c, "20-clobber", b"[profile work]\nregion = us-east-1\n");
+        let error = env.run(&[]).unwrap_err();
+        assert_eq!(Failure::of(&error).0, "conflict");
+        assert!(format!("{error:#}").contains("`region` of profile `work`"), "{error:#}");
+    }
+
+    #[test]
+    fn kubeconfig_test() {
+        let env = TestEnv::new();
+        std::fs::create_dir(env.target_dir.path().join(".kube")).unwrap();
+        let d = env.create_patch_dir("dot-kube/config.d");
+        env.write_named_patch_file(&d, "00-work", b"clusters:
+- name: work
+  cluster:
+    server: https://work
+contexts:
+- name: work
+  context:
+    cluster: work
+current-context: work
+");
+        env.write_named_patch_file(&d, "10-home", b"clusters:
+- name: home
+  cluster:
+    server: https://home
+- name: work
+  cluster:
+    insecure-skip-tls-verify: true
+current-context: home
+");
+        env.run_patch();
+
+        assert_eq!(env.read_target_file(".kube/config"), "clusters:
+- name: work
+  cluster:
+    server: https://work
+    insecure-skip-tls-verify: true
+- name: home
+  cluster:
+    server: https://home
+contexts:
+- name: work
+  context:
+    cluster: work
+current-context: work
+");
+    }
+
+    #[test]
+    fn reg_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("settings.reg.d");
+        env.write_named_patch_file(&d, "00-base", br#"Windows Registry Editor Version 5.00
+
+; Explorer
+[HKEY_CURRENT_USER\Software\Demo]
+"Theme"="light"
+@="default"
+
+[HKEY_CURRENT_USER\Software\Demo\Cache]
+"Size"=dword:00000010
+"#);
+        env.write_named_patch_file(&d, "10-override", br#"Windows Registry Editor Version 5.00
+
+[HKEY_CURRENT_USER\Software\demo]
+"theme"="dark"
+"Data"=hex:01,02,\
+  03
+"Old"=-
+
+[-HKEY_CURRENT_USER\Software\Demo]
+
+[HKEY_CURRENT_USER\Software\Demo]
+"Fresh"="yes"
+"#);
+        env.run_patch();
+
+        assert_eq!(env.read_target_file("settings.reg"), r#"Windows Registry Editor Version 5.00
+
+[-HKEY_CURRENT_USER\Software\Demo]
+
+[HKEY_CURRENT_USER\Software\Demo]
+"Fresh"="yes"
+"#);
+
+        let d = env.create_patch_dir("other.reg.d");
+        env.write_named_patch_file(&d, "00-base", &std::fs::read(env.patch_dir.path()
+            .join("settings.reg.d/00-base")).unwrap());
+        env.write_named_patch_file(&d, "10-override", br#"Windows Registry Editor Version 5.00
+
+[HKEY_CURRENT_USER\Software\demo]
+"theme"="dark"
+"Data"=hex:01,02,\
+  03
+"Old"=-
+"#);
+        env.run_patch();
+
+        assert_eq!(env.read_target_file("other.reg"), r#"Windows Registry Editor Version 5.00
+
+[HKEY_CURRENT_USER\Software\Demo]
+"theme"="dark"
+@="default"
+"Data"=hex:01,02,03
+"Old"=-
+
+[HKEY_CURRENT_USER\Software\Demo\Cache]
+"Size"=dword:00000010
+"#);
+    }
+
+    #[test]
+    fn matrix_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("bundle.d");
+        env.write_named_patch_file(&d, "matrix.toml", br#"
+            target = "bundles/{{host}}/{{profile}}.json"
+            [variables]
+            profile = ["work", "home"]
+            host = ["a", "b"]
+        "#);
+        env.write_named_patch_file(&d, "00-a", br#"{"name": "{{profile}}@{{host}}"}"#);
+        for host in ["a", "b"] {
+            std::fs::create_dir_all(env.target_dir.path().join("bundles").join(host)).unwrap();
+        }
+        env.run_patch();
+
+        for (host, profile) in [("a", "work"), ("a", "home"), ("b", "work"), ("b", "home")] {
+            assert_eq!(env.read_target_json(&format!("bundles/{host}/{profile}.json")),
+                serde_json::json!({"name": format!("{profile}@{host}")}));
+        }
+        assert!(!env.target_dir.path().join("bundle").exists());
+    }
+
+    #[test]
+    fn users_test() {
+        use std::os::unix::fs::MetadataExt;
+
+        let env = TestEnv::new();
+        let name = duct::cmd!("id", "-un").read().unwrap();
+        let home = duct::cmd!("getent", "passwd", &name).read().unwrap()
+            .split(':').nth(5).unwrap().to_string();
+        let root = tempdir_in(env._root.path()).unwrap();
+        let home = root.path().join(home.trim_start_matches('/'));
+        std::fs::create_dir_all(&home).unwrap();
+        let d = env.create_patch_dir("dot-multi.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"a": 1}"#);
+
+        env.run(&["--root", root.path().to_str().unwrap(), "--user", &name]).unwrap();
+        let target = home.join(".multi.json");
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "{\n  \"a\": 1\n}");
+        let uid = duct::cmd!("id", "-u").read().unwrap().parse::<u32>().unwrap();
+        assert_eq!(std::fs::metadata(&target).unwrap().uid(), uid);
+        assert!(!env.target_dir.path().join(".multi.json").exists());
+        assert!(env.run(&["--user", "no-such-user-here"]).is_err());
+    }
+
+    #[test]
+    fn expand_path_test() {
+        let home = std::env::var("HOME").unwrap();
+        let name = duct::cmd!("id", "-un").read().unwrap();
+        let user_home = duct::cmd!("getent", "passwd", &name).read().unwrap()
+            .split(':').nth(5).unwrap().to_string();
+
+        let expand = |path: &str| expand_path(Path::new(path)).unwrap();
+        assert_eq!(expand("~"), Path::new(&home));
+        assert_eq!(expand("~/patches"), Path::new(&home).join("patches"));
+        assert_eq!(expand(&format!("~{name}/x")), Path::new(&user_home).join("x"));
+        assert_eq!(expand("$HOME/a/${HOME}"), Path::new(&format!("{home}/a/{home}")));
+        assert_eq!(expand("/etc/~x"), Path::new("/etc/~x"));
+        assert!(expand_path(Path::new("$PATCH_SURELY_UNSET/x")).is_err());
+    }
+
+    #[test]
+    fn render_as_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("app.toml.d");
+        env.write_named_patch_file(&d, "RENDER_AS", b"json\nyml\n");
+        env.write_named_patch_file(&d, "00-a.toml", b"[server]\nport = 80\n");
+        env.write_named_patch_file(&d, "10-server.host.toml", b"\"example.com\"");
+        env.write_target_file("app.json", br#"{"stale": true}"#);
+        env.run_patch();
+
+        assert_eq!(env.read_target_file("app.toml"),
+            "[server]\nhost = \"example.com\"\nport = 80\n");
+        assert_eq!(env.read_target_json("app.json"),
+            serde_json::json!({"server": {"port": 80, "host": "example.com"}}));
+        assert_eq!(env.read_target_file("app.yml"),
+            "server:\n  port: 80\n  host: example.com\n");
+        env.run(&["lint"]).unwrap();
+    }
+
+    #[test]
+    fn aliases_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-config/nvim/settings.json.d");
+        env.write_named_patch_file(&d, "ALIASES", b".config/nvim-nightly/settings.json\n");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"a": 1}"#);
+        for directory in [".config/nvim", ".config/nvim-nightly"] {
+            std::fs::create_dir_all(env.target_dir.path().join(directory)).unwrap();
+        }
+        env.write_target_file(".config/nvim-nightly/settings.json", br#"{"b": 2}"#);
+        env.run_patch();
+
+        assert_eq!(env.read_target_json(".config/nvim/settings.json"),
+            serde_json::json!({"a": 1}));
+        assert_eq!(env.read_target_json(".config/nvim-nightly/settings.json"),
+            serde_json::json!({"b": 2, "a": 1}));
+
+        // `~` is the target directory, not the home of the user running.
+        let env = TestEnv::new();
+        let target = tempdir_in(env._root.path()).unwrap();
+        let t = env.create_patch_dir("tilde.json.d");
+        env.write_named_patch_file(&t, "ALIASES", b"~/tilde-alias.json\n");
+        env.write_named_patch_file(&t, "00-a.json", br#"{"a": 1}"#);
+        let mut cli = env.cli(&[]);
+        cli.target = target.path().into();
+        start(cli).unwrap();
+        let alias = std::fs::read_to_string(target.path().join("tilde-alias.json")).unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&alias).unwrap(),
+            serde_json::json!({"a": 1}));
+    }
+
+    #[test]
+    fn confine_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("a.json.d");
+        // Next to the target directory, unique to the test.
+        let name = format!("{}.json", env.target_dir.path().file_name().unwrap().to_string_lossy());
+        env.write_named_patch_file(&d, "ALIASES", format!("../{name}\n").as_bytes());
+        env.write_named_patch_file(&d, "00-a.json", br#"{"a": 1}"#);
+        let escaped = env.target_dir.path().parent().unwrap().join(name);
+
+        let error = env.run(&[]).unwrap_err();
+        assert!(format!("{error:#}").contains("--allow-outside-target"));
+        assert!(!escaped.exists());
+        assert!(!env.target_dir.path().join("a.json").exists());
+
+        let cli = env.cli(&[]);
+        assert!(cli.confine(&env.target_dir.path().join("x/../y")).is_ok());
+        assert!(cli.confine(Path::new("/etc/passwd")).is_err());
+
+        env.run(&["--allow-outside-target"]).unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(
+            &std::fs::read_to_string(&escaped).unwrap()).unwrap(),
+            serde_json::json!({"a": 1}));
+        std::fs::remove_file(escaped).unwrap();
+    }
+
+    #[test]
+    fn export_outside_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("a.json.d");
+        env.write_named_patch_file(&d, "ALIASES", b"../outside.json\nsub/../inside.json\n");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"a": 1}"#);
+
+        // Targets outside the target directory are left out of archives.
+        let out = env._root.path().join("export.tar");
+        env.run(&["--allow-outside-target", "export", "--format", "tar",
+            "-o", out.to_str().unwrap()]).unwrap();
+        let mut archive = tar::Archive::new(std::fs::File::open(&out).unwrap());
+        let mut paths: Vec<_> = archive.entries().unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().into_owned())
+            .collect();
+        paths.sort();
+        assert_eq!(paths, [Path::new("a.json"), Path::new("inside.json")]);
+    }
+
+    #[test]
+    fn sensitive_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("app.json.d");
+        env.write_named_patch_file(&d, "SENSITIVE", b".db.password\n");
+        env.write_named_patch_file(&d, "00-a.json",
+            br#"{"db": {"user": "me", "password": "hunter2"}}"#);
+        env.write_named_patch_file(&d, "10-token.sensitive.json", br#"{"token": "abc"}"#);
+        let target = env.write_target_file("app.json", br#"{"db": {"user": "you"}}"#);
+        let entry = entry_of(&env.cli(&[]), &target).unwrap();
+
+        let before = env.read_target_file("app.json");
+        let diff = diff(&entry, &before, &rendered(&env.cli(&[]), &entry).unwrap()).unwrap();
+        assert_eq!(diff, format!("{}\n\
+            ~ .db.user: \"you\" -> \"me\"\n\
+            + .db.password: \"<redacted>\"\n\
+            + .token: \"<redacted>\"\n", target.display()));
+
+        let db: KeyPath = ".db".parse().unwrap();
+        let value = serde_json::json!({"user": "me", "password": "hunter2"});
+        assert_eq!(redact(&db, &value, &entry.sensitive().unwrap()),
+            serde_json::json!({"user": "me", "password": "<redacted>"}));
+
+        env.write_named_patch_file(&d, "10-token.sensitive.json", b"{\"token\": abc");
+        let error = env.run(&[]).unwrap_err();
+        assert!(!format!("{error:#}").contains("abc"), "{error:#}");
+        assert_eq!(Failure::of(&error).1, 4);
+    }
+
+    #[test]
+    fn resolve_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("app.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"a": 1, "b": 2, "c": 3, "d": 4}"#);
+        let target = env.write_target_file("app.json", br#"{"a": 0, "b": 0, "c": 0}"#);
+        let entry = entry_of(&env.cli(&[]), &target).unwrap();
+        let before = env.read_target_file("app.json");
+        let after = rendered(&env.cli(&[]), &entry).unwrap();
+
+        let mut output = vec![];
+        let text = resolve(&entry, &before, after, &b"t\nx\n\nT\n"[..], &mut output).unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+            serde_json::json!({"a": 0, "b": 2, "c": 0, "d": 4}));
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("~ .a: 0 -> 1\nTake [f]ragments or keep [t]arget"), "{output}");
+
+        let d = env.create_patch_dir("notes.d");
+        env.write_named_patch_file(&d, "00-a", b"one\ntwo\nthree\n");
+        let target = env.write_target_file("notes", b"one\n2\nthree\n");
+        let entry = entry_of(&env.cli(&["--no-seed"]), &target).unwrap();
+        let after = rendered(&env.cli(&["--no-seed"]), &entry).unwrap();
+        let text = resolve(&entry, "one\n2\nthree\n", after, &b"t\n"[..], std::io::sink());
+        assert_eq!(text.unwrap(), "one\n2\nthree\n");
+        assert!(resolve(&entry, "1\n", "one\n".into(), &b""[..], std::io::sink()).is_err());
+    }
+
+    #[test]
+    fn ui_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("a.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"a": 1}"#);
+        let d = env.create_patch_dir("b.json.d");
+        env.write_named_patch_file(&d, "00-b.json", br#"{"b": 1}"#);
+        env.run_patch();
+        env.write_target_file("a.json", br#"{"a": 2}"#);
+        let d = env.create_patch_dir("c.json.d");
+        env.write_named_patch_file(&d, "00-c.json", br#"{"c": 1}"#);
+
+        let mut statuses = statuses(&env.cli(&[])).unwrap();
+        statuses.sort_by(|x, y| x.entry.target.cmp(&y.entry.target));
+        let [a, b, c] = &statuses[..] else { panic!() };
+        assert!(a.drifted && a.applied.is_some());
+        assert_eq!(a.diff.as_ref().unwrap().lines().nth(1), Some("~ .a: 2 -> 1"));
+        assert!(!b.drifted && b.diff.as_ref().unwrap().is_empty());
+        assert!(!c.drifted && c.applied.is_none());
+
+        let mut terminal = ratatui::Terminal::new(ratatui::backend::TestBackend::new(120, 10))
+            .unwrap();
+        let mut list = ratatui::widgets::ListState::default().with_selected(Some(0));
+        terminal.draw(|frame| draw_ui(frame, &statuses, &mut list, 0, "")).unwrap();
+        let screen = format!("{:?}", terminal.backend().buffer());
+        for text in ["drift", "ok", "pending", "never", "~ .a: 2 -> 1"] {
+            assert!(screen.contains(text), "{text} in {screen}");
+        }
+    }
+
+    #[test]
+    fn trash_backups_test() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let env = TestEnv::new();
+        // Keep the copy out of the real trash.
+        let data = env._root.path().join("data");
+        unsafe {
+            std::env::set_var("XDG_DATA_HOME", &data);
+        }
+        std::fs::write(env.patch_dir.path().join("patch.toml"),
+            b"[backups]\nenabled = true\ntrash = true\n").unwrap();
+        let target = env.write_target_file("app.json", br#"{"v": 0}"#);
+        std::fs::set_permissions(&target, PermissionsExt::from_mode(0o640)).unwrap();
+        let d = env.create_patch_dir("app.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"v": 1}"#);
+        // Symlinked targets, as deployed by stow, are written through.
+        let real = env._root.path().join("linked.json");
+        std::fs::write(&real, br#"{"v": 0}"#).unwrap();
+        let link = env.target_dir.path().join("linked.json");
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+        let d = env.create_patch_dir("linked.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"v": 1}"#);
+        env.run_patch();
+
+        assert_eq!(env.read_target_json("app.json")["v"], 1);
+        let mode = std::fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+        assert!(link.symlink_metadata().unwrap().is_symlink());
+        assert_eq!(env.read_target_json("linked.json")["v"], 1);
+        assert_eq!(std::fs::read_to_string(&real).unwrap(), env.read_target_file("linked.json"));
+        for name in ["app.json", "linked.json"] {
+            let trashed = data.join("Trash/files").join(name);
+            assert_eq!(std::fs::read_to_string(trashed).unwrap(), r#"{"v": 0}"#);
+        }
+        // No scratch directory is left beside the targets.
+        assert_eq!(std::fs::read_dir(env.target_dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn audit_test() {
+        use sha2::Digest;
+
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("a.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"a": 1}"#);
+        env.run_patch();
+        let hash = format!("{:x}", sha2::Sha256::digest(env.read_target_file("a.json")));
+        env.run_patch();
+        env.run(&["undo"]).unwrap();
+
+        let log = std::fs::read_to_string(env._root.path().join("state/patch/audit.jsonl")).unwrap();
+        let lines: Vec<serde_json::Value> = log.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let [first, second, undo] = &lines[..] else { panic!("{log}") };
+        assert_eq!(first["command"], "apply");
+        assert_eq!(first["targets"][0]["before"], serde_json::Value::Null);
+        assert_eq!(first["targets"][0]["after"], hash);
+        assert!(first["args"].is_array() && first["time"].is_string());
+        assert_eq!(second["targets"], serde_json::json!([]));
+        assert_eq!(undo["command"], "undo");
+        assert_eq!(undo["targets"][0]["before"], hash);
+        assert_eq!(undo["targets"][0]["after"], serde_json::Value::Null);
+
+        // Edits and restores are audited, and journaled for `undo`.
+        let fragment = d.join("00-a.json");
+        let fragment = fragment.to_str().unwrap();
+        env.run(&["edit", "--editor", "sed -i s/1/2/", fragment]).unwrap();
+        let edited = format!("{:x}", sha2::Sha256::digest(env.read_target_file("a.json")));
+        env.run(&["--backup", "edit", "--editor", "sed -i s/2/3/", fragment]).unwrap();
+        let target = env.target_dir.path().join("a.json");
+        env.run(&["backups", "restore", target.to_str().unwrap()]).unwrap();
+        assert_eq!(env.read_target_json("a.json")["a"], 2);
+        env.run(&["undo"]).unwrap();
+        assert_eq!(env.read_target_json("a.json")["a"], 3);
+
+        let log = std::fs::read_to_string(env._root.path().join("state/patch/audit.jsonl")).unwrap();
+        let lines: Vec<serde_json::Value> = log.lines().skip(3)
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let [edit, _, restore, undo] = &lines[..] else { panic!("{log}") };
+        assert_eq!(edit["command"], "edit");
+        assert_eq!(edit["targets"][0]["before"], serde_json::Value::Null);
+        assert_eq!(edit["targets"][0]["after"], edited);
+        assert_eq!(restore["command"], "restore");
+        assert_eq!(restore["targets"][0]["after"], edited);
+        assert_eq!(undo["targets"][0]["before"], edited);
+    }
+
+    #[test]
+    fn notification_test() {
+        let revert = |target: &str| Revert {
+            host: Host::Local, target: target.into(), before: String::new(), after: "a".into(),
+            redacted: false,
+        };
+        assert_eq!(notification(&[], None), None);
+
+        let journal: Vec<Revert> = (0..12).map(|i| revert(&format!("/t/{i}"))).collect();
+        let (summary, body) = notification(&journal[..2], None).unwrap();
+        assert_eq!((summary.as_str(), body.as_str()), ("Updated 2 target(s)", "/t/0\n/t/1"));
+        let (_, body) = notification(&journal, None).unwrap();
+        assert!(body.ends_with("/t/9\nand 2 more"), "{body}");
+
+        let error = anyhow::anyhow!("boom").context("Parse x");
+        assert_eq!(notification(&journal, Some(&error)).unwrap(),
+            ("Applying config patches failed".into(), "Parse x: boom".into()));
+    }
+
+    #[test]
+    fn status_report_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("a.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"a": 1}"#);
+        let report = status_report(&env.cli(&[])).unwrap();
+        assert_eq!(report[0]["state"], "pending");
+        assert_eq!(report[0]["applied"], serde_json::Value::Null);
+
+        env.run_patch();
+        let report = status_report(&env.cli(&[])).unwrap();
+        assert_eq!(report[0]["state"], "ok");
+        assert!(report[0]["applied"].is_u64());
+    }
+
+    #[test]
+    fn metrics_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("a.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"a": 1}"#);
+        let d = env.create_patch_dir("b.json.d");
+        env.write_named_patch_file(&d, "00-b.json", br#"{"b": 1}"#);
+        let path = env._root.path().join("patch.prom");
+        let path = path.to_str().unwrap();
+        env.run(&["--metrics-file", path]).unwrap();
+        env.write_target_file("a.json", br#"{"a": 2}"#);
+        env.run(&["--metrics-file", path]).unwrap();
+
+        let metrics = std::fs::read_to_string(path).unwrap();
+        for line in ["patch_targets 2\n", "patch_targets_drifted 1\n",
+            "patch_targets_changed 1\n", "patch_last_run_success 1\n",
+            "# TYPE patch_targets gauge\n"]
+        {
+            assert!(metrics.contains(line), "{line} in {metrics}");
+        }
 
-    Ok(())
-}
+        env.write_named_patch_file(&d, "00-b.json", b"{");
+        assert!(env.run(&["--metrics-file", path]).is_err());
+        let metrics = std::fs::read_to_string(path).unwrap();
+        assert!(metrics.contains("patch_last_run_success 0\n"), "{metrics}");
+    }
 
-#[derive(Default)]
-enum Config {
-    Json(serde_json::Value),
-    Toml(TomlConfig),
-    Text(String),
-    #[default] None,
-}
+    #[test]
+    fn webhook_test() {
+        use std::io::{BufRead, Read, Write};
 
-struct TomlConfig {
-    value: toml::Value,
-}
+        let journal = [Revert {
+            host: Host::Local, target: "/t/a".into(), before: String::new(), after: "a".into(),
+            redacted: false,
+        }];
+        let webhook = |format| Webhook { url: "https://x/send/".into(), format, token: None };
+        let (method, url, json) =
+            webhook_request(&webhook(WebhookFormat::Json), "Updated", "/t/a", &journal, false);
+        assert_eq!((method, url.as_str()), ("POST", "https://x/send/"));
+        assert_eq!(json, serde_json::json!({
+            "summary": "Updated", "body": "/t/a", "failed": false, "targets": ["/t/a"],
+        }));
+        let (_, _, json) =
+            webhook_request(&webhook(WebhookFormat::Slack), "Updated", "/t/a", &journal, false);
+        assert_eq!(json, serde_json::json!({"text": "Updated\n/t/a"}));
+        let (method, url, json) =
+            webhook_request(&webhook(WebhookFormat::Matrix), "Updated", "/t/a", &journal, false);
+        assert_eq!(method, "PUT");
+        assert!(url.strip_prefix("https://x/send/").unwrap().parse::<u128>().is_ok(), "{url}");
+        assert_eq!(json["msgtype"], "m.text");
 
-impl Default for TomlConfig {
-    fn default() -> Self {
-        TomlConfig {
-            value: toml::Value::Table(Default::default()),
-        }
+        // A server reading one request.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+            let mut head = vec![];
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                head.push(line);
+            }
+            let length: usize = head.iter()
+                .find_map(|line| line.to_lowercase().strip_prefix("content-length: ")
+                    .map(|n| n.trim().parse().unwrap()))
+                .unwrap();
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            (&stream).write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+            (head, String::from_utf8(body).unwrap())
+        });
+        let webhook = Webhook { url, format: WebhookFormat::Json, token: Some("t\"k".into()) };
+        post(&webhook, "Updated \"1\"", "/t/a", &journal, false);
+        let (head, body) = server.join().unwrap();
+        assert!(head.iter().any(|line| line == "Authorization: Bearer t\"k\r\n"), "{head:?}");
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap()["summary"],
+            "Updated \"1\"");
     }
-}
 
-impl ToString for TomlConfig {
-    fn to_string(&self) -> String {
-        toml::to_string_pretty(&self.value)
-            .expect("Serialize a toml::Value should not fail")
+    #[test]
+    fn annotation_test() {
+        assert_eq!(annotation("error", Path::new("patches/a,b:c.d/00-a.json"), "50%\nbad"),
+            "::error file=patches/a%2Cb%3Ac.d/00-a.json::50%25%0Abad");
+        let here = std::env::current_dir().unwrap().join("patches/x.json");
+        assert_eq!(annotation("notice", &here, "m"), "::notice file=patches/x.json::m");
     }
-}
 
-impl Config {
-    fn parse_dispatch(format: &Option<String>, text: String) -> Result<Self> {
-        use anyhow::{bail, Context};
-        use jsonc_parser::parse_to_serde_value;
+    #[test]
+    fn timings_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("timed.json.d");
+        env.write_named_patch_file(&d, "a.json", br#"{"a": 1}"#);
+        env.write_target_file("timed.json", b"{}");
 
-        match format.as_deref() {
-            Some("json") => Ok(Config::Json(
-                parse_to_serde_value(&text, &Default::default())?
-                    .context(format!("Possible empty json: `{text}`"))?
-            )),
-            Some("toml") => Ok(Config::Toml(TomlConfig {
-                value: toml::from_str(&text)
-                    .context(format!("Possible empty toml: `{text}`"))?,
-            })),
-            Some("text") | None => Ok(Config::Text(text)),
-            _ => bail!("Unsupported format: {format:?}"),
-        }
+        let journal = std::sync::Mutex::default();
+        let start = std::time::Instant::now();
+        let (_, stats) = apply_entries(&env.cli(&["--timings"]), &journal, start).unwrap();
+        let timings = stats[0].timings.unwrap();
+        assert!(timings.total() > std::time::Duration::ZERO);
+        let json = serde_json::to_value(&stats[0]).unwrap();
+        assert!(json["timings"]["parse"].is_f64() && json["timings"]["write"].is_f64());
+
+        let (_, stats) = apply_entries(&env.cli(&[]), &journal, start).unwrap();
+        assert_eq!(stats[0].timings, None);
+        env.run(&["--timings"]).unwrap();
+        env.run(&["--timings", "--output", "json"]).unwrap();
     }
 
-    fn try_merge(self, other: Result<Self>) -> Result<Self> {
-        use anyhow::bail;
-        use Config::*;
+    #[test]
+    fn on_parse_error_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("legacy.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"a": 1}"#);
+        env.write_named_patch_file(&d, "10-b.json", br#"{"b": "#);
+        env.write_target_file("legacy.json", br#"{"c": 3}"#);
+        assert!(env.run(&[]).is_err());
 
-        match (self, other?) {
-            (None, other) => Ok(other),
-            (Json(a), Json(b)) => Ok(Json(a.merge(b))),
-            (Toml(a), Toml(b)) => Ok(Toml(a.merge(b))),
-            (Text(a), Text(b)) => Ok(Text(a.merge(b))),
-            _ => bail!("Cannot merge different types"),
-        }
+        std::fs::write(env.patch_dir.path().join("patch.toml"), "on_parse_error = \"skip\"\n")
+            .unwrap();
+        env.run_patch();
+        assert_eq!(env.read_target_json("legacy.json"), serde_json::json!({"a": 1, "c": 3}));
+
+        std::fs::write(env.patch_dir.path().join("patch.toml"), "on_parse_error = \"text\"\n")
+            .unwrap();
+        env.run_patch();
+        env.run_patch();
+        assert_eq!(env.read_target_file("legacy.json"), "{\"a\": 1}\n{\"b\": ");
     }
-}
 
-impl From<Config> for String {
-    fn from(config: Config) -> Self {
-        use Config::*;
-        use serde_json::to_string_pretty;
-        match config {
-            Json(json) => to_string_pretty(&json)
-                .expect("Serialize a serde_json::Value should not fail"),
-            Toml(toml) => toml.to_string(),
-            Text(text) => text,
-            None => String::new(),
+    #[test]
+    fn compressed_test() {
+        let env = TestEnv::new();
+        for (name, compression) in [("state.json.gz", Compression::Gzip),
+            ("state.json.zst", Compression::Zstd)]
+        {
+            let d = env.create_patch_dir(&format!("{name}.d"));
+            env.write_named_patch_file(&d, "a.json", br#"{"a": 1}"#);
+            let target = env.write_target_file(name,
+                &compression.compress(r#"{"b": 2}"#).unwrap());
+            env.run_patch();
+            env.run_patch();
+
+            let text = compression.decompress(&std::fs::read(&target).unwrap()).unwrap();
+            assert_eq!(serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+                serde_json::json!({"b": 2, "a": 1}));
+            assert_eq!(Host::Local.read(&target).unwrap(), text);
         }
     }
-}
 
-trait Mergeable: Default + ToString {
-    fn merge(self, other: Self) -> Self;
-}
+    #[test]
+    fn mapped_read_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("blocklist.d");
+        let hosts: String = (0..100_000).map(|i| format!("0.0.0.0 host{i}.example\n")).collect();
+        assert!(hosts.len() as u64 > MAP_SIZE);
+        env.write_named_patch_file(&d, "00-big", hosts.as_bytes());
+        env.write_named_patch_file(&d, "10-small", b"0.0.0.0 last.example");
+        let target = env.write_target_file("blocklist", b"");
+        env.run(&["--no-seed"]).unwrap();
 
-impl Mergeable for serde_json::Value {
-    fn merge(mut self, other: Self) -> Self {
-        use json_patch::merge;
-        merge(&mut self, &other);
-        self
+        let text = read_text(&target).unwrap();
+        assert_eq!(text, format!("{hosts}\n0.0.0.0 last.example"));
+        assert!(same_content(&target, text.as_bytes()));
+        assert!(!same_content(&target, text.replace("host9", "host8").as_bytes()));
+        assert!(!same_content(&target, b"short"));
+        assert!(!same_content(&target.with_extension("missing"), b""));
     }
-}
 
-impl Mergeable for String {
-    fn merge(mut self, other: Self) -> Self {
-        self.push('\n');
-        self.push_str(&other);
-        self
+    #[test]
+    fn brewfile_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("Brewfile.d");
+        env.write_named_patch_file(&d, "00-base", br#"
+            # Everywhere
+            brew "git"
+            brew "jq" # for scripts
+            cask "firefox", args: { appdir: "~/Applications" }
+        "#);
+        env.write_named_patch_file(&d, "10-dev", br#"
+            tap "homebrew/cask-fonts"
+            brew "git", args: ["HEAD"]
+            brew "jq"
+            mas "Xcode", id: 497799835
+        "#);
+        env.write_target_file("Brewfile", b"brew \"wget\"\nbrew \"git\", args: [\"old\"]\n");
+        env.run_patch();
+        env.run_patch();
+        assert_eq!(env.read_target_file("Brewfile"), r#"tap "homebrew/cask-fonts"
+brew "wget"
+brew "git", args: ["HEAD"]
+brew "jq"
+cask "firefox", args: { appdir: "~/Applications" }
+mas "Xcode", id: 497799835
+"#);
+
+        env.write_named_patch_file(&d, "20-other", b"brew \"git\", args: [\"with-pcre2\"]\n");
+        let error = env.run(&[]).unwrap_err();
+        assert_eq!(Failure::of(&error).0, "conflict");
+        assert!(format!("{error:#}").contains(r#"`brew "git", args: ["HEAD"]`"#), "{error:#}");
+
+        assert!(Brewfile::parse("if OS.mac?\n  cask \"iterm2\"\nend\n").is_err());
+        assert!(Brewfile::parse("brew \"a\", args: [\"x\"]\nbrew \"a\", args: [\"y\"]").is_err());
     }
-}
 
-impl Mergeable for TomlConfig {
-    fn merge(self, other: Self) -> Self {
-        fn merge_values(a: toml::Value, b: toml::Value) -> toml::Value {
-            match (a, b) {
-                (toml::Value::Table(mut left), toml::Value::Table(right)) => {
-                    for (k, v) in right {
-                        let merged = if let Some(existing) = left.remove(&k) {
-                            merge_values(existing, v)
-                        } else {
-                            v
-                        };
-                        left.insert(k, merged);
-                    }
-                    toml::Value::Table(left)
-                }
-                (_, b) => b,
-            }
-        }
+    #[test]
+    fn requirements_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("requirements.txt.d");
+        env.write_named_patch_file(&d, "00-base", br#"
+            # Shared tooling
+            --index-url https://pypi.org/simple
+            requests[socks]>=2.28
+            Django >= 4.2
+            numpy==1.24; python_version < "3.9"
+        "#);
+        env.write_named_patch_file(&d, "10-web", br#"
+            --index-url https://pypi.org/simple
+            -r base.txt
+            requests[security]  # for TLS
+            django<5, >=4.2
+            numpy==2.0; python_version >= "3.9"
+            mylib @ https://example.com/mylib.whl
+            black==24.1 \
+                --hash=sha256:abc
+        "#);
+        env.write_target_file("requirements.txt", b"stale==1\n");
+        env.run_patch();
+        env.run_patch();
+        assert_eq!(env.read_target_file("requirements.txt"), r#"--index-url https://pypi.org/simple
+-r base.txt
+requests[socks,security]>=2.28
+Django>=4.2,<5
+numpy==1.24; python_version < "3.9"
+numpy==2.0; python_version >= "3.9"
+mylib @ https://example.com/mylib.whl
+black==24.1 --hash=sha256:abc
+"#);
 
-        TomlConfig {
-            value: merge_values(self.value, other.value),
-        }
+        env.write_named_patch_file(&d, "20-pin", b"Requests==2.31\n");
+        env.write_named_patch_file(&d, "30-pin", b"requests == 2.32\n");
+        let error = env.run(&[]).unwrap_err();
+        assert_eq!(Failure::of(&error).0, "conflict");
+        assert!(format!("{error:#}").contains("`==2.31` and `==2.32`"), "{error:#}");
     }
-}
 
-#[cfg(test)]
-mod tests {
-    //! Test code are mostly AI-generated.
-    use super::*;
-    use std::io::Write;
-    use tempfile::{tempdir, tempdir_in, Builder, TempDir};
+    #[test]
+    fn front_matter_test() {
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("template.md.d");
+        std::fs::write(d.join("FORMAT"), "frontmatter\n").unwrap();
+        env.write_named_patch_file(&d, "00-base.md", b"---\nlayout: post\ntags: [a]\n---\n");
+        env.write_named_patch_file(&d, "10-draft.md",
+            b"---\n# Hidden until published\ndraft: true\ntags: [b]\n---\n");
+        env.write_target_file("template.md", b"---\ntitle: Hello\n---\n# Hello\n\n---\n\nBody.\n");
+        env.run_patch();
+        env.run_patch();
+        assert_eq!(env.read_target_file("template.md"), "---\ntitle: Hello\nlayout: post\n\
+            tags:\n- b\n# Hidden until published\ndraft: true\n---\n# Hello\n\n---\n\nBody.\n");
 
-    struct TestEnv {
-        _root: TempDir,
-        patch_dir: TempDir,
-        target_dir: TempDir,
+        let d = env.create_patch_dir("note.md.d");
+        std::fs::write(d.join("FORMAT"), "frontmatter\n").unwrap();
+        std::fs::write(d.join("NO_SEED"), "").unwrap();
+        env.write_named_patch_file(&d, "00-head.md", b"---\n---\nFirst.\n");
+        env.write_named_patch_file(&d, "10-tail.md", b"Second.\n");
+        env.write_target_file("note.md", b"");
+        env.run_patch();
+        assert_eq!(env.read_target_file("note.md"), "First.\n\nSecond.\n");
     }
 
-    impl TestEnv {
-        fn new() -> Self {
-            let root = tempdir().unwrap();
-            let patch_dir = tempdir_in(root.path()).unwrap();
-            let target_dir = tempdir_in(root.path()).unwrap();
-            
-            TestEnv {
-                _root: root,
-                patch_dir,
-                target_dir,
-            }
-        }
+    #[test]
+    fn private_test() {
+        use std::os::unix::fs::PermissionsExt;
 
-        fn create_patch_dir(&self, name: &str) -> PathBuf {
-            let d = self.patch_dir.path().join(name);
-            std::fs::create_dir_all(&d).unwrap();
-            d
+        let env = TestEnv::new();
+        let mode = |name: &str| std::fs::metadata(env.target_dir.path().join(name)).unwrap()
+            .permissions().mode() & 0o777;
+        std::fs::write(env.patch_dir.path().join("patch.toml"), "private = [\"secrets/*\"]\n")
+            .unwrap();
+        for (directory, name) in [("dot-netrc.d", ".netrc"), ("dot-ssh/config.d", ".ssh/config"),
+            ("secrets/token.d", "secrets/token"), ("public.d", "public")]
+        {
+            let d = env.create_patch_dir(directory);
+            env.write_named_patch_file(&d, "a", b"x");
+            if let Some(parent) = Path::new(name).parent() {
+                std::fs::create_dir_all(env.target_dir.path().join(parent)).unwrap();
+            }
         }
+        env.write_target_file(".ssh/config", b"");
+        std::fs::set_permissions(env.target_dir.path().join(".ssh/config"),
+            std::fs::Permissions::from_mode(0o644)).unwrap();
+        env.run_patch();
 
-        fn write_patch_file(&self, dir: &Path, prefix: &str, content: &[u8]) {
-            let temp_file = Builder::new().prefix(prefix).tempfile_in(dir).unwrap();
-            let (mut file, _) = temp_file.keep().unwrap();
-            file.write_all(content).unwrap();
-        }
+        assert_eq!(mode(".netrc"), 0o600);
+        assert_eq!(mode("secrets/token"), 0o600);
+        assert_eq!(env.read_target_file("secrets/token"), "x");
+        assert_eq!(mode(".ssh/config"), 0o644);
+        assert_ne!(mode("public"), 0o600);
 
-        fn write_named_patch_file(&self, dir: &Path, name: &str, content: &[u8]) {
-            let mut file = std::fs::File::create(dir.join(name)).unwrap();
-            file.write_all(content).unwrap();
-        }
+        assert!(glob_match(".gnupg/**", ".gnupg/private-keys-v1.d/a.key"));
+        assert!(!glob_match(".ssh/*", ".ssh/keys/id"));
+        assert!(!glob_match(".netrc", "x.netrc"));
+    }
 
-        fn run_patch(&self) {
-            super::start(Cli {
-                directory: self.patch_dir.path().to_path_buf(),
-                target: self.target_dir.path().to_path_buf(),
-                log_level: log::Level::Error,
-            }).unwrap();
-        }
+    #[test]
+    fn bake_test() {
+        use std::os::unix::fs::PermissionsExt;
 
-        fn read_target_file(&self, name: &str) -> String {
-            std::fs::read_to_string(self.target_dir.path().join(name)).unwrap()
-        }
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-config/app.toml.d");
+        env.write_named_patch_file(&d, "00-a.toml", b"a = 1\n");
+        let d = env.create_patch_dir("dot-secret.d");
+        env.write_named_patch_file(&d, "00-a", b"token");
+        std::fs::create_dir(env.target_dir.path().join(".config")).unwrap();
+        let secret = env.write_target_file(".secret", b"");
+        std::fs::set_permissions(&secret, PermissionsExt::from_mode(0o600)).unwrap();
 
-        fn read_target_json(&self, name: &str) -> serde_json::Value {
-            serde_json::from_str(&self.read_target_file(name)).unwrap()
-        }
+        let script = env.patch_dir.path().join("apply-configs");
+        env.run(&["bake", "-o", script.to_str().unwrap()]).unwrap();
+        let mode = std::fs::metadata(&script).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
 
-        fn read_target_toml(&self, name: &str) -> toml::Value {
-            toml::from_str(&self.read_target_file(name)).unwrap()
-        }
+        let home = env._root.path().join("machine");
+        let status = std::process::Command::new("sh").arg(&script).arg(&home)
+            .stdout(std::process::Stdio::null())
+            .status().unwrap();
+        assert!(status.success());
+        assert_eq!(std::fs::read_to_string(home.join(".config/app.toml")).unwrap(), "a = 1\n");
+        assert_eq!(std::fs::read_to_string(home.join(".secret")).unwrap(), "token");
+        let mode = std::fs::metadata(home.join(".secret")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
     }
 
     #[test]
-    fn argparse_test() {
-        use std::env;
-        use clap::Parser;
+    fn export_sh_test() {
+        use std::os::unix::fs::PermissionsExt;
 
-        let root = tempdir().unwrap();
-        let home = root.path().join("home");
-        std::fs::create_dir_all(&home).unwrap();
-        unsafe {
-            env::set_var("HOME", &home);
-        }
+        let env = TestEnv::new();
+        let d = env.create_patch_dir("dot-config/it's.toml.d");
+        env.write_named_patch_file(&d, "00-a.toml", b"a = \"$HOME `x`\"\n");
+        let d = env.create_patch_dir("dot-secret.d");
+        env.write_named_patch_file(&d, "00-a", b"PATCH_EOF\ntoken");
+        let d = env.create_patch_dir("state.json.gz.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"a": 1}"#);
+        std::fs::create_dir(env.target_dir.path().join(".config")).unwrap();
+        let secret = env.write_target_file(".secret", b"");
+        std::fs::set_permissions(&secret, PermissionsExt::from_mode(0o600)).unwrap();
 
-        let cli = Cli::parse_from(["patch"]);
-        assert_eq!(cli.directory, PathBuf::from("patches"));
-        assert_eq!(cli.target, home);
-        assert_eq!(cli.log_level, log::Level::Info);
+        let script = env.patch_dir.path().join("install.sh");
+        env.run(&["export", "--format", "sh", "-o", script.to_str().unwrap()]).unwrap();
+        let home = env._root.path().join("machine");
+        let status = std::process::Command::new("sh").arg(&script).arg(&home).status().unwrap();
+        assert!(status.success());
+
+        assert_eq!(std::fs::read_to_string(home.join(".config/it's.toml")).unwrap(),
+            "a = \"$HOME `x`\"\n");
+        assert_eq!(std::fs::read_to_string(home.join(".secret")).unwrap(), "PATCH_EOF\ntoken");
+        let mode = std::fs::metadata(home.join(".secret")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let state = std::fs::read(home.join("state.json.gz")).unwrap();
+        assert_eq!(Compression::Gzip.decompress(&state).unwrap(), "{\n  \"a\": 1\n}");
     }
 
     #[test]
-    fn plain_text_test() {
+    fn hook_install_test() {
         let env = TestEnv::new();
-        let d = env.create_patch_dir("dot-foo.d");
+        let git = |args: &[&str]| std::process::Command::new("git").arg("-C")
+            .arg(env.patch_dir.path()).args(args).output().unwrap();
+        assert!(git(&["init", "-q"]).status.success());
+        let hook = PathBuf::from(String::from_utf8(git(&["rev-parse", "--path-format=absolute",
+            "--git-path", "hooks"]).stdout).unwrap().trim()).join("pre-commit");
 
-        env.write_patch_file(&d, "000", b"hello");
-        env.write_patch_file(&d, "001", b"world");
-        env.run_patch();
+        std::fs::write(&hook, "#!/bin/sh\nexit 0\n").unwrap();
+        let error = env.run(&["hook", "install"]).unwrap_err();
+        assert_eq!(Failure::of(&error).0, "validation");
+        assert_eq!(std::fs::read_to_string(&hook).unwrap(), "#!/bin/sh\nexit 0\n");
 
-        let result = env.read_target_file(".foo");
-        assert!(result.contains("hello"));
-        assert!(result.contains("world"));
+        env.run(&["hook", "install", "--force"]).unwrap();
+        env.run(&["hook", "install"]).unwrap();
+        let text = std::fs::read_to_string(&hook).unwrap();
+        assert!(text.contains(HOOK_MARKER));
+        assert!(text.contains("git diff --cached --quiet -- '.' && exit 0"), "{text}");
+        assert!(text.contains(r#"--directory "$tmp"/'' "#), "{text}");
+        assert!(text.contains(" lint\n") && text.contains(" fmt --check\n"), "{text}");
+        assert!(std::process::Command::new("sh").arg("-n").arg(&hook).status().unwrap().success());
     }
 
     #[test]
-    fn json_test() {
+    fn schema_test() {
         let env = TestEnv::new();
-        let d = env.create_patch_dir("dot-bar.json.d");
-
-        env.write_patch_file(&d, "000", br#"{"foo": "bar"}"#);
-        env.write_patch_file(&d, "001", br#"{"baz": "qux"}"#);
+        let d = env.create_patch_dir("dot-schema.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"name": "me", "server": {}}"#);
+        let schema = env._root.path().join("schema.json");
+        std::fs::write(&schema, br##"{
+            "properties": {
+                "name": {"type": "string", "default": "nobody"},
+                "port": {"type": "integer", "default": 80},
+                "server": {"$ref": "#/$defs/server"},
+                "cache": {"default": {"size": 1}, "properties": {"ttl": {"default": 60}}}
+            },
+            "allOf": [{"properties": {"debug": {"default": false}}}],
+            "$defs": {"server": {"properties": {"host": {"default": "localhost"}}}}
+        }"##).unwrap();
+        env.write_named_patch_file(&d, "SCHEMA", format!("{}\n", schema.display()).as_bytes());
         env.run_patch();
 
-        let result = env.read_target_json(".bar.json");
-        assert_eq!(result["foo"], "bar");
-        assert_eq!(result["baz"], "qux");
+        assert_eq!(env.read_target_json(".schema.json"), serde_json::json!({
+            "name": "me",
+            "server": {"host": "localhost"},
+            "port": 80,
+            "cache": {"size": 1, "ttl": 60},
+            "debug": false,
+        }));
     }
 
     #[test]
-    fn json_merge_test() {
+    fn coerce_test() {
         let env = TestEnv::new();
-        let d = env.create_patch_dir("dot-baz.json.d");
+        let d = env.create_patch_dir("dot-coerce.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{
+            "port": "8080", "ratio": "0.5", "name": "x",
+            "flags": {"debug": "true", "verbose": "False"}, "ids": ["1", "two"]
+        }"#);
+        env.write_named_patch_file(&d, "COERCE",
+            b".port number\n.ratio number\n.name number\n.flags boolean\n.ids number\n");
+        env.run_patch();
+        assert_eq!(env.read_target_json(".coerce.json"), serde_json::json!({
+            "port": 8080, "ratio": 0.5, "name": "x",
+            "flags": {"debug": true, "verbose": false}, "ids": [1, "two"],
+        }));
 
-        env.write_patch_file(&d, "000", br#"{"a":1,"nested":{"x":1}}"#);
-        env.write_patch_file(&d, "001", br#"{"b":2,"nested":{"y":2}}"#);
+        let t = env.create_patch_dir("dot-coerce.toml.d");
+        env.write_named_patch_file(&t, "00-a.toml", b"[s]\nversion = 3\nenabled = true\n");
+        env.write_named_patch_file(&t, "COERCE", b".s string\n");
         env.run_patch();
+        let toml = env.read_target_toml(".coerce.toml");
+        assert_eq!(toml["s"]["version"].as_str(), Some("3"));
+        assert_eq!(toml["s"]["enabled"].as_str(), Some("true"));
 
-        let result = env.read_target_json(".baz.json");
-        assert_eq!(result["a"], 1);
-        assert_eq!(result["b"], 2);
-        assert_eq!(result["nested"]["x"], 1);
-        assert_eq!(result["nested"]["y"], 2);
+        env.write_named_patch_file(&t, "COERCE", b".s date\n");
+        let error = env.run(&[]).unwrap_err();
+        assert_eq!(Failure::of(&error), ("parse", 4));
     }
 
     #[test]
-    fn jsonc_test() {
+    fn since_test() {
         let env = TestEnv::new();
-        let d = env.create_patch_dir("dot-jsonc.json.d");
+        let git = |args: &[&str]| std::process::Command::new("git").arg("-C")
+            .arg(env.patch_dir.path()).args(args).output().unwrap();
+        let a = env.create_patch_dir("dot-a.d");
+        env.write_named_patch_file(&a, "00-a", b"a\n");
+        let b = env.create_patch_dir("dot-b.d");
+        env.write_named_patch_file(&b, "00-b", b"b\n");
+        assert!(git(&["init", "-q"]).status.success());
+        assert!(git(&["add", "."]).status.success());
+        assert!(git(&["-c", "user.name=t", "-c", "user.email=t@t", "commit", "-qm", "init"])
+            .status.success());
 
-        env.write_patch_file(&d, "000", b"{\n  // line comment\n  \"foo\": 1,\n}\n");
-        env.write_patch_file(&d, "001", b"{\n  /* block comment */\n  \"bar\": 2\n}\n");
-        env.run_patch();
+        env.run(&["--since", "HEAD"]).unwrap();
+        assert!(!env.target_dir.path().join(".a").exists());
 
-        let result = env.read_target_json(".jsonc.json");
-        assert_eq!(result["foo"], 1);
-        assert_eq!(result["bar"], 2);
+        env.write_named_patch_file(&b, "00-b", b"b2\n");
+        let c = env.create_patch_dir("dot-c.d");
+        env.write_named_patch_file(&c, "00-c", b"c\n");
+        env.run(&["--since", "HEAD"]).unwrap();
+        assert!(!env.target_dir.path().join(".a").exists());
+        assert_eq!(env.read_target_file(".b"), "b2\n");
+        assert_eq!(env.read_target_file(".c"), "c\n");
+
+        std::fs::write(env.patch_dir.path().join("patch.toml"), "sniff = true\n").unwrap();
+        env.run(&["--since", "HEAD"]).unwrap();
+        assert_eq!(env.read_target_file(".a"), "a\n");
+
+        let error = env.run(&["--since", "no-such-revision"]).unwrap_err();
+        assert_eq!(Failure::of(&error).0, "discovery");
     }
 
     #[test]
-    fn toml_test() {
+    fn install_login_test() {
         let env = TestEnv::new();
-        let d = env.create_patch_dir("dot-toml.toml.d");
+        let dir = tempdir_in(env._root.path()).unwrap();
+        env.run(&["--prune", "install-login", "--dir", dir.path().to_str().unwrap()]).unwrap();
 
-        env.write_named_patch_file(&d, "000.toml", b"foo = \"bar\"\n[nested]\nx = 1\n");
-        env.write_named_patch_file(&d, "001.toml", b"baz = \"qux\"\n[nested]\ny = 2\n");
-        env.run_patch();
+        let name = if cfg!(target_os = "macos") { "patch.plist" } else { "patch.desktop" };
+        let text = std::fs::read_to_string(dir.path().join(name)).unwrap();
+        assert!(text.contains(&env.patch_dir.path().display().to_string()), "{text}");
+        assert!(text.contains("--prune"), "{text}");
 
-        let result = env.read_target_toml(".toml.toml");
-        assert_eq!(result["foo"].as_str().unwrap(), "bar");
-        assert_eq!(result["baz"].as_str().unwrap(), "qux");
-        assert_eq!(result["nested"]["x"].as_integer().unwrap(), 1);
-        assert_eq!(result["nested"]["y"].as_integer().unwrap(), 2);
+        let words = ["/opt/my tools/patch.rs".into(), "--directory".into(), "a\\b&c%".into()];
+        let entry = autostart_entry(&words, "/bin");
+        let exec = entry.lines().find_map(|l| l.strip_prefix("Exec=")).unwrap();
+        assert_eq!(exec, r#"env PATH=/bin "/opt/my tools/patch.rs" --directory "a\\\\b&c%%""#);
+
+        let agent = launch_agent(&words, "/bin");
+        assert!(agent.contains("<string>a\\b&amp;c%</string>\n"), "{agent}");
+        assert!(agent.contains("<key>RunAtLoad</key>\n    <true/>\n"), "{agent}");
     }
 
     #[test]
-    fn ignore_test() {
-        let env = TestEnv::new();
-        let d = env.create_patch_dir("dot-ignore.d");
+    fn run_journal_test() {
+        use std::os::unix::fs::PermissionsExt;
 
-        env.write_named_patch_file(&d, "000", b"keep-this");
-        env.write_named_patch_file(&d, "AGENTS.md", b"secret");
-        env.write_named_patch_file(&d, "README.md", b"topline");
+        let env = TestEnv::new();
+        std::fs::write(env.patch_dir.path().join("patch.toml"), b"[backups]\nkeep = 2\n").unwrap();
+        let d = env.create_patch_dir("dot-secret.json.d");
+        env.write_named_patch_file(&d, "00-a.json", br#"{"token": "hunter2"}"#);
+        env.write_named_patch_file(&d, "SENSITIVE", b".token\n");
         env.run_patch();
 
-        let result = env.read_target_file(".ignore");
-        assert!(result.contains("keep-this"));
-        assert!(!result.contains("secret"));
-        assert!(!result.contains("topline"));
+        let runs = env._root.path().join("state/patch/runs");
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&runs), 0o700);
+        let journal = runs.read_dir().unwrap().next().unwrap().unwrap().path();
+        assert_eq!(mode(&journal), 0o600);
+        let text = std::fs::read_to_string(&journal).unwrap();
+        assert!(!text.contains("hunter2") && text.contains(r#""redacted":true"#), "{text}");
+
+        // Drift is still told from the hash.
+        let report = status_report(&env.cli(&[])).unwrap();
+        assert_eq!(report[0]["state"], "ok");
+        env.run(&["undo"]).unwrap();
+        assert_eq!(env.read_target_json(".secret.json")["token"], "hunter2");
+
+        for v in 0..3 {
+            env.write_named_patch_file(&d, "00-a.json", format!(r#"{{"v": {v}}}"#).as_bytes());
+            std::thread::sleep(std::time::Duration::from_millis(2));
+            env.run_patch();
+        }
+        assert_eq!(runs.read_dir().unwrap().count(), 2);
     }
 }